@@ -0,0 +1,120 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Measures the cost of sending many small messages over a socket pair.
+//!
+//! Run with `cargo run --release --example small_messages [count]`. Reports the elapsed time
+//! and the number of heap allocations made per message.
+
+extern crate capnp;
+extern crate capnp_gj;
+extern crate gj;
+extern crate gjio;
+
+use std::alloc::{GlobalAlloc, System, Layout};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use capnp::{any_pointer, message, primitive_list};
+use capnp_gj::serialize;
+use gj::{EventLoop, Promise};
+use gjio::{AsyncRead, AsyncWrite};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn write_messages<S>(stream: S,
+                     message: message::Builder<message::HeapAllocator>,
+                     remaining: u64) -> Promise<(), capnp::Error>
+    where S: AsyncWrite + 'static
+{
+    if remaining == 0 {
+        return Promise::ok(())
+    }
+    serialize::write_message(stream, message).then(move |(stream, message)| {
+        write_messages(stream, message, remaining - 1)
+    })
+}
+
+fn read_messages<S>(stream: S, remaining: u64) -> Promise<(), capnp::Error>
+    where S: AsyncRead + 'static
+{
+    if remaining == 0 {
+        return Promise::ok(())
+    }
+    serialize::read_message(stream, message::ReaderOptions::new()).then(move |(stream, _)| {
+        read_messages(stream, remaining - 1)
+    })
+}
+
+pub fn main() {
+    let count: u64 = match ::std::env::args().nth(1) {
+        Some(s) => s.parse().expect("count must be a number"),
+        None => 100_000,
+    };
+    if count == 0 {
+        eprintln!("count must be at least 1");
+        ::std::process::exit(1);
+    }
+
+    let mut message = message::Builder::new_default();
+    {
+        let mut list = message.init_root::<any_pointer::Builder>()
+            .initn_as::<primitive_list::Builder<u64>>(4);
+        for idx in 0..4 {
+            list.set(idx, u64::from(idx));
+        }
+    }
+
+    EventLoop::top_level(move |wait_scope| -> Result<(), capnp::Error> {
+        let mut event_port = try!(gjio::EventPort::new());
+        let network = event_port.get_network();
+        let (stream0, stream1) = try!(network.new_socket_pair());
+
+        let allocations_before = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        let promises = vec![write_messages(stream0, message, count), read_messages(stream1, count)];
+        try!(Promise::all(promises.into_iter()).wait(wait_scope, &mut event_port));
+        let elapsed = start.elapsed();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations_before;
+
+        let nanos = elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos());
+        println!("{} messages in {} ms", count, nanos / 1_000_000);
+        println!("{} ns per message", nanos / count);
+        println!("{:.1} allocations per message", allocations as f64 / count as f64);
+        Ok(())
+    }).expect("event loop failed");
+}
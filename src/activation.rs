@@ -0,0 +1,276 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Adopting sockets passed in by systemd socket activation.
+//!
+//! A socket-activated daemon does not bind its own ports. Instead, systemd opens the sockets
+//! described by the daemon's `.socket` unit and passes them in as file descriptors 3 and up,
+//! announcing them with the `LISTEN_PID` and `LISTEN_FDS` environment variables. With
+//! `Accept=no` these are listening sockets; with `Accept=yes` each is a single connection.
+//!
+//! ```text
+//! for socket in try!(activation::receive_sockets(&network, SocketOptions::new(), true)) {
+//!     match socket {
+//!         ActivatedSocket::Listener(listener) => accept_loop(listener),
+//!         ActivatedSocket::Stream(stream) => serve(stream),
+//!     }
+//! }
+//! ```
+
+use std::env;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc;
+
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite, Network, SocketStream};
+
+use connect::{self, SocketOptions};
+use peer::{self, Credentials};
+
+/// The first file descriptor passed by systemd.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// A socket passed in by systemd.
+pub enum ActivatedSocket {
+    /// A listening socket, as passed for a `.socket` unit with `Accept=no`.
+    Listener(Listener),
+
+    /// A connected socket, as passed for a `.socket` unit with `Accept=yes`.
+    Stream(SocketStream),
+}
+
+/// Returns the file descriptors that systemd passed to this process, or none if it was not
+/// socket-activated. The descriptors are marked close-on-exec. If `unset_env` is true, the
+/// environment variables that announce them are removed, so that child processes do not
+/// mistake them for their own.
+pub fn listen_fds(unset_env: bool) -> io::Result<Vec<RawFd>> {
+    let pid = env::var("LISTEN_PID");
+    let count = env::var("LISTEN_FDS");
+    if unset_env {
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+    }
+    let (pid, count) = match (pid, count) {
+        (Ok(pid), Ok(count)) => (pid, count),
+        _ => return Ok(Vec::new()),
+    };
+    match pid.parse::<u32>() {
+        Ok(pid) if pid == ::std::process::id() => (),
+        Ok(_) => return Ok(Vec::new()),
+        Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid LISTEN_PID: {}", pid))),
+    }
+    let count = match count.parse::<RawFd>() {
+        Ok(count) if count >= 0 => count,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid LISTEN_FDS: {}", count))),
+    };
+
+    let fds: Vec<RawFd> = (LISTEN_FDS_START..LISTEN_FDS_START + count).collect();
+    for &fd in &fds {
+        if unsafe { ::libc::fcntl(fd, ::libc::F_SETFD, ::libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error())
+        }
+    }
+    Ok(fds)
+}
+
+/// Adopts the sockets returned by `listen_fds()`. TCP sockets, both listening and connected,
+/// get `options` applied to their connections. Unix domain sockets are supported too.
+pub fn receive_sockets(network: &Network, options: SocketOptions, unset_env: bool)
+                       -> io::Result<Vec<ActivatedSocket>>
+{
+    let mut result = Vec::new();
+    for fd in try!(listen_fds(unset_env)) {
+        result.push(try!(unsafe { adopt(network, fd, options) }));
+    }
+    Ok(result)
+}
+
+/// Takes ownership of the socket `fd`, which must be a listening or connected stream socket.
+pub unsafe fn adopt(network: &Network, fd: RawFd, options: SocketOptions) -> io::Result<ActivatedSocket> {
+    let mut listening: ::libc::c_int = 0;
+    let mut len = ::std::mem::size_of::<::libc::c_int>() as ::libc::socklen_t;
+    if ::libc::getsockopt(fd, ::libc::SOL_SOCKET, ::libc::SO_ACCEPTCONN,
+                          &mut listening as *mut _ as *mut ::libc::c_void, &mut len) < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    let mut addr: ::libc::sockaddr_storage = ::std::mem::zeroed();
+    let mut len = ::std::mem::size_of::<::libc::sockaddr_storage>() as ::libc::socklen_t;
+    if ::libc::getsockname(fd, &mut addr as *mut _ as *mut ::libc::sockaddr, &mut len) < 0 {
+        return Err(io::Error::last_os_error())
+    }
+
+    match (::libc::c_int::from(addr.ss_family), listening != 0) {
+        (::libc::AF_INET, true) | (::libc::AF_INET6, true) =>
+            Ok(ActivatedSocket::Listener(try!(Listener::from_tcp_listener(network, TcpListener::from_raw_fd(fd),
+                                                                          options)))),
+        (::libc::AF_UNIX, true) =>
+            Ok(ActivatedSocket::Listener(try!(Listener::from_unix_listener(network,
+                                                                           UnixListener::from_raw_fd(fd))))),
+        (::libc::AF_INET, false) | (::libc::AF_INET6, false) =>
+            Ok(ActivatedSocket::Stream(try!(connect::wrap_tcp_stream(network, TcpStream::from_raw_fd(fd),
+                                                                     options)))),
+        (::libc::AF_UNIX, false) =>
+            Ok(ActivatedSocket::Stream(try!(network.wrap_raw_socket_descriptor(fd)))),
+        (family, _) => {
+            ::libc::close(fd);
+            Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported socket family {}", family)))
+        }
+    }
+}
+
+enum StdListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+enum Accepted {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl StdListener {
+    fn accept(&self) -> io::Result<Accepted> {
+        match *self {
+            StdListener::Tcp(ref listener) => listener.accept().map(|(stream, _)| Accepted::Tcp(stream)),
+            StdListener::Unix(ref listener) => listener.accept().map(|(stream, _)| Accepted::Unix(stream)),
+        }
+    }
+}
+
+/// A listening socket that was opened outside of the event loop.
+///
+/// `gjio` can only listen on sockets that it opened itself, so connections are accepted on a
+/// helper thread, which passes each one to the event loop. The thread accepts connections as
+/// they arrive, whether or not `accept()` has been called; it exits once the `Listener` has
+/// been dropped and one more connection has come in, which is closed.
+pub struct Listener {
+    network: Network,
+    options: SocketOptions,
+    wakeup: SocketStream,
+    receiver: mpsc::Receiver<io::Result<Accepted>>,
+    peer_policy: Option<Box<FnMut(&Credentials) -> bool>>,
+}
+
+impl Listener {
+    /// Accepts connections on `listener`, applying `options` to each one.
+    pub fn from_tcp_listener(network: &Network, listener: TcpListener, options: SocketOptions)
+                             -> io::Result<Listener>
+    {
+        Listener::spawn(network, StdListener::Tcp(listener), options)
+    }
+
+    /// Accepts connections on the Unix domain socket `listener`.
+    pub fn from_unix_listener(network: &Network, listener: UnixListener) -> io::Result<Listener> {
+        Listener::spawn(network, StdListener::Unix(listener), SocketOptions::new())
+    }
+
+    fn spawn(network: &Network, listener: StdListener, options: SocketOptions) -> io::Result<Listener> {
+        // The helper thread blocks in accept(), whatever the socket was set up with.
+        try!(match listener {
+            StdListener::Tcp(ref listener) => listener.set_nonblocking(false),
+            StdListener::Unix(ref listener) => listener.set_nonblocking(false),
+        });
+        let (sender, receiver) = mpsc::channel();
+        let spawned = network.socket_spawn(move |mut wakeup, wait_scope, mut event_port| {
+            loop {
+                let result = listener.accept();
+                let keep_going = match result {
+                    Ok(_) => true,
+                    Err(ref e) => e.kind() == io::ErrorKind::ConnectionAborted,
+                };
+                if sender.send(result).is_err() ||
+                    wakeup.write(vec![0u8]).wait(wait_scope, &mut event_port).is_err() ||
+                    !keep_going
+                {
+                    return Ok(())
+                }
+            }
+        });
+        match spawned {
+            Ok((_, wakeup)) => Ok(Listener {
+                network: network.clone(),
+                options: options,
+                wakeup: wakeup,
+                receiver: receiver,
+                peer_policy: None,
+            }),
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, format!("{}", e))),
+        }
+    }
+
+    /// Calls `policy` with the credentials of each process that connects to a Unix domain socket,
+    /// and closes the connection unless it returns true. A connection whose credentials cannot be
+    /// retrieved is closed too. Either way, nothing is read from it. TCP connections are not
+    /// affected.
+    pub fn peer_policy<F>(mut self, policy: F) -> Listener
+        where F: FnMut(&Credentials) -> bool + 'static
+    {
+        self.peer_policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Waits for the next connection.
+    pub fn accept(self) -> Promise<(Listener, SocketStream), io::Error> {
+        self.accept_with_credentials().map(|(listener, stream, _)| Ok((listener, stream)))
+    }
+
+    /// Waits for the next connection, and returns its peer's credentials if it came in on a Unix
+    /// domain socket.
+    pub fn accept_with_credentials(mut self)
+                                   -> Promise<(Listener, SocketStream, Option<Credentials>), io::Error>
+    {
+        self.wakeup.try_read(vec![0u8], 1).then(move |(_, n)| {
+            if n == 0 {
+                return Promise::err(io::Error::new(io::ErrorKind::Other, "accept thread has exited"))
+            }
+            let accepted = match self.receiver.recv().expect("wakeup without a connection") {
+                Ok(accepted) => accepted,
+                Err(e) => return Promise::err(e),
+            };
+            let accepted = match accepted {
+                Accepted::Tcp(stream) =>
+                    connect::wrap_tcp_stream(&self.network, stream, self.options).map(|stream| (stream, None)),
+                Accepted::Unix(stream) => {
+                    let credentials = peer::credentials(stream.as_raw_fd()).ok();
+                    let admitted = match (self.peer_policy.as_mut(), credentials) {
+                        (None, _) => true,
+                        (Some(policy), Some(credentials)) => policy(&credentials),
+                        (Some(_), None) => false,
+                    };
+                    if !admitted {
+                        drop(stream);
+                        return self.accept_with_credentials()
+                    }
+                    unsafe { self.network.wrap_raw_socket_descriptor(stream.into_raw_fd()) }
+                        .map(|stream| (stream, credentials))
+                }
+            };
+            match accepted {
+                Ok((stream, credentials)) => Promise::ok((self, stream, credentials)),
+                Err(e) => Promise::err(e),
+            }
+        })
+    }
+}
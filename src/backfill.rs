@@ -0,0 +1,160 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Catching up from a log before following a live stream.
+//!
+//! A consumer that was away reads what it missed from a persisted log, such as a file of
+//! messages in the standard framing, and then moves on to a live stream of the same messages.
+//! The two usually overlap, since the live stream starts wherever its producer happens to be.
+//! Each message carries a sequence number, which the application extracts; `BackfillReader`
+//! skips live messages that the log already had, and fails if there is a gap between them.
+
+use capnp::message;
+use gj::Promise;
+use gjio::AsyncRead;
+use serialize::{self, OwnedSegments};
+
+type Message = message::Reader<OwnedSegments>;
+
+/// A read from a `BackfillReader`, which hands back the reader along with `M`.
+type Read<L, S, F, M> = Promise<(BackfillReader<L, S, F>, M), ::capnp::Error>;
+
+/// Reads the messages of `log`, then those of `live` that come after them.
+pub struct BackfillReader<L, S, F> where L: AsyncRead, S: AsyncRead {
+    log: Option<L>,
+    live: S,
+    sequence_of: F,
+    last: Option<u64>,
+    reader_options: message::ReaderOptions,
+    options: serialize::Options,
+}
+
+impl <L, S, F> BackfillReader<L, S, F>
+    where L: AsyncRead + 'static, S: AsyncRead + 'static,
+          F: FnMut(&message::Reader<OwnedSegments>) -> ::capnp::Result<u64> + 'static
+{
+    /// `sequence_of` returns a message's sequence number. Sequence numbers must increase by one
+    /// from each message to the next.
+    pub fn new(log: L, live: S, sequence_of: F) -> BackfillReader<L, S, F> {
+        BackfillReader::with_options(log, live, sequence_of, message::ReaderOptions::new(), serialize::Options::new())
+    }
+
+    pub fn with_options(log: L, live: S, sequence_of: F,
+                        reader_options: message::ReaderOptions,
+                        options: serialize::Options) -> BackfillReader<L, S, F>
+    {
+        BackfillReader {
+            log: Some(log),
+            live: live,
+            sequence_of: sequence_of,
+            last: None,
+            reader_options: reader_options,
+            options: options,
+        }
+    }
+
+    /// Returns true once the log has been read to its end, and messages come from the live
+    /// stream.
+    pub fn is_live(&self) -> bool {
+        self.log.is_none()
+    }
+
+    /// The sequence number of the last message returned.
+    pub fn last_sequence(&self) -> Option<u64> {
+        self.last
+    }
+
+    /// Returns None on EOF of the live stream.
+    pub fn try_read_message(self) -> Read<L, S, F, Option<Message>> {
+        let BackfillReader { log, live, sequence_of, last, reader_options, options } = self;
+        match log {
+            Some(log) => {
+                serialize::try_read_message_with_options(log, reader_options, options)
+                    .then(move |(log, message)| {
+                        let mut reader = BackfillReader {
+                            log: None,
+                            live: live,
+                            sequence_of: sequence_of,
+                            last: last,
+                            reader_options: reader_options,
+                            options: options,
+                        };
+                        match message {
+                            // The log is done with, so it is closed here.
+                            None => reader.try_read_message(),
+                            Some(message) => {
+                                let sequence = pry!((reader.sequence_of)(&message));
+                                if let Some(last) = last {
+                                    if sequence <= last {
+                                        return Promise::err(::capnp::Error::failed(
+                                            format!("Log has message {} after message {}", sequence, last)))
+                                    }
+                                }
+                                reader.log = Some(log);
+                                reader.last = Some(sequence);
+                                Promise::ok((reader, Some(message)))
+                            }
+                        }
+                    })
+            }
+            None => {
+                serialize::try_read_message_with_options(live, reader_options, options)
+                    .then(move |(live, message)| {
+                        let mut reader = BackfillReader {
+                            log: None,
+                            live: live,
+                            sequence_of: sequence_of,
+                            last: last,
+                            reader_options: reader_options,
+                            options: options,
+                        };
+                        let message = match message {
+                            None => return Promise::ok((reader, None)),
+                            Some(m) => m,
+                        };
+                        let sequence = pry!((reader.sequence_of)(&message));
+                        match last {
+                            // Already read from the log.
+                            Some(last) if sequence <= last => reader.try_read_message(),
+                            Some(last) if sequence > last + 1 => Promise::err(::capnp::Error::failed(
+                                format!("Live stream skips from message {} to message {}", last, sequence))),
+                            _ => {
+                                reader.last = Some(sequence);
+                                Promise::ok((reader, Some(message)))
+                            }
+                        }
+                    })
+            }
+        }
+    }
+
+    pub fn read_message(self) -> Read<L, S, F, Message> {
+        self.try_read_message().map(|(reader, message)| match message {
+            Some(m) => Ok((reader, m)),
+            None => Err(::Error::CleanEof.into()),
+        })
+    }
+
+    /// Returns the log, if it has not been read to its end, and the live stream.
+    pub fn into_inner(self) -> (Option<L>, S) {
+        (self.log, self.live)
+    }
+}
@@ -0,0 +1,207 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Bounding the memory held by messages that are being read, across many connections at once.
+//!
+//! Each connection's reader options bound the size of one message, but a server with many
+//! peers that all send large messages at the same moment can still run out of memory. A
+//! `MemoryBudget` is a number of bytes shared by every read made with it. A read takes its share
+//! once the segment table has arrived and before space for the body is allocated, and gives it
+//! back when the message it produced is dropped. When the budget is used up, a read either
+//! fails with `Error::BudgetExceeded` or waits for other messages to be dropped.
+//!
+//! ```text
+//! let budget = MemoryBudget::new(256 << 20, WhenExhausted::Wait);
+//! // For each connection:
+//! budget::read_message(stream, ReaderOptions::new(), serialize::Options::new(), &budget)
+//! ```
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use capnp::{message, Word};
+use gj::Promise;
+use gjio::AsyncRead;
+
+use fulfiller::{self, Fulfiller};
+use serialize::{self, OwnedSegments};
+use Error;
+
+/// What a read does when the budget does not have room for its message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WhenExhausted {
+    /// Fail with `Error::BudgetExceeded`.
+    Fail,
+
+    /// Wait until enough of the budget has been given back. Waiting reads are served in the
+    /// order they started waiting.
+    Wait,
+}
+
+struct Budget {
+    capacity: u64,
+    used: u64,
+    when_exhausted: WhenExhausted,
+    waiting: VecDeque<(u64, Fulfiller<Reservation>)>,
+}
+
+/// A number of bytes shared by reads on any number of connections. Clones share the budget.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    budget: Rc<RefCell<Budget>>,
+}
+
+impl MemoryBudget {
+    pub fn new(capacity_bytes: u64, when_exhausted: WhenExhausted) -> MemoryBudget {
+        MemoryBudget {
+            budget: Rc::new(RefCell::new(Budget {
+                capacity: capacity_bytes,
+                used: 0,
+                when_exhausted: when_exhausted,
+                waiting: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// The number of bytes currently held by reservations.
+    pub fn used(&self) -> u64 {
+        self.budget.borrow().used
+    }
+
+    /// The number of bytes not currently held by reservations.
+    pub fn available(&self) -> u64 {
+        let budget = self.budget.borrow();
+        budget.capacity - budget.used
+    }
+
+    /// Reserves `bytes` of the budget, for as long as the returned `Reservation` is kept. Fails
+    /// at once if `bytes` is more than the whole budget.
+    pub fn reserve(&self, bytes: u64) -> Promise<Reservation, ::capnp::Error> {
+        let mut budget = self.budget.borrow_mut();
+        let available = budget.capacity - budget.used;
+        if bytes <= available && budget.waiting.is_empty() {
+            budget.used += bytes;
+            return Promise::ok(Reservation { budget: self.clone(), bytes: bytes })
+        }
+        if bytes > budget.capacity || budget.when_exhausted == WhenExhausted::Fail {
+            return Promise::err(Error::BudgetExceeded { requested: bytes, available: available }.into())
+        }
+        let (promise, fulfiller) = fulfiller::and_fulfiller();
+        budget.waiting.push_back((bytes, fulfiller));
+        promise
+    }
+
+    fn release(&self, bytes: u64) {
+        let mut granted = Vec::new();
+        {
+            let mut budget = self.budget.borrow_mut();
+            budget.used -= bytes;
+            while budget.waiting.front().map_or(false, |&(n, _)| n <= budget.capacity - budget.used) {
+                let (n, fulfiller) = budget.waiting.pop_front().unwrap();
+                budget.used += n;
+                granted.push((n, fulfiller));
+            }
+        }
+        // Fulfilled outside the borrow, since a reservation whose waiter has gone away is
+        // dropped, and so released, right away.
+        for (n, fulfiller) in granted {
+            fulfiller.fulfill(Reservation { budget: self.clone(), bytes: n });
+        }
+    }
+}
+
+/// A share of a `MemoryBudget`, given back when dropped.
+pub struct Reservation {
+    budget: MemoryBudget,
+    bytes: u64,
+}
+
+impl Reservation {
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+/// The segments of a message read against a budget, which hold their share of it until they are
+/// dropped.
+pub struct BudgetedSegments {
+    segments: OwnedSegments,
+    reservation: Reservation,
+}
+
+impl BudgetedSegments {
+    pub fn reservation(&self) -> &Reservation {
+        &self.reservation
+    }
+}
+
+impl message::ReaderSegments for BudgetedSegments {
+    fn get_segment(&self, id: u32) -> Option<&[Word]> {
+        self.segments.get_segment(id)
+    }
+}
+
+/// Like `serialize::try_read_message_with_options()`, but takes the space for the message body
+/// out of `budget` first.
+pub fn try_read_message<S>(stream: S,
+                           reader_options: message::ReaderOptions,
+                           options: serialize::Options,
+                           budget: &MemoryBudget)
+                           -> Promise<(S, Option<message::Reader<BudgetedSegments>>), ::capnp::Error>
+    where S: AsyncRead + 'static
+{
+    let budget = budget.clone();
+    serialize::read_segment_table(stream, reader_options, options).then(move |(stream, table)| {
+        let table = match table {
+            Some(t) => t,
+            None => return Promise::ok((stream, None)),
+        };
+        budget.reserve(table.total_words() as u64 * 8).then(move |reservation| {
+            serialize::read_message_body(stream, table, reader_options, options).map(move |(stream, m)| {
+                let segments = BudgetedSegments { segments: m.into_segments(), reservation: reservation };
+                Ok((stream, Some(message::Reader::new(segments, reader_options))))
+            })
+        })
+    })
+}
+
+/// Like `serialize::read_message_with_options()`, but takes the space for the message body out
+/// of `budget` first.
+pub fn read_message<S>(stream: S,
+                       reader_options: message::ReaderOptions,
+                       options: serialize::Options,
+                       budget: &MemoryBudget)
+                       -> Promise<(S, message::Reader<BudgetedSegments>), ::capnp::Error>
+    where S: AsyncRead + 'static
+{
+    try_read_message(stream, reader_options, options, budget).map(|(stream, message)| match message {
+        Some(m) => Ok((stream, m)),
+        None => Err(Error::CleanEof.into()),
+    })
+}
@@ -0,0 +1,194 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Passing messages between event loops on different threads.
+//!
+//! gj event loops are single-threaded, so a loop cannot simply be handed a promise to fulfill
+//! from another thread. A channel keeps its messages in a queue behind a mutex, and wakes the
+//! receiving loop through a socket pair, whose readable end that loop waits on like any other
+//! socket.
+//!
+//! ```text
+//! let (sender, receiver) = try!(channel::channel());
+//! thread::spawn(move || EventLoop::top_level(move |wait_scope| {
+//!     let mut event_port = try!(EventPort::new());
+//!     let mut receiver = try!(receiver.attach(&event_port.get_network()));
+//!     while let Some(message) = try!(receiver.recv().wait(wait_scope, &mut event_port)) {
+//!         ...
+//!     }
+//!     Ok(())
+//! }));
+//! try!(sender.send(&message));
+//! ```
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+
+use capnp::{message, Word};
+use gj::Promise;
+use gjio::{AsyncRead, Network, SocketStream};
+
+use fulfiller;
+use serialize::OwnedSegments;
+
+struct Shared {
+    messages: VecDeque<OwnedSegments>,
+    receiver_dropped: bool,
+}
+
+/// The receiver's hold on the queue, which turns away further messages once dropped.
+struct ReceiverShared(Arc<Mutex<Shared>>);
+
+impl Drop for ReceiverShared {
+    fn drop(&mut self) {
+        let mut shared = self.0.lock().unwrap();
+        shared.receiver_dropped = true;
+        shared.messages.clear();
+    }
+}
+
+/// Creates a channel. Both halves may be moved to other threads.
+pub fn channel() -> io::Result<(Sender, Receiver)> {
+    let (wake_write, wake_read) = try!(UnixStream::pair());
+    try!(wake_write.set_nonblocking(true));
+    let shared = Arc::new(Mutex::new(Shared { messages: VecDeque::new(), receiver_dropped: false }));
+    Ok((Sender { shared: shared.clone(), wakeups: Arc::new(wake_write) },
+        Receiver { shared: ReceiverShared(shared), wakeups: wake_read }))
+}
+
+/// The sending half of a channel. Clones send to the same receiver. Once every clone has been
+/// dropped, the receiver receives None after the messages already sent.
+#[derive(Clone)]
+pub struct Sender {
+    shared: Arc<Mutex<Shared>>,
+    wakeups: Arc<UnixStream>,
+}
+
+impl Sender {
+    /// Sends a copy of `message`. Does not block, and fails only if the receiver has been dropped.
+    pub fn send<A>(&self, message: &message::Builder<A>) -> ::capnp::Result<()>
+        where A: message::Allocator
+    {
+        self.send_segments(&message.get_segments_for_output())
+    }
+
+    /// Sends a copy of a message given as its segments.
+    pub fn send_segments(&self, segments: &[&[Word]]) -> ::capnp::Result<()> {
+        self.send_owned(OwnedSegments::copy_of(segments))
+    }
+
+    /// Sends a message that has already been read into memory, without copying it.
+    pub fn send_owned(&self, segments: OwnedSegments) -> ::capnp::Result<()> {
+        {
+            let mut shared = self.shared.lock().unwrap();
+            if shared.receiver_dropped {
+                return Err(::capnp::Error::disconnected("the receiver has been dropped".to_string()))
+            }
+            shared.messages.push_back(segments);
+        }
+        // A full socket buffer means that a wakeup is already pending.
+        match (&*self.wakeups).write(&[0]) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e.into()),
+            Ok(_) => Ok(()),
+        }
+    }
+}
+
+/// The receiving half of a channel, before it has been attached to the event loop that is to
+/// receive from it.
+pub struct Receiver {
+    shared: ReceiverShared,
+    wakeups: UnixStream,
+}
+
+impl Receiver {
+    /// Attaches to the event loop that `network` belongs to. Call this on that loop's thread.
+    pub fn attach(self, network: &Network) -> io::Result<AttachedReceiver> {
+        self.attach_with_options(network, message::ReaderOptions::new())
+    }
+
+    /// Like `attach()`, but received messages are read with `reader_options`.
+    pub fn attach_with_options(self, network: &Network, reader_options: message::ReaderOptions)
+                               -> io::Result<AttachedReceiver>
+    {
+        let shared = self.shared;
+        let wakeups = try!(unsafe { network.wrap_raw_socket_descriptor(self.wakeups.into_raw_fd()) });
+        Ok(AttachedReceiver {
+            shared: shared,
+            reads: Promise::ok(wakeups),
+            reader_options: reader_options,
+        })
+    }
+}
+
+/// The receiving half of a channel, attached to an event loop.
+pub struct AttachedReceiver {
+    shared: ReceiverShared,
+    reads: Promise<SocketStream, ::capnp::Error>,
+    reader_options: message::ReaderOptions,
+}
+
+impl AttachedReceiver {
+    /// Receives the next message, or None once every sender has been dropped and every message
+    /// they sent has been received. Receives resolve in the order they were made.
+    pub fn recv(&mut self) -> Promise<Option<message::Reader<OwnedSegments>>, ::capnp::Error> {
+        let shared = self.shared.0.clone();
+        let reader_options = self.reader_options;
+        let (done, fulfiller) = fulfiller::and_fulfiller();
+        let previous = ::std::mem::replace(&mut self.reads, Promise::never_done());
+        self.reads = previous.then(move |wakeups| {
+            next_message(shared, wakeups)
+        }).map_else(move |r| match r {
+            Ok((wakeups, segments)) => {
+                fulfiller.fulfill(segments.map(|s| message::Reader::new(s, reader_options)));
+                Ok(wakeups)
+            }
+            Err(e) => {
+                fulfiller.reject(e.clone());
+                Err(e)
+            }
+        }).eagerly_evaluate();
+        done
+    }
+}
+
+/// Takes the next message from the queue, waiting for a wakeup if there is none yet.
+fn next_message(shared: Arc<Mutex<Shared>>, mut wakeups: SocketStream)
+                -> Promise<(SocketStream, Option<OwnedSegments>), ::capnp::Error>
+{
+    if let Some(segments) = shared.lock().unwrap().messages.pop_front() {
+        return Promise::ok((wakeups, Some(segments)))
+    }
+    wakeups.try_read(vec![0u8; 64], 1).lift().then(move |(_, n)| {
+        if n == 0 {
+            // Every sender is gone, so nothing more can arrive.
+            let segments = shared.lock().unwrap().messages.pop_front();
+            Promise::ok((wakeups, segments))
+        } else {
+            next_message(shared, wakeups)
+        }
+    })
+}
@@ -0,0 +1,153 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! The standard framing as a state machine that does no I/O of its own, for applications that
+//! run their own event loop. Feed `MessageDecoder` whatever bytes arrive and it hands back each
+//! message once all of it is there; `MessageEncoder` produces the bytes to send.
+//!
+//! `serialize::BufferedMessageReader` is built on the same framing code.
+//!
+//! With futures, the decoder fits behind a `tokio_io::codec::Decoder`:
+//!
+//! ```text
+//! impl Decoder for MessageCodec {
+//!     type Item = message::Reader<OwnedSegments>;
+//!     type Error = io::Error;
+//!     fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+//!         self.decoder.push(&buf.split_to(buf.len()));
+//!         self.decoder.next_message().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+//!     }
+//! }
+//! ```
+
+use capnp::{message, Word};
+
+use serialize::{self, OwnedSegments};
+use Error;
+
+/// Splits a stream of bytes into messages.
+pub struct MessageDecoder {
+    buf: Vec<u8>,
+    start: usize,
+    reader_options: message::ReaderOptions,
+    options: serialize::Options,
+}
+
+impl MessageDecoder {
+    pub fn new(reader_options: message::ReaderOptions, options: serialize::Options) -> MessageDecoder {
+        MessageDecoder {
+            buf: Vec::new(),
+            start: 0,
+            reader_options: reader_options,
+            options: options,
+        }
+    }
+
+    /// Adds `bytes` to what has been received and returns every message that is now complete,
+    /// in order. Fails if a segment table breaks the limits in the options, in which case every
+    /// later call fails too.
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> Result<Vec<message::Reader<OwnedSegments>>, Error> {
+        self.push(bytes);
+        let mut messages = Vec::new();
+        while let Some(m) = try!(self.next_message()) {
+            messages.push(m);
+        }
+        Ok(messages)
+    }
+
+    /// Adds `bytes` to what has been received, without looking for messages. Use with
+    /// `next_message()` to take messages one at a time.
+    pub fn push(&mut self, bytes: &[u8]) {
+        if self.start == self.buf.len() {
+            self.buf.clear();
+            self.start = 0;
+        } else if self.start > self.buf.len() / 2 {
+            self.buf.drain(0..self.start);
+            self.start = 0;
+        }
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Returns the next message if all of it has been received.
+    pub fn next_message(&mut self) -> Result<Option<message::Reader<OwnedSegments>>, Error> {
+        let bytes = &self.buf[self.start..];
+        let (table_len, message_len) = try!(serialize::buffered_frame_len(bytes, &self.options, &self.reader_options));
+        match message_len {
+            Some(len) if len <= bytes.len() => {
+                let message = serialize::message_from_frame(&bytes[..len], table_len, self.reader_options);
+                self.start += len;
+                Ok(Some(message))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The number of bytes that have been received but not yet returned as part of a message.
+    pub fn buffered_bytes(&self) -> usize {
+        self.buf.len() - self.start
+    }
+
+    /// Checks that the stream ended between messages. Call this on EOF.
+    pub fn finish(&self) -> Result<(), Error> {
+        let bytes = &self.buf[self.start..];
+        if bytes.is_empty() {
+            return Ok(())
+        }
+        let (table_len, message_len) = try!(serialize::buffered_frame_len(bytes, &self.options, &self.reader_options));
+        Err(match message_len {
+            Some(len) if bytes.len() >= table_len =>
+                Error::TruncatedBody { received: (bytes.len() - table_len) as u64,
+                                       expected: (len - table_len) as u64 },
+            _ => Error::TruncatedHeader { received: bytes.len(), expected: table_len },
+        })
+    }
+}
+
+/// Turns messages into bytes in the standard framing.
+#[derive(Clone, Debug, Default)]
+pub struct MessageEncoder;
+
+impl MessageEncoder {
+    pub fn new() -> MessageEncoder {
+        MessageEncoder
+    }
+
+    /// Appends `message`, segment table first, to `out`.
+    pub fn encode<A>(&self, message: &message::Builder<A>, out: &mut Vec<u8>) where A: message::Allocator {
+        self.encode_segments(&message.get_segments_for_output(), out)
+    }
+
+    /// Appends a message made of `segments`, segment table first, to `out`.
+    ///
+    /// Panics if `segments` is empty, since no segment table can describe such a message. A
+    /// builder always has at least one segment.
+    pub fn encode_segments(&self, segments: &[&[Word]], out: &mut Vec<u8>) {
+        let table = match serialize::segment_table(segments) {
+            Ok(table) => table,
+            Err(e) => panic!("{}", e),
+        };
+        out.extend_from_slice(&table);
+        for segment in segments {
+            out.extend_from_slice(Word::words_to_bytes(segment));
+        }
+    }
+}
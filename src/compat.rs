@@ -0,0 +1,98 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Functions named and shaped like those of `capnp::serialize` and `capnp::serialize_packed`.
+//!
+//! Code that reads and writes messages with the synchronous functions can move onto an event loop
+//! by changing `use capnp::serialize;` to `use capnp_gj::compat::serialize;`, and likewise for
+//! `serialize_packed`. Each function takes the stream by value, rather than by reference, and
+//! hands it back when the returned promise resolves; otherwise the arguments are the same.
+//! Functions that do no I/O are re-exported as they are.
+//!
+//! ```text
+//! serialize::write_message(stream, &message).then(|stream| {
+//!     serialize_packed::read_message(stream, ReaderOptions::new())
+//! })
+//! ```
+
+pub mod serialize {
+    use capnp::message;
+    use gj::Promise;
+    use gjio::{AsyncRead, AsyncWrite};
+
+    pub use capnp::serialize::{read_message_from_words, write_message_to_words};
+    pub use serialize::{OwnedSegments, compute_serialized_size_in_words};
+
+    /// Reads a message from `stream`.
+    pub fn read_message<S>(stream: S, options: message::ReaderOptions)
+                           -> Promise<(S, message::Reader<OwnedSegments>), ::capnp::Error>
+        where S: AsyncRead
+    {
+        ::serialize::read_message(stream, options)
+    }
+
+    /// Like `read_message()`, but returns None on EOF.
+    pub fn try_read_message<S>(stream: S, options: message::ReaderOptions)
+                               -> Promise<(S, Option<message::Reader<OwnedSegments>>), ::capnp::Error>
+        where S: AsyncRead
+    {
+        ::serialize::try_read_message(stream, options)
+    }
+
+    /// Writes `message` to `stream`.
+    pub fn write_message<S, A>(stream: S, message: &message::Builder<A>) -> Promise<S, ::capnp::Error>
+        where S: AsyncWrite, A: message::Allocator
+    {
+        ::serialize::write_message_ref(stream, message)
+    }
+}
+
+pub mod serialize_packed {
+    use capnp::message;
+    use gj::Promise;
+    use gjio::{AsyncRead, AsyncWrite};
+
+    use serialize::OwnedSegments;
+
+    /// Reads a packed message from `stream`.
+    pub fn read_message<S>(stream: S, options: message::ReaderOptions)
+                           -> Promise<(S, message::Reader<OwnedSegments>), ::capnp::Error>
+        where S: AsyncRead
+    {
+        ::serialize_packed::read_packed_message(stream, options)
+    }
+
+    /// Like `read_message()`, but returns None on EOF.
+    pub fn try_read_message<S>(stream: S, options: message::ReaderOptions)
+                               -> Promise<(S, Option<message::Reader<OwnedSegments>>), ::capnp::Error>
+        where S: AsyncRead
+    {
+        ::serialize_packed::try_read_packed_message(stream, options)
+    }
+
+    /// Writes `message` to `stream` in packed form.
+    pub fn write_message<S, A>(stream: S, message: &message::Builder<A>) -> Promise<S, ::capnp::Error>
+        where S: AsyncWrite, A: message::Allocator
+    {
+        ::serialize_packed::write_packed_message(stream, message)
+    }
+}
@@ -0,0 +1,346 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Helpers for setting up TCP connections that carry Cap'n Proto messages.
+//!
+//! Messages tend to be small and latency-sensitive, so the defaults here differ from the
+//! operating system's: Nagle's algorithm is disabled, since otherwise a small request written
+//! right after another can sit in the kernel for up to 40ms waiting for an acknowledgement,
+//! and keepalive probes are enabled so that a peer that vanishes without closing the
+//! connection is eventually noticed.
+
+use std::cell::Cell;
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite, Network, SocketStream};
+use net2::TcpStreamExt;
+
+/// Socket options to apply to a connection before it is handed to the event loop.
+#[derive(Clone, Copy, Debug)]
+pub struct SocketOptions {
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    connect_timeout: Duration,
+}
+
+impl SocketOptions {
+    pub fn new() -> SocketOptions {
+        SocketOptions {
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(60)),
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            connect_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Sets `TCP_NODELAY`. Defaults to true.
+    pub fn nodelay(mut self, value: bool) -> SocketOptions {
+        self.nodelay = value;
+        self
+    }
+
+    /// Sets `SO_KEEPALIVE`, with the given idle time before the first probe, or disables
+    /// keepalive probes if `None`. Defaults to 60 seconds.
+    pub fn keepalive(mut self, value: Option<Duration>) -> SocketOptions {
+        self.keepalive = value;
+        self
+    }
+
+    /// Sets `SO_RCVBUF`. By default, the operating system's size is left in place, which on most
+    /// systems lets it tune the buffer to the connection.
+    pub fn recv_buffer_size(mut self, value: usize) -> SocketOptions {
+        self.recv_buffer_size = Some(value);
+        self
+    }
+
+    /// Sets `SO_SNDBUF`. By default, the operating system's size is left in place.
+    pub fn send_buffer_size(mut self, value: usize) -> SocketOptions {
+        self.send_buffer_size = Some(value);
+        self
+    }
+
+    /// How long `connect()` and `connect_host()` keep trying before giving up with an error of
+    /// kind `TimedOut`. For `connect_host()`, this covers all of the attempts together, not
+    /// counting the time taken to resolve the host. Defaults to 30 seconds.
+    pub fn connect_timeout(mut self, value: Duration) -> SocketOptions {
+        self.connect_timeout = value;
+        self
+    }
+
+    /// Applies these options to `stream`.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        try!(stream.set_nodelay(self.nodelay));
+        try!(TcpStreamExt::set_keepalive(stream, self.keepalive));
+        if let Some(size) = self.recv_buffer_size {
+            try!(stream.set_recv_buffer_size(size));
+        }
+        if let Some(size) = self.send_buffer_size {
+            try!(stream.set_send_buffer_size(size));
+        }
+        Ok(())
+    }
+}
+
+impl Default for SocketOptions {
+    fn default() -> SocketOptions { SocketOptions::new() }
+}
+
+/// Applies `options` to `stream` and then hands it to the event loop. Useful for connections
+/// accepted from a `std::net::TcpListener`.
+pub fn wrap_tcp_stream(network: &Network, stream: TcpStream, options: SocketOptions)
+                       -> io::Result<SocketStream>
+{
+    try!(options.apply(&stream));
+    network.wrap_std_tcp_stream(stream)
+}
+
+/// Connects to `addr` and applies `options` to the new connection.
+///
+/// `gjio` offers no way to reach the descriptor of a `SocketStream`, so the connection is set
+/// up on a helper thread and handed to the event loop once it is established.
+#[cfg(unix)]
+pub fn connect(network: &Network, addr: SocketAddr, options: SocketOptions)
+               -> Promise<SocketStream, io::Error>
+{
+    let deadline = ::std::time::Instant::now() + options.connect_timeout;
+    spawn_connect(network, move || {
+        let stream = try!(connect_before(addr, deadline));
+        try!(options.apply(&stream));
+        Ok(stream)
+    })
+}
+
+/// How long `connect_host()` waits for an attempt to succeed or fail before starting the next
+/// one, as recommended by RFC 8305.
+const ATTEMPT_DELAY_MS: u64 = 250;
+
+/// Connects to `host`, trying each of its addresses, and applies `options` to the connection.
+/// The addresses are looked up with `resolve()`, so the event loop keeps running meanwhile.
+///
+/// Attempts are made in the style of "happy eyeballs" (RFC 8305): addresses alternate between
+/// IPv6 and IPv4, starting with the family the resolver listed first, and each attempt starts
+/// as soon as the previous one fails, or 250ms after it started if it is still going by then.
+/// The promise resolves with the first connection to be established, at which point the
+/// remaining attempts are abandoned. If every attempt fails, it is rejected with the error from
+/// the last one to fail.
+///
+/// Each attempt runs on a helper thread of its own, which lives no longer than the connect
+/// timeout in `options`, even if the attempt is abandoned while the address is not answering.
+#[cfg(unix)]
+pub fn connect_host(network: &Network, host: &str, port: u16, options: SocketOptions)
+                    -> Promise<SocketStream, io::Error>
+{
+    let network = network.clone();
+    let host = host.to_string();
+    resolve(&network, &host, port).then(move |addrs| {
+        let addrs = interleave_families(addrs);
+        if addrs.is_empty() {
+            return Promise::err(io::Error::new(io::ErrorKind::Other,
+                                               format!("{} did not resolve to any addresses", host)))
+        }
+        race_addresses(&network, addrs, options)
+    })
+}
+
+/// Looks up the addresses of `host` on a helper thread, since the system resolver blocks, and a
+/// slow DNS server would otherwise stall every connection on the event loop.
+#[cfg(unix)]
+pub fn resolve(network: &Network, host: &str, port: u16) -> Promise<Vec<SocketAddr>, io::Error> {
+    let host = host.to_string();
+    spawn_blocking(network, move || {
+        Ok(try!((&host[..], port).to_socket_addrs()).collect())
+    })
+}
+
+/// Races connection attempts to `addrs`, which must not be empty.
+#[cfg(unix)]
+fn race_addresses(network: &Network, addrs: Vec<SocketAddr>, options: SocketOptions)
+                  -> Promise<SocketStream, io::Error>
+{
+    let deadline = ::std::time::Instant::now() + options.connect_timeout;
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let remaining = Rc::new(Cell::new(addrs.len()));
+    let mut previous: Option<mpsc::Receiver<Attempt>> = None;
+    let attempts: Vec<_> = addrs.into_iter().map(|addr| {
+        let cancelled = cancelled.clone();
+        let remaining = remaining.clone();
+        let (next, next_receiver) = mpsc::channel();
+        let previous = ::std::mem::replace(&mut previous, Some(next_receiver));
+        spawn_connect(network, move || {
+            if let Some(previous) = previous {
+                wait_for_turn(&previous, deadline);
+            }
+            if cancelled.load(Ordering::SeqCst) {
+                return Err(io::Error::new(io::ErrorKind::Other, "connection attempt abandoned"))
+            }
+            let _ = next.send(Attempt::Started);
+            let result = connect_before(addr, deadline).and_then(|stream| {
+                try!(options.apply(&stream));
+                Ok(stream)
+            });
+            if result.is_err() {
+                let _ = next.send(Attempt::Failed);
+            }
+            result
+        }).then_else(move |r| match r {
+            Ok(stream) => Promise::ok(stream),
+            Err(e) => {
+                // Only the last attempt to fail gets to reject the race.
+                remaining.set(remaining.get() - 1);
+                if remaining.get() == 0 { Promise::err(e) } else { Promise::never_done() }
+            }
+        })
+    }).collect();
+    let mut attempts = attempts.into_iter();
+    let first = attempts.next().expect("no addresses");
+    attempts.fold(first, |race, attempt| race.exclusive_join(attempt)).attach(Cancel(cancelled))
+}
+
+/// What a connection attempt tells the one after it.
+#[cfg(unix)]
+enum Attempt {
+    Started,
+    Failed,
+}
+
+/// Connects to `addr`, failing with an error of kind `TimedOut` if that is not done by
+/// `deadline`.
+#[cfg(unix)]
+fn connect_before(addr: SocketAddr, deadline: ::std::time::Instant) -> io::Result<TcpStream> {
+    let now = ::std::time::Instant::now();
+    if now >= deadline {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, format!("timed out connecting to {}", addr)))
+    }
+    TcpStream::connect_timeout(&addr, deadline - now)
+}
+
+/// Blocks until the attempt that reports to `previous` has failed, or has been going for
+/// `ATTEMPT_DELAY_MS`, or `deadline` has passed, whichever comes first.
+#[cfg(unix)]
+fn wait_for_turn(previous: &mpsc::Receiver<Attempt>, deadline: ::std::time::Instant) {
+    let now = ::std::time::Instant::now();
+    if now >= deadline {
+        return
+    }
+    match previous.recv_timeout(deadline - now) {
+        Ok(Attempt::Started) => (),
+        // It never started, because the race was over or it could not, so there is nothing
+        // to wait for.
+        Ok(Attempt::Failed) | Err(_) => return,
+    }
+    let turn = ::std::time::Instant::now() + Duration::from_millis(ATTEMPT_DELAY_MS);
+    let deadline = ::std::cmp::min(deadline, turn);
+    loop {
+        let now = ::std::time::Instant::now();
+        if now >= deadline {
+            return
+        }
+        match previous.recv_timeout(deadline - now) {
+            Ok(Attempt::Failed) | Err(mpsc::RecvTimeoutError::Timeout) => return,
+            Ok(Attempt::Started) => (),
+            // It connected, so the race is about to be called off. Starting now would only
+            // open a connection to throw away.
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                ::std::thread::sleep(deadline - now);
+                return
+            }
+        }
+    }
+}
+
+/// Tells attempts that have not started yet not to bother, once the race is over.
+#[cfg(unix)]
+struct Cancel(Arc<AtomicBool>);
+
+#[cfg(unix)]
+impl Drop for Cancel {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Reorders `addrs` so that IPv6 and IPv4 addresses alternate, starting with the family of the
+/// first address and otherwise keeping the resolver's order.
+#[cfg(unix)]
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    if addrs.is_empty() {
+        return addrs
+    }
+    let prefer_v6 = addrs[0].is_ipv6();
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.into_iter().partition(|addr| addr.is_ipv6() == prefer_v6);
+    let mut result = Vec::with_capacity(preferred.len() + other.len());
+    let mut other = other.into_iter();
+    for addr in preferred {
+        result.push(addr);
+        result.extend(other.next());
+    }
+    result.extend(other);
+    result
+}
+
+/// Runs `connect` on a new thread and wraps the stream it returns.
+#[cfg(unix)]
+fn spawn_connect<F>(network: &Network, connect: F) -> Promise<SocketStream, io::Error>
+    where F: FnOnce() -> io::Result<TcpStream> + Send + 'static
+{
+    let network = network.clone();
+    spawn_blocking(&network, connect).map(move |stream| network.wrap_std_tcp_stream(stream))
+}
+
+/// Runs `func` on a new thread, so that it can block without holding up the event loop.
+///
+/// The result travels over a channel; the socket pair only wakes up the event loop. That way, if
+/// the returned promise is dropped first, the result (an open stream, say) is dropped along with
+/// the channel rather than leaked.
+#[cfg(unix)]
+fn spawn_blocking<F, T>(network: &Network, func: F) -> Promise<T, io::Error>
+    where F: FnOnce() -> io::Result<T> + Send + 'static,
+          T: Send + 'static
+{
+    let (sender, receiver) = mpsc::channel();
+    let spawned = network.socket_spawn(move |mut wakeup, wait_scope, mut event_port| {
+        if sender.send(func()).is_ok() {
+            let _ = wakeup.write(vec![0u8]).wait(wait_scope, &mut event_port);
+        }
+        Ok(())
+    });
+    let mut wakeup = match spawned {
+        Ok((_, wakeup)) => wakeup,
+        Err(e) => return Promise::err(io::Error::new(io::ErrorKind::Other, format!("{}", e))),
+    };
+    wakeup.read(vec![0u8], 1).map(move |_| {
+        drop(wakeup);
+        match receiver.try_recv() {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "helper thread exited without a result")),
+        }
+    })
+}
@@ -0,0 +1,123 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! A message stream that can be read from and written to at the same time.
+
+use std::rc::Rc;
+
+use capnp::{message, Word};
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite};
+
+use fulfiller;
+use metrics::{self, Metrics};
+use serialize::{self, OwnedSegments};
+use writer::{AsyncMessageWriter, StreamWriter};
+
+/// Sends and receives messages on a pair of streams, usually two clones of one socket, without
+/// the caller having to pass the streams from promise to promise. Both `send()` and `recv()`
+/// may be called again before earlier calls have completed: sends are written in order, and
+/// receives resolve in order, each with the next message from the stream.
+pub struct Connection<R, W> where R: AsyncRead + 'static, W: AsyncWrite + 'static {
+    reads: Promise<R, ::capnp::Error>,
+    writer: StreamWriter<W>,
+    reader_options: message::ReaderOptions,
+    options: serialize::Options,
+    metrics: Option<Rc<Metrics>>,
+}
+
+impl <R, W> Connection<R, W> where R: AsyncRead + 'static, W: AsyncWrite + 'static {
+    pub fn new(reader: R, writer: W) -> Connection<R, W> {
+        Connection::with_options(reader, writer, message::ReaderOptions::new(), serialize::Options::new())
+    }
+
+    pub fn with_options(reader: R, writer: W,
+                        reader_options: message::ReaderOptions,
+                        options: serialize::Options) -> Connection<R, W> {
+        Connection {
+            reads: Promise::ok(reader),
+            writer: StreamWriter::with_options(writer, options),
+            reader_options: reader_options,
+            options: options,
+            metrics: None,
+        }
+    }
+
+    /// Queues `message` for writing. It is copied before this method returns. The returned
+    /// promise resolves once the message has been written, but the write happens even if the
+    /// promise is dropped.
+    pub fn send<A>(&mut self, message: &message::Builder<A>) -> Promise<(), ::capnp::Error>
+        where A: message::Allocator
+    {
+        self.writer.write_segments(&message.get_segments_for_output())
+    }
+
+    /// Receives the next message, or None on a clean EOF. The message is read from the stream
+    /// even if the returned promise is dropped, and is then lost. Once a read has failed, every
+    /// later `recv()` fails with the same error.
+    pub fn recv(&mut self) -> Promise<Option<message::Reader<OwnedSegments>>, ::capnp::Error> {
+        let reader_options = self.reader_options;
+        let options = self.options;
+        let metrics = self.metrics.clone();
+        let (done, fulfiller) = fulfiller::and_fulfiller();
+        let previous = ::std::mem::replace(&mut self.reads, Promise::never_done());
+        self.reads = previous.then(move |stream| match metrics {
+            Some(ref metrics) => metrics::try_read_message(stream, reader_options, options, metrics),
+            None => serialize::try_read_message_with_options(stream, reader_options, options),
+        }).map_else(move |r| match r {
+            Ok((stream, message)) => {
+                fulfiller.fulfill(message);
+                Ok(stream)
+            }
+            Err(e) => {
+                fulfiller.reject(e.clone());
+                Err(e)
+            }
+        }).eagerly_evaluate();
+        done
+    }
+
+    /// Reports every message received and sent from now on to `metrics`.
+    pub fn set_metrics(&mut self, metrics: Rc<Metrics>) {
+        self.writer.set_metrics(metrics.clone());
+        self.metrics = Some(metrics);
+    }
+
+    /// The number of messages passed to `send()` that have not yet been written.
+    pub fn pending_sends(&self) -> usize {
+        self.writer.pending_len()
+    }
+
+    /// Waits for all pending receives and sends to complete and then returns the streams.
+    pub fn into_inner(self) -> Promise<(R, W), ::capnp::Error> {
+        let writer = self.writer;
+        self.reads.then(move |reader| {
+            writer.into_stream().map(move |writer| Ok((reader, writer)))
+        })
+    }
+}
+
+impl <R, W> AsyncMessageWriter for Connection<R, W> where R: AsyncRead + 'static, W: AsyncWrite + 'static {
+    fn write_segments(&mut self, segments: &[&[Word]]) -> Promise<(), ::capnp::Error> {
+        self.writer.write_segments(segments)
+    }
+}
@@ -0,0 +1,288 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Matching replies to requests on a single stream, for protocols that are simpler than full RPC.
+//!
+//! A `Correlator` wraps each outgoing message in a frame (see the `frame` module) that carries a
+//! 64-bit request ID ahead of the serialized message. A request frame (kind 0) asks the peer for
+//! a reply, and a reply frame (kind 1) answers the request with the same ID. `request()` returns a
+//! promise for the reply. Both ends may send requests, and requests that arrive from the peer are
+//! handed out by `next_request()` to be answered with `reply()`, in any order.
+//!
+//! When the stream ends or fails, every request that is still waiting for its reply fails with
+//! `Disconnected`, as does every later request.
+//!
+//! ```text
+//! let correlator = Correlator::new(stream.clone(), stream, correlate::Options::new());
+//! correlator.request(&message).then(|reply| ...)
+//! ```
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::Duration;
+
+use capnp::message;
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite, Timer};
+
+use frame;
+use fulfiller::{self, Fulfiller};
+use serialize::{self, OwnedSegments};
+use Error;
+
+const REQUEST: u32 = 0;
+const REPLY: u32 = 1;
+
+/// Options for a `Correlator`.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    reader_options: message::ReaderOptions,
+    serialize_options: serialize::Options,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            reader_options: message::ReaderOptions::new(),
+            serialize_options: serialize::Options::new(),
+        }
+    }
+
+    /// Limits on the messages that arrive. The traversal limit also bounds the size of a frame.
+    pub fn reader_options(mut self, value: message::ReaderOptions) -> Options {
+        self.reader_options = value;
+        self
+    }
+
+    pub fn serialize_options(mut self, value: serialize::Options) -> Options {
+        self.serialize_options = value;
+        self
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options { Options::new() }
+}
+
+/// A request that arrived from the peer. Answer it by passing its `id()` to
+/// `Correlator::reply()`.
+pub struct IncomingRequest {
+    id: u64,
+    message: message::Reader<OwnedSegments>,
+}
+
+impl IncomingRequest {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get(&self) -> &message::Reader<OwnedSegments> {
+        &self.message
+    }
+
+    pub fn into_message(self) -> message::Reader<OwnedSegments> {
+        self.message
+    }
+}
+
+struct Shared<W> where W: AsyncWrite + 'static {
+    writes: Promise<W, ::capnp::Error>,
+    next_id: u64,
+    pending: HashMap<u64, Fulfiller<message::Reader<OwnedSegments>>>,
+    incoming: VecDeque<IncomingRequest>,
+    waiting: VecDeque<Fulfiller<Option<IncomingRequest>>>,
+
+    /// Why the stream stopped, once it has.
+    closed: Option<::capnp::Error>,
+}
+
+impl <W> Shared<W> where W: AsyncWrite + 'static {
+    fn close(&mut self, error: ::capnp::Error) {
+        if self.closed.is_some() {
+            return
+        }
+        for (_, fulfiller) in self.pending.drain() {
+            fulfiller.reject(error.clone());
+        }
+        for fulfiller in self.waiting.drain(..) {
+            fulfiller.fulfill(None);
+        }
+        self.closed = Some(error);
+    }
+}
+
+/// Sends requests and replies on one stream, and matches the replies that come back to the
+/// requests that they answer.
+pub struct Correlator<W> where W: AsyncWrite + 'static {
+    shared: Rc<RefCell<Shared<W>>>,
+    options: Options,
+    _reads: Promise<(), ::capnp::Error>,
+}
+
+impl <W> Correlator<W> where W: AsyncWrite + 'static {
+    /// Starts reading frames from `reader` at once. Dropping the `Correlator` stops the reads and
+    /// drops both streams.
+    pub fn new<R>(reader: R, writer: W, options: Options) -> Correlator<W> where R: AsyncRead + 'static {
+        let shared = Rc::new(RefCell::new(Shared {
+            writes: Promise::ok(writer),
+            next_id: 0,
+            pending: HashMap::new(),
+            incoming: VecDeque::new(),
+            waiting: VecDeque::new(),
+            closed: None,
+        }));
+        let shared1 = shared.clone();
+        let reads = read_loop(reader, shared.clone(), options).map_else(move |r| {
+            let error = match r {
+                Ok(()) => ::capnp::Error::disconnected("Peer closed the connection".to_string()),
+                Err(e) => ::capnp::Error::disconnected(format!("Connection failed: {}", e)),
+            };
+            shared1.borrow_mut().close(error);
+            Ok(())
+        }).eagerly_evaluate();
+        Correlator { shared: shared, options: options, _reads: reads }
+    }
+
+    /// Sends `message` as a request and resolves to the reply. Dropping the promise does not
+    /// withdraw the request; the reply is discarded when it arrives.
+    pub fn request<A>(&self, message: &message::Builder<A>)
+                      -> Promise<message::Reader<OwnedSegments>, ::capnp::Error>
+        where A: message::Allocator
+    {
+        let (promise, fulfiller) = fulfiller::and_fulfiller();
+        let id = {
+            let mut shared = self.shared.borrow_mut();
+            if let Some(ref e) = shared.closed {
+                return Promise::err(e.clone())
+            }
+            let id = shared.next_id;
+            shared.next_id += 1;
+            shared.pending.insert(id, fulfiller);
+            id
+        };
+        let shared = self.shared.clone();
+        self.send(REQUEST, id, message).then_else(move |r| match r {
+            Ok(()) => promise,
+            Err(e) => {
+                shared.borrow_mut().pending.remove(&id);
+                Promise::err(e)
+            }
+        })
+    }
+
+    /// Like `request()`, but fails with `Error::TimedOut` if the reply has not arrived within
+    /// `timeout`. A reply that arrives later is discarded.
+    pub fn request_with_timeout<A>(&self, message: &message::Builder<A>, timer: &Timer, timeout: Duration)
+                                   -> Promise<message::Reader<OwnedSegments>, ::capnp::Error>
+        where A: message::Allocator
+    {
+        let id = self.shared.borrow().next_id;
+        let shared = self.shared.clone();
+        let timed_out = timer.after_delay(timeout).map_else(move |r| {
+            shared.borrow_mut().pending.remove(&id);
+            match r {
+                Ok(()) => Err(Error::TimedOut.into()),
+                Err(e) => Err(Error::Io(e).into()),
+            }
+        });
+        self.request(message).exclusive_join(timed_out)
+    }
+
+    /// Resolves to the next request from the peer, or to None once the stream has ended.
+    pub fn next_request(&self) -> Promise<Option<IncomingRequest>, ::capnp::Error> {
+        let mut shared = self.shared.borrow_mut();
+        if let Some(request) = shared.incoming.pop_front() {
+            return Promise::ok(Some(request))
+        }
+        if shared.closed.is_some() {
+            return Promise::ok(None)
+        }
+        let (promise, fulfiller) = fulfiller::and_fulfiller();
+        shared.waiting.push_back(fulfiller);
+        promise
+    }
+
+    /// Sends `message` as the reply to the request with the given ID.
+    pub fn reply<A>(&self, id: u64, message: &message::Builder<A>) -> Promise<(), ::capnp::Error>
+        where A: message::Allocator
+    {
+        self.send(REPLY, id, message)
+    }
+
+    /// The number of requests that are still waiting for their replies.
+    pub fn pending_requests(&self) -> usize {
+        self.shared.borrow().pending.len()
+    }
+
+    /// Queues a frame behind earlier ones and resolves once it has been written.
+    fn send<A>(&self, kind: u32, id: u64, message: &message::Builder<A>) -> Promise<(), ::capnp::Error>
+        where A: message::Allocator
+    {
+        let options = self.options.serialize_options;
+        pry!(options.check_outgoing_words(
+            message.get_segments_for_output().iter().fold(0, |n, s| n + s.len() as u64)));
+        let mut frame = frame::begin(kind);
+        frame.push(frame::from_u64(id));
+        frame.extend(::capnp::serialize::write_message_to_words(message));
+        frame::finish(&mut frame);
+
+        frame::write_after(&mut self.shared.borrow_mut().writes, frame, options)
+    }
+}
+
+fn read_loop<R, W>(reader: R, shared: Rc<RefCell<Shared<W>>>, options: Options) -> Promise<(), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let max_payload_words = options.reader_options.traversal_limit_in_words + 1;
+    frame::try_read(reader, max_payload_words).then(move |(reader, frame)| {
+        let (kind, mut payload) = match frame {
+            Some(f) => f,
+            None => return Promise::ok(()),
+        };
+        if payload.is_empty() {
+            return Promise::err(::capnp::Error::failed("Frame is missing its request ID".to_string()))
+        }
+        let id = frame::to_u64(payload[0]);
+        let segments = pry!(OwnedSegments::from_words(payload.split_off(1)));
+        let message = message::Reader::new(segments, options.reader_options);
+        match kind {
+            REQUEST => {
+                let request = IncomingRequest { id: id, message: message };
+                let waiter = shared.borrow_mut().waiting.pop_front();
+                match waiter {
+                    Some(fulfiller) => fulfiller.fulfill(Some(request)),
+                    None => shared.borrow_mut().incoming.push_back(request),
+                }
+            }
+            REPLY => {
+                // A reply to a request that timed out, or that we never sent, is dropped.
+                let fulfiller = shared.borrow_mut().pending.remove(&id);
+                if let Some(fulfiller) = fulfiller {
+                    fulfiller.fulfill(message);
+                }
+            }
+            _ => return Promise::err(::capnp::Error::failed(format!("Unknown frame kind: {}", kind))),
+        }
+        read_loop(reader, shared, options)
+    })
+}
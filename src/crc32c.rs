@@ -0,0 +1,59 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! CRC-32C (Castagnoli), as used by `layer::Checksum::Crc32c` and the checksummed framing in
+//! `serialize`.
+
+/// A checksum that can be fed its bytes in pieces.
+pub struct Crc32c {
+    table: [u32; 256],
+    crc: u32,
+}
+
+impl Crc32c {
+    pub fn new() -> Crc32c {
+        let mut table = [0u32; 256];
+        for (idx, entry) in table.iter_mut().enumerate() {
+            let mut crc = idx as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+            }
+            *entry = crc;
+        }
+        Crc32c { table: table, crc: !0 }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        let table = &self.table;
+        self.crc = bytes.iter().fold(self.crc, |crc, &b| (crc >> 8) ^ table[((crc ^ u32::from(b)) & 0xff) as usize]);
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+pub fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32c::new();
+    crc.update(bytes);
+    crc.finish()
+}
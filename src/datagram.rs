@@ -0,0 +1,103 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! One message per UDP datagram, for traffic that has no use for a stream.
+//!
+//! A datagram holds a single message in the standard framing: the segment table followed by
+//! the segments, and nothing else. gjio has no datagram sockets, so the send and receive
+//! functions here take a `std::net::UdpSocket` and block or not according to how it has been
+//! configured; on a nonblocking socket, a receive with nothing waiting fails with
+//! `Error::Io` of kind `WouldBlock`. `encode_datagram()` and `decode_datagram()` do the
+//! framing alone, for sockets from elsewhere.
+
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+use capnp::message;
+
+use codec::MessageEncoder;
+use serialize::{self, OwnedSegments};
+use Error;
+
+/// The largest UDP payload that fits in a 1500-byte Ethernet frame over IPv4 without
+/// fragmentation.
+pub const DEFAULT_MAX_DATAGRAM_LEN: usize = 1472;
+
+/// Serializes `message` into a single datagram, failing with `Error::DatagramTooLarge` if it
+/// would be longer than `max_len` bytes.
+pub fn encode_datagram<A>(message: &message::Builder<A>, max_len: usize) -> Result<Vec<u8>, Error>
+    where A: message::Allocator
+{
+    let len = serialize::compute_serialized_size_in_words(message) * 8;
+    if len > max_len {
+        return Err(Error::DatagramTooLarge { len: len, limit: max_len })
+    }
+    let mut bytes = Vec::with_capacity(len);
+    MessageEncoder::new().encode(message, &mut bytes);
+    Ok(bytes)
+}
+
+/// Parses a datagram that holds exactly one message. The segment table is checked against
+/// `options` and `reader_options` before anything is copied.
+pub fn decode_datagram(bytes: &[u8],
+                       reader_options: message::ReaderOptions,
+                       options: serialize::Options) -> Result<message::Reader<OwnedSegments>, Error>
+{
+    let (table_len, message_len) = try!(serialize::buffered_frame_len(bytes, &options, &reader_options));
+    match message_len {
+        None => Err(Error::TruncatedHeader { received: bytes.len(), expected: table_len }),
+        Some(len) if len > bytes.len() =>
+            Err(Error::TruncatedBody { received: (bytes.len() - table_len) as u64,
+                                       expected: (len - table_len) as u64 }),
+        Some(len) if len < bytes.len() => Err(Error::TrailingBytes { count: bytes.len() - len }),
+        Some(len) => Ok(serialize::message_from_frame(&bytes[..len], table_len, reader_options)),
+    }
+}
+
+/// Sends `message` to `addr` as a single datagram of at most `max_len` bytes.
+pub fn send_message_datagram<A, T>(socket: &UdpSocket,
+                                   addr: T,
+                                   message: &message::Builder<A>,
+                                   max_len: usize) -> Result<(), Error>
+    where A: message::Allocator, T: ToSocketAddrs
+{
+    let bytes = try!(encode_datagram(message, max_len));
+    try!(socket.send_to(&bytes, addr));
+    Ok(())
+}
+
+/// Receives one datagram and parses it as a message, returning the message and its sender.
+/// A datagram longer than `max_len` bytes fails with `Error::DatagramTooLarge` rather than
+/// being parsed from what fit.
+pub fn recv_message_datagram(socket: &UdpSocket,
+                             max_len: usize,
+                             reader_options: message::ReaderOptions,
+                             options: serialize::Options)
+                             -> Result<(message::Reader<OwnedSegments>, SocketAddr), Error>
+{
+    let mut buf = vec![0; max_len + 1];
+    let (len, addr) = try!(socket.recv_from(&mut buf));
+    if len > max_len {
+        return Err(Error::DatagramTooLarge { len: len, limit: max_len })
+    }
+    let message = try!(decode_datagram(&buf[..len], reader_options, options));
+    Ok((message, addr))
+}
@@ -0,0 +1,262 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! A codec that replaces a recently sent message with a short reference to it.
+//!
+//! This suits fan-out of state that is often re-sent unchanged. Both ends keep a cache of the
+//! most recently used messages, keyed by a 64-bit hash of their serialized form. The stream is
+//! a series of frames, each starting with a word that holds two little-endian `u32`s: the frame
+//! kind and the number of words that follow. Those words start with the message's key.
+//!
+//! * A full frame (kind 0) carries the key followed by the complete serialized message, which
+//!   the reader adds to its cache.
+//! * A repeat frame (kind 1) carries only the key of a message in the cache.
+//!
+//! The writer only sends a repeat after comparing the message with the cached copy, so a hash
+//! collision costs a full frame rather than a wrong message. Both ends must be configured with
+//! the same `cache_entries`, because the reader mirrors the writer's cache evictions.
+
+use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+use capnp::{message, Word};
+use frame;
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite};
+use layer::ByteCounts;
+use serialize::{self, OwnedSegments};
+
+const FULL: u32 = 0;
+const REPEAT: u32 = 1;
+
+/// Options controlling a `DedupWriter` or `DedupReader`.
+#[derive(Clone, Copy, Debug)]
+pub struct DedupOptions {
+    cache_entries: usize,
+    reader_options: message::ReaderOptions,
+    serialize_options: serialize::Options,
+}
+
+impl DedupOptions {
+    pub fn new() -> DedupOptions {
+        DedupOptions {
+            cache_entries: 16,
+            reader_options: message::ReaderOptions::new(),
+            serialize_options: serialize::Options::new(),
+        }
+    }
+
+    /// The number of messages that each end remembers. Defaults to 16.
+    pub fn cache_entries(mut self, value: usize) -> DedupOptions {
+        self.cache_entries = ::std::cmp::max(value, 1);
+        self
+    }
+
+    /// Options for the messages returned by `DedupReader`. The traversal limit also bounds the
+    /// size of the frames that the reader will accept.
+    pub fn reader_options(mut self, value: message::ReaderOptions) -> DedupOptions {
+        self.reader_options = value;
+        self
+    }
+
+    /// Options used by `DedupWriter` when writing frames.
+    pub fn serialize_options(mut self, value: serialize::Options) -> DedupOptions {
+        self.serialize_options = value;
+        self
+    }
+}
+
+impl Default for DedupOptions {
+    fn default() -> DedupOptions { DedupOptions::new() }
+}
+
+/// Recently used messages, least recently used first.
+struct Cache {
+    entries: VecDeque<(u64, Vec<Word>)>,
+    capacity: usize,
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Cache {
+        Cache { entries: VecDeque::new(), capacity: capacity }
+    }
+
+    /// Looks up `key`, marking it as the most recently used entry.
+    fn get(&mut self, key: u64) -> Option<&Vec<Word>> {
+        match self.entries.iter().position(|e| e.0 == key) {
+            Some(idx) => {
+                let entry = self.entries.remove(idx).expect("index is in range");
+                self.entries.push_back(entry);
+                self.entries.back().map(|e| &e.1)
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, key: u64, words: Vec<Word>) {
+        if let Some(idx) = self.entries.iter().position(|e| e.0 == key) {
+            self.entries.remove(idx);
+        }
+        self.entries.push_back((key, words));
+        if self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+    }
+}
+
+fn hash_words(words: &[Word]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(Word::words_to_bytes(words));
+    hasher.finish()
+}
+
+/// Writes messages to a stream, sending repeats of recently sent messages by reference.
+pub struct DedupWriter<S> where S: AsyncWrite {
+    stream: S,
+    cache: Cache,
+    counts: ByteCounts,
+    options: DedupOptions,
+}
+
+impl <S> DedupWriter<S> where S: AsyncWrite + 'static {
+    pub fn new(stream: S, options: DedupOptions) -> DedupWriter<S> {
+        DedupWriter {
+            stream: stream,
+            cache: Cache::new(options.cache_entries),
+            counts: ByteCounts::default(),
+            options: options,
+        }
+    }
+
+    pub fn write_message<A>(self, message: &message::Builder<A>) -> Promise<DedupWriter<S>, ::capnp::Error>
+        where A: message::Allocator
+    {
+        let DedupWriter { stream, mut cache, counts, options } = self;
+        let words = ::capnp::serialize::write_message_to_words(message);
+        let message_bytes = words.len() as u64 * 8;
+        let key = hash_words(&words);
+        let repeat = match cache.get(key) {
+            Some(cached) => *cached == words,
+            None => false,
+        };
+        let mut encoded = frame::begin(if repeat { REPEAT } else { FULL });
+        encoded.push(frame::from_u64(key));
+        if !repeat {
+            encoded.extend_from_slice(&words);
+            cache.insert(key, words);
+        }
+        frame::finish(&mut encoded);
+        let counts = ByteCounts {
+            decoded_bytes: counts.decoded_bytes + message_bytes,
+            encoded_bytes: counts.encoded_bytes + encoded.len() as u64 * 8,
+        };
+        frame::write(stream, encoded, options.serialize_options).map(move |stream| {
+            Ok(DedupWriter { stream: stream, cache: cache, counts: counts, options: options })
+        })
+    }
+
+    /// Bytes of serialized messages written so far, and the bytes of frames that carried them.
+    pub fn written_bytes(&self) -> ByteCounts {
+        self.counts
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+/// Reads messages written by a `DedupWriter`.
+pub struct DedupReader<S> where S: AsyncRead {
+    stream: S,
+    cache: Cache,
+    counts: ByteCounts,
+    options: DedupOptions,
+}
+
+impl <S> DedupReader<S> where S: AsyncRead + 'static {
+    pub fn new(stream: S, options: DedupOptions) -> DedupReader<S> {
+        DedupReader {
+            stream: stream,
+            cache: Cache::new(options.cache_entries),
+            counts: ByteCounts::default(),
+            options: options,
+        }
+    }
+
+    /// Returns None on EOF.
+    pub fn try_read_message(self)
+                            -> Promise<(DedupReader<S>, Option<message::Reader<OwnedSegments>>), ::capnp::Error>
+    {
+        let DedupReader { stream, mut cache, counts, options } = self;
+        let max_payload_words = options.reader_options.traversal_limit_in_words + 1;
+        frame::try_read(stream, max_payload_words).map(move |(stream, frame)| {
+            let (kind, mut payload) = match frame {
+                None => {
+                    let reader = DedupReader { stream: stream, cache: cache, counts: counts, options: options };
+                    return Ok((reader, None))
+                }
+                Some(f) => f,
+            };
+            if payload.is_empty() {
+                return Err(::capnp::Error::failed("Frame is missing its key".to_string()))
+            }
+            let frame_bytes = (payload.len() as u64 + 1) * 8;
+            let key = frame::to_u64(payload[0]);
+            let words = match kind {
+                FULL => {
+                    let words = payload.split_off(1);
+                    cache.insert(key, words.clone());
+                    words
+                }
+                REPEAT => match cache.get(key) {
+                    Some(words) => words.clone(),
+                    None => return Err(::capnp::Error::failed(
+                        format!("Repeat of message {:016x}, which is not in the cache", key))),
+                },
+                _ => return Err(::capnp::Error::failed(format!("Unknown frame kind: {}", kind))),
+            };
+            let counts = ByteCounts {
+                decoded_bytes: counts.decoded_bytes + words.len() as u64 * 8,
+                encoded_bytes: counts.encoded_bytes + frame_bytes,
+            };
+            let segments = try!(OwnedSegments::from_words(words));
+            let message = message::Reader::new(segments, options.reader_options);
+            Ok((DedupReader { stream: stream, cache: cache, counts: counts, options: options }, Some(message)))
+        })
+    }
+
+    pub fn read_message(self) -> Promise<(DedupReader<S>, message::Reader<OwnedSegments>), ::capnp::Error> {
+        self.try_read_message().map(|(reader, message)| match message {
+            Some(m) => Ok((reader, m)),
+            None => Err(::Error::CleanEof.into()),
+        })
+    }
+
+    /// Bytes of frames read so far, and the bytes of the serialized messages they carried.
+    pub fn read_bytes(&self) -> ByteCounts {
+        self.counts
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
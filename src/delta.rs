@@ -0,0 +1,281 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! A codec that sends each message as the difference from the message before it.
+//!
+//! This suits streams, such as telemetry, where consecutive messages differ in only a few
+//! fields. Every message is first serialized as usual, and its words are compared with those of
+//! the previous message. The stream is a series of frames, each starting with a word that holds
+//! two little-endian `u32`s: the frame kind and the number of words that follow.
+//!
+//! * A keyframe (kind 0) is followed by the complete serialized message.
+//! * A delta (kind 1) is followed by a word holding the total length of the new message in words
+//!   and the number of runs, and then the runs. Each run is a word holding the offset and length
+//!   of the run, in words, followed by that many words to overwrite. Words not covered by any run
+//!   are the same as in the previous message, which is truncated or zero-extended to the new
+//!   length first.
+//!
+//! The writer falls back to a keyframe whenever a delta would not be smaller, and in any case
+//! every `keyframe_interval` messages.
+
+use capnp::{message, Word};
+use frame::{self, pair, read_pair};
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite};
+use layer::ByteCounts;
+use serialize::{self, OwnedSegments};
+
+const KEYFRAME: u32 = 0;
+const DELTA: u32 = 1;
+
+/// Options controlling a `DeltaWriter` or `DeltaReader`.
+#[derive(Clone, Copy, Debug)]
+pub struct DeltaOptions {
+    keyframe_interval: u32,
+    reader_options: message::ReaderOptions,
+    serialize_options: serialize::Options,
+}
+
+impl DeltaOptions {
+    pub fn new() -> DeltaOptions {
+        DeltaOptions {
+            keyframe_interval: 64,
+            reader_options: message::ReaderOptions::new(),
+            serialize_options: serialize::Options::new(),
+        }
+    }
+
+    /// Sends a complete message at least once every this many messages. Defaults to 64.
+    pub fn keyframe_interval(mut self, value: u32) -> DeltaOptions {
+        self.keyframe_interval = ::std::cmp::max(value, 1);
+        self
+    }
+
+    /// Options for the messages returned by `DeltaReader`. The traversal limit also bounds the
+    /// size of the frames that the reader will accept.
+    pub fn reader_options(mut self, value: message::ReaderOptions) -> DeltaOptions {
+        self.reader_options = value;
+        self
+    }
+
+    /// Options used by `DeltaWriter` when writing frames.
+    pub fn serialize_options(mut self, value: serialize::Options) -> DeltaOptions {
+        self.serialize_options = value;
+        self
+    }
+}
+
+impl Default for DeltaOptions {
+    fn default() -> DeltaOptions { DeltaOptions::new() }
+}
+
+fn encode_keyframe(words: &[Word]) -> Vec<Word> {
+    let mut keyframe = frame::begin(KEYFRAME);
+    keyframe.extend_from_slice(words);
+    frame::finish(&mut keyframe);
+    keyframe
+}
+
+fn encode_delta(previous: &[Word], words: &[Word]) -> Vec<Word> {
+    let mut delta = frame::begin(DELTA);
+    delta.push(pair(words.len() as u32, 0));
+    let unchanged = |idx: usize| idx < previous.len() && previous[idx] == words[idx];
+    let mut run_count = 0;
+    let mut idx = 0;
+    while idx < words.len() {
+        if unchanged(idx) {
+            idx += 1;
+            continue
+        }
+        let start = idx;
+        while idx < words.len() && !unchanged(idx) {
+            idx += 1;
+        }
+        delta.push(pair(start as u32, (idx - start) as u32));
+        delta.extend_from_slice(&words[start..idx]);
+        run_count += 1;
+    }
+    delta[1] = pair(words.len() as u32, run_count);
+    frame::finish(&mut delta);
+    delta
+}
+
+/// Applies a delta frame's payload to `previous`, returning the new message's words.
+fn apply_delta(previous: &[Word], payload: &[Word], reader_options: &message::ReaderOptions)
+               -> ::capnp::Result<Vec<Word>>
+{
+    if previous.is_empty() {
+        return Err(::capnp::Error::failed("Delta frame without a preceding keyframe".to_string()))
+    }
+    if payload.is_empty() {
+        return Err(::capnp::Error::failed("Delta frame is missing its header".to_string()))
+    }
+    let (total_words, run_count) = read_pair(payload[0]);
+    let total_words = total_words as usize;
+    if total_words as u64 > reader_options.traversal_limit_in_words {
+        return Err(::capnp::Error::failed(
+            format!("Message of {} words exceeds the traversal limit", total_words)))
+    }
+    let mut words = previous.to_vec();
+    words.resize(total_words, frame::zero_word());
+    let mut pos = 1;
+    for _ in 0..run_count {
+        if pos >= payload.len() {
+            return Err(::capnp::Error::failed("Delta frame ends in the middle of a run".to_string()))
+        }
+        let (offset, len) = read_pair(payload[pos]);
+        let (offset, len) = (offset as usize, len as usize);
+        pos += 1;
+        if pos + len > payload.len() || offset + len > total_words {
+            return Err(::capnp::Error::failed(
+                format!("Delta run of {} words at offset {} is out of bounds", len, offset)))
+        }
+        words[offset..(offset + len)].copy_from_slice(&payload[pos..(pos + len)]);
+        pos += len;
+    }
+    if pos != payload.len() {
+        return Err(::capnp::Error::failed(
+            format!("{} words follow the last run of a delta frame", payload.len() - pos)))
+    }
+    Ok(words)
+}
+
+/// Writes messages to a stream as keyframes and deltas.
+pub struct DeltaWriter<S> where S: AsyncWrite {
+    stream: S,
+    previous: Vec<Word>,
+    since_keyframe: u32,
+    counts: ByteCounts,
+    options: DeltaOptions,
+}
+
+impl <S> DeltaWriter<S> where S: AsyncWrite + 'static {
+    pub fn new(stream: S, options: DeltaOptions) -> DeltaWriter<S> {
+        DeltaWriter {
+            stream: stream,
+            previous: Vec::new(),
+            since_keyframe: 0,
+            counts: ByteCounts::default(),
+            options: options,
+        }
+    }
+
+    pub fn write_message<A>(self, message: &message::Builder<A>) -> Promise<DeltaWriter<S>, ::capnp::Error>
+        where A: message::Allocator
+    {
+        let DeltaWriter { stream, previous, since_keyframe, counts, options } = self;
+        let words = ::capnp::serialize::write_message_to_words(message);
+        let mut delta = None;
+        if !previous.is_empty() && since_keyframe + 1 < options.keyframe_interval {
+            let encoded = encode_delta(&previous, &words);
+            if encoded.len() <= words.len() {
+                delta = Some(encoded);
+            }
+        }
+        let (encoded, since_keyframe) = match delta {
+            Some(encoded) => (encoded, since_keyframe + 1),
+            None => (encode_keyframe(&words), 0),
+        };
+        let counts = ByteCounts {
+            decoded_bytes: counts.decoded_bytes + words.len() as u64 * 8,
+            encoded_bytes: counts.encoded_bytes + encoded.len() as u64 * 8,
+        };
+        frame::write(stream, encoded, options.serialize_options).map(move |stream| {
+            Ok(DeltaWriter {
+                stream: stream,
+                previous: words,
+                since_keyframe: since_keyframe,
+                counts: counts,
+                options: options,
+            })
+        })
+    }
+
+    /// Bytes of serialized messages written so far, and the bytes of frames that carried them.
+    pub fn written_bytes(&self) -> ByteCounts {
+        self.counts
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+/// Reads messages written by a `DeltaWriter`.
+pub struct DeltaReader<S> where S: AsyncRead {
+    stream: S,
+    previous: Vec<Word>,
+    counts: ByteCounts,
+    options: DeltaOptions,
+}
+
+impl <S> DeltaReader<S> where S: AsyncRead + 'static {
+    pub fn new(stream: S, options: DeltaOptions) -> DeltaReader<S> {
+        DeltaReader { stream: stream, previous: Vec::new(), counts: ByteCounts::default(), options: options }
+    }
+
+    /// Returns None on EOF.
+    pub fn try_read_message(self)
+                            -> Promise<(DeltaReader<S>, Option<message::Reader<OwnedSegments>>), ::capnp::Error>
+    {
+        let DeltaReader { stream, previous, counts, options } = self;
+        // A delta can hold at most one run header per word, plus its own header.
+        let max_payload_words = 2 * options.reader_options.traversal_limit_in_words + 1;
+        frame::try_read(stream, max_payload_words).map(move |(stream, frame)| {
+            let (kind, payload) = match frame {
+                None => {
+                    let reader = DeltaReader { stream: stream, previous: previous, counts: counts, options: options };
+                    return Ok((reader, None))
+                }
+                Some(f) => f,
+            };
+            let frame_bytes = (payload.len() as u64 + 1) * 8;
+            let words = match kind {
+                KEYFRAME => payload,
+                DELTA => try!(apply_delta(&previous, &payload, &options.reader_options)),
+                _ => return Err(::capnp::Error::failed(format!("Unknown frame kind: {}", kind))),
+            };
+            let counts = ByteCounts {
+                decoded_bytes: counts.decoded_bytes + words.len() as u64 * 8,
+                encoded_bytes: counts.encoded_bytes + frame_bytes,
+            };
+            let segments = try!(OwnedSegments::from_words(words.clone()));
+            let message = message::Reader::new(segments, options.reader_options);
+            Ok((DeltaReader { stream: stream, previous: words, counts: counts, options: options }, Some(message)))
+        })
+    }
+
+    pub fn read_message(self) -> Promise<(DeltaReader<S>, message::Reader<OwnedSegments>), ::capnp::Error> {
+        self.try_read_message().map(|(reader, message)| match message {
+            Some(m) => Ok((reader, m)),
+            None => Err(::Error::CleanEof.into()),
+        })
+    }
+
+    /// Bytes of frames read so far, and the bytes of the serialized messages they carried.
+    pub fn read_bytes(&self) -> ByteCounts {
+        self.counts
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
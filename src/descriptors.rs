@@ -0,0 +1,170 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Passing file descriptors over a Unix domain socket along with a message.
+//!
+//! `write_message()` attaches the descriptors to the first byte of the message with
+//! `SCM_RIGHTS`, and `read_message()` hands them back next to the message they came with, so
+//! that a process can hand another one a backing file or a connection together with the message
+//! that says what it is for. Messages with and without descriptors can be mixed on one socket.
+//!
+//! ```text
+//! try!(descriptors::write_message(&socket, &message, &[file.as_raw_fd()]));
+//!
+//! let (fds, message) = try!(descriptors::read_message(&socket, ReaderOptions::new()));
+//! ```
+//!
+//! gjio does not expose the descriptor behind a `SocketStream`, so these functions take a
+//! `std::os::unix::net::UnixStream` and block or not according to how it has been configured.
+
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+use capnp::message;
+
+/// The most descriptors that can be passed with a single message.
+pub const MAX_FDS: usize = 253;
+
+/// Writes `message` to `socket` with `fds` attached. The descriptors stay open in this process
+/// too.
+pub fn write_message<A>(socket: &UnixStream, message: &message::Builder<A>, fds: &[RawFd]) -> io::Result<()>
+    where A: message::Allocator
+{
+    if fds.len() > MAX_FDS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("cannot pass {} descriptors with one message; the limit is {}", fds.len(), MAX_FDS)))
+    }
+    let words = ::capnp::serialize::write_message_to_words(message);
+    let bytes = ::capnp::Word::words_to_bytes(&words);
+
+    // The descriptors travel with the first byte, and the rest follows as ordinary data.
+    let mut iov = ::libc::iovec { iov_base: bytes.as_ptr() as *mut ::libc::c_void, iov_len: 1 };
+    let mut control = ControlBuffer::new(fds.len());
+    unsafe {
+        let mut msg: ::libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if !fds.is_empty() {
+            msg.msg_control = control.as_mut_ptr();
+            msg.msg_controllen = control.len() as _;
+            let cmsg = ::libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = ::libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = ::libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = ::libc::CMSG_LEN((fds.len() * mem::size_of::<RawFd>()) as u32) as _;
+            ptr::copy_nonoverlapping(fds.as_ptr() as *const u8, ::libc::CMSG_DATA(cmsg),
+                                     fds.len() * mem::size_of::<RawFd>());
+        }
+        if ::libc::sendmsg(socket.as_raw_fd(), &msg, 0) < 1 {
+            return Err(io::Error::last_os_error())
+        }
+    }
+    let mut socket = socket;
+    socket.write_all(&bytes[1..])
+}
+
+/// Reads a message from `socket` along with any descriptors that were attached to it. The
+/// returned descriptors belong to the caller, and are marked close-on-exec. If reading fails
+/// partway, any descriptors that did arrive are closed.
+pub fn read_message(socket: &UnixStream, options: message::ReaderOptions)
+               -> io::Result<(Vec<RawFd>, message::Reader<::capnp::serialize::OwnedSegments>)>
+{
+    let mut first = [0u8; 1];
+    let mut iov = ::libc::iovec { iov_base: first.as_mut_ptr() as *mut ::libc::c_void, iov_len: 1 };
+    let mut control = ControlBuffer::new(MAX_FDS);
+    let mut fds = Vec::new();
+    unsafe {
+        let mut msg: ::libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr();
+        msg.msg_controllen = control.len() as _;
+        let n = ::libc::recvmsg(socket.as_raw_fd(), &mut msg, 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        let mut cmsg = ::libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == ::libc::SOL_SOCKET && (*cmsg).cmsg_type == ::libc::SCM_RIGHTS {
+                let data = ::libc::CMSG_DATA(cmsg);
+                let len = (*cmsg).cmsg_len as usize - (data as usize - cmsg as usize);
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    let mut fd: RawFd = -1;
+                    ptr::copy_nonoverlapping(data.add(i * mem::size_of::<RawFd>()), &mut fd as *mut RawFd as *mut u8,
+                                             mem::size_of::<RawFd>());
+                    fds.push(fd);
+                }
+            }
+            cmsg = ::libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        if msg.msg_flags & ::libc::MSG_CTRUNC != 0 {
+            close_all(&fds);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "received more descriptors than fit"))
+        }
+        if n == 0 {
+            close_all(&fds);
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "socket closed before a message"))
+        }
+    }
+    for &fd in &fds {
+        if unsafe { ::libc::fcntl(fd, ::libc::F_SETFD, ::libc::FD_CLOEXEC) } < 0 {
+            let e = io::Error::last_os_error();
+            close_all(&fds);
+            return Err(e)
+        }
+    }
+    let mut rest = (&first[..]).chain(socket);
+    match ::capnp::serialize::read_message(&mut rest, options) {
+        Ok(message) => Ok((fds, message)),
+        Err(e) => {
+            close_all(&fds);
+            Err(io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+}
+
+fn close_all(fds: &[RawFd]) {
+    for &fd in fds {
+        unsafe { ::libc::close(fd); }
+    }
+}
+
+/// Space for a control message carrying `count` descriptors, aligned as `cmsghdr` requires.
+struct ControlBuffer(Vec<usize>);
+
+impl ControlBuffer {
+    fn new(count: usize) -> ControlBuffer {
+        let bytes = unsafe { ::libc::CMSG_SPACE((count * mem::size_of::<RawFd>()) as u32) } as usize;
+        let words = (bytes + mem::size_of::<usize>() - 1) / mem::size_of::<usize>();
+        ControlBuffer(vec![0; words])
+    }
+
+    fn len(&self) -> usize {
+        self.0.len() * mem::size_of::<usize>()
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut ::libc::c_void {
+        self.0.as_mut_ptr() as *mut ::libc::c_void
+    }
+}
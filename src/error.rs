@@ -0,0 +1,177 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! An error type that says what went wrong while reading or writing a message.
+//!
+//! Most functions in this crate fail with a `capnp::Error`, whose description is meant for
+//! people. `serialize::read_message_detailed()` fails with an `Error` instead, so that a caller
+//! can tell a peer that hung up between messages from one that hung up partway through a
+//! message, or from one that sent a segment table that no valid message could have, and decide
+//! whether to retry, resynchronize, or drop the connection. Every `Error` converts into a
+//! `capnp::Error` with the same description.
+
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The stream ended before the first byte of a message.
+    CleanEof,
+
+    /// The stream ended partway through a segment table, after `received` of the `expected`
+    /// bytes that are known to be needed.
+    TruncatedHeader { received: usize, expected: usize },
+
+    /// The stream ended partway through a message body, after `received` of its `expected`
+    /// bytes. For a source whose length was declared with `Options::remaining_source_bytes()`,
+    /// this is reported as soon as the segment table has been read, and `received` is the
+    /// number of bytes that the source has left for the body.
+    TruncatedBody { received: u64, expected: u64 },
+
+    /// The segment table lists more segments than the limit allows.
+    TooManySegments { count: u64, limit: usize },
+
+    /// The message, or one of its segments, has more words than the limit allows.
+    MessageTooLarge { words: u64, limit: u64 },
+
+    /// The checksum that followed a message does not match the message.
+    ChecksumMismatch { expected: u32, actual: u32 },
+
+    /// No complete message arrived within the time allowed.
+    TimedOut,
+
+    /// A message could not be written within the time allowed. `written` of its `expected` bytes
+    /// were known to have been written.
+    WriteTimedOut { written: u64, expected: u64 },
+
+    /// A datagram of `len` bytes is longer than the `limit` allowed for one.
+    DatagramTooLarge { len: usize, limit: usize },
+
+    /// `count` bytes followed the message in a datagram that should have held only the message.
+    TrailingBytes { count: usize },
+
+    /// A message needed `requested` bytes of a `budget::MemoryBudget` that had only `available`.
+    BudgetExceeded { requested: u64, available: u64 },
+
+    /// A packet began with a `kind` byte that no packet has.
+    UnknownPacketKind { kind: u8 },
+
+    /// A message to be written has no segments, so no segment table can describe it.
+    NoSegments,
+
+    /// The stream failed.
+    Io(io::Error),
+}
+
+impl Error {
+    /// Returns true if the stream ended, whether between messages or partway through one.
+    pub fn is_eof(&self) -> bool {
+        match *self {
+            Error::CleanEof | Error::TruncatedHeader { .. } | Error::TruncatedBody { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::CleanEof => write!(fmt, "premature EOF"),
+            Error::TruncatedHeader { received, expected } =>
+                write!(fmt, "premature EOF: segment table cut off after {} of {} bytes", received, expected),
+            Error::TruncatedBody { received, expected } =>
+                write!(fmt, "truncated message: body has {} bytes, but only {} are available", expected, received),
+            Error::TooManySegments { count, limit } =>
+                write!(fmt, "Too many segments: {}, which exceeds the limit of {}", count, limit),
+            Error::MessageTooLarge { words, limit } =>
+                write!(fmt, "Message has {} words, which exceeds the limit of {}", words, limit),
+            Error::ChecksumMismatch { expected, actual } =>
+                write!(fmt, "Checksum mismatch: expected {:08x}, got {:08x}", expected, actual),
+            Error::TimedOut => write!(fmt, "timed out waiting for a message"),
+            Error::WriteTimedOut { written, expected } =>
+                write!(fmt, "timed out writing a message: wrote {} of {} bytes", written, expected),
+            Error::DatagramTooLarge { len, limit } =>
+                write!(fmt, "Datagram has {} bytes, which exceeds the limit of {}", len, limit),
+            Error::TrailingBytes { count } =>
+                write!(fmt, "{} unexpected bytes follow the message", count),
+            Error::UnknownPacketKind { kind } => write!(fmt, "Unknown packet kind: {}", kind),
+            Error::NoSegments => write!(fmt, "Cannot write a message with no segments"),
+            Error::BudgetExceeded { requested, available } =>
+                write!(fmt, "Message needs {} bytes of the memory budget, but only {} are available",
+                       requested, available),
+            Error::Io(ref e) => write!(fmt, "{}", e),
+        }
+    }
+}
+
+impl ::std::error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::CleanEof => "premature EOF",
+            Error::TruncatedHeader { .. } => "segment table cut off",
+            Error::TruncatedBody { .. } => "truncated message",
+            Error::TooManySegments { .. } => "too many segments",
+            Error::MessageTooLarge { .. } => "message too large",
+            Error::ChecksumMismatch { .. } => "checksum mismatch",
+            Error::TimedOut => "timed out",
+            Error::WriteTimedOut { .. } => "timed out writing a message",
+            Error::DatagramTooLarge { .. } => "datagram too large",
+            Error::TrailingBytes { .. } => "trailing bytes after message",
+            Error::UnknownPacketKind { .. } => "unknown packet kind",
+            Error::NoSegments => "message has no segments",
+            Error::BudgetExceeded { .. } => "memory budget exceeded",
+            Error::Io(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&::std::error::Error> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for ::capnp::Error {
+    fn from(err: Error) -> ::capnp::Error {
+        match err {
+            Error::Io(e) => e.into(),
+            Error::CleanEof | Error::TruncatedHeader { .. } | Error::TruncatedBody { .. } =>
+                ::capnp::Error::disconnected(format!("{}", err)),
+            Error::TimedOut | Error::WriteTimedOut { .. } | Error::BudgetExceeded { .. } =>
+                ::capnp::Error::overloaded(format!("{}", err)),
+            _ => ::capnp::Error::failed(format!("{}", err)),
+        }
+    }
+}
+
+impl ::gj::FulfillerDropped for Error {
+    fn fulfiller_dropped() -> Error {
+        Error::Io(io::Error::new(io::ErrorKind::Other, "Promise fulfiller was dropped."))
+    }
+}
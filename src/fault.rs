@@ -0,0 +1,235 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! A stream wrapper that misbehaves on cue, for testing how an application copes with broken
+//! peers and failing networks.
+//!
+//! Faults are placed at byte offsets counted from when the stream was wrapped, separately for
+//! each direction:
+//!
+//! ```text
+//! let stream = FaultyStream::new(stream)
+//!     .corrupt_read(4, 0xff)                  // garble the first segment's length
+//!     .fail_writes_at(100, libc::ECONNRESET)
+//!     .delay_reads(&timer, Duration::from_millis(50));
+//! ```
+
+use std::cell::Cell;
+use std::cmp;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite, Timer};
+
+/// The faults scripted for one direction.
+#[derive(Clone)]
+struct Faults {
+    truncate_at: Option<u64>,
+    fail_at: Option<(u64, i32)>,
+    corruptions: Vec<(u64, u8)>,
+    delay: Option<(Timer, Duration)>,
+}
+
+impl Faults {
+    fn new() -> Faults {
+        Faults { truncate_at: None, fail_at: None, corruptions: Vec::new(), delay: None }
+    }
+
+    /// How many bytes starting at `offset` may pass before a truncation or failure.
+    fn limit(&self, offset: u64) -> u64 {
+        let truncate_at = self.truncate_at.unwrap_or(u64::max_value());
+        let fail_at = self.fail_at.map_or(u64::max_value(), |(at, _)| at);
+        cmp::min(truncate_at, fail_at).saturating_sub(offset)
+    }
+
+    /// The error to fail with once `offset` bytes have passed, if any.
+    fn failure(&self, offset: u64) -> Option<io::Error> {
+        match self.fail_at {
+            Some((at, errno)) if offset >= at => Some(io::Error::from_raw_os_error(errno)),
+            _ => None,
+        }
+    }
+
+    /// Flips bits in `bytes`, which start at `offset` in the stream.
+    fn corrupt(&self, offset: u64, bytes: &mut [u8]) {
+        for &(at, mask) in &self.corruptions {
+            if at >= offset && at - offset < bytes.len() as u64 {
+                bytes[(at - offset) as usize] ^= mask;
+            }
+        }
+    }
+
+    fn delayed<T>(&self, promise: Promise<T, io::Error>) -> Promise<T, io::Error> {
+        match self.delay {
+            Some((ref timer, delay)) => {
+                let timer = timer.clone();
+                promise.then_else(move |r| timer.after_delay(delay).map(move |()| r))
+            }
+            None => promise,
+        }
+    }
+}
+
+/// The first `len` bytes of a buffer.
+struct Prefix<T> {
+    buf: T,
+    len: usize,
+}
+
+impl <T> AsMut<[u8]> for Prefix<T> where T: AsMut<[u8]> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.buf.as_mut()[..self.len]
+    }
+}
+
+/// Wraps a stream and injects the faults it has been given. A stream with no faults passes
+/// everything through unchanged.
+pub struct FaultyStream<S> {
+    stream: S,
+    reads: Faults,
+    writes: Faults,
+    read_offset: Rc<Cell<u64>>,
+    write_offset: u64,
+}
+
+impl <S> FaultyStream<S> {
+    pub fn new(stream: S) -> FaultyStream<S> {
+        FaultyStream {
+            stream: stream,
+            reads: Faults::new(),
+            writes: Faults::new(),
+            read_offset: Rc::new(Cell::new(0)),
+            write_offset: 0,
+        }
+    }
+
+    /// Ends the stream after `offset` bytes have been read, as though the peer had hung up.
+    pub fn truncate_reads_at(mut self, offset: u64) -> FaultyStream<S> {
+        self.reads.truncate_at = Some(offset);
+        self
+    }
+
+    /// Silently discards everything written after the first `offset` bytes.
+    pub fn truncate_writes_at(mut self, offset: u64) -> FaultyStream<S> {
+        self.writes.truncate_at = Some(offset);
+        self
+    }
+
+    /// XORs the byte read at `offset` with `mask`.
+    pub fn corrupt_read(mut self, offset: u64, mask: u8) -> FaultyStream<S> {
+        self.reads.corruptions.push((offset, mask));
+        self
+    }
+
+    /// XORs the byte written at `offset` with `mask`.
+    pub fn corrupt_write(mut self, offset: u64, mask: u8) -> FaultyStream<S> {
+        self.writes.corruptions.push((offset, mask));
+        self
+    }
+
+    /// Fails, with the OS error `errno`, any read that needs more than the first `offset` bytes.
+    pub fn fail_reads_at(mut self, offset: u64, errno: i32) -> FaultyStream<S> {
+        self.reads.fail_at = Some((offset, errno));
+        self
+    }
+
+    /// Passes on the first `offset` bytes written, and fails, with the OS error `errno`, the
+    /// write that reaches past them and every write after it.
+    pub fn fail_writes_at(mut self, offset: u64, errno: i32) -> FaultyStream<S> {
+        self.writes.fail_at = Some((offset, errno));
+        self
+    }
+
+    /// Holds back the completion of every read by `delay`.
+    pub fn delay_reads(mut self, timer: &Timer, delay: Duration) -> FaultyStream<S> {
+        self.reads.delay = Some((timer.clone(), delay));
+        self
+    }
+
+    /// Holds back the completion of every write by `delay`.
+    pub fn delay_writes(mut self, timer: &Timer, delay: Duration) -> FaultyStream<S> {
+        self.writes.delay = Some((timer.clone(), delay));
+        self
+    }
+
+    /// The number of bytes read so far.
+    pub fn bytes_read(&self) -> u64 {
+        self.read_offset.get()
+    }
+
+    /// The number of bytes written so far, including any that were discarded.
+    pub fn bytes_written(&self) -> u64 {
+        self.write_offset
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl <S> AsyncRead for FaultyStream<S> where S: AsyncRead {
+    fn try_read<T>(&mut self, mut buf: T, min_bytes: usize) -> Promise<(T, usize), io::Error>
+        where T: AsMut<[u8]>
+    {
+        let offset = self.read_offset.get();
+        let len = cmp::min(buf.as_mut().len() as u64, self.reads.limit(offset)) as usize;
+        if len == 0 && min_bytes > 0 {
+            let result = match self.reads.failure(offset) {
+                Some(e) => Promise::err(e),
+                None => Promise::ok((buf, 0)),
+            };
+            return self.reads.delayed(result)
+        }
+        let faults = self.reads.clone();
+        let read_offset = self.read_offset.clone();
+        let read = self.stream.try_read(Prefix { buf: buf, len: len }, cmp::min(min_bytes, len));
+        self.reads.delayed(read.map(move |(mut prefix, n)| {
+            faults.corrupt(offset, &mut prefix.as_mut()[..n]);
+            read_offset.set(offset + n as u64);
+            if n < min_bytes {
+                if let Some(e) = faults.failure(offset + n as u64) {
+                    return Err(e)
+                }
+            }
+            Ok((prefix.buf, n))
+        }))
+    }
+}
+
+impl <S> AsyncWrite for FaultyStream<S> where S: AsyncWrite {
+    fn write<T>(&mut self, buf: T) -> Promise<T, io::Error> where T: AsRef<[u8]> {
+        let offset = self.write_offset;
+        let len = buf.as_ref().len();
+        let passed = cmp::min(len as u64, self.writes.limit(offset)) as usize;
+        let mut bytes = buf.as_ref()[..passed].to_vec();
+        self.writes.corrupt(offset, &mut bytes);
+        self.write_offset += len as u64;
+        let failure = if passed < len { self.writes.failure(offset + passed as u64) } else { None };
+        let written = if passed > 0 { self.stream.write(bytes).map(|_| Ok(())) } else { Promise::ok(()) };
+        self.writes.delayed(written.map(move |()| match failure {
+            Some(e) => Err(e),
+            None => Ok(buf),
+        }))
+    }
+}
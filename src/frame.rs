@@ -0,0 +1,148 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Framing shared by the codecs that wrap messages in their own frames.
+//!
+//! A frame starts with a word that holds two little-endian `u32`s: the frame kind and the number
+//! of words of payload that follow. What the kind and the payload mean is up to the codec.
+
+use byteorder::{ByteOrder, LittleEndian};
+use capnp::Word;
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite};
+use fulfiller;
+use serialize;
+
+/// A vector of words that can be read into or written from as bytes.
+pub struct Words(pub Vec<Word>);
+
+impl AsRef<[u8]> for Words {
+    fn as_ref(&self) -> &[u8] {
+        Word::words_to_bytes(&self.0[..])
+    }
+}
+
+impl AsMut<[u8]> for Words {
+    fn as_mut(&mut self) -> &mut [u8] {
+        Word::words_to_bytes_mut(&mut self.0[..])
+    }
+}
+
+pub fn zero_word() -> Word {
+    // Word is a #[repr(C)] wrapper around a u64, so the two have the same layout.
+    unsafe { ::std::mem::transmute(0u64) }
+}
+
+/// Returns a word holding `a` and `b`.
+pub fn pair(a: u32, b: u32) -> Word {
+    let mut word = zero_word();
+    {
+        let bytes = Word::words_to_bytes_mut(::std::slice::from_mut(&mut word));
+        LittleEndian::write_u32(&mut bytes[0..4], a);
+        LittleEndian::write_u32(&mut bytes[4..8], b);
+    }
+    word
+}
+
+pub fn read_pair(word: Word) -> (u32, u32) {
+    let bytes = Word::words_to_bytes(::std::slice::from_ref(&word));
+    (LittleEndian::read_u32(&bytes[0..4]), LittleEndian::read_u32(&bytes[4..8]))
+}
+
+pub fn from_u64(value: u64) -> Word {
+    let mut word = zero_word();
+    LittleEndian::write_u64(Word::words_to_bytes_mut(::std::slice::from_mut(&mut word)), value);
+    word
+}
+
+pub fn to_u64(word: Word) -> u64 {
+    LittleEndian::read_u64(Word::words_to_bytes(::std::slice::from_ref(&word)))
+}
+
+/// Starts a frame of the given kind. Call `finish()` once the payload has been appended.
+pub fn begin(kind: u32) -> Vec<Word> {
+    vec![pair(kind, 0)]
+}
+
+/// Fills in the payload length of a frame started with `begin()`.
+pub fn finish(frame: &mut [Word]) {
+    let (kind, _) = read_pair(frame[0]);
+    frame[0] = pair(kind, frame.len() as u32 - 1);
+}
+
+pub fn write<S>(stream: S, frame: Vec<Word>, options: serialize::Options) -> Promise<S, ::capnp::Error>
+    where S: AsyncWrite
+{
+    serialize::write_raw_message_with_options(stream, Words(frame), None, options).map(|(stream, _)| Ok(stream))
+}
+
+/// Writes `frame` once every write already chained on `writes` has finished, and leaves `writes`
+/// holding the stream again after this one. Resolves once the frame has been written. After a
+/// failed write, later frames fail with the same error.
+pub fn write_after<S>(writes: &mut Promise<S, ::capnp::Error>,
+                      frame: Vec<Word>,
+                      options: serialize::Options) -> Promise<(), ::capnp::Error>
+    where S: AsyncWrite + 'static
+{
+    let (promise, fulfiller) = fulfiller::and_fulfiller();
+    let earlier = ::std::mem::replace(writes, Promise::never_done());
+    *writes = earlier.then_else(move |r| match r {
+        Ok(stream) => write(stream, frame, options),
+        Err(e) => Promise::err(e),
+    }).map_else(move |r| {
+        match r {
+            Ok(_) => fulfiller.fulfill(()),
+            Err(ref e) => fulfiller.reject(e.clone()),
+        }
+        r
+    }).eagerly_evaluate();
+    promise
+}
+
+/// A frame's kind and payload.
+pub type Frame = (u32, Vec<Word>);
+
+/// Reads a frame, returning its kind and payload, or None on EOF. Fails if the payload is longer
+/// than `max_payload_words`.
+pub fn try_read<S>(mut stream: S, max_payload_words: u64) -> Promise<(S, Option<Frame>), ::capnp::Error>
+    where S: AsyncRead
+{
+    let buf: Vec<u8> = vec![0; 8];
+    stream.try_read(buf, 8).then_else(move |r| match r {
+        Err(e) => Promise::err(e.into()),
+        Ok((_, 0)) => Promise::ok((stream, None)),
+        Ok((_, n)) if n < 8 =>
+            Promise::err(::Error::TruncatedHeader { received: n, expected: 8 }.into()),
+        Ok((buf, _)) => {
+            let kind = LittleEndian::read_u32(&buf[0..4]);
+            let payload_words = LittleEndian::read_u32(&buf[4..8]) as usize;
+            if payload_words as u64 > max_payload_words {
+                return Promise::err(::capnp::Error::failed(
+                    format!("Frame of {} words exceeds the limit of {}", payload_words, max_payload_words)))
+            }
+            let payload = Words(Word::allocate_zeroed_vec(payload_words));
+            stream.read(payload, payload_words * 8).map_else(move |r| match r {
+                Err(e) => Err(e.into()),
+                Ok((Words(payload), _)) => Ok((stream, Some((kind, payload)))),
+            })
+        }
+    })
+}
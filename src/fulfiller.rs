@@ -0,0 +1,62 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Fulfillers for promises that fail with a `capnp::Error`.
+//!
+//! A `gj::PromiseFulfiller` needs its error type to say what to fail with if it is dropped
+//! unused. `capnp::Error` does so only when capnp is built with its "rpc" feature, which this
+//! crate otherwise has no use for, so the fulfillers here wrap the error in a type that does.
+
+use gj::{FulfillerDropped, Promise, PromiseFulfiller};
+
+struct Rejected(::capnp::Error);
+
+impl FulfillerDropped for Rejected {
+    fn fulfiller_dropped() -> Rejected {
+        Rejected(::capnp::Error::failed("Promise fulfiller was dropped.".to_string()))
+    }
+}
+
+/// Stands in for a `gj::PromiseFulfiller<T, ::capnp::Error>`.
+pub struct Fulfiller<T> where T: 'static {
+    inner: PromiseFulfiller<T, Rejected>,
+}
+
+impl <T> Fulfiller<T> where T: 'static {
+    pub fn fulfill(self, value: T) {
+        self.inner.fulfill(value)
+    }
+
+    pub fn reject(self, error: ::capnp::Error) {
+        self.inner.reject(Rejected(error))
+    }
+
+    pub fn resolve(self, result: Result<T, ::capnp::Error>) {
+        self.inner.resolve(result.map_err(Rejected))
+    }
+}
+
+/// Stands in for `Promise::and_fulfiller()`.
+pub fn and_fulfiller<T>() -> (Promise<T, ::capnp::Error>, Fulfiller<T>) where T: 'static {
+    let (promise, fulfiller) = Promise::and_fulfiller();
+    (promise.map_err(|Rejected(e)| e), Fulfiller { inner: fulfiller })
+}
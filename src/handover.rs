@@ -0,0 +1,71 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Passing sockets to another process, for upgrading a server without dropping connections.
+//!
+//! The old process sends the sockets that the new one should take over, listeners and
+//! connections alike, over a Unix domain socket, together with a Cap'n Proto message that says
+//! what each one is for and carries whatever state goes with it. The new process adopts them,
+//! for example with `activation::adopt()`, and carries on.
+//!
+//! ```text
+//! // In the old process, once the new one has connected to `upgrade_socket`:
+//! try!(handover::send(&upgrade_socket, &[listener.as_raw_fd(), client.as_raw_fd()], &state));
+//!
+//! // In the new process:
+//! let (fds, state) = try!(handover::receive(&upgrade_socket, ReaderOptions::new()));
+//! ```
+//!
+//! A connection has to be handed over between messages. Bytes that the old process has already
+//! read from it stay with the old process, so it must have finished reading whatever message it
+//! was in the middle of, or else put the bytes it has read so far in the state message for the
+//! new process to pick up from. Likewise for stream-level state such as `delta` or `dedup`
+//! dictionaries, which the new process would otherwise start out without.
+//!
+//! These functions block, and are meant for the few moments of an upgrade rather than for use
+//! on an event loop.
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+
+use capnp::message;
+
+use descriptors;
+
+/// The most sockets that can be passed in a single `send()`.
+pub const MAX_FDS: usize = descriptors::MAX_FDS;
+
+/// Sends `fds` and `state` over `socket`. The sockets stay open in this process too; close them
+/// once the receiving process has confirmed that it took over.
+pub fn send<A>(socket: &UnixStream, fds: &[RawFd], state: &message::Builder<A>) -> io::Result<()>
+    where A: message::Allocator
+{
+    descriptors::write_message(socket, state, fds)
+}
+
+/// Receives what a `send()` on the other end of `socket` sent. The returned descriptors belong
+/// to the caller, and are marked close-on-exec.
+pub fn receive(socket: &UnixStream, options: message::ReaderOptions)
+               -> io::Result<(Vec<RawFd>, message::Reader<::capnp::serialize::OwnedSegments>)>
+{
+    descriptors::read_message(socket, options)
+}
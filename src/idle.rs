@@ -0,0 +1,337 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Closing connections that have gone quiet.
+//!
+//! A server with many clients, most of them idle most of the time, wants to let go of the ones
+//! that have been silent for too long. Streams wrapped by a `Reaper` remember when they last
+//! moved a byte. `Reaper::check()`, or `Reaper::run()` on a timer, reaps those that have been idle
+//! for longer than the set time: every read and write pending on a reaped stream fails with an
+//! `IdleTimeout` error, as does every later one, so whatever is waiting on the connection finds
+//! out and can drop it, closing the socket.
+//!
+//! Every chunk of data that a read or write moves counts as activity, so a large transfer that is
+//! still making progress is not reaped partway. A read that is waiting for data does not count as
+//! activity, so a client that connects and then says nothing is reaped too. Unlike a `watchdog::Watchdog`, which reports reads and writes
+//! that have stopped partway, a reaper also reaps streams with nothing pending at all.
+//!
+//! ```text
+//! let reaper = Reaper::new(Duration::from_secs(300));
+//! let stream = reaper.watch(stream);
+//! let reaping = reaper.run(&timer, Duration::from_secs(10));
+//! ```
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+
+use gj::{Promise, PromiseFulfiller};
+use gjio::{AsyncRead, AsyncWrite, Timer};
+
+/// Writes are carried out in steps of at most this many bytes, each of which counts as activity.
+const WRITE_CHUNK_BYTES: usize = 64 * 1024;
+
+/// The error with which a reaped stream fails its reads and writes, inside an `io::Error` of
+/// kind `TimedOut`.
+#[derive(Clone, Copy, Debug)]
+pub struct IdleTimeout {
+    /// How long the stream had been idle when it was reaped.
+    pub idle: Duration,
+}
+
+impl fmt::Display for IdleTimeout {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Connection was closed after being idle for {:?}", self.idle)
+    }
+}
+
+impl error::Error for IdleTimeout {
+    fn description(&self) -> &str {
+        "connection idle for too long"
+    }
+}
+
+/// Returns true if `error` is the one that a reaped stream fails with.
+pub fn is_idle_timeout(error: &io::Error) -> bool {
+    error.get_ref().map_or(false, |e| e.is::<IdleTimeout>())
+}
+
+fn idle_error(timeout: IdleTimeout) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, timeout)
+}
+
+struct Activity {
+    last: Instant,
+    reaped: Option<IdleTimeout>,
+    next_id: u64,
+
+    /// Rejected to cancel each pending read and write when the stream is reaped.
+    pending: HashMap<u64, PromiseFulfiller<(), io::Error>>,
+}
+
+impl Activity {
+    fn reap(&mut self, idle: Duration) {
+        let timeout = IdleTimeout { idle: idle };
+        self.reaped = Some(timeout);
+        for (_, fulfiller) in self.pending.drain() {
+            fulfiller.reject(idle_error(timeout));
+        }
+    }
+}
+
+struct Inner {
+    idle_after: Duration,
+    streams: Vec<Weak<RefCell<Activity>>>,
+}
+
+/// Keeps track of when streams were last active and reaps those that have been idle for too
+/// long. Clones share the same set of streams.
+#[derive(Clone)]
+pub struct Reaper {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl Reaper {
+    pub fn new(idle_after: Duration) -> Reaper {
+        Reaper { inner: Rc::new(RefCell::new(Inner { idle_after: idle_after, streams: Vec::new() })) }
+    }
+
+    /// Wraps `stream` so that its activity is tracked. The stream counts as active from now.
+    pub fn watch<S>(&self, stream: S) -> Idle<S> {
+        let activity = Rc::new(RefCell::new(Activity {
+            last: Instant::now(),
+            reaped: None,
+            next_id: 0,
+            pending: HashMap::new(),
+        }));
+        self.inner.borrow_mut().streams.push(Rc::downgrade(&activity));
+        Idle { stream: stream, activity: activity, writes: Promise::ok(()) }
+    }
+
+    /// The number of streams being tracked that have not been reaped or dropped.
+    pub fn len(&self) -> usize {
+        self.inner.borrow().streams.iter().filter(|s| {
+            s.upgrade().map_or(false, |a| a.borrow().reaped.is_none())
+        }).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reaps every stream that has been idle for too long, and returns how many there were.
+    /// Forgets streams that have been dropped or reaped.
+    pub fn check(&self) -> usize {
+        let now = Instant::now();
+        let mut inner = self.inner.borrow_mut();
+        let idle_after = inner.idle_after;
+        let mut reaped = 0;
+        inner.streams.retain(|stream| {
+            let activity = match stream.upgrade() {
+                Some(a) => a,
+                None => return false,
+            };
+            let mut activity = activity.borrow_mut();
+            let idle = now.duration_since(activity.last);
+            if idle > idle_after {
+                activity.reap(idle);
+                reaped += 1;
+                false
+            } else {
+                true
+            }
+        });
+        reaped
+    }
+
+    /// Calls `check()` every `interval`, until the returned promise is dropped.
+    pub fn run(&self, timer: &Timer, interval: Duration) -> Promise<(), ::capnp::Error> {
+        let reaper = self.clone();
+        let timer1 = timer.clone();
+        timer.after_delay(interval).lift().then(move |()| {
+            reaper.check();
+            reaper.run(&timer1, interval)
+        })
+    }
+}
+
+/// A stream whose activity a `Reaper` keeps track of. Clones share the same record of
+/// activity, so a socket's reading and writing halves are reaped together.
+///
+/// As with a `watchdog::Watched`, reads and writes need the underlying stream to be cloneable so
+/// that they can be carried out in steps, and writes are queued so that the steps of one never end
+/// up between those of another.
+pub struct Idle<S> {
+    stream: S,
+    activity: Rc<RefCell<Activity>>,
+
+    /// Resolves once the last write queued has finished, whether or not it succeeded.
+    writes: Promise<(), io::Error>,
+}
+
+impl <S> Clone for Idle<S> where S: Clone {
+    fn clone(&self) -> Idle<S> {
+        Idle { stream: self.stream.clone(), activity: self.activity.clone(), writes: Promise::ok(()) }
+    }
+}
+
+impl <S> Idle<S> {
+    /// How long it has been since the stream last read or wrote a byte.
+    pub fn idle_for(&self) -> Duration {
+        self.activity.borrow().last.elapsed()
+    }
+
+    pub fn is_reaped(&self) -> bool {
+        self.activity.borrow().reaped.is_some()
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+/// An entry in `Activity::pending`, removed when dropped, so that an operation whose promise is
+/// dropped before it finishes does not leave its fulfiller behind.
+struct Registration {
+    activity: Rc<RefCell<Activity>>,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.activity.borrow_mut().pending.remove(&self.id);
+    }
+}
+
+/// Lets `op` be cancelled by reaping.
+fn guard<T>(activity: &Rc<RefCell<Activity>>, op: Promise<T, io::Error>) -> Promise<T, io::Error> {
+    let (reaped, fulfiller) = Promise::and_fulfiller();
+    let id = {
+        let mut activity = activity.borrow_mut();
+        if let Some(timeout) = activity.reaped {
+            return Promise::err(idle_error(timeout))
+        }
+        let id = activity.next_id;
+        activity.next_id += 1;
+        activity.pending.insert(id, fulfiller);
+        id
+    };
+    let registration = Registration { activity: activity.clone(), id: id };
+    op.exclusive_join(reaped.then(|()| Promise::never_done())).map_else(move |r| {
+        drop(registration);
+        r
+    })
+}
+
+/// The part of a buffer after `start`.
+struct Suffix<T> {
+    buf: T,
+    start: usize,
+}
+
+impl <T> AsMut<[u8]> for Suffix<T> where T: AsMut<[u8]> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.buf.as_mut()[self.start..]
+    }
+}
+
+/// The part of a buffer from `start` to `end`.
+struct Window<T> {
+    buf: T,
+    start: usize,
+    end: usize,
+}
+
+impl <T> AsRef<[u8]> for Window<T> where T: AsRef<[u8]> {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf.as_ref()[self.start..self.end]
+    }
+}
+
+fn read_loop<S, T>(mut stream: S, activity: Rc<RefCell<Activity>>, buf: T, done: usize, min_bytes: usize)
+                   -> Promise<(T, usize), io::Error>
+    where S: AsyncRead + 'static, T: AsMut<[u8]> + 'static
+{
+    let read = stream.try_read(Suffix { buf: buf, start: done }, 1);
+    guard(&activity, read).then(move |(suffix, n)| {
+        let done = done + n;
+        if n > 0 {
+            activity.borrow_mut().last = Instant::now();
+        }
+        if n == 0 || done >= min_bytes {
+            Promise::ok((suffix.buf, done))
+        } else {
+            read_loop(stream, activity, suffix.buf, done, min_bytes)
+        }
+    })
+}
+
+fn write_loop<S, T>(mut stream: S, activity: Rc<RefCell<Activity>>, buf: T, done: usize) -> Promise<T, io::Error>
+    where S: AsyncWrite + 'static, T: AsRef<[u8]> + 'static
+{
+    let len = buf.as_ref().len();
+    if done >= len {
+        return Promise::ok(buf)
+    }
+    let end = ::std::cmp::min(len, done + WRITE_CHUNK_BYTES);
+    let write = stream.write(Window { buf: buf, start: done, end: end });
+    guard(&activity, write).then(move |window| {
+        activity.borrow_mut().last = Instant::now();
+        write_loop(stream, activity, window.buf, end)
+    })
+}
+
+impl <S> AsyncRead for Idle<S> where S: AsyncRead + Clone + 'static {
+    fn try_read<T>(&mut self, mut buf: T, min_bytes: usize) -> Promise<(T, usize), io::Error>
+        where T: AsMut<[u8]>
+    {
+        let min_bytes = ::std::cmp::min(min_bytes, buf.as_mut().len());
+        if min_bytes == 0 {
+            let read = self.stream.try_read(buf, 0);
+            return guard(&self.activity, read)
+        }
+        read_loop(self.stream.clone(), self.activity.clone(), buf, 0, min_bytes)
+    }
+}
+
+impl <S> AsyncWrite for Idle<S> where S: AsyncWrite + Clone + 'static {
+    fn write<T>(&mut self, buf: T) -> Promise<T, io::Error> where T: AsRef<[u8]> {
+        if let Some(timeout) = self.activity.borrow().reaped {
+            return Promise::err(idle_error(timeout))
+        }
+        let stream = self.stream.clone();
+        let activity = self.activity.clone();
+        let (written, fulfiller) = Promise::and_fulfiller();
+        let previous = ::std::mem::replace(&mut self.writes, Promise::ok(()));
+        self.writes = previous.then(move |()| {
+            write_loop(stream, activity, buf, 0).map_else(move |r| {
+                fulfiller.resolve(r);
+                Ok(())
+            })
+        }).eagerly_evaluate();
+        written
+    }
+}
@@ -0,0 +1,616 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Stacking byte-level transformations, such as checksums, on top of a message stream.
+//!
+//! Layers are added with `layer()`, innermost first:
+//!
+//! ```text
+//! let stream = layer::Layered::new(stream).layer(Lz4).layer(Checksum::Crc32c).layer(encryption);
+//! ```
+//!
+//! When writing, each message is serialized and then passed through the layers in the order
+//! they were added, so the example above compresses, then checksums the compressed bytes, then
+//! encrypts the result. When reading, the layers are applied in the opposite order. Each
+//! encoded message is sent as a little-endian `u32` length followed by that many bytes.
+//!
+//! A layer that is expensive enough to hold up the event loop, such as compression of large
+//! messages, can be run on a worker thread with `offload::Offloaded` and `offloaded_layer()`.
+
+use byteorder::{ByteOrder, LittleEndian};
+use capnp::{message, Word};
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite};
+use crc32c::crc32c;
+use Error;
+use lz4;
+#[cfg(unix)]
+use offload::Offloaded;
+use serialize::{self, OwnedSegments};
+use serialize_packed;
+
+/// A reversible transformation of the bytes of each message.
+pub trait Layer {
+    /// Transforms the bytes of an outgoing message.
+    fn encode(&mut self, bytes: Vec<u8>) -> ::capnp::Result<Vec<u8>>;
+
+    /// Reverses `encode()` on the bytes of an incoming message.
+    fn decode(&mut self, bytes: Vec<u8>) -> ::capnp::Result<Vec<u8>>;
+
+    /// Like `decode()`, but fails rather than return more than `max_bytes` bytes. Layers whose
+    /// output can be much larger than their input, such as decompression, should override this to
+    /// give up as soon as the limit is passed, so that a small frame cannot make the receiver
+    /// allocate gigabytes. The default implementation checks only once `decode()` is done.
+    fn decode_limited(&mut self, bytes: Vec<u8>, max_bytes: usize) -> ::capnp::Result<Vec<u8>> {
+        let decoded = try!(self.decode(bytes));
+        try!(check_decoded_len(decoded.len(), max_bytes));
+        Ok(decoded)
+    }
+}
+
+/// Returns an error if a layer has decoded `len` bytes and is allowed at most `max_bytes`.
+pub fn check_decoded_len(len: usize, max_bytes: usize) -> ::capnp::Result<()> {
+    if len > max_bytes {
+        Err(::capnp::Error::failed(format!("Decoded message exceeds the limit of {} bytes", max_bytes)))
+    } else {
+        Ok(())
+    }
+}
+
+/// Layers that append a checksum to each message and verify it on the way in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Checksum {
+    /// CRC-32C (Castagnoli), appended as a little-endian `u32`.
+    Crc32c,
+}
+
+impl Layer for Checksum {
+    fn encode(&mut self, mut bytes: Vec<u8>) -> ::capnp::Result<Vec<u8>> {
+        let mut checksum = [0; 4];
+        LittleEndian::write_u32(&mut checksum, crc32c(&bytes));
+        bytes.extend_from_slice(&checksum);
+        Ok(bytes)
+    }
+
+    fn decode(&mut self, mut bytes: Vec<u8>) -> ::capnp::Result<Vec<u8>> {
+        if bytes.len() < 4 {
+            return Err(::capnp::Error::failed("Message too short to hold a checksum".to_string()))
+        }
+        let body_len = bytes.len() - 4;
+        let expected = LittleEndian::read_u32(&bytes[body_len..]);
+        bytes.truncate(body_len);
+        let actual = crc32c(&bytes);
+        if actual != expected {
+            return Err(Error::ChecksumMismatch { expected: expected, actual: actual }.into())
+        }
+        Ok(bytes)
+    }
+}
+
+/// A message authentication code, such as HMAC-SHA256. This crate does not implement any
+/// cryptographic primitives itself; an implementation from a vetted library is expected.
+pub trait Mac {
+    /// The length of the tags that `tag()` returns.
+    fn tag_len(&self) -> usize;
+
+    /// Computes the tag for the concatenation of `parts`.
+    fn tag(&self, parts: &[&[u8]]) -> Vec<u8>;
+
+    /// Returns true if `tag` is the tag for the concatenation of `parts`. Implementations should
+    /// take the same time however much of the tag matches.
+    fn verify(&self, parts: &[&[u8]], tag: &[u8]) -> bool;
+}
+
+/// A layer that appends a tag computed with `Mac` to each message and checks it on the way in,
+/// so that a peer without the key cannot forge or alter messages. The tag also covers a count of
+/// the messages that came before in the same direction, which is not sent, so that a message
+/// that is replayed, dropped or reordered fails to verify too. Both ends must therefore see every
+/// message, in order, from the start of the stream.
+///
+/// This gives authenticity, not secrecy: messages are still sent in the clear.
+#[derive(Clone)]
+pub struct Authenticated<M> {
+    mac: M,
+    sent: u64,
+    received: u64,
+}
+
+impl <M> Authenticated<M> where M: Mac {
+    pub fn new(mac: M) -> Authenticated<M> {
+        Authenticated { mac: mac, sent: 0, received: 0 }
+    }
+
+    pub fn into_inner(self) -> M {
+        self.mac
+    }
+}
+
+fn sequence_bytes(sequence: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    LittleEndian::write_u64(&mut bytes, sequence);
+    bytes
+}
+
+impl <M> Layer for Authenticated<M> where M: Mac {
+    fn encode(&mut self, mut bytes: Vec<u8>) -> ::capnp::Result<Vec<u8>> {
+        let tag = self.mac.tag(&[&sequence_bytes(self.sent), &bytes]);
+        self.sent += 1;
+        bytes.extend_from_slice(&tag);
+        Ok(bytes)
+    }
+
+    fn decode(&mut self, mut bytes: Vec<u8>) -> ::capnp::Result<Vec<u8>> {
+        let tag_len = self.mac.tag_len();
+        if bytes.len() < tag_len {
+            return Err(::capnp::Error::failed("Message too short to hold an authentication tag".to_string()))
+        }
+        let body_len = bytes.len() - tag_len;
+        if !self.mac.verify(&[&sequence_bytes(self.received), &bytes[..body_len]], &bytes[body_len..]) {
+            return Err(::capnp::Error::failed("Message authentication failed".to_string()))
+        }
+        self.received += 1;
+        bytes.truncate(body_len);
+        Ok(bytes)
+    }
+}
+
+/// A layer that applies the packed encoding of `serialize_packed` to each message. Packing works
+/// on whole words, so this must be the first layer added, where it sees the serialized message.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Packed;
+
+impl Layer for Packed {
+    fn encode(&mut self, bytes: Vec<u8>) -> ::capnp::Result<Vec<u8>> {
+        if bytes.len() % 8 != 0 {
+            return Err(::capnp::Error::failed(
+                format!("Cannot pack {} bytes, which is not a whole number of words", bytes.len())))
+        }
+        Ok(serialize_packed::pack(&bytes))
+    }
+
+    fn decode(&mut self, bytes: Vec<u8>) -> ::capnp::Result<Vec<u8>> {
+        serialize_packed::unpack_bytes(&bytes, ::std::usize::MAX)
+    }
+
+    fn decode_limited(&mut self, bytes: Vec<u8>, max_bytes: usize) -> ::capnp::Result<Vec<u8>> {
+        serialize_packed::unpack_bytes(&bytes, max_bytes)
+    }
+}
+
+/// A layer that compresses each message with LZ4, in the block format, preceded by the length
+/// of the uncompressed message as a little-endian `u32`. Every message is compressed on its own,
+/// so this layer can also sit inside `Selective`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lz4;
+
+impl Layer for Lz4 {
+    fn encode(&mut self, bytes: Vec<u8>) -> ::capnp::Result<Vec<u8>> {
+        if bytes.len() > ::std::u32::MAX as usize {
+            return Err(::capnp::Error::failed(format!("Message of {} bytes is too long to compress", bytes.len())))
+        }
+        let compressed = lz4::compress(&bytes);
+        let mut encoded = vec![0; 4];
+        LittleEndian::write_u32(&mut encoded, bytes.len() as u32);
+        encoded.extend_from_slice(&compressed);
+        Ok(encoded)
+    }
+
+    fn decode(&mut self, bytes: Vec<u8>) -> ::capnp::Result<Vec<u8>> {
+        self.decode_limited(bytes, ::std::usize::MAX)
+    }
+
+    fn decode_limited(&mut self, bytes: Vec<u8>, max_bytes: usize) -> ::capnp::Result<Vec<u8>> {
+        if bytes.len() < 4 {
+            return Err(::capnp::Error::failed("Message too short to hold a decompressed length".to_string()))
+        }
+        let len = LittleEndian::read_u32(&bytes[0..4]) as usize;
+        try!(check_decoded_len(len, max_bytes));
+        lz4::decompress(&bytes[4..], len)
+    }
+}
+
+/// Applies an inner layer, typically compression, only to those messages likely to benefit from
+/// it, so that small or already-compressed messages don't cost CPU time for no gain. A trailing
+/// byte on each message records whether the inner layer was applied.
+///
+/// A message is passed through the inner layer only if it is at least `min_bytes()` long and,
+/// when it is longer than `sample_bytes()`, a sample of that many bytes from its start shrinks
+/// to at most `max_sample_percent()` of its size. If the result is no smaller than the original,
+/// the original is sent instead. Because of the sampling, the inner layer must encode each
+/// message independently of the ones before it.
+pub struct Selective<L> {
+    inner: L,
+    min_bytes: usize,
+    sample_bytes: usize,
+    max_sample_percent: usize,
+}
+
+const STORED: u8 = 0;
+const ENCODED: u8 = 1;
+
+impl <L> Selective<L> where L: Layer {
+    pub fn new(inner: L) -> Selective<L> {
+        Selective {
+            inner: inner,
+            min_bytes: 512,
+            sample_bytes: 4096,
+            max_sample_percent: 90,
+        }
+    }
+
+    /// Messages shorter than this are never encoded. Defaults to 512.
+    pub fn min_bytes(mut self, value: usize) -> Selective<L> {
+        self.min_bytes = value;
+        self
+    }
+
+    /// The length of the sample that is trial-encoded for longer messages. Defaults to 4096.
+    pub fn sample_bytes(mut self, value: usize) -> Selective<L> {
+        self.sample_bytes = value;
+        self
+    }
+
+    /// Messages whose sample does not shrink to this percentage of its length or less are not
+    /// encoded. Defaults to 90.
+    pub fn max_sample_percent(mut self, value: usize) -> Selective<L> {
+        self.max_sample_percent = value;
+        self
+    }
+
+    pub fn into_inner(self) -> L {
+        self.inner
+    }
+
+    fn worth_encoding(&mut self, bytes: &[u8]) -> ::capnp::Result<bool> {
+        if bytes.len() < self.min_bytes {
+            return Ok(false)
+        }
+        if bytes.len() <= self.sample_bytes {
+            return Ok(true)
+        }
+        let sample = try!(self.inner.encode(bytes[..self.sample_bytes].to_vec()));
+        Ok(sample.len() * 100 <= self.sample_bytes * self.max_sample_percent)
+    }
+}
+
+impl <L> Layer for Selective<L> where L: Layer {
+    fn encode(&mut self, mut bytes: Vec<u8>) -> ::capnp::Result<Vec<u8>> {
+        if try!(self.worth_encoding(&bytes)) {
+            let mut encoded = try!(self.inner.encode(bytes.clone()));
+            if encoded.len() < bytes.len() {
+                encoded.push(ENCODED);
+                return Ok(encoded)
+            }
+        }
+        bytes.push(STORED);
+        Ok(bytes)
+    }
+
+    fn decode(&mut self, mut bytes: Vec<u8>) -> ::capnp::Result<Vec<u8>> {
+        match bytes.pop() {
+            Some(STORED) => Ok(bytes),
+            Some(ENCODED) => self.inner.decode(bytes),
+            Some(flag) => Err(::capnp::Error::failed(format!("Unknown selective encoding flag {}", flag))),
+            None => Err(::capnp::Error::failed("Message too short to hold an encoding flag".to_string())),
+        }
+    }
+
+    fn decode_limited(&mut self, mut bytes: Vec<u8>, max_bytes: usize) -> ::capnp::Result<Vec<u8>> {
+        match bytes.last() {
+            Some(&ENCODED) => {
+                bytes.pop();
+                self.inner.decode_limited(bytes, max_bytes)
+            }
+            _ => {
+                let decoded = try!(self.decode(bytes));
+                try!(check_decoded_len(decoded.len(), max_bytes));
+                Ok(decoded)
+            }
+        }
+    }
+}
+
+/// Byte totals for a layer, or a stack of layers, over the lifetime of a stream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ByteCounts {
+    /// Bytes on the message side: passed to `encode()` or returned from `decode()`.
+    pub decoded_bytes: u64,
+
+    /// Bytes on the wire side: returned from `encode()` or passed to `decode()`.
+    pub encoded_bytes: u64,
+}
+
+impl ByteCounts {
+    /// Encoded bytes per decoded byte, so that a compression layer that is paying off has a
+    /// ratio below 1. Returns 1 if no bytes have been counted.
+    pub fn ratio(&self) -> f64 {
+        if self.decoded_bytes == 0 {
+            1.0
+        } else {
+            self.encoded_bytes as f64 / self.decoded_bytes as f64
+        }
+    }
+}
+
+/// Per-layer byte counts, in the order the layers were added, for each direction.
+struct Counts {
+    written: Vec<ByteCounts>,
+    read: Vec<ByteCounts>,
+}
+
+fn total(counts: &[ByteCounts]) -> ByteCounts {
+    match (counts.first(), counts.last()) {
+        (Some(innermost), Some(outermost)) => ByteCounts {
+            decoded_bytes: innermost.decoded_bytes,
+            encoded_bytes: outermost.encoded_bytes,
+        },
+        _ => ByteCounts::default(),
+    }
+}
+
+/// A layer in a `Layered` stack, run either on the event loop or on a worker thread.
+enum Stage {
+    Inline(Box<Layer>),
+    #[cfg(unix)]
+    Offloaded(Offloaded),
+}
+
+/// Either the bytes a stage produced right away, or a promise for them.
+enum Step {
+    Ready(Vec<u8>),
+    Pending(Promise<Vec<u8>, ::capnp::Error>),
+}
+
+/// A stream of messages that pass through a stack of layers.
+pub struct Layered<S> {
+    stream: S,
+    layers: Vec<Stage>,
+    counts: Counts,
+    reader_options: message::ReaderOptions,
+    max_encoded_bytes: u32,
+    max_decoded_bytes: usize,
+    serialize_options: serialize::Options,
+}
+
+impl <S> Layered<S> where S: 'static {
+    pub fn new(stream: S) -> Layered<S> {
+        Layered {
+            stream: stream,
+            layers: Vec::new(),
+            counts: Counts { written: Vec::new(), read: Vec::new() },
+            reader_options: message::ReaderOptions::new(),
+            max_encoded_bytes: 64 * 1024 * 1024 + 4096,
+            max_decoded_bytes: 64 * 1024 * 1024 + 4096,
+            serialize_options: serialize::Options::new(),
+        }
+    }
+
+    /// Adds `layer` outside all of the layers added so far.
+    pub fn layer<L>(self, layer: L) -> Layered<S> where L: Layer + 'static {
+        self.stage(Stage::Inline(Box::new(layer)))
+    }
+
+    /// Adds a layer that runs on a worker thread outside all of the layers added so far. Messages
+    /// wait for the worker, in order, but the event loop is free to do other work meanwhile.
+    #[cfg(unix)]
+    pub fn offloaded_layer(self, layer: Offloaded) -> Layered<S> {
+        self.stage(Stage::Offloaded(layer))
+    }
+
+    fn stage(mut self, stage: Stage) -> Layered<S> {
+        self.layers.push(stage);
+        self.counts.written.push(ByteCounts::default());
+        self.counts.read.push(ByteCounts::default());
+        self
+    }
+
+    /// Options for the messages returned by `read_message()`.
+    pub fn reader_options(mut self, value: message::ReaderOptions) -> Layered<S> {
+        self.reader_options = value;
+        self
+    }
+
+    /// Rejects any incoming message whose encoded form is longer than this. Defaults to a little
+    /// more than 64 MiB, the size of the default traversal limit.
+    pub fn max_encoded_bytes(mut self, value: u32) -> Layered<S> {
+        self.max_encoded_bytes = value;
+        self
+    }
+
+    /// Rejects any incoming message that any layer decodes to more than this many bytes, passing
+    /// the limit to `Layer::decode_limited()`. This bounds the memory that decoding a single
+    /// message can take, however small its encoded form. Defaults to a little more than 64 MiB.
+    pub fn max_decoded_bytes(mut self, value: usize) -> Layered<S> {
+        self.max_decoded_bytes = value;
+        self
+    }
+
+    /// Options used when writing encoded messages. Their limits on segments also apply to the
+    /// messages that incoming ones decode to.
+    pub fn serialize_options(mut self, value: serialize::Options) -> Layered<S> {
+        self.serialize_options = value;
+        self
+    }
+
+    /// Totals for all of the messages written so far, across the whole stack of layers.
+    pub fn written_bytes(&self) -> ByteCounts {
+        total(&self.counts.written)
+    }
+
+    /// Totals for all of the messages written so far, for each layer in the order the layers
+    /// were added.
+    pub fn written_bytes_by_layer(&self) -> &[ByteCounts] {
+        &self.counts.written
+    }
+
+    /// Totals for all of the messages read so far, across the whole stack of layers.
+    pub fn read_bytes(&self) -> ByteCounts {
+        total(&self.counts.read)
+    }
+
+    /// Totals for all of the messages read so far, for each layer in the order the layers were
+    /// added.
+    pub fn read_bytes_by_layer(&self) -> &[ByteCounts] {
+        &self.counts.read
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Passes `bytes` through the layers from the one at `index` outwards.
+    fn encode_from(mut self, index: usize, bytes: Vec<u8>) -> Promise<(Layered<S>, Vec<u8>), ::capnp::Error> {
+        if index == self.layers.len() {
+            return Promise::ok((self, bytes))
+        }
+        self.counts.written[index].decoded_bytes += bytes.len() as u64;
+        let step = match self.layers[index] {
+            Stage::Inline(ref mut layer) => Step::Ready(pry!(layer.encode(bytes))),
+            #[cfg(unix)]
+            Stage::Offloaded(ref mut layer) => Step::Pending(layer.encode(bytes)),
+        };
+        let encoded = match step {
+            Step::Ready(bytes) => Promise::ok(bytes),
+            Step::Pending(promise) => promise,
+        };
+        encoded.then(move |bytes| {
+            self.counts.written[index].encoded_bytes += bytes.len() as u64;
+            self.encode_from(index + 1, bytes)
+        })
+    }
+
+    /// Passes `bytes` through the layers from the one below `index` inwards.
+    fn decode_from(mut self, index: usize, bytes: Vec<u8>) -> Promise<(Layered<S>, Vec<u8>), ::capnp::Error> {
+        if index == 0 {
+            return Promise::ok((self, bytes))
+        }
+        let index = index - 1;
+        let max_decoded_bytes = self.max_decoded_bytes;
+        self.counts.read[index].encoded_bytes += bytes.len() as u64;
+        let step = match self.layers[index] {
+            Stage::Inline(ref mut layer) => Step::Ready(pry!(layer.decode_limited(bytes, max_decoded_bytes))),
+            #[cfg(unix)]
+            Stage::Offloaded(ref mut layer) => Step::Pending(layer.decode_limited(bytes, max_decoded_bytes)),
+        };
+        let decoded = match step {
+            Step::Ready(bytes) => Promise::ok(bytes),
+            Step::Pending(promise) => promise,
+        };
+        decoded.then(move |bytes| {
+            self.counts.read[index].decoded_bytes += bytes.len() as u64;
+            self.decode_from(index, bytes)
+        })
+    }
+}
+
+impl <S> Layered<S> where S: AsyncWrite + 'static {
+    pub fn write_message<A>(self, message: &message::Builder<A>) -> Promise<Layered<S>, ::capnp::Error>
+        where A: message::Allocator
+    {
+        let words = ::capnp::serialize::write_message_to_words(message);
+        let bytes = Word::words_to_bytes(&words[..]).to_vec();
+        self.encode_from(0, bytes).then(|(layered, bytes)| {
+            // The length has to fit in the u32 in front of the encoded message.
+            let limit = u64::from(::std::u32::MAX);
+            if bytes.len() as u64 > limit {
+                let words = (bytes.len() as u64 + 7) / 8;
+                return Promise::err(Error::MessageTooLarge { words: words, limit: limit / 8 }.into())
+            }
+            let mut encoded = vec![0; 4];
+            LittleEndian::write_u32(&mut encoded, bytes.len() as u32);
+            encoded.extend_from_slice(&bytes);
+
+            let Layered {
+                stream, layers, counts, reader_options, max_encoded_bytes, max_decoded_bytes, serialize_options
+            } = layered;
+            serialize::write_raw_message_with_options(stream, encoded, None, serialize_options).map(move |(stream, _)| {
+                Ok(Layered {
+                    stream: stream,
+                    layers: layers,
+                    counts: counts,
+                    reader_options: reader_options,
+                    max_encoded_bytes: max_encoded_bytes,
+                    max_decoded_bytes: max_decoded_bytes,
+                    serialize_options: serialize_options,
+                })
+            })
+        })
+    }
+}
+
+impl <S> Layered<S> where S: AsyncRead + 'static {
+    /// Returns None on EOF.
+    pub fn try_read_message(self) -> Promise<(Layered<S>, Option<message::Reader<OwnedSegments>>), ::capnp::Error> {
+        let Layered {
+            mut stream, layers, counts, reader_options, max_encoded_bytes, max_decoded_bytes, serialize_options
+        } = self;
+        let buf: Vec<u8> = vec![0; 4];
+        stream.try_read(buf, 4).then_else(move |r| {
+            let mut layered = Layered {
+                stream: stream,
+                layers: layers,
+                counts: counts,
+                reader_options: reader_options,
+                max_encoded_bytes: max_encoded_bytes,
+                max_decoded_bytes: max_decoded_bytes,
+                serialize_options: serialize_options,
+            };
+            match r {
+                Err(e) => Promise::err(e.into()),
+                Ok((_, 0)) => Promise::ok((layered, None)),
+                Ok((_, n)) if n < 4 =>
+                    Promise::err(::Error::TruncatedHeader { received: n, expected: 4 }.into()),
+                Ok((buf, _)) => {
+                    let len = LittleEndian::read_u32(&buf);
+                    if len > layered.max_encoded_bytes {
+                        return Promise::err(::capnp::Error::failed(
+                            format!("Encoded message of {} bytes exceeds the limit of {}",
+                                    len, layered.max_encoded_bytes)))
+                    }
+                    let len = len as usize;
+                    layered.stream.read(vec![0; len], len).lift().then(move |(bytes, _)| {
+                        let layer_count = layered.layers.len();
+                        layered.decode_from(layer_count, bytes)
+                    }).map(|(layered, bytes)| {
+                        if bytes.len() % 8 != 0 {
+                            return Err(::capnp::Error::failed(
+                                format!("Decoded message of {} bytes is not a whole number of words",
+                                        bytes.len())))
+                        }
+                        let mut words = Word::allocate_zeroed_vec(bytes.len() / 8);
+                        Word::words_to_bytes_mut(&mut words[..]).copy_from_slice(&bytes);
+                        let segments = try!(OwnedSegments::from_words_with_options(words,
+                                                                                   &layered.serialize_options));
+                        let message = message::Reader::new(segments, layered.reader_options);
+                        Ok((layered, Some(message)))
+                    })
+                }
+            }
+        })
+    }
+
+    pub fn read_message(self) -> Promise<(Layered<S>, message::Reader<OwnedSegments>), ::capnp::Error> {
+        self.try_read_message().map(|(layered, message)| match message {
+            Some(m) => Ok((layered, m)),
+            None => Err(::Error::CleanEof.into()),
+        })
+    }
+}
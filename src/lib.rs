@@ -21,8 +21,54 @@
 
 extern crate byteorder;
 extern crate capnp;
-extern crate gj;
+#[macro_use] extern crate gj;
 extern crate gjio;
+extern crate libc;
+extern crate net2;
 
+#[cfg(unix)] pub mod activation;
+pub mod backfill;
+pub mod budget;
+#[cfg(unix)] pub mod channel;
+pub mod codec;
+pub mod compat;
+pub mod connect;
+pub mod connection;
+pub mod correlate;
+mod crc32c;
+pub mod datagram;
+pub mod dedup;
+pub mod delta;
+#[cfg(unix)] pub mod descriptors;
+pub mod error;
+pub mod fault;
+mod frame;
+mod fulfiller;
+#[cfg(unix)] pub mod handover;
+pub mod idle;
+pub mod layer;
+pub mod loopback;
+mod lz4;
+pub mod metrics;
+pub mod mux;
+#[cfg(unix)] pub mod offload;
+pub mod peer;
+pub mod proxy;
+pub mod relay;
+pub mod reconnect;
+pub mod reliable;
+pub mod ring;
 pub mod serialize;
+pub mod serialize_packed;
+pub mod server;
+#[cfg(unix)] pub mod shard;
+pub mod testing;
+pub mod throttle;
+pub mod timing;
+pub mod typed;
+pub mod upload;
+pub mod watchdog;
+pub mod writer;
+
+pub use error::Error;
 
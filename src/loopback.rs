@@ -0,0 +1,137 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! A pair of connected message endpoints within one process, for tests and for components that
+//! talk to each other as though over a connection. Messages pass from one end to the other as
+//! copies of their segments, without being framed into bytes and parsed back.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use capnp::{message, Word};
+use gj::Promise;
+
+use fulfiller::{self, Fulfiller};
+use serialize::OwnedSegments;
+use writer::AsyncMessageWriter;
+use Error;
+
+/// Messages travelling in one direction.
+struct Queue {
+    messages: VecDeque<OwnedSegments>,
+    receivers: VecDeque<Fulfiller<Option<OwnedSegments>>>,
+    sender_dropped: bool,
+    receiver_dropped: bool,
+}
+
+impl Queue {
+    fn new() -> Rc<RefCell<Queue>> {
+        Rc::new(RefCell::new(Queue {
+            messages: VecDeque::new(),
+            receivers: VecDeque::new(),
+            sender_dropped: false,
+            receiver_dropped: false,
+        }))
+    }
+}
+
+/// One end of a loopback pair. Messages sent from one end are received at the other in the
+/// order they were sent. Dropping an end is like closing a connection: the other end receives
+/// whatever was already sent and then None, and its sends fail as disconnected.
+pub struct Loopback {
+    incoming: Rc<RefCell<Queue>>,
+    outgoing: Rc<RefCell<Queue>>,
+    reader_options: message::ReaderOptions,
+}
+
+/// Returns two connected ends.
+pub fn pair() -> (Loopback, Loopback) {
+    pair_with_options(message::ReaderOptions::new())
+}
+
+/// Like `pair()`, but received messages are read with `reader_options`.
+pub fn pair_with_options(reader_options: message::ReaderOptions) -> (Loopback, Loopback) {
+    let a_to_b = Queue::new();
+    let b_to_a = Queue::new();
+    (Loopback { incoming: b_to_a.clone(), outgoing: a_to_b.clone(), reader_options: reader_options },
+     Loopback { incoming: a_to_b, outgoing: b_to_a, reader_options: reader_options })
+}
+
+impl Loopback {
+    /// Sends a copy of `message` to the other end. The returned promise resolves at once, or
+    /// fails if the other end has been dropped.
+    pub fn send<A>(&mut self, message: &message::Builder<A>) -> Promise<(), ::capnp::Error>
+        where A: message::Allocator
+    {
+        self.write_segments(&message.get_segments_for_output())
+    }
+
+    /// Receives the next message from the other end, or None once the other end has been dropped
+    /// and every message it sent has been received. Receives resolve in the order they were made. A
+    /// message that arrives for a receive whose promise has been dropped is lost.
+    pub fn recv(&mut self) -> Promise<Option<message::Reader<OwnedSegments>>, ::capnp::Error> {
+        let reader_options = self.reader_options;
+        let mut incoming = self.incoming.borrow_mut();
+        let segments = if let Some(segments) = incoming.messages.pop_front() {
+            Promise::ok(Some(segments))
+        } else if incoming.sender_dropped {
+            Promise::ok(None)
+        } else {
+            let (promise, fulfiller) = fulfiller::and_fulfiller();
+            incoming.receivers.push_back(fulfiller);
+            promise
+        };
+        segments.map(move |segments| Ok(segments.map(|s| message::Reader::new(s, reader_options))))
+    }
+}
+
+impl AsyncMessageWriter for Loopback {
+    fn write_segments(&mut self, segments: &[&[Word]]) -> Promise<(), ::capnp::Error> {
+        let mut outgoing = self.outgoing.borrow_mut();
+        if outgoing.receiver_dropped {
+            return Promise::err(::capnp::Error::disconnected("the other end has been dropped".to_string()))
+        }
+        if segments.is_empty() {
+            return Promise::err(Error::NoSegments.into())
+        }
+        let segments = OwnedSegments::copy_of(segments);
+        match outgoing.receivers.pop_front() {
+            Some(fulfiller) => fulfiller.fulfill(Some(segments)),
+            None => outgoing.messages.push_back(segments),
+        }
+        Promise::ok(())
+    }
+}
+
+impl Drop for Loopback {
+    fn drop(&mut self) {
+        let mut outgoing = self.outgoing.borrow_mut();
+        outgoing.sender_dropped = true;
+        for fulfiller in outgoing.receivers.drain(..) {
+            fulfiller.fulfill(None);
+        }
+        let mut incoming = self.incoming.borrow_mut();
+        incoming.receiver_dropped = true;
+        incoming.messages.clear();
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! The LZ4 block format, used by `layer::Lz4`.
+//!
+//! A block is a series of sequences, each a run of literal bytes followed by a copy of earlier
+//! output. A sequence starts with a token byte whose high nibble is the number of literals and
+//! whose low nibble is the length of the copy minus 4; a nibble of 15 means that more length
+//! bytes follow, each added to it, until one that is not 255. Then come the literals, and then
+//! the distance back to the start of the copy as a little-endian `u16`, and then any more length
+//! bytes for the copy. The last sequence has literals only, and holds at least the last 5 bytes.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+const MIN_MATCH: usize = 4;
+
+// A copy must end at least this many bytes before the end of the input.
+const LAST_LITERALS: usize = 5;
+
+// A copy must start at least this many bytes before the end of the input.
+const MF_LIMIT: usize = 12;
+
+const MAX_OFFSET: usize = 65535;
+
+const HASH_LOG: u32 = 12;
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+fn write_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn write_literals(out: &mut Vec<u8>, literals: &[u8], match_nibble: u8) {
+    let len = literals.len();
+    out.push(((::std::cmp::min(len, 15) as u8) << 4) | match_nibble);
+    if len >= 15 {
+        write_length(out, len - 15);
+    }
+    out.extend_from_slice(literals);
+}
+
+/// Compresses `input` into a single block, finding copies greedily through a table of where
+/// each 4-byte sequence was last seen.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 16);
+    let mut anchor = 0;
+    if input.len() > MF_LIMIT {
+        let mut table = vec![0usize; 1 << HASH_LOG];
+        let match_limit = input.len() - LAST_LITERALS;
+        let search_limit = input.len() - MF_LIMIT;
+        let mut pos = 0;
+        while pos <= search_limit {
+            let sequence = LittleEndian::read_u32(&input[pos..]);
+            let slot = &mut table[hash(sequence)];
+            // Entries are positions plus one, so that zero means empty.
+            let candidate = *slot;
+            *slot = pos + 1;
+            if candidate > 0 && pos + 1 - candidate <= MAX_OFFSET &&
+                LittleEndian::read_u32(&input[(candidate - 1)..]) == sequence
+            {
+                let start = candidate - 1;
+                let mut len = MIN_MATCH;
+                while pos + len < match_limit && input[start + len] == input[pos + len] {
+                    len += 1;
+                }
+                let match_len = len - MIN_MATCH;
+                write_literals(&mut out, &input[anchor..pos], ::std::cmp::min(match_len, 15) as u8);
+                let mut offset = [0; 2];
+                LittleEndian::write_u16(&mut offset, (pos - start) as u16);
+                out.extend_from_slice(&offset);
+                if match_len >= 15 {
+                    write_length(&mut out, match_len - 15);
+                }
+                pos += len;
+                anchor = pos;
+            } else {
+                pos += 1;
+            }
+        }
+    }
+    write_literals(&mut out, &input[anchor..], 0);
+    out
+}
+
+fn read_length(input: &[u8], pos: &mut usize) -> ::capnp::Result<usize> {
+    let mut len = 0;
+    loop {
+        let byte = match input.get(*pos) {
+            Some(&b) => b,
+            None => return Err(truncated()),
+        };
+        *pos += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Ok(len)
+        }
+    }
+}
+
+fn truncated() -> ::capnp::Error {
+    ::capnp::Error::failed("LZ4 block is truncated".to_string())
+}
+
+/// Decompresses a block that holds exactly `decompressed_len` bytes, never allocating or writing
+/// more than that.
+pub fn decompress(input: &[u8], decompressed_len: usize) -> ::capnp::Result<Vec<u8>> {
+    let mut out: Vec<u8> = Vec::with_capacity(decompressed_len);
+    let mut pos = 0;
+    loop {
+        let token = match input.get(pos) {
+            Some(&t) => t,
+            None => return Err(truncated()),
+        };
+        pos += 1;
+        let mut literal_len = (token >> 4) as usize;
+        if literal_len == 15 {
+            literal_len += try!(read_length(input, &mut pos));
+        }
+        if input.len() - pos < literal_len {
+            return Err(truncated())
+        }
+        if decompressed_len - out.len() < literal_len {
+            return Err(overrun(decompressed_len))
+        }
+        out.extend_from_slice(&input[pos..(pos + literal_len)]);
+        pos += literal_len;
+        if pos == input.len() {
+            break
+        }
+
+        if input.len() - pos < 2 {
+            return Err(truncated())
+        }
+        let offset = LittleEndian::read_u16(&input[pos..]) as usize;
+        pos += 2;
+        if offset == 0 || offset > out.len() {
+            return Err(::capnp::Error::failed(
+                format!("LZ4 copy reaches {} bytes back, but only {} have been decompressed", offset, out.len())))
+        }
+        let mut match_len = (token & 15) as usize;
+        if match_len == 15 {
+            match_len += try!(read_length(input, &mut pos));
+        }
+        match_len += MIN_MATCH;
+        if decompressed_len - out.len() < match_len {
+            return Err(overrun(decompressed_len))
+        }
+        // The copy may overlap the bytes it produces, so it goes a byte at a time.
+        let start = out.len() - offset;
+        for idx in start..(start + match_len) {
+            let byte = out[idx];
+            out.push(byte);
+        }
+    }
+    if out.len() != decompressed_len {
+        return Err(::capnp::Error::failed(
+            format!("LZ4 block decompressed to {} bytes, but {} were expected", out.len(), decompressed_len)))
+    }
+    Ok(out)
+}
+
+fn overrun(decompressed_len: usize) -> ::capnp::Error {
+    ::capnp::Error::failed(format!("LZ4 block decompresses to more than the expected {} bytes", decompressed_len))
+}
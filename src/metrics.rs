@@ -0,0 +1,182 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Counting the messages that pass through, for throughput and latency metrics.
+//!
+//! An implementation of `Metrics` is told the size of each message read or written and how long
+//! it took. Give one to a `StreamWriter` or a `Connection` once, and every message through it is
+//! counted; for one-off reads and writes, the functions here do what their namesakes in
+//! `serialize` do and report to it too. `Counters` keeps running totals.
+//!
+//! ```text
+//! let counters = Rc::new(metrics::Counters::new());
+//! connection.set_metrics(counters.clone());
+//! ...
+//! export("bytes_read", counters.bytes_read());
+//! ```
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use capnp::message;
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite};
+
+use serialize::{self, OwnedSegments};
+
+/// What was measured for one message.
+#[derive(Clone, Copy, Debug)]
+pub struct MessageStats {
+    /// The size of the message on the wire, segment table included.
+    pub bytes: u64,
+    pub segments: usize,
+
+    /// For a read, from when the read started until the whole message had arrived; for a write,
+    /// from when the message was handed over until all of it had been written.
+    pub elapsed: Duration,
+}
+
+/// Receives measurements. Both methods do nothing unless overridden.
+pub trait Metrics {
+    fn message_read(&self, _stats: &MessageStats) {}
+    fn message_written(&self, _stats: &MessageStats) {}
+}
+
+/// Running totals of everything read and written.
+#[derive(Debug, Default)]
+pub struct Counters {
+    messages_read: Cell<u64>,
+    bytes_read: Cell<u64>,
+    read_time: Cell<Duration>,
+    messages_written: Cell<u64>,
+    bytes_written: Cell<u64>,
+    write_time: Cell<Duration>,
+}
+
+impl Counters {
+    pub fn new() -> Counters {
+        Counters::default()
+    }
+
+    pub fn messages_read(&self) -> u64 {
+        self.messages_read.get()
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.get()
+    }
+
+    /// The sum of `elapsed` over every message read.
+    pub fn read_time(&self) -> Duration {
+        self.read_time.get()
+    }
+
+    pub fn messages_written(&self) -> u64 {
+        self.messages_written.get()
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.get()
+    }
+
+    /// The sum of `elapsed` over every message written.
+    pub fn write_time(&self) -> Duration {
+        self.write_time.get()
+    }
+}
+
+impl Metrics for Counters {
+    fn message_read(&self, stats: &MessageStats) {
+        self.messages_read.set(self.messages_read.get() + 1);
+        self.bytes_read.set(self.bytes_read.get() + stats.bytes);
+        self.read_time.set(self.read_time.get() + stats.elapsed);
+    }
+
+    fn message_written(&self, stats: &MessageStats) {
+        self.messages_written.set(self.messages_written.get() + 1);
+        self.bytes_written.set(self.bytes_written.get() + stats.bytes);
+        self.write_time.set(self.write_time.get() + stats.elapsed);
+    }
+}
+
+/// Reports a message that was read, and hands it back.
+pub(crate) fn report_read(metrics: &Metrics,
+                          started: Instant,
+                          message: message::Reader<OwnedSegments>,
+                          reader_options: message::ReaderOptions) -> message::Reader<OwnedSegments> {
+    let segments = message.into_segments();
+    metrics.message_read(&MessageStats {
+        bytes: segments.wire_size_in_bytes() as u64,
+        segments: segments.segment_count(),
+        elapsed: started.elapsed(),
+    });
+    message::Reader::new(segments, reader_options)
+}
+
+/// Like `serialize::try_read_message_with_options()`, and reports the message to `metrics`.
+/// Nothing is reported on EOF.
+pub fn try_read_message<S>(stream: S,
+                           reader_options: message::ReaderOptions,
+                           options: serialize::Options,
+                           metrics: &Rc<Metrics>)
+                           -> Promise<(S, Option<message::Reader<OwnedSegments>>), ::capnp::Error>
+    where S: AsyncRead + 'static
+{
+    let started = Instant::now();
+    let metrics = metrics.clone();
+    serialize::try_read_message_with_options(stream, reader_options, options).map(move |(stream, message)| {
+        Ok((stream, message.map(|m| report_read(&*metrics, started, m, reader_options))))
+    })
+}
+
+/// Like `serialize::read_message_with_options()`, and reports the message to `metrics`.
+pub fn read_message<S>(stream: S,
+                       reader_options: message::ReaderOptions,
+                       options: serialize::Options,
+                       metrics: &Rc<Metrics>)
+                       -> Promise<(S, message::Reader<OwnedSegments>), ::capnp::Error>
+    where S: AsyncRead + 'static
+{
+    try_read_message(stream, reader_options, options, metrics).map(|(stream, message)| match message {
+        Some(m) => Ok((stream, m)),
+        None => Err(::Error::CleanEof.into()),
+    })
+}
+
+/// Like `serialize::write_message_ref_with_options()`, and reports the message to `metrics`.
+pub fn write_message<S, A>(stream: S,
+                           message: &message::Builder<A>,
+                           options: serialize::Options,
+                           metrics: &Rc<Metrics>)
+                           -> Promise<S, ::capnp::Error>
+    where S: AsyncWrite + 'static, A: message::Allocator
+{
+    let started = Instant::now();
+    let segments = message.get_segments_for_output().len();
+    let bytes = serialize::compute_serialized_size_in_words(message) as u64 * 8;
+    let metrics = metrics.clone();
+    serialize::write_message_ref_with_options(stream, message, options).map(move |stream| {
+        metrics.message_written(&MessageStats { bytes: bytes, segments: segments, elapsed: started.elapsed() });
+        Ok(stream)
+    })
+}
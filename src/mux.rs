@@ -0,0 +1,574 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Carrying many independent message streams, called channels, over one connection.
+//!
+//! Each message travels in a frame (see the `frame` module) whose payload starts with a word
+//! holding the 64-bit channel ID, followed by the serialized message. A message frame has kind 0.
+//! A close frame (kind 1) carries only the channel ID and tells the peer that no more messages
+//! will follow on that channel.
+//!
+//! With `Options::window_bytes()`, each channel also has a flow-control window, so that a
+//! channel whose reader falls behind cannot fill up memory or hold back the others. A sender may
+//! have at most that many bytes of messages outstanding on a channel; further sends on that
+//! channel wait, while other channels carry on. The receiver counts a message as taken once
+//! `recv()` has handed it out, and returns the credit in a window frame (kind 2), whose payload is
+//! the channel ID followed by the number of bytes credited. A message larger than the whole
+//! window is sent once nothing else is outstanding on its channel. Both ends must be given the
+//! same window, and a peer that overruns it is treated as a protocol error.
+//!
+//! `Multiplexer::keepalive()` sends a ping frame (kind 3) at a fixed interval, and the peer
+//! answers each with a pong frame (kind 4). Both carry a sequence number in place of the channel
+//! ID. The time until the pong arrives is the round-trip time, and a connection that stops
+//! answering is ended rather than left to fail on the next write, perhaps long after a NAT along
+//! the way has forgotten it.
+//!
+//! Either end may open a channel with any ID it likes by calling `Multiplexer::channel()`, so the
+//! two ends must agree on who picks which IDs, for instance by having one side use only even IDs.
+//! A channel that the peer sends on before it has been opened here is handed out by `accept()`.
+//! Messages that arrive for a channel are queued until they are received, so a channel that is
+//! never read holds on to everything sent to it.
+//!
+//! ```text
+//! let mux = Multiplexer::new(stream.clone(), stream, mux::Options::new());
+//! let mut control = mux.channel(0);
+//! control.send(&message);
+//! ```
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+
+use capnp::{message, Word};
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite, Timer};
+
+use frame;
+use fulfiller::{self, Fulfiller};
+use serialize::{self, OwnedSegments};
+use writer::{self, AsyncMessageWriter};
+
+const MESSAGE: u32 = 0;
+const CLOSE: u32 = 1;
+const WINDOW: u32 = 2;
+const PING: u32 = 3;
+const PONG: u32 = 4;
+
+/// Options for a `Multiplexer`.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    reader_options: message::ReaderOptions,
+    serialize_options: serialize::Options,
+    window_bytes: Option<u64>,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            reader_options: message::ReaderOptions::new(),
+            serialize_options: serialize::Options::new(),
+            window_bytes: None,
+        }
+    }
+
+    /// Gives each channel a flow-control window of this many bytes of serialized messages. By
+    /// default, channels have no window and a sender never waits for its peer to catch up.
+    pub fn window_bytes(mut self, value: u64) -> Options {
+        self.window_bytes = Some(::std::cmp::max(value, 1));
+        self
+    }
+
+    /// Limits on the messages that arrive. The traversal limit also bounds the size of a frame.
+    pub fn reader_options(mut self, value: message::ReaderOptions) -> Options {
+        self.reader_options = value;
+        self
+    }
+
+    pub fn serialize_options(mut self, value: serialize::Options) -> Options {
+        self.serialize_options = value;
+        self
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options { Options::new() }
+}
+
+type Received = Option<message::Reader<OwnedSegments>>;
+
+/// A message frame that is waiting for credit, with its size and the fulfiller to hand its write
+/// to once it is sent.
+type Blocked = (u64, Vec<Word>, Fulfiller<Promise<(), ::capnp::Error>>);
+
+#[derive(Default)]
+struct ChannelState {
+    messages: VecDeque<(message::Reader<OwnedSegments>, u64)>,
+    receivers: VecDeque<Fulfiller<Received>>,
+    closed_by_peer: bool,
+    opened: bool,
+
+    /// Bytes sent on the channel that the peer has not yet credited back.
+    unacknowledged_sent: u64,
+    blocked: VecDeque<Blocked>,
+
+    /// Bytes received on the channel that have not yet been credited back to the peer, and how
+    /// many of those have been handed out by `recv()`.
+    unacknowledged_received: u64,
+    taken: u64,
+}
+
+/// Whether `bytes` more may be sent with `outstanding` bytes not yet credited back.
+fn fits(outstanding: u64, bytes: u64, window: u64) -> bool {
+    outstanding == 0 || outstanding + bytes <= window
+}
+
+#[derive(Default)]
+struct Keepalive {
+    next_seq: u64,
+
+    /// The ping that has not been answered yet, and when it was sent.
+    outstanding: Option<(u64, Instant)>,
+    missed: u32,
+    last_rtt: Option<Duration>,
+    smoothed_rtt: Option<Duration>,
+}
+
+struct Shared<W> where W: AsyncWrite + 'static {
+    writes: Promise<W, ::capnp::Error>,
+    channels: HashMap<u64, ChannelState>,
+    options: Options,
+
+    /// Channels that the peer started and that have not been opened here yet.
+    unclaimed: VecDeque<u64>,
+    acceptors: VecDeque<Fulfiller<Option<u64>>>,
+
+    keepalive: Keepalive,
+
+    /// How the connection ended, once it has.
+    ended: Option<Result<(), ::capnp::Error>>,
+}
+
+impl <W> Shared<W> where W: AsyncWrite + 'static {
+    fn end(&mut self, result: Result<(), ::capnp::Error>) {
+        if self.ended.is_some() {
+            return
+        }
+        for (_, channel) in self.channels.iter_mut() {
+            for fulfiller in channel.receivers.drain(..) {
+                match result {
+                    Ok(()) => fulfiller.fulfill(None),
+                    Err(ref e) => fulfiller.reject(e.clone()),
+                }
+            }
+            // No more credit can arrive.
+            for (_, _, fulfiller) in channel.blocked.drain(..) {
+                fulfiller.reject(match result {
+                    Ok(()) => ::capnp::Error::disconnected("Peer closed the connection".to_string()),
+                    Err(ref e) => e.clone(),
+                });
+            }
+        }
+        for fulfiller in self.acceptors.drain(..) {
+            fulfiller.fulfill(None);
+        }
+        self.ended = Some(result);
+    }
+
+    /// Takes the next channel from `unclaimed` that has still not been opened, and opens it.
+    fn claim(&mut self) -> Option<u64> {
+        while let Some(id) = self.unclaimed.pop_front() {
+            let channel = self.channels.entry(id).or_insert_with(ChannelState::default);
+            if !channel.opened {
+                channel.opened = true;
+                return Some(id)
+            }
+        }
+        None
+    }
+
+    fn deliver(&mut self, id: u64, message: message::Reader<OwnedSegments>, bytes: u64)
+               -> ::capnp::Result<()>
+    {
+        let new = !self.channels.contains_key(&id);
+        let taken = {
+            let channel = self.channels.entry(id).or_insert_with(ChannelState::default);
+            if let Some(window) = self.options.window_bytes {
+                if !fits(channel.unacknowledged_received, bytes, window) {
+                    return Err(::capnp::Error::failed(
+                        format!("Peer overran the flow-control window of channel {}", id)))
+                }
+                channel.unacknowledged_received += bytes;
+            }
+            match channel.receivers.pop_front() {
+                Some(fulfiller) => {
+                    fulfiller.fulfill(Some(message));
+                    true
+                }
+                None => {
+                    channel.messages.push_back((message, bytes));
+                    false
+                }
+            }
+        };
+        if taken {
+            self.take(id, bytes);
+        }
+        if new {
+            self.unclaimed.push_back(id);
+            if !self.acceptors.is_empty() {
+                if let Some(id) = self.claim() {
+                    self.acceptors.pop_front().unwrap().fulfill(Some(id));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records that `recv()` has handed out `bytes` on channel `id`, and credits the peer once
+    /// half of the window has been taken.
+    fn take(&mut self, id: u64, bytes: u64) {
+        let window = match self.options.window_bytes {
+            Some(w) => w,
+            None => return,
+        };
+        let Shared { ref mut channels, ref mut writes, options, .. } = *self;
+        let channel = channels.entry(id).or_insert_with(ChannelState::default);
+        channel.taken += bytes;
+        if channel.taken >= ::std::cmp::max(window / 2, 1) {
+            let mut frame = frame::begin(WINDOW);
+            frame.push(frame::from_u64(id));
+            frame.push(frame::from_u64(channel.taken));
+            frame::finish(&mut frame);
+            channel.unacknowledged_received -= channel.taken;
+            channel.taken = 0;
+            let _ = frame::write_after(writes, frame, options.serialize_options);
+        }
+    }
+
+    /// Adds the credit that the peer has returned for channel `id`, and sends whatever it lets
+    /// through.
+    fn credit(&mut self, id: u64, bytes: u64) {
+        let window = self.options.window_bytes.unwrap_or(u64::max_value());
+        let Shared { ref mut channels, ref mut writes, options, .. } = *self;
+        let channel = channels.entry(id).or_insert_with(ChannelState::default);
+        channel.unacknowledged_sent -= ::std::cmp::min(bytes, channel.unacknowledged_sent);
+        while channel.blocked.front().map_or(false, |b| fits(channel.unacknowledged_sent, b.0, window)) {
+            let (bytes, frame, fulfiller) = channel.blocked.pop_front().unwrap();
+            channel.unacknowledged_sent += bytes;
+            fulfiller.fulfill(frame::write_after(writes, frame, options.serialize_options));
+        }
+    }
+
+    /// Sends the next ping, first ending the connection if the last `max_missed` pings have gone
+    /// unanswered.
+    fn ping(&mut self, max_missed: u32) -> ::capnp::Result<()> {
+        if self.keepalive.outstanding.is_some() {
+            self.keepalive.missed += 1;
+            if self.keepalive.missed >= max_missed {
+                let error = ::capnp::Error::disconnected(
+                    format!("Peer did not answer {} pings", self.keepalive.missed));
+                self.end(Err(error.clone()));
+                return Err(error)
+            }
+        }
+        let seq = self.keepalive.next_seq;
+        self.keepalive.next_seq += 1;
+        self.keepalive.outstanding = Some((seq, Instant::now()));
+        self.send_control(PING, seq);
+        Ok(())
+    }
+
+    fn pong(&mut self, seq: u64) {
+        let sent = match self.keepalive.outstanding {
+            Some((outstanding, sent)) if outstanding == seq => sent,
+            // An answer to a ping that was already given up on.
+            _ => return,
+        };
+        let rtt = sent.elapsed();
+        self.keepalive.outstanding = None;
+        self.keepalive.missed = 0;
+        self.keepalive.last_rtt = Some(rtt);
+        self.keepalive.smoothed_rtt = Some(match self.keepalive.smoothed_rtt {
+            Some(smoothed) => smoothed * 7 / 8 + rtt / 8,
+            None => rtt,
+        });
+    }
+
+    /// Sends a frame that carries only `value` in place of a channel ID.
+    fn send_control(&mut self, kind: u32, value: u64) {
+        let mut frame = frame::begin(kind);
+        frame.push(frame::from_u64(value));
+        frame::finish(&mut frame);
+        let _ = frame::write_after(&mut self.writes, frame, self.options.serialize_options);
+    }
+
+    /// Sends a message frame of `bytes` on channel `id`, or queues it until there is credit.
+    fn send_message(&mut self, id: u64, bytes: u64, frame: Vec<Word>) -> Promise<(), ::capnp::Error> {
+        let window = match self.options.window_bytes {
+            Some(w) => w,
+            None => return frame::write_after(&mut self.writes, frame, self.options.serialize_options),
+        };
+        let Shared { ref mut channels, ref mut writes, options, .. } = *self;
+        let channel = channels.entry(id).or_insert_with(ChannelState::default);
+        if channel.blocked.is_empty() && fits(channel.unacknowledged_sent, bytes, window) {
+            channel.unacknowledged_sent += bytes;
+            frame::write_after(writes, frame, options.serialize_options)
+        } else {
+            let (promise, fulfiller) = fulfiller::and_fulfiller();
+            channel.blocked.push_back((bytes, frame, fulfiller));
+            promise.then(|written| written)
+        }
+    }
+
+    fn close_by_peer(&mut self, id: u64) {
+        let channel = self.channels.entry(id).or_insert_with(ChannelState::default);
+        channel.closed_by_peer = true;
+        for fulfiller in channel.receivers.drain(..) {
+            fulfiller.fulfill(None);
+        }
+    }
+}
+
+/// Carries channels over one pair of streams, usually two clones of one socket.
+pub struct Multiplexer<W> where W: AsyncWrite + 'static {
+    shared: Rc<RefCell<Shared<W>>>,
+    options: Options,
+    _reads: Promise<(), ::capnp::Error>,
+}
+
+impl <W> Multiplexer<W> where W: AsyncWrite + 'static {
+    /// Starts reading frames from `reader` at once. Dropping the `Multiplexer` stops the reads,
+    /// but the streams live on until every `Channel` has been dropped too.
+    pub fn new<R>(reader: R, writer: W, options: Options) -> Multiplexer<W> where R: AsyncRead + 'static {
+        let shared = Rc::new(RefCell::new(Shared {
+            writes: Promise::ok(writer),
+            channels: HashMap::new(),
+            options: options,
+            unclaimed: VecDeque::new(),
+            acceptors: VecDeque::new(),
+            keepalive: Keepalive::default(),
+            ended: None,
+        }));
+        let shared1 = shared.clone();
+        let reads = read_loop(reader, shared.clone(), options).map_else(move |r| {
+            shared1.borrow_mut().end(r.map_err(|e| {
+                ::capnp::Error::disconnected(format!("Connection failed: {}", e))
+            }));
+            Ok(())
+        }).eagerly_evaluate();
+        Multiplexer { shared: shared, options: options, _reads: reads }
+    }
+
+    /// Opens the channel with the given ID. Opening an ID twice gives two handles on one channel.
+    pub fn channel(&self, id: u64) -> Channel<W> {
+        self.shared.borrow_mut().channels.entry(id).or_insert_with(ChannelState::default).opened = true;
+        self.handle(id)
+    }
+
+    /// Resolves to the next channel that the peer sent on before it was opened here, or to None
+    /// once the connection has ended.
+    pub fn accept(&self) -> Promise<Option<Channel<W>>, ::capnp::Error> {
+        let shared = self.shared.clone();
+        let options = self.options;
+        let id = {
+            let mut shared = self.shared.borrow_mut();
+            if let Some(id) = shared.claim() {
+                return Promise::ok(Some(self.handle(id)))
+            }
+            if shared.ended.is_some() {
+                return Promise::ok(None)
+            }
+            let (promise, fulfiller) = fulfiller::and_fulfiller();
+            shared.acceptors.push_back(fulfiller);
+            promise
+        };
+        id.map(move |id| Ok(id.map(|id| Channel { id: id, shared: shared, options: options })))
+    }
+
+    /// Sends a ping every `interval`. If `max_missed` pings in a row are still unanswered when the
+    /// next one is due, the connection is ended with a `Disconnected` error, which every channel
+    /// sees, and the returned promise fails with that error. The multiplexer should then be
+    /// dropped to close the streams. Runs until the connection ends, the multiplexer is dropped,
+    /// or the promise is dropped. Pings from the peer are answered whether or not this is called.
+    pub fn keepalive(&self, timer: &Timer, interval: Duration, max_missed: u32)
+                     -> Promise<(), ::capnp::Error>
+    {
+        keepalive_loop(Rc::downgrade(&self.shared), timer.clone(), interval, ::std::cmp::max(max_missed, 1))
+    }
+
+    /// The round-trip time measured by the most recent ping.
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.shared.borrow().keepalive.last_rtt
+    }
+
+    /// A moving average of the round-trip times measured by pings, weighted towards recent ones.
+    pub fn smoothed_rtt(&self) -> Option<Duration> {
+        self.shared.borrow().keepalive.smoothed_rtt
+    }
+
+    /// The number of pings in a row that have gone unanswered.
+    pub fn missed_pings(&self) -> u32 {
+        self.shared.borrow().keepalive.missed
+    }
+
+    fn handle(&self, id: u64) -> Channel<W> {
+        Channel { id: id, shared: self.shared.clone(), options: self.options }
+    }
+}
+
+/// One of the message streams carried by a `Multiplexer`.
+pub struct Channel<W> where W: AsyncWrite + 'static {
+    id: u64,
+    shared: Rc<RefCell<Shared<W>>>,
+    options: Options,
+}
+
+impl <W> Channel<W> where W: AsyncWrite + 'static {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Queues `message` behind every frame already sent on the connection, on any channel, and
+    /// resolves once it has been written.
+    pub fn send<A>(&mut self, message: &message::Builder<A>) -> Promise<(), ::capnp::Error>
+        where A: message::Allocator
+    {
+        self.write_segments(&message.get_segments_for_output())
+    }
+
+    /// Resolves to the next message on this channel, or to None once the peer has closed the
+    /// channel or the connection has ended.
+    pub fn recv(&self) -> Promise<Option<message::Reader<OwnedSegments>>, ::capnp::Error> {
+        let mut shared = self.shared.borrow_mut();
+        let ended = shared.ended.clone();
+        let queued = {
+            let channel = shared.channels.entry(self.id).or_insert_with(ChannelState::default);
+            match channel.messages.pop_front() {
+                Some(queued) => queued,
+                None if channel.closed_by_peer => return Promise::ok(None),
+                None => return match ended {
+                    Some(Ok(())) => Promise::ok(None),
+                    Some(Err(e)) => Promise::err(e),
+                    None => {
+                        let (promise, fulfiller) = fulfiller::and_fulfiller();
+                        channel.receivers.push_back(fulfiller);
+                        promise
+                    }
+                },
+            }
+        };
+        let (message, bytes) = queued;
+        shared.take(self.id, bytes);
+        Promise::ok(Some(message))
+    }
+
+    /// The number of sends on this channel that are waiting for the peer to return credit.
+    pub fn waiting_for_credit(&self) -> usize {
+        self.shared.borrow().channels.get(&self.id).map_or(0, |c| c.blocked.len())
+    }
+
+    /// Tells the peer that no more messages will be sent on this channel. The close frame waits
+    /// behind any sends on the channel that are waiting for credit.
+    pub fn close(&mut self) -> Promise<(), ::capnp::Error> {
+        self.send_frame(CLOSE, Vec::new())
+    }
+
+    fn send_frame(&mut self, kind: u32, message: Vec<Word>) -> Promise<(), ::capnp::Error> {
+        let bytes = message.len() as u64 * 8;
+        let mut frame = frame::begin(kind);
+        frame.push(frame::from_u64(self.id));
+        frame.extend(message);
+        frame::finish(&mut frame);
+        let mut shared = self.shared.borrow_mut();
+        if let Some(Err(ref e)) = shared.ended {
+            return Promise::err(e.clone())
+        }
+        shared.send_message(self.id, bytes, frame)
+    }
+}
+
+impl <W> AsyncMessageWriter for Channel<W> where W: AsyncWrite + 'static {
+    fn write_segments(&mut self, segments: &[&[Word]]) -> Promise<(), ::capnp::Error> {
+        pry!(self.options.serialize_options.check_outgoing_words(
+            segments.iter().fold(0, |n, s| n + s.len() as u64)));
+        self.send_frame(MESSAGE, pry!(writer::flatten(segments)))
+    }
+}
+
+fn keepalive_loop<W>(shared: Weak<RefCell<Shared<W>>>, timer: Timer, interval: Duration, max_missed: u32)
+                     -> Promise<(), ::capnp::Error>
+    where W: AsyncWrite + 'static
+{
+    timer.after_delay(interval).lift().then(move |()| {
+        {
+            let shared = match shared.upgrade() {
+                Some(s) => s,
+                None => return Promise::ok(()),
+            };
+            let mut shared = shared.borrow_mut();
+            match shared.ended {
+                Some(Ok(())) => return Promise::ok(()),
+                Some(Err(ref e)) => return Promise::err(e.clone()),
+                None => (),
+            }
+            pry!(shared.ping(max_missed));
+        }
+        keepalive_loop(shared, timer, interval, max_missed)
+    })
+}
+
+fn read_loop<R, W>(reader: R, shared: Rc<RefCell<Shared<W>>>, options: Options) -> Promise<(), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let max_payload_words = options.reader_options.traversal_limit_in_words + 1;
+    frame::try_read(reader, max_payload_words).then(move |(reader, frame)| {
+        let (kind, mut payload) = match frame {
+            Some(f) => f,
+            None => return Promise::ok(()),
+        };
+        if payload.is_empty() {
+            return Promise::err(::capnp::Error::failed("Frame is missing its channel ID".to_string()))
+        }
+        let id = frame::to_u64(payload[0]);
+        match kind {
+            MESSAGE => {
+                let bytes = (payload.len() as u64 - 1) * 8;
+                let segments = pry!(OwnedSegments::from_words(payload.split_off(1)));
+                let message = message::Reader::new(segments, options.reader_options);
+                pry!(shared.borrow_mut().deliver(id, message, bytes));
+            }
+            CLOSE => shared.borrow_mut().close_by_peer(id),
+            PING => shared.borrow_mut().send_control(PONG, id),
+            PONG => shared.borrow_mut().pong(id),
+            WINDOW => {
+                if payload.len() != 2 {
+                    return Promise::err(::capnp::Error::failed(
+                        "Window frame has the wrong length".to_string()))
+                }
+                shared.borrow_mut().credit(id, frame::to_u64(payload[1]));
+            }
+            _ => return Promise::err(::capnp::Error::failed(format!("Unknown frame kind: {}", kind))),
+        }
+        read_loop(reader, shared, options)
+    })
+}
@@ -0,0 +1,125 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Running expensive layers, such as compression, on a worker thread.
+//!
+//! Compressing or decompressing a large message can take long enough to hold up everything else
+//! on the event loop. An `Offloaded` layer runs on a thread of its own instead, and the event loop
+//! only hands buffers over and picks up the results. Its jobs are carried out one at a time in
+//! the order they were submitted, so a layer that keeps state between messages sees them in the
+//! same order it would have on the event loop.
+//!
+//! ```text
+//! let compression = try!(Offloaded::new(&network, Lz4));
+//! let stream = layer::Layered::new(stream).offloaded_layer(compression).layer(Checksum::Crc32c);
+//! ```
+
+use std::io;
+use std::rc::Rc;
+use std::sync::mpsc;
+
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite, Network, SocketStream};
+
+use fulfiller;
+use layer::Layer;
+
+enum Job {
+    Encode(Vec<u8>),
+    Decode(Vec<u8>, usize),
+}
+
+/// A layer that runs on a worker thread. The thread exits once the `Offloaded` is dropped and
+/// any jobs already submitted are done.
+pub struct Offloaded {
+    jobs: mpsc::Sender<Job>,
+    results: Rc<mpsc::Receiver<::capnp::Result<Vec<u8>>>>,
+
+    /// Receives a byte from the worker for each result it sends.
+    wakeup: SocketStream,
+
+    /// Resolves once the result of the last job submitted has been picked up.
+    pickups: Promise<(), io::Error>,
+}
+
+impl Offloaded {
+    /// Starts a thread that runs `layer`.
+    pub fn new<L>(network: &Network, layer: L) -> ::capnp::Result<Offloaded> where L: Layer + Send + 'static {
+        let (jobs, job_receiver) = mpsc::channel();
+        let (result_sender, results) = mpsc::channel();
+        let spawned = network.socket_spawn(move |mut wakeup, wait_scope, mut event_port| {
+            let mut layer = layer;
+            for job in job_receiver.iter() {
+                let result = match job {
+                    Job::Encode(bytes) => layer.encode(bytes),
+                    Job::Decode(bytes, max_bytes) => layer.decode_limited(bytes, max_bytes),
+                };
+                if result_sender.send(result).is_err() {
+                    break
+                }
+                try!(wakeup.write(vec![0u8]).wait(wait_scope, &mut event_port));
+            }
+            Ok(())
+        });
+        let wakeup = match spawned {
+            Ok((_, wakeup)) => wakeup,
+            Err(e) => return Err(::capnp::Error::failed(format!("Could not start a worker thread: {}", e))),
+        };
+        Ok(Offloaded { jobs: jobs, results: Rc::new(results), wakeup: wakeup, pickups: Promise::ok(()) })
+    }
+
+    /// Runs `Layer::encode()` on the worker.
+    pub fn encode(&mut self, bytes: Vec<u8>) -> Promise<Vec<u8>, ::capnp::Error> {
+        self.submit(Job::Encode(bytes))
+    }
+
+    /// Runs `Layer::decode_limited()` on the worker.
+    pub fn decode_limited(&mut self, bytes: Vec<u8>, max_bytes: usize) -> Promise<Vec<u8>, ::capnp::Error> {
+        self.submit(Job::Decode(bytes, max_bytes))
+    }
+
+    fn submit(&mut self, job: Job) -> Promise<Vec<u8>, ::capnp::Error> {
+        if self.jobs.send(job).is_err() {
+            return Promise::err(worker_exited())
+        }
+        // Results arrive in the order the jobs were sent, so they are picked up in that order
+        // too, even if the promise for one of them has been dropped.
+        let results = self.results.clone();
+        let mut wakeup = self.wakeup.clone();
+        let (result, fulfiller) = fulfiller::and_fulfiller();
+        let previous = ::std::mem::replace(&mut self.pickups, Promise::ok(()));
+        self.pickups = previous.then(move |()| {
+            wakeup.read(vec![0u8], 1).map_else(move |r| {
+                fulfiller.resolve(match r {
+                    Err(e) => Err(e.into()),
+                    Ok(_) => results.try_recv().unwrap_or_else(|_| Err(worker_exited())),
+                });
+                Ok(())
+            })
+        }).eagerly_evaluate();
+        result
+    }
+}
+
+fn worker_exited() -> ::capnp::Error {
+    ::capnp::Error::failed("Offloaded layer's worker thread has exited".to_string())
+}
@@ -0,0 +1,88 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! What is known about the process on the other end of a connection.
+
+#[cfg(unix)] use std::io;
+#[cfg(unix)] use std::os::unix::io::RawFd;
+
+/// Who is on the other end of a connection, as far as the transport or an authentication step
+/// has established. Authorization code can match on this without caring how the connection
+/// was made.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Identity {
+    /// Nothing is known, as for a TCP connection before any authentication.
+    Unknown,
+
+    /// A local process, identified by the kernel through a Unix domain socket.
+    Unix(Credentials),
+
+    /// The subject of a certificate that the peer presented and that was verified, for example by
+    /// a TLS layer.
+    Certificate { subject: String },
+
+    /// The identity behind a token that the peer presented and that was verified, for example in
+    /// an application-level login message.
+    Token(String),
+}
+
+impl Identity {
+    pub fn is_known(&self) -> bool {
+        *self != Identity::Unknown
+    }
+}
+
+impl Default for Identity {
+    fn default() -> Identity { Identity::Unknown }
+}
+
+/// The credentials of the process on the other end of a Unix domain socket, as the kernel
+/// recorded them when the connection was made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    /// Not every platform reports the peer's process id.
+    pub pid: Option<u32>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Returns the credentials of the peer of the connected Unix domain socket `fd`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn credentials(fd: RawFd) -> io::Result<Credentials> {
+    let mut cred = ::libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = ::std::mem::size_of::<::libc::ucred>() as ::libc::socklen_t;
+    if unsafe { ::libc::getsockopt(fd, ::libc::SOL_SOCKET, ::libc::SO_PEERCRED,
+                                   &mut cred as *mut _ as *mut ::libc::c_void, &mut len) } < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(Credentials { pid: Some(cred.pid as u32), uid: cred.uid, gid: cred.gid })
+}
+
+/// Returns the credentials of the peer of the connected Unix domain socket `fd`.
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+pub fn credentials(fd: RawFd) -> io::Result<Credentials> {
+    let mut uid = 0;
+    let mut gid = 0;
+    if unsafe { ::libc::getpeereid(fd, &mut uid, &mut gid) } < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(Credentials { pid: None, uid: uid, gid: gid })
+}
@@ -0,0 +1,281 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Tunneling outgoing connections through a SOCKS5 or HTTP CONNECT proxy.
+//!
+//! The proxy handshake runs on the event loop like any other I/O. Once it is done, the stream
+//! carries bytes straight to the destination and can be handed to the message layer as usual.
+
+use std::io;
+use std::net::IpAddr;
+
+use byteorder::{BigEndian, ByteOrder};
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite, Network, SocketStream};
+
+use connect::{self, SocketOptions};
+
+/// The protocol spoken by a proxy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// SOCKS version 5 (RFC 1928), with username/password authentication (RFC 1929) if
+    /// credentials are given.
+    Socks5,
+
+    /// An HTTP proxy that supports the `CONNECT` method, with basic authentication if credentials
+    /// are given.
+    HttpConnect,
+}
+
+/// A proxy through which to tunnel connections.
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    protocol: Protocol,
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+}
+
+impl Proxy {
+    pub fn new(protocol: Protocol, host: &str, port: u16) -> Proxy {
+        Proxy {
+            protocol: protocol,
+            host: host.to_string(),
+            port: port,
+            credentials: None,
+        }
+    }
+
+    pub fn socks5(host: &str, port: u16) -> Proxy {
+        Proxy::new(Protocol::Socks5, host, port)
+    }
+
+    pub fn http_connect(host: &str, port: u16) -> Proxy {
+        Proxy::new(Protocol::HttpConnect, host, port)
+    }
+
+    /// Authenticates to the proxy with this username and password.
+    pub fn credentials(mut self, username: &str, password: &str) -> Proxy {
+        self.credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+}
+
+/// Connects to `proxy` and asks it to open a tunnel to `host`. Socket options apply to the
+/// connection to the proxy. The destination host name is passed on to the proxy unresolved, so
+/// it may name a host that only the proxy can resolve.
+#[cfg(unix)]
+pub fn connect(network: &Network, proxy: &Proxy, host: &str, port: u16, options: SocketOptions)
+               -> Promise<SocketStream, io::Error>
+{
+    let proxy = proxy.clone();
+    let host = host.to_string();
+    connect::connect_host(network, &proxy.host, proxy.port, options).then(move |stream| {
+        handshake(stream, &proxy, &host, port)
+    })
+}
+
+/// Asks the proxy at the other end of `stream` to open a tunnel to `host`, and returns the
+/// stream once it has.
+pub fn handshake<S>(stream: S, proxy: &Proxy, host: &str, port: u16) -> Promise<S, io::Error>
+    where S: AsyncRead + AsyncWrite + 'static
+{
+    match proxy.protocol {
+        Protocol::Socks5 => socks5_handshake(stream, proxy.credentials.clone(), host, port),
+        Protocol::HttpConnect => http_connect_handshake(stream, &proxy.credentials, host, port),
+    }
+}
+
+fn proxy_error(description: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, description)
+}
+
+fn write_then_read<S>(mut stream: S, request: Vec<u8>, len: usize) -> Promise<(S, Vec<u8>), io::Error>
+    where S: AsyncRead + AsyncWrite + 'static
+{
+    stream.write(request).then(move |_| {
+        read_exactly(stream, len)
+    })
+}
+
+fn read_exactly<S>(mut stream: S, len: usize) -> Promise<(S, Vec<u8>), io::Error>
+    where S: AsyncRead + 'static
+{
+    stream.read(vec![0; len], len).map(move |(buf, _)| Ok((stream, buf)))
+}
+
+fn socks5_handshake<S>(stream: S, credentials: Option<(String, String)>, host: &str, port: u16)
+                       -> Promise<S, io::Error>
+    where S: AsyncRead + AsyncWrite + 'static
+{
+    let mut connect_request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(addr)) => {
+            connect_request.push(1);
+            connect_request.extend_from_slice(&addr.octets());
+        }
+        Ok(IpAddr::V6(addr)) => {
+            connect_request.push(4);
+            connect_request.extend_from_slice(&addr.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Promise::err(proxy_error(format!("host name too long for SOCKS5: {}", host)))
+            }
+            connect_request.push(3);
+            connect_request.push(host.len() as u8);
+            connect_request.extend_from_slice(host.as_bytes());
+        }
+    }
+    let mut port_bytes = [0; 2];
+    BigEndian::write_u16(&mut port_bytes, port);
+    connect_request.extend_from_slice(&port_bytes);
+
+    let greeting = if credentials.is_some() { vec![5, 2, 0, 2] } else { vec![5, 1, 0] };
+    write_then_read(stream, greeting, 2).then(move |(stream, reply)| {
+        if reply[0] != 5 {
+            return Promise::err(proxy_error(format!("not a SOCKS5 proxy (version {})", reply[0])))
+        }
+        match (reply[1], credentials) {
+            (0, _) => Promise::ok(stream),
+            (2, Some((username, password))) => {
+                if username.len() > 255 || password.len() > 255 {
+                    return Promise::err(proxy_error("SOCKS5 credentials too long".to_string()))
+                }
+                let mut request = vec![1, username.len() as u8];
+                request.extend_from_slice(username.as_bytes());
+                request.push(password.len() as u8);
+                request.extend_from_slice(password.as_bytes());
+                write_then_read(stream, request, 2).map(|(stream, reply)| {
+                    if reply[1] == 0 {
+                        Ok(stream)
+                    } else {
+                        Err(proxy_error("SOCKS5 proxy rejected the credentials".to_string()))
+                    }
+                })
+            }
+            (method, _) => {
+                Promise::err(proxy_error(format!("SOCKS5 proxy offered no acceptable authentication method \
+                                                  (it chose {})", method)))
+            }
+        }
+    }).then(move |stream| {
+        // Version, reply code, reserved byte, address type, and the first byte of the address.
+        write_then_read(stream, connect_request, 5)
+    }).then(|(stream, reply)| {
+        if reply[1] != 0 {
+            return Promise::err(proxy_error(format!("SOCKS5 proxy could not connect: {}",
+                                                    socks5_reply_description(reply[1]))))
+        }
+        // The rest of the bound address, then the bound port.
+        let remaining = match reply[3] {
+            1 => 3 + 2,
+            3 => usize::from(reply[4]) + 2,
+            4 => 15 + 2,
+            atyp => return Promise::err(proxy_error(format!("unknown SOCKS5 address type {}", atyp))),
+        };
+        read_exactly(stream, remaining).map(|(stream, _)| Ok(stream))
+    })
+}
+
+fn socks5_reply_description(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// The most header bytes we are willing to read from an HTTP proxy's response.
+const MAX_HTTP_RESPONSE_BYTES: usize = 8192;
+
+fn http_connect_handshake<S>(stream: S, credentials: &Option<(String, String)>, host: &str, port: u16)
+                             -> Promise<S, io::Error>
+    where S: AsyncRead + AsyncWrite + 'static
+{
+    let authority = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if let Some((ref username, ref password)) = *credentials {
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n",
+                                  base64(format!("{}:{}", username, password).as_bytes())));
+    }
+    request.push_str("\r\n");
+
+    let mut stream = stream;
+    stream.write(request.into_bytes()).then(move |_| {
+        read_http_response_head(stream, Vec::new())
+    }).then(|(stream, head)| {
+        let status_line = head.split(|&b| b == b'\r').next().unwrap_or(&[]);
+        let status_line = String::from_utf8_lossy(status_line).into_owned();
+        let mut words = status_line.split(' ');
+        match (words.next(), words.next()) {
+            (Some(version), Some(status)) if version.starts_with("HTTP/1.") => {
+                if status.starts_with('2') {
+                    Promise::ok(stream)
+                } else {
+                    Promise::err(proxy_error(format!("HTTP proxy refused to connect: {}", status_line)))
+                }
+            }
+            _ => Promise::err(proxy_error(format!("malformed HTTP proxy response: {}", status_line))),
+        }
+    })
+}
+
+/// Reads the status line and headers of an HTTP response, one byte at a time so as not to
+/// consume any bytes that the destination sends after them.
+fn read_http_response_head<S>(stream: S, head: Vec<u8>) -> Promise<(S, Vec<u8>), io::Error>
+    where S: AsyncRead + 'static
+{
+    read_exactly(stream, 1).then(move |(stream, byte)| {
+        let mut head = head;
+        head.push(byte[0]);
+        if head.ends_with(b"\r\n\r\n") {
+            Promise::ok((stream, head))
+        } else if head.len() >= MAX_HTTP_RESPONSE_BYTES {
+            Promise::err(proxy_error("HTTP proxy response headers too long".to_string()))
+        } else {
+            read_http_response_head(stream, head)
+        }
+    })
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut result = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                result.push(ALPHABET[(n >> (18 - 6 * idx) & 0x3f) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
@@ -0,0 +1,434 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! A connection that re-establishes its streams whenever they fail.
+//!
+//! A `ReconnectingConnection` is given a closure that opens a new pair of streams, for instance
+//! by dialing a server. It calls the closure once at the start, and again whenever a send or
+//! receive fails or the peer closes the connection, waiting longer after each attempt that fails:
+//! the delay starts at `Options::initial_delay()` and doubles up to `Options::max_delay()`. Unless
+//! jitter is turned off, each delay is drawn at random from between half and all of that, so that
+//! many clients that lost the same server do not all come back at the same moment.
+//!
+//! Each successful connection starts a new epoch, numbered from 1. `recv()` reports the start of
+//! every epoch with `Event::Connected` before the first message of that epoch, so the application
+//! can tell where one connection ended and the next began, for instance to send a fresh
+//! handshake. Messages are not carried over from one epoch to the next: a send that fails is not
+//! retried, since the peer may or may not have seen it, while sends and receives made while no
+//! connection is up wait for the next one.
+//!
+//! ```text
+//! let connection = ReconnectingConnection::new(move || dial(&network, &address), &timer,
+//!                                              reconnect::Options::new());
+//! connection.recv().then(|event| match event {
+//!     Event::Connected(epoch) => ...,
+//!     Event::Message(message) => ...,
+//! })
+//! ```
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use capnp::{message, Word};
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite, Timer};
+
+use connection::Connection;
+use fulfiller::{self, Fulfiller};
+use serialize::{self, OwnedSegments};
+use writer::AsyncMessageWriter;
+
+/// Options for a `ReconnectingConnection`.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    reader_options: message::ReaderOptions,
+    serialize_options: serialize::Options,
+    initial_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+    max_attempts: Option<u32>,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            reader_options: message::ReaderOptions::new(),
+            serialize_options: serialize::Options::new(),
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+            jitter: true,
+            max_attempts: None,
+        }
+    }
+
+    /// How long to wait before reconnecting after a connection is lost, and after the first
+    /// attempt that fails. Defaults to 100 milliseconds.
+    pub fn initial_delay(mut self, value: Duration) -> Options {
+        self.initial_delay = value;
+        self
+    }
+
+    /// The longest the delay between attempts may grow to. Defaults to 30 seconds.
+    pub fn max_delay(mut self, value: Duration) -> Options {
+        self.max_delay = value;
+        self
+    }
+
+    /// Whether to randomize each delay. Defaults to true.
+    pub fn jitter(mut self, value: bool) -> Options {
+        self.jitter = value;
+        self
+    }
+
+    /// Gives up after this many attempts in a row have failed, failing every pending and later
+    /// send and receive with the error of the last attempt. By default, attempts never stop.
+    pub fn max_attempts(mut self, value: u32) -> Options {
+        self.max_attempts = Some(::std::cmp::max(value, 1));
+        self
+    }
+
+    pub fn reader_options(mut self, value: message::ReaderOptions) -> Options {
+        self.reader_options = value;
+        self
+    }
+
+    pub fn serialize_options(mut self, value: serialize::Options) -> Options {
+        self.serialize_options = value;
+        self
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options { Options::new() }
+}
+
+/// What `ReconnectingConnection::recv()` resolves to.
+pub enum Event {
+    /// A new connection is up, and this is its epoch.
+    Connected(u64),
+    Message(message::Reader<OwnedSegments>),
+}
+
+fn to_nanos(duration: Duration) -> u64 {
+    duration.as_secs().saturating_mul(1_000_000_000).saturating_add(u64::from(duration.subsec_nanos()))
+}
+
+fn from_nanos(nanos: u64) -> Duration {
+    Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+}
+
+type Connect<R, W> = Box<FnMut() -> Promise<(R, W), ::capnp::Error>>;
+
+enum State<R, W> where R: AsyncRead + 'static, W: AsyncWrite + 'static {
+    Connecting,
+    Connected(Box<Connection<R, W>>),
+    Failed(::capnp::Error),
+}
+
+struct Inner<R, W> where R: AsyncRead + 'static, W: AsyncWrite + 'static {
+    connect: Connect<R, W>,
+    timer: Timer,
+    options: Options,
+    state: State<R, W>,
+    epoch: u64,
+
+    /// The last epoch that `recv()` has reported with `Event::Connected`.
+    reported_epoch: u64,
+    failed_attempts: u32,
+    random: u64,
+
+    /// Sends and receives waiting for a connection.
+    waiters: VecDeque<Fulfiller<()>>,
+
+    /// The attempts to connect, running between the loss of one connection and the next.
+    connecting: Promise<(), ::capnp::Error>,
+}
+
+impl <R, W> Inner<R, W> where R: AsyncRead + 'static, W: AsyncWrite + 'static {
+    /// The delay after `doublings` attempts beyond the first have failed.
+    fn delay(&mut self, doublings: u32) -> Duration {
+        let initial = to_nanos(self.options.initial_delay);
+        let max = to_nanos(self.options.max_delay);
+        let mut nanos = initial;
+        for _ in 0..doublings {
+            if nanos >= max {
+                break
+            }
+            nanos = nanos.saturating_mul(2);
+        }
+        nanos = ::std::cmp::min(nanos, max);
+        if self.options.jitter && nanos > 1 {
+            // xorshift
+            self.random ^= self.random << 13;
+            self.random ^= self.random >> 7;
+            self.random ^= self.random << 17;
+            nanos = nanos / 2 + self.random % (nanos - nanos / 2 + 1);
+        }
+        from_nanos(nanos)
+    }
+
+    fn connected(&mut self, reader: R, writer: W) {
+        let options = self.options;
+        self.state = State::Connected(Box::new(
+            Connection::with_options(reader, writer, options.reader_options, options.serialize_options)));
+        self.epoch += 1;
+        self.failed_attempts = 0;
+        for fulfiller in self.waiters.drain(..) {
+            fulfiller.fulfill(());
+        }
+    }
+
+    /// Records an attempt that failed with `error`, and returns how long to wait before the next
+    /// one, or None if there are to be no more.
+    fn attempt_failed(&mut self, error: ::capnp::Error) -> Option<Duration> {
+        self.failed_attempts += 1;
+        match self.options.max_attempts {
+            Some(max) if self.failed_attempts >= max => {
+                for fulfiller in self.waiters.drain(..) {
+                    fulfiller.reject(error.clone());
+                }
+                self.state = State::Failed(error);
+                None
+            }
+            _ => {
+                let doublings = self.failed_attempts - 1;
+                Some(self.delay(doublings))
+            }
+        }
+    }
+}
+
+/// Sends and receives messages like a `Connection`, over streams that are replaced whenever they
+/// fail. All methods take `&self`; a handle may be cloned to share the connection.
+pub struct ReconnectingConnection<R, W> where R: AsyncRead + 'static, W: AsyncWrite + 'static {
+    inner: Rc<RefCell<Inner<R, W>>>,
+}
+
+impl <R, W> Clone for ReconnectingConnection<R, W> where R: AsyncRead + 'static, W: AsyncWrite + 'static {
+    fn clone(&self) -> ReconnectingConnection<R, W> {
+        ReconnectingConnection { inner: self.inner.clone() }
+    }
+}
+
+impl <R, W> ReconnectingConnection<R, W> where R: AsyncRead + 'static, W: AsyncWrite + 'static {
+    /// Starts connecting by calling `connect`, which must not call back into this connection.
+    pub fn new<F>(connect: F, timer: &Timer, options: Options) -> ReconnectingConnection<R, W>
+        where F: FnMut() -> Promise<(R, W), ::capnp::Error> + 'static
+    {
+        let seed = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => to_nanos(d),
+            Err(_) => 0,
+        };
+        let inner = Rc::new(RefCell::new(Inner {
+            connect: Box::new(connect),
+            timer: timer.clone(),
+            options: options,
+            state: State::Connecting,
+            epoch: 0,
+            reported_epoch: 0,
+            failed_attempts: 0,
+            random: seed | 1,
+            waiters: VecDeque::new(),
+            connecting: Promise::ok(()),
+        }));
+        let connecting = connect_loop(Rc::downgrade(&inner), timer.clone(), Duration::from_secs(0));
+        inner.borrow_mut().connecting = connecting;
+        ReconnectingConnection { inner: inner }
+    }
+
+    /// The number of connections made so far, which is also the epoch of the current one.
+    pub fn epoch(&self) -> u64 {
+        self.inner.borrow().epoch
+    }
+
+    pub fn is_connected(&self) -> bool {
+        match self.inner.borrow().state {
+            State::Connected(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The number of attempts in a row that have failed since the last connection was made.
+    pub fn failed_attempts(&self) -> u32 {
+        self.inner.borrow().failed_attempts
+    }
+
+    /// Resolves once a connection is up, or fails if `Options::max_attempts()` have failed.
+    pub fn ready(&self) -> Promise<(), ::capnp::Error> {
+        ready(&self.inner)
+    }
+
+    /// Queues `message` for writing on the current connection, or on the next one if none is up.
+    /// The returned promise fails if that connection is lost before the message has been
+    /// written, in which case the message may or may not have reached the peer.
+    pub fn send<A>(&self, message: &message::Builder<A>) -> Promise<(), ::capnp::Error>
+        where A: message::Allocator
+    {
+        let segments = message.get_segments_for_output();
+        send_loop(self.inner.clone(), segments.iter().map(|s| s.to_vec()).collect())
+    }
+
+    /// Resolves to the next event: `Event::Connected` once at the start of each epoch, and
+    /// otherwise the next message. A connection that fails or is closed by the peer is replaced,
+    /// and the receive carries on on the new one.
+    pub fn recv(&self) -> Promise<Event, ::capnp::Error> {
+        recv_loop(self.inner.clone())
+    }
+}
+
+impl <R, W> AsyncMessageWriter for ReconnectingConnection<R, W>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    fn write_segments(&mut self, segments: &[&[Word]]) -> Promise<(), ::capnp::Error> {
+        send_loop(self.inner.clone(), segments.iter().map(|s| s.to_vec()).collect())
+    }
+}
+
+fn ready<R, W>(inner: &Rc<RefCell<Inner<R, W>>>) -> Promise<(), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let mut guard = inner.borrow_mut();
+    let inner = &mut *guard;
+    match inner.state {
+        State::Connected(_) => Promise::ok(()),
+        State::Failed(ref e) => Promise::err(e.clone()),
+        State::Connecting => {
+            let (promise, fulfiller) = fulfiller::and_fulfiller();
+            inner.waiters.push_back(fulfiller);
+            promise
+        }
+    }
+}
+
+/// Replaces the connection of `epoch`, if it is still the current one, after it has failed or
+/// been closed.
+fn lost<R, W>(inner: &Rc<RefCell<Inner<R, W>>>, epoch: u64)
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let weak = Rc::downgrade(inner);
+    let mut inner = inner.borrow_mut();
+    match inner.state {
+        State::Connected(_) if inner.epoch == epoch => (),
+        _ => return,
+    }
+    inner.state = State::Connecting;
+    let delay = inner.delay(0);
+    let timer = inner.timer.clone();
+    inner.connecting = connect_loop(weak, timer, delay);
+}
+
+fn connect_loop<R, W>(inner: Weak<RefCell<Inner<R, W>>>, timer: Timer, delay: Duration)
+                      -> Promise<(), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    timer.after_delay(delay).lift().then(move |()| {
+        let attempt = match inner.upgrade() {
+            Some(rc) => {
+                let mut guard = rc.borrow_mut();
+                let connect = &mut guard.connect;
+                connect()
+            }
+            None => return Promise::ok(()),
+        };
+        attempt.then_else(move |r| {
+            let delay = {
+                let rc = match inner.upgrade() {
+                    Some(rc) => rc,
+                    None => return Promise::ok(()),
+                };
+                let mut rc = rc.borrow_mut();
+                match r {
+                    Ok((reader, writer)) => {
+                        rc.connected(reader, writer);
+                        return Promise::ok(())
+                    }
+                    Err(e) => match rc.attempt_failed(e) {
+                        Some(delay) => delay,
+                        None => return Promise::ok(()),
+                    },
+                }
+            };
+            connect_loop(inner, timer, delay)
+        })
+    })
+}
+
+fn send_loop<R, W>(inner: Rc<RefCell<Inner<R, W>>>, segments: Vec<Vec<Word>>) -> Promise<(), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    ready(&inner).then(move |()| {
+        let written = {
+            let mut guard = inner.borrow_mut();
+            let epoch = guard.epoch;
+            match guard.state {
+                State::Connected(ref mut connection) => {
+                    let slices: Vec<&[Word]> = segments.iter().map(|s| &s[..]).collect();
+                    Some((epoch, connection.write_segments(&slices)))
+                }
+                _ => None,
+            }
+        };
+        match written {
+            // The connection was lost again before this send got its turn.
+            None => send_loop(inner, segments),
+            Some((epoch, written)) => written.map_else(move |r| {
+                if r.is_err() {
+                    lost(&inner, epoch);
+                }
+                r
+            }),
+        }
+    })
+}
+
+fn recv_loop<R, W>(inner: Rc<RefCell<Inner<R, W>>>) -> Promise<Event, ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    ready(&inner).then(move |()| {
+        let received = {
+            let mut guard = inner.borrow_mut();
+            let epoch = guard.epoch;
+            if guard.reported_epoch != epoch {
+                if let State::Connected(_) = guard.state {
+                    guard.reported_epoch = epoch;
+                    return Promise::ok(Event::Connected(epoch))
+                }
+            }
+            match guard.state {
+                State::Connected(ref mut connection) => Some((epoch, connection.recv())),
+                _ => None,
+            }
+        };
+        match received {
+            None => recv_loop(inner),
+            Some((epoch, received)) => received.then_else(move |r| match r {
+                Ok(Some(message)) => Promise::ok(Event::Message(message)),
+                Ok(None) | Err(_) => {
+                    lost(&inner, epoch);
+                    recv_loop(inner)
+                }
+            }),
+        }
+    })
+}
@@ -0,0 +1,593 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Helpers for moving messages from one stream to another.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::rc::Rc;
+
+use capnp::message;
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite};
+use fulfiller::{self, Fulfiller};
+use serialize::{self, OwnedSegments};
+
+/// Options controlling a call to `pump()`.
+#[derive(Clone, Copy, Debug)]
+pub struct PumpOptions {
+    reader_options: message::ReaderOptions,
+    serialize_options: serialize::Options,
+    max_messages: Option<u64>,
+    max_bytes: Option<u64>,
+}
+
+impl PumpOptions {
+    pub fn new() -> PumpOptions {
+        PumpOptions {
+            reader_options: message::ReaderOptions::new(),
+            serialize_options: serialize::Options::new(),
+            max_messages: None,
+            max_bytes: None,
+        }
+    }
+
+    /// Options used when reading messages from the source stream.
+    pub fn reader_options(mut self, value: message::ReaderOptions) -> PumpOptions {
+        self.reader_options = value;
+        self
+    }
+
+    /// Options used when reading from the source and writing to the destination.
+    pub fn serialize_options(mut self, value: serialize::Options) -> PumpOptions {
+        self.serialize_options = value;
+        self
+    }
+
+    /// Stops pumping after this many messages have been transferred.
+    pub fn max_messages(mut self, value: u64) -> PumpOptions {
+        self.max_messages = Some(value);
+        self
+    }
+
+    /// Stops pumping once at least this many bytes have been transferred. The check happens
+    /// between messages, so the final count may exceed the limit by up to one message.
+    pub fn max_bytes(mut self, value: u64) -> PumpOptions {
+        self.max_bytes = Some(value);
+        self
+    }
+
+    fn done(&self, stats: &PumpStats) -> bool {
+        self.max_messages.map_or(false, |n| stats.messages >= n) ||
+            self.max_bytes.map_or(false, |n| stats.bytes >= n)
+    }
+}
+
+impl Default for PumpOptions {
+    fn default() -> PumpOptions { PumpOptions::new() }
+}
+
+/// Totals reported when a pump finishes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PumpStats {
+    /// The number of messages written to the destination stream.
+    pub messages: u64,
+
+    /// The number of bytes written to the destination stream, including segment tables.
+    pub bytes: u64,
+
+    /// The number of messages that were read but not forwarded.
+    pub dropped: u64,
+}
+
+/// Reads messages from `from` and writes them to `to` until `from` reaches EOF or one of the
+/// limits in `options` is hit. Returns both streams along with the transfer totals.
+pub fn pump<R, W>(from: R, to: W, options: PumpOptions) -> Promise<(R, W, PumpStats), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    pump_filtered(from, to, options, |_| true)
+}
+
+/// Like `pump()`, but only forwards messages for which `predicate` returns true. Other messages
+/// are dropped and counted in `PumpStats::dropped`. The limits in `options` apply to forwarded
+/// messages only.
+pub fn pump_filtered<R, W, F>(from: R,
+                              to: W,
+                              options: PumpOptions,
+                              predicate: F) -> Promise<(R, W, PumpStats), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static,
+          F: FnMut(&message::Reader<OwnedSegments>) -> bool + 'static
+{
+    pump_loop(from, to, options, predicate, PumpStats::default())
+}
+
+fn pump_loop<R, W, F>(from: R,
+                      to: W,
+                      options: PumpOptions,
+                      mut predicate: F,
+                      mut stats: PumpStats) -> Promise<(R, W, PumpStats), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static,
+          F: FnMut(&message::Reader<OwnedSegments>) -> bool + 'static
+{
+    if options.done(&stats) {
+        return Promise::ok((from, to, stats));
+    }
+    serialize::try_read_message_with_options(from, options.reader_options,
+                                              options.serialize_options).then(move |(from, message)| {
+        match message {
+            None => Promise::ok((from, to, stats)),
+            Some(message) => {
+                if !predicate(&message) {
+                    stats.dropped += 1;
+                    return pump_loop(from, to, options, predicate, stats);
+                }
+                let segments = message.into_segments();
+                let bytes = segments.wire_size_in_bytes() as u64;
+                serialize::write_owned_segments(to, segments, options.serialize_options).then(move |(to, _)| {
+                    stats.messages += 1;
+                    stats.bytes += bytes;
+                    pump_loop(from, to, options, predicate, stats)
+                })
+            }
+        }
+    })
+}
+
+/// Like `pump()`, but passes each incoming message to `transform`, which builds the message
+/// that actually gets forwarded. An error from `transform` stops the pump.
+pub fn pump_transformed<R, W, F, A>(from: R,
+                                    to: W,
+                                    options: PumpOptions,
+                                    transform: F) -> Promise<(R, W, PumpStats), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static,
+          F: FnMut(&message::Reader<OwnedSegments>) -> ::capnp::Result<message::Builder<A>> + 'static,
+          A: message::Allocator + 'static
+{
+    pump_transformed_loop(from, to, options, transform, PumpStats::default())
+}
+
+fn pump_transformed_loop<R, W, F, A>(from: R,
+                                     to: W,
+                                     options: PumpOptions,
+                                     mut transform: F,
+                                     mut stats: PumpStats) -> Promise<(R, W, PumpStats), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static,
+          F: FnMut(&message::Reader<OwnedSegments>) -> ::capnp::Result<message::Builder<A>> + 'static,
+          A: message::Allocator + 'static
+{
+    if options.done(&stats) {
+        return Promise::ok((from, to, stats));
+    }
+    serialize::try_read_message_with_options(from, options.reader_options,
+                                              options.serialize_options).then(move |(from, message)| {
+        match message {
+            None => Promise::ok((from, to, stats)),
+            Some(message) => {
+                let output = pry!(transform(&message));
+                let bytes = serialize::compute_serialized_size_in_words(&output) as u64 * 8;
+                serialize::write_message_with_options(to, output, options.serialize_options).then(move |(to, _)| {
+                    stats.messages += 1;
+                    stats.bytes += bytes;
+                    pump_transformed_loop(from, to, options, transform, stats)
+                })
+            }
+        }
+    })
+}
+
+/// How `merge()` chooses among sources that all have a message ready to be written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fairness {
+    /// Write messages in the order in which they were fully received.
+    Arrival,
+
+    /// Cycle through the sources, so that a source which always has a message ready cannot
+    /// starve the others.
+    RoundRobin,
+}
+
+struct MergeState<W> where W: AsyncWrite + 'static {
+    to: Option<W>,
+    options: PumpOptions,
+    fairness: Fairness,
+    stats: PumpStats,
+
+    // Each source has at most one message waiting to be written. The fulfiller tells the
+    // source that its message has been written and that it may read another one.
+    pending: Vec<Option<(OwnedSegments, Fulfiller<()>)>>,
+
+    // The sources in the order their pending messages arrived. Only kept under
+    // `Fairness::Arrival`, which is the only policy that consumes it.
+    arrivals: VecDeque<usize>,
+    next_source: usize,
+    live_sources: usize,
+    wake_writer: Option<Fulfiller<()>>,
+}
+
+impl <W> MergeState<W> where W: AsyncWrite + 'static {
+    fn wake_writer(&mut self) {
+        if let Some(f) = self.wake_writer.take() {
+            f.fulfill(());
+        }
+    }
+
+    fn take_next(&mut self) -> Option<(OwnedSegments, Fulfiller<()>)> {
+        match self.fairness {
+            Fairness::Arrival => {
+                self.arrivals.pop_front().and_then(|idx| self.pending[idx].take())
+            }
+            Fairness::RoundRobin => {
+                let count = self.pending.len();
+                for offset in 0..count {
+                    let idx = (self.next_source + offset) % count;
+                    if self.pending[idx].is_some() {
+                        self.next_source = idx + 1;
+                        return self.pending[idx].take();
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Concurrently reads messages from all of `sources` and writes them to `to`. Each message is
+/// written in its entirety before the next one starts, and `fairness` decides the order when
+/// several sources have a message ready. Finishes when every source has reached EOF or when one
+/// of the limits in `options` is hit, and fails if any read or write fails.
+pub fn merge<R, W>(sources: Vec<R>,
+                   to: W,
+                   options: PumpOptions,
+                   fairness: Fairness) -> Promise<(W, PumpStats), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let state = Rc::new(RefCell::new(MergeState {
+        to: Some(to),
+        options: options,
+        fairness: fairness,
+        stats: PumpStats::default(),
+        pending: sources.iter().map(|_| None).collect(),
+        arrivals: VecDeque::new(),
+        next_source: 0,
+        live_sources: sources.len(),
+        wake_writer: None,
+    }));
+
+    let reads = sources.into_iter().enumerate().map(|(idx, source)| {
+        merge_read_loop(state.clone(), source, idx)
+    }).collect::<Vec<_>>();
+
+    // The read loops only ever resolve early by failing, in which case the whole merge fails.
+    let reads = Promise::all(reads.into_iter()).then(|_| Promise::never_done());
+
+    let state1 = state.clone();
+    merge_write_loop(state).exclusive_join(reads).map(move |()| {
+        let mut state = state1.borrow_mut();
+        let to = state.to.take().expect("merge finished with a write in progress");
+        Ok((to, state.stats))
+    })
+}
+
+fn merge_read_loop<R, W>(state: Rc<RefCell<MergeState<W>>>,
+                         source: R,
+                         idx: usize) -> Promise<(), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let options = state.borrow().options;
+    serialize::try_read_message_with_options(source, options.reader_options,
+                                             options.serialize_options).then(move |(source, message)| {
+        match message {
+            None => {
+                let mut s = state.borrow_mut();
+                s.live_sources -= 1;
+                s.wake_writer();
+                Promise::ok(())
+            }
+            Some(message) => {
+                let (written, fulfiller) = fulfiller::and_fulfiller();
+                {
+                    let mut s = state.borrow_mut();
+                    s.pending[idx] = Some((message.into_segments(), fulfiller));
+                    if s.fairness == Fairness::Arrival {
+                        s.arrivals.push_back(idx);
+                    }
+                    s.wake_writer();
+                }
+                written.then(move |()| merge_read_loop(state, source, idx))
+            }
+        }
+    })
+}
+
+enum MergeStep<W> {
+    Write(W, OwnedSegments, Fulfiller<()>, serialize::Options),
+    Wait(Promise<(), ::capnp::Error>),
+    Done,
+}
+
+fn merge_write_loop<W>(state: Rc<RefCell<MergeState<W>>>) -> Promise<(), ::capnp::Error>
+    where W: AsyncWrite + 'static
+{
+    let step = {
+        let mut s = state.borrow_mut();
+        if s.options.done(&s.stats) {
+            MergeStep::Done
+        } else {
+            match s.take_next() {
+                Some((segments, fulfiller)) => {
+                    let to = s.to.take().expect("merge already has a write in progress");
+                    MergeStep::Write(to, segments, fulfiller, s.options.serialize_options)
+                }
+                None if s.live_sources == 0 => MergeStep::Done,
+                None => {
+                    let (woken, fulfiller) = fulfiller::and_fulfiller();
+                    s.wake_writer = Some(fulfiller);
+                    MergeStep::Wait(woken)
+                }
+            }
+        }
+    };
+
+    match step {
+        MergeStep::Done => Promise::ok(()),
+        MergeStep::Wait(woken) => woken.then(move |()| merge_write_loop(state)),
+        MergeStep::Write(to, segments, fulfiller, options) => {
+            let bytes = segments.wire_size_in_bytes() as u64;
+            serialize::write_owned_segments(to, segments, options).then(move |(to, _)| {
+                {
+                    let mut s = state.borrow_mut();
+                    s.to = Some(to);
+                    s.stats.messages += 1;
+                    s.stats.bytes += bytes;
+                }
+                fulfiller.fulfill(());
+                merge_write_loop(state)
+            })
+        }
+    }
+}
+
+struct RouteOutput<K, W> where W: AsyncWrite + 'static {
+    key: K,
+    stream: Option<W>,
+    queue: VecDeque<OwnedSegments>,
+    wake_writer: Option<Fulfiller<()>>,
+    wake_reader: Option<Fulfiller<()>>,
+}
+
+struct RouteState<K, W> where K: Hash + Eq, W: AsyncWrite + 'static {
+    /// In the order in which they were passed to `route()`.
+    outputs: Vec<RouteOutput<K, W>>,
+
+    /// The position in `outputs` of the output for each key.
+    indices: HashMap<K, usize>,
+
+    serialize_options: serialize::Options,
+    max_queue_len: usize,
+    reading_done: bool,
+
+    /// Messages and bytes accepted into queues, which is what the limits in the options apply to.
+    queued: PumpStats,
+
+    /// Messages and bytes actually written.
+    stats: PumpStats,
+}
+
+/// What `route()` resolves to: the source, the outputs with their keys, and the transfer totals.
+pub type Routed<R, K, W> = (R, Vec<(K, W)>, PumpStats);
+
+/// Reads messages from `from` and forwards each one to the output stream paired with the
+/// key that `key_fn` extracts from it. Messages for which `key_fn` returns `None`, or returns a
+/// key with no registered output, are dropped.
+///
+/// Every output has its own queue holding up to `max_queue_len` messages, so a slow output only
+/// holds up the source once its queue is full. The limits in `options` apply to routed messages.
+/// Once `from` reaches EOF and all queues have drained, returns the source, the outputs in the
+/// order they were given, and the transfer totals, which count a message once it has been
+/// written. Fails right away if two outputs have the same key.
+pub fn route<R, K, W, F>(from: R,
+                         outputs: Vec<(K, W)>,
+                         options: PumpOptions,
+                         max_queue_len: usize,
+                         key_fn: F) -> Promise<Routed<R, K, W>, ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static,
+          K: Hash + Eq + Clone + 'static,
+          F: FnMut(&message::Reader<OwnedSegments>) -> Option<K> + 'static
+{
+    let indices: HashMap<K, usize> = outputs.iter().enumerate()
+        .map(|(i, &(ref key, _))| (key.clone(), i)).collect();
+    if indices.len() != outputs.len() {
+        return Promise::err(::capnp::Error::failed("route outputs must have distinct keys".to_string()))
+    }
+    let output_count = outputs.len();
+    let state = Rc::new(RefCell::new(RouteState {
+        outputs: outputs.into_iter().map(|(key, stream)| {
+            RouteOutput {
+                key: key,
+                stream: Some(stream),
+                queue: VecDeque::new(),
+                wake_writer: None,
+                wake_reader: None,
+            }
+        }).collect(),
+        indices: indices,
+        serialize_options: options.serialize_options,
+        max_queue_len: ::std::cmp::max(max_queue_len, 1),
+        reading_done: false,
+        queued: PumpStats::default(),
+        stats: PumpStats::default(),
+    }));
+
+    let writers = (0..output_count).map(|i| route_write_loop(state.clone(), i)).collect::<Vec<_>>();
+    let mut writers = Promise::all(writers.into_iter()).fork();
+
+    // A failed output would otherwise leave the reader waiting forever for queue space.
+    let failures = writers.add_branch().then(|_| Promise::never_done());
+
+    let state1 = state.clone();
+    route_read_loop(state, from, options, key_fn).then(move |from| {
+        writers.add_branch().map(move |_| Ok(from))
+    }).exclusive_join(failures).map(move |from| {
+        let mut state = state1.borrow_mut();
+        let mut outputs = Vec::with_capacity(state.outputs.len());
+        for mut output in state.outputs.drain(..) {
+            match output.stream.take() {
+                Some(stream) => outputs.push((output.key, stream)),
+                None => return Err(write_in_progress()),
+            }
+        }
+        Ok((from, outputs, state.stats))
+    })
+}
+
+enum RouteStep<R> {
+    Read(R),
+    WaitForSpace(R, Promise<(), ::capnp::Error>),
+    Done(R),
+}
+
+fn route_read_loop<R, K, W, F>(state: Rc<RefCell<RouteState<K, W>>>,
+                               from: R,
+                               options: PumpOptions,
+                               key_fn: F) -> Promise<R, ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static,
+          K: Hash + Eq + Clone + 'static,
+          F: FnMut(&message::Reader<OwnedSegments>) -> Option<K> + 'static
+{
+    if options.done(&state.borrow().queued) {
+        route_finish_reading(&state);
+        return Promise::ok(from);
+    }
+    serialize::try_read_message_with_options(from, options.reader_options,
+                                              options.serialize_options).then(move |(from, message)| {
+        let mut key_fn = key_fn;
+        let step = match message {
+            None => RouteStep::Done(from),
+            Some(message) => {
+                let key = key_fn(&message);
+                let mut s = state.borrow_mut();
+                let max_queue_len = s.max_queue_len;
+                let RouteState { ref mut outputs, ref indices, ref mut queued, ref mut stats, .. } = *s;
+                match key.and_then(|key| indices.get(&key)) {
+                    None => {
+                        stats.dropped += 1;
+                        RouteStep::Read(from)
+                    }
+                    Some(&i) => {
+                        let output = &mut outputs[i];
+                        let segments = message.into_segments();
+                        queued.messages += 1;
+                        queued.bytes += segments.wire_size_in_bytes() as u64;
+                        output.queue.push_back(segments);
+                        if let Some(f) = output.wake_writer.take() {
+                            f.fulfill(());
+                        }
+                        if output.queue.len() >= max_queue_len {
+                            let (space, fulfiller) = fulfiller::and_fulfiller();
+                            output.wake_reader = Some(fulfiller);
+                            RouteStep::WaitForSpace(from, space)
+                        } else {
+                            RouteStep::Read(from)
+                        }
+                    }
+                }
+            }
+        };
+        match step {
+            RouteStep::Done(from) => {
+                route_finish_reading(&state);
+                Promise::ok(from)
+            }
+            RouteStep::Read(from) => route_read_loop(state, from, options, key_fn),
+            RouteStep::WaitForSpace(from, space) => {
+                space.then(move |()| route_read_loop(state, from, options, key_fn))
+            }
+        }
+    })
+}
+
+fn route_finish_reading<K, W>(state: &Rc<RefCell<RouteState<K, W>>>)
+    where K: Hash + Eq, W: AsyncWrite + 'static
+{
+    let mut s = state.borrow_mut();
+    s.reading_done = true;
+    for output in &mut s.outputs {
+        if let Some(f) = output.wake_writer.take() {
+            f.fulfill(());
+        }
+    }
+}
+
+fn write_in_progress() -> ::capnp::Error {
+    ::capnp::Error::failed("route output already has a write in progress".to_string())
+}
+
+enum RouteWriteStep<W> {
+    Write(W, OwnedSegments, serialize::Options),
+    Wait(Promise<(), ::capnp::Error>),
+    Done,
+}
+
+fn route_write_loop<K, W>(state: Rc<RefCell<RouteState<K, W>>>, index: usize) -> Promise<(), ::capnp::Error>
+    where K: Hash + Eq + 'static, W: AsyncWrite + 'static
+{
+    let step = {
+        let mut s = state.borrow_mut();
+        let reading_done = s.reading_done;
+        let serialize_options = s.serialize_options;
+        let output = &mut s.outputs[index];
+        match output.queue.pop_front() {
+            Some(segments) => {
+                if let Some(f) = output.wake_reader.take() {
+                    f.fulfill(());
+                }
+                match output.stream.take() {
+                    Some(stream) => RouteWriteStep::Write(stream, segments, serialize_options),
+                    None => return Promise::err(write_in_progress()),
+                }
+            }
+            None if reading_done => RouteWriteStep::Done,
+            None => {
+                let (woken, fulfiller) = fulfiller::and_fulfiller();
+                output.wake_writer = Some(fulfiller);
+                RouteWriteStep::Wait(woken)
+            }
+        }
+    };
+
+    match step {
+        RouteWriteStep::Done => Promise::ok(()),
+        RouteWriteStep::Wait(woken) => woken.then(move |()| route_write_loop(state, index)),
+        RouteWriteStep::Write(stream, segments, options) => {
+            let bytes = segments.wire_size_in_bytes() as u64;
+            serialize::write_owned_segments(stream, segments, options).then(move |(stream, _)| {
+                {
+                    let mut s = state.borrow_mut();
+                    s.outputs[index].stream = Some(stream);
+                    s.stats.messages += 1;
+                    s.stats.bytes += bytes;
+                }
+                route_write_loop(state, index)
+            })
+        }
+    }
+}
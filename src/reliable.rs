@@ -0,0 +1,293 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Reliable, in-order delivery of messages over datagrams, for links where TCP is unavailable.
+//!
+//! An `Endpoint` numbers the messages it sends, keeps each one until the other side acknowledges
+//! it, and sends it again if no acknowledgement comes in time, waiting twice as long after each
+//! attempt. Messages that arrive out of order are held back until the ones before them have
+//! arrived. Acknowledgements are cumulative and ride along on data packets when there are any.
+//!
+//! An `Endpoint` does no I/O and keeps no clock of its own. Pass it every datagram that arrives
+//! from the other side, send every packet that `poll_transmit()` returns until it returns
+//! `None`, and call `poll_transmit()` again at `next_timeout()`, for instance from a
+//! `gjio::Timer`:
+//!
+//! ```text
+//! endpoint.handle_datagram(&buf[..len])?;
+//! while let Some(packet) = endpoint.poll_transmit(Instant::now())? {
+//!     socket.send_to(&packet, peer)?;
+//! }
+//! if let Some(at) = endpoint.next_timeout() {
+//!     timer.after_delay(at - Instant::now())  // then poll_transmit() again
+//! }
+//! ```
+
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, LittleEndian};
+use capnp::message;
+
+use codec::MessageEncoder;
+use datagram::{self, DEFAULT_MAX_DATAGRAM_LEN};
+use serialize::{self, OwnedSegments};
+use Error;
+
+const DATA: u8 = 0;
+const ACK: u8 = 1;
+
+/// A data packet starts with its kind, its sequence number, and the acknowledgement.
+const DATA_HEADER_LEN: usize = 17;
+
+/// An acknowledgement is its kind followed by the sequence number that is expected next.
+const ACK_LEN: usize = 9;
+
+/// Options controlling windowing and retransmission.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    max_datagram_len: usize,
+    window: usize,
+    initial_retransmit_delay: Duration,
+    max_retransmit_delay: Duration,
+    max_attempts: u32,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            max_datagram_len: DEFAULT_MAX_DATAGRAM_LEN,
+            window: 32,
+            initial_retransmit_delay: Duration::from_millis(200),
+            max_retransmit_delay: Duration::from_secs(5),
+            max_attempts: 10,
+        }
+    }
+
+    /// The longest packet that will be sent, headers included. Defaults to
+    /// `datagram::DEFAULT_MAX_DATAGRAM_LEN`.
+    pub fn max_datagram_len(mut self, value: usize) -> Options {
+        self.max_datagram_len = value;
+        self
+    }
+
+    /// How many messages may be awaiting acknowledgement at once; later ones wait their turn.
+    /// The receiving side holds back at most this many messages that arrived early, so both
+    /// sides should agree on it. Defaults to 32.
+    pub fn window(mut self, value: usize) -> Options {
+        self.window = cmp::max(value, 1);
+        self
+    }
+
+    /// How long to wait for an acknowledgement before sending a message the second time.
+    /// Defaults to 200 milliseconds.
+    pub fn initial_retransmit_delay(mut self, value: Duration) -> Options {
+        self.initial_retransmit_delay = value;
+        self
+    }
+
+    /// The longest that the wait between attempts is allowed to grow. Defaults to 5 seconds.
+    pub fn max_retransmit_delay(mut self, value: Duration) -> Options {
+        self.max_retransmit_delay = value;
+        self
+    }
+
+    /// How many times a message is sent before the other side is given up on and
+    /// `poll_transmit()` fails with `Error::TimedOut`. Defaults to 10.
+    pub fn max_attempts(mut self, value: u32) -> Options {
+        self.max_attempts = cmp::max(value, 1);
+        self
+    }
+
+    fn retransmit_delay(&self, attempts: u32) -> Duration {
+        let mut delay = self.initial_retransmit_delay;
+        for _ in 1..attempts {
+            if delay >= self.max_retransmit_delay {
+                break
+            }
+            delay *= 2;
+        }
+        cmp::min(delay, self.max_retransmit_delay)
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options::new()
+    }
+}
+
+/// A message that has been given a sequence number but not yet acknowledged.
+struct Outstanding {
+    packet: Vec<u8>,
+    attempts: u32,
+
+    /// When to send the packet again, or `None` if it has not been sent yet.
+    deadline: Option<Instant>,
+}
+
+/// One side of a reliable exchange of messages.
+pub struct Endpoint {
+    options: Options,
+    reader_options: message::ReaderOptions,
+
+    /// Messages that are waiting for room in the window.
+    queued: VecDeque<Vec<u8>>,
+
+    /// Messages in the window, starting with sequence number `send_base`.
+    unacked: VecDeque<Outstanding>,
+    send_base: u64,
+
+    /// The sequence number of the next message to be delivered.
+    recv_next: u64,
+    early: BTreeMap<u64, message::Reader<OwnedSegments>>,
+    delivered: VecDeque<message::Reader<OwnedSegments>>,
+    ack_pending: bool,
+}
+
+impl Endpoint {
+    pub fn new(reader_options: message::ReaderOptions, options: Options) -> Endpoint {
+        Endpoint {
+            options: options,
+            reader_options: reader_options,
+            queued: VecDeque::new(),
+            unacked: VecDeque::new(),
+            send_base: 0,
+            recv_next: 0,
+            early: BTreeMap::new(),
+            delivered: VecDeque::new(),
+            ack_pending: false,
+        }
+    }
+
+    /// Queues `message` to be sent. Fails with `Error::DatagramTooLarge` if the message does not
+    /// fit in one packet.
+    pub fn send<A>(&mut self, message: &message::Builder<A>) -> Result<(), Error>
+        where A: message::Allocator
+    {
+        let len = DATA_HEADER_LEN + serialize::compute_serialized_size_in_words(message) * 8;
+        if len > self.options.max_datagram_len {
+            return Err(Error::DatagramTooLarge { len: len, limit: self.options.max_datagram_len })
+        }
+        let mut packet = Vec::with_capacity(len);
+        packet.extend_from_slice(&[DATA; DATA_HEADER_LEN]);
+        MessageEncoder::new().encode(message, &mut packet);
+        self.queued.push_back(packet);
+        Ok(())
+    }
+
+    /// Takes in a packet from the other side. Malformed packets fail without changing any state.
+    pub fn handle_datagram(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        match bytes.first() {
+            None => Err(Error::TruncatedHeader { received: 0, expected: ACK_LEN }),
+            Some(&ACK) => {
+                if bytes.len() < ACK_LEN {
+                    return Err(Error::TruncatedHeader { received: bytes.len(), expected: ACK_LEN })
+                } else if bytes.len() > ACK_LEN {
+                    return Err(Error::TrailingBytes { count: bytes.len() - ACK_LEN })
+                }
+                self.acknowledged(LittleEndian::read_u64(&bytes[1..9]));
+                Ok(())
+            }
+            Some(&DATA) => {
+                if bytes.len() < DATA_HEADER_LEN {
+                    return Err(Error::TruncatedHeader { received: bytes.len(), expected: DATA_HEADER_LEN })
+                }
+                let seq = LittleEndian::read_u64(&bytes[1..9]);
+                let in_window = seq >= self.recv_next && seq - self.recv_next < self.options.window as u64;
+                if in_window && !self.early.contains_key(&seq) {
+                    let message = try!(datagram::decode_datagram(&bytes[DATA_HEADER_LEN..],
+                                                                 self.reader_options,
+                                                                 serialize::Options::new()));
+                    self.early.insert(seq, message);
+                    while let Some(message) = self.early.remove(&self.recv_next) {
+                        self.delivered.push_back(message);
+                        self.recv_next += 1;
+                    }
+                }
+                self.acknowledged(LittleEndian::read_u64(&bytes[9..17]));
+                self.ack_pending = true;
+                Ok(())
+            }
+            Some(&kind) => Err(Error::UnknownPacketKind { kind: kind }),
+        }
+    }
+
+    fn acknowledged(&mut self, next_expected: u64) {
+        while self.send_base < next_expected && !self.unacked.is_empty() {
+            self.unacked.pop_front();
+            self.send_base += 1;
+        }
+    }
+
+    /// Returns the next packet to send to the other side, if any is due at `now`. Fails with
+    /// `Error::TimedOut` once a message has gone unacknowledged through every attempt allowed.
+    pub fn poll_transmit(&mut self, now: Instant) -> Result<Option<Vec<u8>>, Error> {
+        while self.unacked.len() < self.options.window {
+            match self.queued.pop_front() {
+                Some(mut packet) => {
+                    let seq = self.send_base + self.unacked.len() as u64;
+                    LittleEndian::write_u64(&mut packet[1..9], seq);
+                    self.unacked.push_back(Outstanding { packet: packet, attempts: 0, deadline: None });
+                }
+                None => break,
+            }
+        }
+
+        let due = self.unacked.iter().position(|o| o.deadline.map_or(true, |d| d <= now));
+        if let Some(idx) = due {
+            let outstanding = &mut self.unacked[idx];
+            if outstanding.attempts >= self.options.max_attempts {
+                return Err(Error::TimedOut)
+            }
+            outstanding.attempts += 1;
+            outstanding.deadline = Some(now + self.options.retransmit_delay(outstanding.attempts));
+            LittleEndian::write_u64(&mut outstanding.packet[9..17], self.recv_next);
+            self.ack_pending = false;
+            return Ok(Some(outstanding.packet.clone()))
+        }
+
+        if self.ack_pending {
+            self.ack_pending = false;
+            let mut packet = vec![ACK; ACK_LEN];
+            LittleEndian::write_u64(&mut packet[1..9], self.recv_next);
+            return Ok(Some(packet))
+        }
+        Ok(None)
+    }
+
+    /// When `poll_transmit()` next has something to send, if nothing else happens before then.
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.unacked.iter().filter_map(|o| o.deadline).min()
+    }
+
+    /// Returns the next message from the other side, in the order they were sent.
+    pub fn next_message(&mut self) -> Option<message::Reader<OwnedSegments>> {
+        self.delivered.pop_front()
+    }
+
+    /// The number of messages that have been passed to `send()` but not yet acknowledged.
+    pub fn pending_len(&self) -> usize {
+        self.queued.len() + self.unacked.len()
+    }
+}
@@ -0,0 +1,306 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Reading messages into a fixed ring of words, so that a busy connection does not allocate a
+//! buffer for each message it receives.
+//!
+//! A `RingReader` allocates its ring once, reads each message body into the next free part of it,
+//! and hands out Readers whose segments point straight into the ring. A message's words become
+//! free again once it has been dropped along with every message that was read before it, so the
+//! ring works best when messages are handled roughly in the order they arrive. When the ring has
+//! no room for the next message, the read waits for the application to drop earlier ones and reads
+//! nothing more from the stream in the meantime, which pushes back on the sender. A message larger
+//! than the whole ring fails with `Error::MessageTooLarge`.
+//!
+//! Only the small segment table of each message is still allocated separately.
+//!
+//! ```text
+//! let reader = RingReader::new(stream, 1 << 20, ReaderOptions::new(), serialize::Options::new());
+//! reader.read_message().then(|(reader, message)| ...)
+//! ```
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::slice;
+
+use capnp::{message, Word};
+use gj::Promise;
+use gjio::AsyncRead;
+
+use fulfiller::{self, Fulfiller};
+use serialize;
+use Error;
+
+/// The words of one message, from `start` to `end`.
+struct Region {
+    start: usize,
+    end: usize,
+    released: bool,
+}
+
+struct Regions {
+    /// Oldest first. Never empty ranges, so no two share a `start`.
+    live: VecDeque<Region>,
+    waiting: Option<Fulfiller<()>>,
+}
+
+struct Ring {
+    // The words are owned through this raw pointer, from `Box::into_raw()`, and only ever reached
+    // through it, since a Reader may be looking at one region while another is being read into.
+    // `Drop` turns it back into a box.
+    base: *mut Word,
+    capacity: usize,
+    regions: RefCell<Regions>,
+}
+
+impl Ring {
+    fn new(capacity: usize) -> Ring {
+        let words = Word::allocate_zeroed_vec(capacity).into_boxed_slice();
+        let base = Box::into_raw(words) as *mut Word;
+        Ring {
+            base: base,
+            capacity: capacity,
+            regions: RefCell::new(Regions { live: VecDeque::new(), waiting: None }),
+        }
+    }
+
+    /// Claims `len` free words right after the newest region, or at the front of the ring if they
+    /// do not fit before its end.
+    fn allocate(&self, len: usize) -> Option<usize> {
+        let mut regions = self.regions.borrow_mut();
+        let start = match (regions.live.front(), regions.live.back()) {
+            (Some(oldest), Some(newest)) => {
+                if newest.start < oldest.start {
+                    // Already wrapped around, so the free words are those between the two.
+                    if oldest.start - newest.end >= len { Some(newest.end) } else { None }
+                } else if self.capacity - newest.end >= len {
+                    Some(newest.end)
+                } else if oldest.start >= len {
+                    Some(0)
+                } else {
+                    None
+                }
+            }
+            _ => if len <= self.capacity { Some(0) } else { None },
+        };
+        if let Some(start) = start {
+            regions.live.push_back(Region { start: start, end: start + len, released: false });
+        }
+        start
+    }
+
+    fn release(&self, start: usize) {
+        let waiting = {
+            let mut regions = self.regions.borrow_mut();
+            for region in regions.live.iter_mut() {
+                if region.start == start {
+                    region.released = true;
+                    break
+                }
+            }
+            let mut freed = false;
+            while regions.live.front().map_or(false, |r| r.released) {
+                regions.live.pop_front();
+                freed = true;
+            }
+            if freed { regions.waiting.take() } else { None }
+        };
+        if let Some(fulfiller) = waiting {
+            fulfiller.fulfill(());
+        }
+    }
+
+    fn used_words(&self) -> usize {
+        self.regions.borrow().live.iter().map(|r| r.end - r.start).sum()
+    }
+
+    fn words(&self, start: usize, end: usize) -> &[Word] {
+        assert!(start <= end && end <= self.capacity);
+        // A region is written only while it is being read into, before any Reader can see it,
+        // and regions never overlap.
+        unsafe { slice::from_raw_parts(self.base.add(start), end - start) }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        // `base` and `capacity` came from a boxed slice in `new()`, and no region outlives the
+        // ring, since each holds an `Rc` of it.
+        unsafe { drop(Box::from_raw(slice::from_raw_parts_mut(self.base, self.capacity))) }
+    }
+}
+
+/// The words of a region that is being read into.
+struct RingRange {
+    ring: Rc<Ring>,
+    start: usize,
+    end: usize,
+}
+
+impl AsMut<[u8]> for RingRange {
+    fn as_mut(&mut self) -> &mut [u8] {
+        assert!(self.start <= self.end && self.end <= self.ring.capacity);
+        // The region was claimed by `allocate()` and no Reader has been handed out for it yet.
+        let words = unsafe {
+            slice::from_raw_parts_mut(self.ring.base.add(self.start), self.end - self.start)
+        };
+        Word::words_to_bytes_mut(words)
+    }
+}
+
+/// The segments of a message that lives in a `RingReader`'s ring. Dropping them gives the words
+/// back to the ring.
+pub struct RingSegments {
+    ring: Rc<Ring>,
+    start: usize,
+    end: usize,
+    segment_slices: Vec<(usize, usize)>,
+}
+
+impl message::ReaderSegments for RingSegments {
+    fn get_segment(&self, id: u32) -> Option<&[Word]> {
+        self.segment_slices.get(id as usize).map(|&(a, b)| {
+            self.ring.words(self.start + a, self.start + b)
+        })
+    }
+}
+
+impl Drop for RingSegments {
+    fn drop(&mut self) {
+        if self.end > self.start {
+            self.ring.release(self.start);
+        }
+    }
+}
+
+/// Reads messages from a stream into a ring of `capacity_words` words.
+pub struct RingReader<S> where S: AsyncRead {
+    stream: S,
+    ring: Rc<Ring>,
+    reader_options: message::ReaderOptions,
+    options: serialize::Options,
+}
+
+impl <S> RingReader<S> where S: AsyncRead + 'static {
+    pub fn new(stream: S,
+               capacity_words: usize,
+               reader_options: message::ReaderOptions,
+               options: serialize::Options) -> RingReader<S> {
+        RingReader {
+            stream: stream,
+            ring: Rc::new(Ring::new(capacity_words)),
+            reader_options: reader_options,
+            options: options,
+        }
+    }
+
+    pub fn capacity_words(&self) -> usize {
+        self.ring.capacity
+    }
+
+    /// The number of words held by messages that have not been dropped yet, or that were read
+    /// after one that has not.
+    pub fn used_words(&self) -> usize {
+        self.ring.used_words()
+    }
+
+    /// Returns the stream. Messages that are still alive keep the ring alive with them.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Returns None on EOF.
+    pub fn try_read_message(self)
+                            -> Promise<(RingReader<S>, Option<message::Reader<RingSegments>>), ::capnp::Error>
+    {
+        let RingReader { stream, ring, reader_options, options } = self;
+        serialize::read_segment_table(stream, reader_options, options).then(move |(stream, table)| {
+            let reader = RingReader {
+                stream: stream,
+                ring: ring,
+                reader_options: reader_options,
+                options: options,
+            };
+            match table {
+                None => Promise::ok((reader, None)),
+                Some(table) => {
+                    let len = table.total_words();
+                    reader.read_body(len, table.into_parts().1)
+                }
+            }
+        })
+    }
+
+    pub fn read_message(self) -> Promise<(RingReader<S>, message::Reader<RingSegments>), ::capnp::Error> {
+        self.try_read_message().map(|(reader, message)| match message {
+            Some(m) => Ok((reader, m)),
+            None => Err(Error::CleanEof.into()),
+        })
+    }
+
+    fn read_body(self, len: usize, segment_slices: Vec<(usize, usize)>)
+                 -> Promise<(RingReader<S>, Option<message::Reader<RingSegments>>), ::capnp::Error>
+    {
+        if len > self.ring.capacity {
+            let limit = self.ring.capacity as u64;
+            return Promise::err(Error::MessageTooLarge { words: len as u64, limit: limit }.into())
+        }
+        let start = if len == 0 {
+            0
+        } else {
+            match self.ring.allocate(len) {
+                Some(start) => start,
+                None => {
+                    let (promise, fulfiller) = fulfiller::and_fulfiller();
+                    self.ring.regions.borrow_mut().waiting = Some(fulfiller);
+                    return promise.then(move |()| self.read_body(len, segment_slices))
+                }
+            }
+        };
+
+        // Made before the read so that the words go back to the ring if it fails.
+        let segments = RingSegments {
+            ring: self.ring.clone(),
+            start: start,
+            end: start + len,
+            segment_slices: segment_slices,
+        };
+        let range = RingRange { ring: self.ring.clone(), start: start, end: start + len };
+        let RingReader { mut stream, ring, reader_options, options } = self;
+        stream.try_read(range, len * 8).map_else(move |r| match r {
+            Err(e) => Err(Error::Io(e).into()),
+            Ok((_, n)) if n < len * 8 => {
+                Err(Error::TruncatedBody { received: n as u64, expected: (len * 8) as u64 }.into())
+            }
+            Ok(_) => {
+                let reader = RingReader {
+                    stream: stream,
+                    ring: ring,
+                    reader_options: reader_options,
+                    options: options,
+                };
+                Ok((reader, Some(message::Reader::new(segments, reader_options))))
+            }
+        })
+    }
+}
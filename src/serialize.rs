@@ -19,16 +19,248 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
 use byteorder::{ByteOrder, LittleEndian};
 use capnp::{Word, message};
 use gj::Promise;
-use gjio::{AsyncRead, AsyncWrite};
+use gjio::{AsyncRead, AsyncWrite, Timer};
+
+use crc32c;
+use frame;
+use Error;
+
+/// Options controlling how message bytes are moved between memory and a stream.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    max_chunk_bytes: Option<usize>,
+    max_bytes_per_turn: Option<usize>,
+    remaining_source_bytes: Option<u64>,
+    max_segment_words: Option<usize>,
+    max_segments: usize,
+    gather_bytes: usize,
+    max_message_words: Option<u64>,
+}
+
+/// How many bytes of small segments `write_message()` gathers into a single write unless
+/// `Options::gather_bytes()` says otherwise.
+pub const DEFAULT_GATHER_BYTES: usize = 8192;
+
+/// The most segments that a message may have unless `Options::max_segments()` says otherwise.
+/// This is the same limit that `capnp::serialize` applies.
+pub const DEFAULT_MAX_SEGMENTS: usize = 511;
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            max_chunk_bytes: None,
+            max_bytes_per_turn: None,
+            remaining_source_bytes: None,
+            max_segment_words: None,
+            max_segments: DEFAULT_MAX_SEGMENTS,
+            gather_bytes: DEFAULT_GATHER_BYTES,
+            max_message_words: None,
+        }
+    }
+
+    /// Transfers message bodies in pieces of at most this many bytes, starting each piece in a
+    /// new turn of the event loop. This keeps a multi-megabyte message from holding up every
+    /// other connection on the loop while it is copied. By default, each segment is transferred
+    /// in a single piece.
+    pub fn max_chunk_bytes(mut self, value: usize) -> Options {
+        self.max_chunk_bytes = Some(::std::cmp::max(value, 1));
+        self
+    }
+
+    /// Once this many bytes of a message have been transferred without yielding, defers the rest
+    /// of the transfer to a later turn of the event loop, behind any events that are already
+    /// queued. Useful for messages with many segments, whose transfer would otherwise run as one
+    /// long chain of immediately-ready steps. By default, the transfer never yields voluntarily.
+    pub fn max_bytes_per_turn(mut self, value: usize) -> Options {
+        self.max_bytes_per_turn = Some(value);
+        self
+    }
+
+    /// Declares that the stream being read holds exactly this many more bytes, as is the case for
+    /// a file whose length is known. A message whose segment table declares more bytes than remain
+    /// is then rejected as truncated as soon as the table has been read, rather than failing with
+    /// a premature EOF partway through the body.
+    pub fn remaining_source_bytes(mut self, value: u64) -> Options {
+        self.remaining_source_bytes = Some(value);
+        self
+    }
+
+    /// Rejects any message that has a segment larger than this many words. Unlike the traversal
+    /// limit in `ReaderOptions`, which bounds the message as a whole, this bounds each segment, and
+    /// it is enforced as soon as the segment table has been read, before any space is allocated
+    /// for the body.
+    pub fn max_segment_words(mut self, value: usize) -> Options {
+        self.max_segment_words = Some(value);
+        self
+    }
+
+    /// Rejects any message that has more than this many segments. Defaults to
+    /// `DEFAULT_MAX_SEGMENTS`. Raising the limit lets through messages from writers that use
+    /// many small segments, at the cost of a larger segment table being read and allocated for
+    /// each message.
+    pub fn max_segments(mut self, value: usize) -> Options {
+        self.max_segments = value;
+        self
+    }
+
+    /// Copies the segment table and any run of segments that together fit in this many bytes into
+    /// one buffer, and writes them with a single call to the stream. A message no larger than
+    /// this, such as a typical RPC call, then goes out in one write and so one packet, and a
+    /// message with many small segments costs a few writes rather than one per segment. Larger
+    /// segments are still written straight from the message. Applies to `write_message()` and
+    /// `write_owned_segments()`. Defaults to `DEFAULT_GATHER_BYTES`; zero writes the segment table
+    /// and each segment separately.
+    pub fn gather_bytes(mut self, value: usize) -> Options {
+        self.gather_bytes = value;
+        self
+    }
+
+    /// Refuses to write any message whose segments add up to more than this many words, failing
+    /// with `Error::MessageTooLarge` before a single byte is written. This keeps a sender from
+    /// starting a message that a peer with a receive-side limit would drop the connection over
+    /// partway through. By default, messages of any size are written.
+    pub fn max_message_words(mut self, value: u64) -> Options {
+        self.max_message_words = Some(value);
+        self
+    }
+
+    /// Checks the size of a message that is about to be written.
+    pub(crate) fn check_outgoing_words(&self, words: u64) -> Result<(), Error> {
+        match self.max_message_words {
+            Some(limit) if words > limit => Err(Error::MessageTooLarge { words: words, limit: limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks a segment count, as decoded from the first word of a segment table.
+    pub(crate) fn check_segment_count(&self, segment_count: u64) -> Result<(), Error> {
+        if segment_count > self.max_segments as u64 {
+            Err(Error::TooManySegments { count: segment_count, limit: self.max_segments })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks a just-read segment table against the limits in these options.
+    fn check_segment_table(&self, total_words: usize, segment_slices: &[(usize, usize)]) -> Result<(), Error> {
+        if let Some(max) = self.max_segment_words {
+            for &(a, b) in segment_slices {
+                if b - a > max {
+                    return Err(Error::MessageTooLarge { words: (b - a) as u64, limit: max as u64 })
+                }
+            }
+        }
+        if let Some(remaining) = self.remaining_source_bytes {
+            let table_len = segment_table_len_in_bytes(segment_slices.len()) as u64;
+            let body_len = total_words as u64 * 8;
+            if table_len + body_len > remaining {
+                return Err(Error::TruncatedBody { received: remaining.saturating_sub(table_len), expected: body_len })
+            }
+        }
+        Ok(())
+    }
+
+    fn chunk_len(&self, remaining: usize) -> usize {
+        match self.max_chunk_bytes {
+            Some(n) if n < remaining => n,
+            _ => remaining,
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options { Options::new() }
+}
+
+/// Tracks how much work a transfer has done since it last let other events run.
+#[derive(Clone, Copy)]
+struct TurnBudget {
+    limit: Option<usize>,
+    used: usize,
+}
+
+impl TurnBudget {
+    fn new(options: &Options) -> TurnBudget {
+        TurnBudget { limit: options.max_bytes_per_turn, used: 0 }
+    }
+
+    /// Records `bytes` of work and returns true if the caller should yield before doing more.
+    fn spend(&mut self, bytes: usize) -> bool {
+        self.used += bytes;
+        match self.limit {
+            Some(limit) if self.used >= limit => {
+                self.used = 0;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Calls `func`, first yielding to the event loop if `yield_first` is true.
+fn continue_after<T, E, F>(yield_first: bool, func: F) -> Promise<T, E>
+    where F: FnOnce() -> Promise<T, E> + 'static
+{
+    if yield_first {
+        Promise::ok(()).then(move |()| func())
+    } else {
+        func()
+    }
+}
 
 pub struct OwnedSegments {
     segment_slices : Vec<(usize, usize)>,
     owned_space : Vec<Word>,
 }
 
+impl OwnedSegments {
+    /// Takes ownership of a message that has already been read into memory in its serialized
+    /// form, segment table included, as produced by `capnp::serialize::write_message_to_words()`.
+    pub fn from_words(words: Vec<Word>) -> ::capnp::Result<OwnedSegments> {
+        words_to_segments(words, &Options::new(), true)
+    }
+
+    /// Like `from_words()`, but applies the limits on segments in `options`.
+    pub fn from_words_with_options(words: Vec<Word>, options: &Options) -> ::capnp::Result<OwnedSegments> {
+        words_to_segments(words, options, true)
+    }
+
+    /// Copies `segments` into a single allocation.
+    pub(crate) fn copy_of(segments: &[&[Word]]) -> OwnedSegments {
+        let mut segment_slices = Vec::with_capacity(segments.len());
+        let mut owned_space = Vec::with_capacity(segments.iter().fold(0, |n, s| n + s.len()));
+        for segment in segments {
+            segment_slices.push((owned_space.len(), owned_space.len() + segment.len()));
+            owned_space.extend_from_slice(segment);
+        }
+        OwnedSegments { segment_slices: segment_slices, owned_space: owned_space }
+    }
+
+    /// Returns the number of segments.
+    pub fn segment_count(&self) -> usize {
+        self.segment_slices.len()
+    }
+
+    /// Returns the number of bytes that these segments occupy on the wire,
+    /// including the segment table.
+    pub fn wire_size_in_bytes(&self) -> usize {
+        segment_table_len_in_bytes(self.segment_slices.len()) + self.owned_space.len() * 8
+    }
+}
+
+impl Default for OwnedSegments {
+    /// Returns no segments and no space, for a first call to `read_message_into()`.
+    fn default() -> OwnedSegments {
+        OwnedSegments { segment_slices: Vec::new(), owned_space: Vec::new() }
+    }
+}
+
 impl message::ReaderSegments for OwnedSegments {
     fn get_segment<'a>(&'a self, id: u32) -> Option<&'a [Word]> {
         if id < self.segment_slices.len() as u32 {
@@ -46,13 +278,17 @@ pub fn try_read_message<S>(
     options: message::ReaderOptions) -> Promise<(S, Option<message::Reader<OwnedSegments>>), ::capnp::Error>
     where S: AsyncRead
 {
-    try_read_segment_table(stream).then(move |(s, r)| {
-        match r {
-            Some((total_words, segment_slices)) =>
-                read_segments(s, total_words, segment_slices, options).map(|(s,m)| Ok((s, Some(m)))),
-            None => Promise::ok((s, None))
-        }
-    })
+    try_read_message_with_options(stream, options, Options::new())
+}
+
+/// Like `try_read_message()`, but with control over how the bytes are read.
+pub fn try_read_message_with_options<S>(
+    stream: S,
+    reader_options: message::ReaderOptions,
+    options: Options) -> Promise<(S, Option<message::Reader<OwnedSegments>>), ::capnp::Error>
+    where S: AsyncRead
+{
+    read_message_inner(stream, reader_options, options, Vec::new(), Ok)
 }
 
 pub fn read_message<S>(stream: S,
@@ -60,181 +296,2043 @@ pub fn read_message<S>(stream: S,
                                                                    ::capnp::Error>
     where S: AsyncRead
 {
-    try_read_message(stream, options).map(|(s, r)| {
-        match r {
-            Some(m) => Ok((s, m)),
-            None => Err(::capnp::Error::failed("premature EOF".to_string())),
-        }
-    })
+    read_message_with_options(stream, options, Options::new())
 }
 
-fn try_read_segment_table<S>(mut stream: S)
-                         -> Promise<(S, Option<(usize, Vec<(usize, usize)>)>), ::capnp::Error>
+/// Like `read_message()`, but with control over how the bytes are read.
+pub fn read_message_with_options<S>(stream: S,
+                                    reader_options: message::ReaderOptions,
+                                    options: Options)
+                                    -> Promise<(S, message::Reader<OwnedSegments>), ::capnp::Error>
     where S: AsyncRead
 {
-    let buf: Vec<u8> = vec![0; 8];
-    stream.try_read(buf, 8).then_else(move |r| match r {
-        Err(e) => Promise::err(e.into()),
-        Ok((_, 0)) => Promise::ok((stream, None)),
-        Ok(( _, n)) if n < 8 =>
-            Promise::err(::capnp::Error::failed("premature EOF".to_string())),
-        Ok((buf, _)) => {
-            let segment_count = LittleEndian::read_u32(&buf[0..4]).wrapping_add(1) as usize;
-            if segment_count >= 512 {
-                return Promise::err(::capnp::Error::failed(format!("Too many segments: {}", segment_count)))
-            } else if segment_count == 0 {
-                return Promise::err(::capnp::Error::failed(format!("Too few segments: {}", segment_count)))
-            }
-            let mut segment_slices = Vec::with_capacity(segment_count);
-            let mut total_words = LittleEndian::read_u32(&buf[4..8]) as usize;
-            segment_slices.push((0, total_words));
-            if segment_count > 1 {
-                let buf: Vec<u8> =
-                    if segment_count == 2 {
-                        buf
-                    } else {
-                        vec![0; 4 * (segment_count & !1)]
-                    };
-                let buf_len = buf.len();
-                stream.read(buf, buf_len).map_else(move |r| match r {
-                    Err(e) => Err(e.into()),
-                    Ok((buf, _)) => {
-                        for idx in 0..(segment_count - 1) {
-                            let segment_len =
-                                LittleEndian::read_u32(&buf[(idx * 4)..((idx + 1) * 4)]) as usize;
-                            segment_slices.push((total_words, total_words + segment_len));
-                            total_words += segment_len;
-                        }
-                        Ok((stream, Some((total_words, segment_slices))))
-                    }
-                })
-            } else {
-                Promise::ok((stream, Some((total_words, segment_slices))))
-            }
+    read_message_inner(stream, reader_options, options, Vec::new(), expect_message)
+}
+
+/// Like `read_message_with_options()`, but says what went wrong in an `Error`, which is
+/// `Error::CleanEof` if the stream ends before the message starts.
+pub fn read_message_detailed<S>(stream: S,
+                                reader_options: message::ReaderOptions,
+                                options: Options)
+                                -> Promise<(S, message::Reader<OwnedSegments>), Error>
+    where S: AsyncRead
+{
+    read_message_inner(stream, reader_options, options, Vec::new(), expect_message)
+}
+
+/// Like `try_read_message_with_options()`, but reads the message into the space that `recycled`
+/// occupies, growing it only if it is too small. Get back the segments of a message that is no
+/// longer needed with `message::Reader::into_segments()`, and pass them in here for the next
+/// one, so that a loop that reads many messages does not allocate for each of them.
+pub fn try_read_message_into<S>(stream: S,
+                                reader_options: message::ReaderOptions,
+                                options: Options,
+                                recycled: OwnedSegments)
+                                -> Promise<(S, Option<message::Reader<OwnedSegments>>), ::capnp::Error>
+    where S: AsyncRead
+{
+    read_message_inner(stream, reader_options, options, recycled.owned_space, Ok)
+}
+
+/// Like `try_read_message_into()`, but treats EOF as an error.
+pub fn read_message_into<S>(stream: S,
+                            reader_options: message::ReaderOptions,
+                            options: Options,
+                            recycled: OwnedSegments)
+                            -> Promise<(S, message::Reader<OwnedSegments>), ::capnp::Error>
+    where S: AsyncRead
+{
+    read_message_inner(stream, reader_options, options, recycled.owned_space, expect_message)
+}
+
+/// The result of `try_read_message_with_timeout()`.
+pub enum TimedRead {
+    Message(message::Reader<OwnedSegments>),
+
+    /// The stream ended cleanly, between messages.
+    Eof,
+
+    /// No complete message arrived in time. The read has been cancelled, but it may have consumed
+    /// part of a message, so the stream should be closed rather than read from again.
+    TimedOut,
+}
+
+/// Like `try_read_message_with_options()`, but gives up on a message that has not fully arrived
+/// within `timeout`. The stream is returned in every case, with no read pending on it.
+pub fn try_read_message_with_timeout<S>(stream: S,
+                                        reader_options: message::ReaderOptions,
+                                        options: Options,
+                                        timer: &Timer,
+                                        timeout: ::std::time::Duration)
+                                        -> Promise<(S, TimedRead), ::capnp::Error>
+    where S: AsyncRead + 'static
+{
+    read_with_timeout(stream, reader_options, options, timer, timeout)
+}
+
+/// Like `read_message_detailed()`, but fails with `Error::TimedOut` if the message has not fully
+/// arrived within `timeout`. Use `try_read_message_with_timeout()` to get the stream back after
+/// a timeout; here it is dropped, which closes a socket.
+pub fn read_message_with_timeout<S>(stream: S,
+                                    reader_options: message::ReaderOptions,
+                                    options: Options,
+                                    timer: &Timer,
+                                    timeout: ::std::time::Duration)
+                                    -> Promise<(S, message::Reader<OwnedSegments>), Error>
+    where S: AsyncRead + 'static
+{
+    read_with_timeout(stream, reader_options, options, timer, timeout).map(|(stream, outcome)| match outcome {
+        TimedRead::Message(m) => Ok((stream, m)),
+        TimedRead::Eof => Err(Error::CleanEof),
+        TimedRead::TimedOut => Err(Error::TimedOut),
+    })
+}
+
+fn read_with_timeout<S, E>(stream: S,
+                           reader_options: message::ReaderOptions,
+                           options: Options,
+                           timer: &Timer,
+                           timeout: ::std::time::Duration)
+                           -> Promise<(S, TimedRead), E>
+    where S: AsyncRead + 'static, E: From<Error> + 'static
+{
+    let stream = Rc::new(RefCell::new(stream));
+    let read = read_message_inner(SharedStream(stream.clone()), reader_options, options, Vec::new(), |m| {
+        Ok(match m {
+            Some(m) => TimedRead::Message(m),
+            None => TimedRead::Eof,
+        })
+    }).map(|(_, outcome)| Ok(outcome));
+    let timed_out = timer.after_delay(timeout).map_else(|r| match r {
+        Ok(()) => Ok(TimedRead::TimedOut),
+        Err(e) => Err(Error::Io(e).into()),
+    });
+    // Whichever side loses is dropped before this runs, taking its handle to the stream with it.
+    read.exclusive_join(timed_out).map(move |outcome| Ok((take_shared(stream), outcome)))
+}
+
+/// An error, together with the stream on which it happened, so that the caller can still shut
+/// the stream down or pass on its file descriptor. The stream is wherever the failed transfer
+/// left it, which may be partway through a message.
+pub struct StreamError<S> {
+    pub stream: S,
+    pub error: Error,
+
+    /// For a write, the number of bytes of the message that were known to be written. Always 0
+    /// for a read.
+    pub written: u64,
+}
+
+/// Like `try_read_message_with_options()`, but hands the stream back when the read fails.
+pub fn try_read_message_keeping_stream<S>(stream: S,
+                                          reader_options: message::ReaderOptions,
+                                          options: Options)
+                                          -> Promise<(S, Option<message::Reader<OwnedSegments>>), StreamError<S>>
+    where S: AsyncRead + 'static
+{
+    let stream = Rc::new(RefCell::new(stream));
+    let shared = SharedStream(stream.clone());
+    read_message_inner(shared, reader_options, options, Vec::new(), Ok).map_else(move |r| match r {
+        Ok((shared, m)) => {
+            drop(shared);
+            Ok((take_shared(stream), m))
         }
+        Err(e) => Err(StreamError { stream: take_shared(stream), error: e, written: 0 }),
     })
 }
 
-struct WordVec(Vec<Word>);
+/// Like `write_message_with_options()`, but hands the stream back when the write fails.
+pub fn write_message_keeping_stream<S, A>(stream: S,
+                                          message: message::Builder<A>,
+                                          options: Options)
+                                          -> Promise<(S, message::Builder<A>), StreamError<S>>
+    where S: AsyncWrite + 'static, A: message::Allocator + 'static
+{
+    let stream = Rc::new(RefCell::new(stream));
+    let written = Rc::new(Cell::new(0));
+    let counting = CountingStream { inner: SharedStream(stream.clone()), written: written.clone() };
+    write_segments(counting, OutputSegmentsContainer::new(message), options, |counting, segments| {
+        drop(counting);
+        segments.message
+    }).map_else(move |r: Result<_, Error>| match r {
+        Ok(message) => Ok((take_shared(stream), message)),
+        Err(e) => Err(StreamError { stream: take_shared(stream), error: e, written: written.get() }),
+    })
+}
 
-impl AsRef<[u8]> for WordVec {
-    fn as_ref<'a>(&'a self) -> &'a [u8] {
-        Word::words_to_bytes(&self.0[..])
+/// A stream that a transfer can be given while its owner keeps a handle to it, so that the owner
+/// can take it back once the transfer has finished, failed, or been cancelled.
+struct SharedStream<S>(Rc<RefCell<S>>);
+
+/// Takes back a stream once every `SharedStream` for it has been dropped. A transfer drops its
+/// handle as soon as it completes, fails, or is cancelled, so by the time its result is seen,
+/// nothing else holds the stream.
+fn take_shared<S>(stream: Rc<RefCell<S>>) -> S {
+    match Rc::try_unwrap(stream) {
+        Ok(stream) => stream.into_inner(),
+        Err(_) => unreachable!("a finished transfer still holds the stream"),
     }
 }
 
-impl AsMut<[u8]> for WordVec {
-    fn as_mut<'a>(&'a mut self) -> &'a mut [u8] {
-        Word::words_to_bytes_mut (&mut self.0[..])
+impl <S> AsyncRead for SharedStream<S> where S: AsyncRead {
+    fn try_read<T>(&mut self, buf: T, min_bytes: usize) -> Promise<(T, usize), ::std::io::Error>
+        where T: AsMut<[u8]>
+    {
+        self.0.borrow_mut().try_read(buf, min_bytes)
     }
 }
 
-fn read_segments<S>(mut stream: S,
-                    total_words: usize,
-                    segment_slices: Vec<(usize, usize)>,
-                    options: message::ReaderOptions) -> Promise<(S, message::Reader<OwnedSegments>),
-                                                                   ::capnp::Error>
-    where S: AsyncRead
+impl <S> AsyncWrite for SharedStream<S> where S: AsyncWrite {
+    fn write<T>(&mut self, buf: T) -> Promise<T, ::std::io::Error> where T: AsRef<[u8]> {
+        self.0.borrow_mut().write(buf)
+    }
+}
+
+/// Like `read_message_with_options()`, and calls `report(done, total)` with the bytes of the
+/// message read so far and its size on the wire, segment table included, after each read from
+/// the stream that completes once the segment table has arrived. Use
+/// `Options::max_chunk_bytes()` to get a report at least every so many bytes.
+pub fn read_message_with_progress<S, F>(stream: S,
+                                        reader_options: message::ReaderOptions,
+                                        options: Options,
+                                        report: F)
+                                        -> Promise<(S, message::Reader<OwnedSegments>), ::capnp::Error>
+    where S: AsyncRead + 'static, F: FnMut(u64, u64) + 'static
+{
+    let progress = Rc::new(RefCell::new(ReadProgress { header: Vec::new(), total: None, done: 0, report: report }));
+    let stream = ProgressReader { inner: stream, progress: progress };
+    read_message_with_options(stream, reader_options, options).map(|(stream, message)| Ok((stream.inner, message)))
+}
+
+struct ReadProgress<F> {
+    /// The segment table, until all of it has arrived.
+    header: Vec<u8>,
+    total: Option<u64>,
+    done: u64,
+    report: F,
+}
+
+impl <F> ReadProgress<F> where F: FnMut(u64, u64) {
+    fn advance(&mut self, bytes: &[u8]) {
+        self.done += bytes.len() as u64;
+        for &b in bytes {
+            if self.total.is_some() {
+                break
+            }
+            self.header.push(b);
+            if self.header.len() >= 4 {
+                let segment_count = u64::from(LittleEndian::read_u32(&self.header[0..4])) + 1;
+                let table_len = segment_table_len_in_bytes(segment_count as usize) as u64;
+                if self.header.len() as u64 == table_len {
+                    let total_words = (0..segment_count as usize).fold(0, |n, idx| {
+                        n + u64::from(LittleEndian::read_u32(&self.header[((idx + 1) * 4)..((idx + 2) * 4)]))
+                    });
+                    self.total = Some(table_len + total_words * 8);
+                }
+            }
+        }
+        if let Some(total) = self.total {
+            (self.report)(self.done, total);
+        }
+    }
+}
+
+/// Passes reads through to a `ReadProgress`.
+struct ProgressReader<S, F> {
+    inner: S,
+    progress: Rc<RefCell<ReadProgress<F>>>,
+}
+
+impl <S, F> AsyncRead for ProgressReader<S, F> where S: AsyncRead, F: FnMut(u64, u64) + 'static {
+    fn try_read<T>(&mut self, buf: T, min_bytes: usize) -> Promise<(T, usize), ::std::io::Error>
+        where T: AsMut<[u8]>
+    {
+        let progress = self.progress.clone();
+        self.inner.try_read(buf, min_bytes).map(move |(mut buf, n)| {
+            progress.borrow_mut().advance(&buf.as_mut()[..n]);
+            Ok((buf, n))
+        })
+    }
+}
+
+/// Reads messages until a clean EOF, passing each one to `f` and waiting for the promise that it
+/// returns before reading the next. Returns the stream once it has ended, or the first error from
+/// either the stream or `f`.
+pub fn for_each_message<S, F>(stream: S,
+                              reader_options: message::ReaderOptions,
+                              options: Options,
+                              mut f: F)
+                              -> Promise<S, ::capnp::Error>
+    where S: AsyncRead + 'static,
+          F: FnMut(message::Reader<OwnedSegments>) -> Promise<(), ::capnp::Error> + 'static
 {
-    let owned_space = WordVec(Word::allocate_zeroed_vec(total_words));
-    let len = owned_space.as_ref().len();
-    stream.read(owned_space, len).map_else(move |r| match r {
-        Err(e) => Err(e.into()),
-        Ok((vec, _)) => {
-            let segments = OwnedSegments { segment_slices: segment_slices, owned_space: vec.0 };
-            Ok((stream, message::Reader::new(segments, options)))
+    try_read_message_with_options(stream, reader_options, options).then(move |(stream, message)| {
+        match message {
+            Some(m) => f(m).then(move |()| for_each_message(stream, reader_options, options, f)),
+            None => Promise::ok(stream),
         }
     })
 }
 
+fn expect_message<T, E>(message: Option<T>) -> Result<T, E> where E: From<Error> {
+    match message {
+        Some(m) => Ok(m),
+        None => Err(Error::CleanEof.into()),
+    }
+}
+
+/// How many bytes a `BufferedMessageReader` asks the stream for at a time unless it is given
+/// a capacity.
+pub const DEFAULT_READ_AHEAD_BYTES: usize = 8192;
 
-pub struct OutputSegmentsContainer<A> where A: message::Allocator {
-    message: message::Builder<A>,
-    segments: ::capnp::OutputSegments<'static>
+/// Reads messages through a buffer, asking the stream for as many bytes as the buffer can hold
+/// and then parsing every complete message that arrived, so that a stream of small messages
+/// costs about one read per buffer rather than two or three reads per message. Bytes past the
+/// end of a message stay in the buffer for the next one. A message larger than the buffer grows
+/// it for as long as that message is being read.
+pub struct BufferedMessageReader<S> where S: AsyncRead {
+    stream: S,
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+    capacity: usize,
+    reader_options: message::ReaderOptions,
+    options: Options,
 }
 
-impl <A> OutputSegmentsContainer<A> where A: message::Allocator {
-    fn new(message: message::Builder<A>) -> OutputSegmentsContainer<A> {
-        let segments: ::capnp::OutputSegments<'static> = {
-            let output_segments = message.get_segments_for_output();
-            unsafe {
-                ::std::mem::transmute(output_segments)
+impl <S> BufferedMessageReader<S> where S: AsyncRead + 'static {
+    pub fn new(stream: S,
+               reader_options: message::ReaderOptions,
+               options: Options) -> BufferedMessageReader<S> {
+        BufferedMessageReader::with_capacity(stream, DEFAULT_READ_AHEAD_BYTES, reader_options, options)
+    }
+
+    pub fn with_capacity(stream: S,
+                         capacity: usize,
+                         reader_options: message::ReaderOptions,
+                         options: Options) -> BufferedMessageReader<S> {
+        let capacity = ::std::cmp::max(capacity, 8);
+        BufferedMessageReader {
+            stream: stream,
+            buf: vec![0; capacity],
+            start: 0,
+            end: 0,
+            capacity: capacity,
+            reader_options: reader_options,
+            options: options,
+        }
+    }
+
+    /// The number of bytes that have been read from the stream but not yet returned as part of
+    /// a message.
+    pub fn buffered_bytes(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Returns the stream and the bytes counted by `buffered_bytes()`.
+    pub fn into_inner(mut self) -> (S, Vec<u8>) {
+        self.buf.truncate(self.end);
+        self.buf.drain(0..self.start);
+        (self.stream, self.buf)
+    }
+
+    /// Returns None on EOF.
+    pub fn try_read_message(mut self)
+                            -> Promise<(BufferedMessageReader<S>, Option<message::Reader<OwnedSegments>>),
+                                       ::capnp::Error>
+    {
+        let (table_len, message_len) =
+            pry!(buffered_frame_len(&self.buf[self.start..self.end], &self.options, &self.reader_options));
+        match message_len {
+            Some(len) if len <= self.buffered_bytes() => {
+                let message = message_from_frame(&self.buf[self.start..(self.start + len)],
+                                                 table_len, self.reader_options);
+                self.start += len;
+                return Promise::ok((self, Some(message)))
             }
-        };
-        OutputSegmentsContainer {
-            message: message,
-            segments: segments
+            _ => (),
         }
+
+        // Move what is left of the buffer to its front, and make room for all of the message if
+        // its size is known.
+        let needed = message_len.unwrap_or(table_len);
+        self.buf.truncate(self.end);
+        self.buf.drain(0..self.start);
+        self.end -= self.start;
+        self.start = 0;
+        self.buf.resize(::std::cmp::max(self.capacity, needed), 0);
+
+        let buf = ::std::mem::replace(&mut self.buf, Vec::new());
+        let len = buf.len();
+        let range = BufferRange { buf: buf, start: self.end, end: len };
+        self.stream.try_read(range, 1).then_else(move |r| match r {
+            Err(e) => Promise::err(Error::Io(e).into()),
+            Ok((range, n)) => {
+                self.buf = range.buf;
+                if n > 0 {
+                    self.end += n;
+                    self.try_read_message()
+                } else if self.end == 0 {
+                    Promise::ok((self, None))
+                } else {
+                    let received = self.end;
+                    Promise::err(match message_len {
+                        None => Error::TruncatedHeader { received: received, expected: table_len },
+                        Some(len) => Error::TruncatedBody { received: (received - table_len) as u64,
+                                                            expected: (len - table_len) as u64 },
+                    }.into())
+                }
+            }
+        })
+    }
+
+    pub fn read_message(self)
+                        -> Promise<(BufferedMessageReader<S>, message::Reader<OwnedSegments>), ::capnp::Error>
+    {
+        self.try_read_message().map(|(reader, m)| {
+            let m: Result<_, ::capnp::Error> = expect_message(m);
+            Ok((reader, try!(m)))
+        })
+    }
+}
+
+/// Returns the length of the segment table at the front of `bytes`, or 8 if too little of it has
+/// arrived to tell, and the length of the whole message if all of the table has arrived.
+pub(crate) fn buffered_frame_len(bytes: &[u8],
+                      options: &Options,
+                      reader_options: &message::ReaderOptions) -> Result<(usize, Option<usize>), Error> {
+    if bytes.len() < 4 {
+        return Ok((8, None))
     }
-    fn get<'a>(&'a self) -> &'a ::capnp::OutputSegments<'a> {
-        &self.segments
+    let segment_count = try!(parse_segment_count(&bytes[0..4], options));
+    let table_len = segment_table_len_in_bytes(segment_count);
+    if bytes.len() < table_len {
+        return Ok((table_len, None))
     }
+    let (total_words, segment_slices) = parse_segment_lengths(&bytes[4..(4 + 4 * segment_count)]);
+    try!(options.check_segment_table(total_words, &segment_slices));
+    try!(check_traversal_limit(total_words as u64, reader_options));
+    Ok((table_len, Some(table_len + total_words * 8)))
 }
 
-pub fn write_message<S, A>(stream: S,
-                           message: message::Builder<A>)
-                           -> Promise<(S, message::Builder<A>), ::capnp::Error>
-    where S: AsyncWrite, A: message::Allocator + 'static
+/// The segment table that starts every message in the standard framing: the segment count and
+/// the length of each segment, padded to a whole number of words. One is read from a stream by
+/// `read_segment_table()`, and transports that move bytes some other way can use `parse()` and
+/// `write_to()` directly.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SegmentTable {
+    bytes: Vec<u8>,
+    segment_slices: Vec<(usize, usize)>,
+    total_words: usize,
+}
+
+impl SegmentTable {
+    /// Makes the table for segments of the given lengths in words.
+    pub fn new(segment_lengths: &[usize]) -> ::capnp::Result<SegmentTable> {
+        if segment_lengths.is_empty() {
+            return Err(::capnp::Error::failed("A message must have at least one segment".to_string()))
+        }
+        if segment_lengths.len() > u32::max_value() as usize {
+            return Err(::capnp::Error::failed(format!("Too many segments: {}", segment_lengths.len())))
+        }
+        let mut bytes = vec![0; segment_table_len_in_bytes(segment_lengths.len())];
+        LittleEndian::write_u32(&mut bytes[0..4], (segment_lengths.len() - 1) as u32);
+        for (idx, &len) in segment_lengths.iter().enumerate() {
+            if len > u32::max_value() as usize {
+                return Err(::capnp::Error::failed(format!("Segment too large: {} words", len)))
+            }
+            LittleEndian::write_u32(&mut bytes[((idx + 1) * 4)..((idx + 2) * 4)], len as u32);
+        }
+        Ok(SegmentTable::from_bytes(bytes))
+    }
+
+    /// Parses the segment table at the front of `bytes`, which may go on past it, and checks it
+    /// against the traversal limit in `reader_options`.
+    pub fn parse(bytes: &[u8], reader_options: &message::ReaderOptions) -> ::capnp::Result<SegmentTable> {
+        SegmentTable::parse_with_options(bytes, reader_options, &Options::new())
+    }
+
+    /// Like `parse()`, but also applies the limits on segments in `options`.
+    pub fn parse_with_options(bytes: &[u8],
+                              reader_options: &message::ReaderOptions,
+                              options: &Options) -> ::capnp::Result<SegmentTable> {
+        match try!(buffered_frame_len(bytes, options, reader_options)) {
+            (table_len, Some(_)) => Ok(SegmentTable::from_bytes(bytes[..table_len].to_vec())),
+            (table_len, None) => Err(Error::TruncatedHeader { received: bytes.len(), expected: table_len }.into()),
+        }
+    }
+
+    /// Takes a whole table that `buffered_frame_len()` has accepted.
+    fn from_bytes(bytes: Vec<u8>) -> SegmentTable {
+        let segment_count = LittleEndian::read_u32(&bytes[0..4]) as usize + 1;
+        let (total_words, segment_slices) = parse_segment_lengths(&bytes[4..(4 + 4 * segment_count)]);
+        SegmentTable { bytes: bytes, segment_slices: segment_slices, total_words: total_words }
+    }
+
+    /// Copies the table into the front of `buf` and returns its length in bytes.
+    pub fn write_to(&self, buf: &mut [u8]) -> ::capnp::Result<usize> {
+        if buf.len() < self.bytes.len() {
+            return Err(::capnp::Error::failed(
+                format!("Buffer of {} bytes is too small for a segment table of {} bytes",
+                        buf.len(), self.bytes.len())))
+        }
+        buf[..self.bytes.len()].copy_from_slice(&self.bytes);
+        Ok(self.bytes.len())
+    }
+
+    /// The length of the table itself in bytes, padding included.
+    pub fn len_in_bytes(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.segment_slices.len()
+    }
+
+    /// The length in words of segment `id`, if there is one.
+    pub fn segment_words(&self, id: usize) -> Option<usize> {
+        self.segment_slices.get(id).map(|&(a, b)| b - a)
+    }
+
+    /// The length in words of all of the segments together.
+    pub fn total_words(&self) -> usize {
+        self.total_words
+    }
+
+    /// The number of bytes that the whole message occupies on the wire, segment table included.
+    pub fn message_bytes(&self) -> u64 {
+        (self.bytes.len() + self.total_words * 8) as u64
+    }
+
+    /// The table as it was read, padding included.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub(crate) fn into_parts(self) -> (Vec<u8>, Vec<(usize, usize)>) {
+        (self.bytes, self.segment_slices)
+    }
+}
+
+/// Reads only the segment table at the front of `stream`, checking it against the limits in
+/// `reader_options` and `options`, so that the caller can look at the size of the message before
+/// anything is allocated for its body. Follow it with `read_message_body()` or
+/// `skip_message_body()`, or close the stream. Returns None on EOF.
+pub fn read_segment_table<S>(stream: S,
+                             reader_options: message::ReaderOptions,
+                             options: Options)
+                             -> Promise<(S, Option<SegmentTable>), ::capnp::Error>
+    where S: AsyncRead + 'static
+{
+    read_segment_table_loop(stream, vec![0; 8], reader_options, options)
+}
+
+/// Reads the next word of a segment table into the end of `table`, which holds what has been
+/// read so far.
+fn read_segment_table_loop<S>(mut stream: S,
+                              table: Vec<u8>,
+                              reader_options: message::ReaderOptions,
+                              options: Options)
+                              -> Promise<(S, Option<SegmentTable>), ::capnp::Error>
+    where S: AsyncRead + 'static
 {
-    let segments = OutputSegmentsContainer::new(message);
-    write_segment_table(stream, segments).then(|(stream, segments)| {
-        write_segments(stream, segments)
-    }).map(|(stream, segments)| {
-        Ok((stream, segments.message))
+    let start = table.len() - 8;
+    let end = table.len();
+    stream.try_read(BufferRange { buf: table, start: start, end: end }, 8).then_else(move |r| {
+        let (range, n) = match r {
+            Ok(r) => r,
+            Err(e) => return Promise::err(Error::Io(e).into()),
+        };
+        let mut table = range.buf;
+        if n == 0 && start == 0 {
+            return Promise::ok((stream, None))
+        }
+        if n < 8 {
+            return Promise::err(Error::TruncatedHeader { received: start + n, expected: table.len() }.into())
+        }
+        match pry!(buffered_frame_len(&table, &options, &reader_options)) {
+            (_, Some(_)) => Promise::ok((stream, Some(SegmentTable::from_bytes(table)))),
+            (_, None) => {
+                // Tables are a whole number of words, so the rest is read a word at a time.
+                let len = table.len() + 8;
+                table.resize(len, 0);
+                read_segment_table_loop(stream, table, reader_options, options)
+            }
+        }
     })
 }
 
-fn write_segment_table<S, A>(mut stream: S,
-                             segments: OutputSegmentsContainer<A>)
-                             -> Promise<(S, OutputSegmentsContainer<A>), ::capnp::Error>
-    where S: AsyncWrite, A: message::Allocator + 'static
+/// Reads the body of a message whose segment table was read by `read_segment_table()`.
+pub fn read_message_body<S>(stream: S,
+                            table: SegmentTable,
+                            reader_options: message::ReaderOptions,
+                            options: Options)
+                            -> Promise<(S, message::Reader<OwnedSegments>), ::capnp::Error>
+    where S: AsyncRead + 'static
 {
-    let segment_count = segments.get().len();
-    let mut buf: Vec<u8> = vec![0; ((2 + segment_count) & !1 ) * 4];
+    let SegmentTable { segment_slices, total_words, .. } = table;
+    read_segments(stream, total_words, segment_slices, reader_options, options, Vec::new(), expect_message)
+}
 
-    LittleEndian::write_u32(&mut buf[0..4], segment_count as u32 - 1);
-    for idx in 0..segment_count {
-        LittleEndian::write_u32(&mut buf[((idx + 1) * 4)..((idx + 2) * 4)], segments.get()[idx].len() as u32);
+/// Reads past the body of a message whose segment table was read by `read_segment_table()`,
+/// a piece at a time into `scratch`, which is handed back so that it can be used again. An empty
+/// `scratch` is given one word.
+pub fn skip_message_body<S>(stream: S,
+                            table: SegmentTable,
+                            mut scratch: Vec<u8>)
+                            -> Promise<(S, Vec<u8>), ::capnp::Error>
+    where S: AsyncRead + 'static
+{
+    if scratch.is_empty() {
+        scratch.resize(8, 0);
     }
-    stream.write(buf).map_else(move |r| match r {
-        Err(e) => Err(e.into()),
-        Ok(_) => Ok((stream, segments))
-    })
+    let body_len = table.total_words * 8;
+    skip_body_loop(stream, scratch, body_len, body_len)
 }
 
-struct WritingSegment<A> where A: message::Allocator + 'static {
-    idx: usize,
-    segments: OutputSegmentsContainer<A>
+/// Copies a message out of `bytes`, which hold exactly one message, starting with a segment table
+/// of `table_len` bytes that `buffered_frame_len()` has accepted.
+pub(crate) fn message_from_frame(bytes: &[u8],
+                                 table_len: usize,
+                                 reader_options: message::ReaderOptions) -> message::Reader<OwnedSegments> {
+    let segment_count = LittleEndian::read_u32(&bytes[0..4]) as usize + 1;
+    let (total_words, segment_slices) = parse_segment_lengths(&bytes[4..(4 + 4 * segment_count)]);
+    let mut owned_space = allocate_read_buffer(total_words);
+    Word::words_to_bytes_mut(&mut owned_space[..]).copy_from_slice(&bytes[table_len..]);
+    let segments = OwnedSegments { segment_slices: segment_slices, owned_space: owned_space };
+    message::Reader::new(segments, reader_options)
 }
 
-impl <A> AsRef<[u8]> for WritingSegment<A> where A: message::Allocator + 'static {
-    fn as_ref<'a>(&'a self) -> &'a [u8] {
-        Word::words_to_bytes(self.segments.get()[self.idx])
+/// A message as it arrived on the wire, read without being parsed: its segment table and the
+/// words of its segments. A proxy can forward or store one byte for byte with
+/// `write_raw_frame()`, which writes the table as it was read rather than deriving it again.
+#[derive(Clone, Debug)]
+pub struct RawMessage {
+    table: Vec<u8>,
+    segment_slices: Vec<(usize, usize)>,
+    words: Vec<Word>,
+}
+
+impl RawMessage {
+    /// The segment table, padding included.
+    pub fn segment_table(&self) -> &[u8] {
+        &self.table
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.segment_slices.len()
+    }
+
+    /// The words of segment `id`, if there is one.
+    pub fn segment(&self, id: usize) -> Option<&[Word]> {
+        self.segment_slices.get(id).map(|&(a, b)| &self.words[a..b])
+    }
+
+    /// The words of every segment, in order.
+    pub fn words(&self) -> &[Word] {
+        &self.words
+    }
+
+    /// The number of bytes that the message occupies on the wire, segment table included.
+    pub fn len_in_bytes(&self) -> usize {
+        self.table.len() + self.words.len() * 8
+    }
+
+    /// Parses the message after all, without copying it.
+    pub fn into_reader(self, reader_options: message::ReaderOptions) -> message::Reader<OwnedSegments> {
+        let segments = OwnedSegments { segment_slices: self.segment_slices, owned_space: self.words };
+        message::Reader::new(segments, reader_options)
     }
 }
 
-fn write_segments<S, A>(stream: S,
-                        segments: OutputSegmentsContainer<A>)
-                        -> Promise<(S, OutputSegmentsContainer<A>), ::capnp::Error>
-    where S: AsyncWrite, A: message::Allocator + 'static
-{
-    write_segments_loop(stream, segments, 0)
+/// The part of a message that arrived before its stream ended.
+#[derive(Clone, Debug)]
+pub struct PartialMessage {
+    segment_lengths: Option<Vec<usize>>,
+    bytes: Vec<u8>,
 }
 
-fn write_segments_loop<S, A>(mut stream: S,
-                             segments: OutputSegmentsContainer<A>,
-                             idx: usize)
-                        -> Promise<(S, OutputSegmentsContainer<A>), ::capnp::Error>
-    where S: AsyncWrite, A: message::Allocator + 'static
+impl PartialMessage {
+    /// The length in words of each segment, if the whole segment table arrived.
+    pub fn segment_lengths(&self) -> Option<&[usize]> {
+        self.segment_lengths.as_ref().map(|v| &v[..])
+    }
+
+    /// The number of bytes that the message would have occupied on the wire, segment table
+    /// included, if the whole segment table arrived.
+    pub fn expected_bytes(&self) -> Option<usize> {
+        self.segment_lengths.as_ref().map(|lengths| {
+            segment_table_len_in_bytes(lengths.len()) + lengths.iter().sum::<usize>() * 8
+        })
+    }
+
+    /// Everything that arrived, segment table included.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// The result of `try_read_message_or_partial()`.
+pub enum ReadOutcome {
+    Message(message::Reader<OwnedSegments>),
+
+    /// The stream ended cleanly, between messages.
+    Eof,
+
+    /// The stream ended partway through a message.
+    Truncated(PartialMessage),
+}
+
+/// Like `try_read_message_with_options()`, but when the stream ends partway through a message,
+/// returns the segment table and the bytes that did arrive instead of failing with a premature
+/// EOF, so that they can be logged or salvaged.
+pub fn try_read_message_or_partial<S>(mut stream: S,
+                                      reader_options: message::ReaderOptions,
+                                      options: Options)
+                                      -> Promise<(S, ReadOutcome), ::capnp::Error>
+    where S: AsyncRead
+{
+    let buf: Vec<u8> = vec![0; 8];
+    stream.try_read(buf, 8).then_else(move |r| match r {
+        Err(e) => Promise::err(e.into()),
+        Ok((_, 0)) => Promise::ok((stream, ReadOutcome::Eof)),
+        Ok((mut buf, n)) => if n < 8 {
+            buf.truncate(n);
+            Promise::ok((stream, ReadOutcome::Truncated(PartialMessage { segment_lengths: None, bytes: buf })))
+        } else {
+            let segment_count = pry!(parse_segment_count(&buf[0..4], &options));
+            let table_len = segment_table_len_in_bytes(segment_count);
+            let mut table: Vec<u8> = vec![0; table_len];
+            table[0..8].copy_from_slice(&buf[..]);
+            let table = BufferRange { buf: table, start: 8, end: table_len };
+            stream.try_read(table, table_len - 8).then_else(move |r| match r {
+                Err(e) => Promise::err(e.into()),
+                Ok((table, n)) => if n < table_len - 8 {
+                    let mut bytes = table.buf;
+                    bytes.truncate(8 + n);
+                    let partial = PartialMessage { segment_lengths: None, bytes: bytes };
+                    Promise::ok((stream, ReadOutcome::Truncated(partial)))
+                } else {
+                    let table = table.buf;
+                    let (total_words, segment_slices) =
+                        parse_segment_lengths(&table[4..(4 + 4 * segment_count)]);
+                    pry!(options.check_segment_table(total_words, &segment_slices));
+                    pry!(check_traversal_limit(total_words as u64, &reader_options));
+                    let owned_space = allocate_read_buffer(total_words);
+                    let budget = TurnBudget::new(&options);
+                    salvage_segments_loop(stream, owned_space, 0, options, budget).map(move |(stream, words, n)| {
+                        if n == total_words * 8 {
+                            let segments = OwnedSegments { segment_slices: segment_slices, owned_space: words };
+                            return Ok((stream, ReadOutcome::Message(message::Reader::new(segments, reader_options))))
+                        }
+                        let mut bytes = table;
+                        bytes.extend_from_slice(&Word::words_to_bytes(&words[..])[..n]);
+                        let lengths = segment_slices.iter().map(|&(a, b)| b - a).collect();
+                        let partial = PartialMessage { segment_lengths: Some(lengths), bytes: bytes };
+                        Ok((stream, ReadOutcome::Truncated(partial)))
+                    })
+                }
+            })
+        }
+    })
+}
+
+/// Like `read_segments_loop()`, but stops early at EOF. Returns the words along with the number
+/// of bytes that were filled.
+fn salvage_segments_loop<S>(mut stream: S,
+                            owned_space: Vec<Word>,
+                            already_read: usize,
+                            options: Options,
+                            mut budget: TurnBudget) -> Promise<(S, Vec<Word>, usize), ::capnp::Error>
+    where S: AsyncRead + 'static
+{
+    let total_bytes = owned_space.len() * 8;
+    if already_read == total_bytes {
+        return Promise::ok((stream, owned_space, already_read))
+    }
+    let end = already_read + options.chunk_len(total_bytes - already_read);
+    let buf = WordVecRange { words: owned_space, start: already_read, end: end };
+    stream.try_read(buf, end - already_read).then_else(move |r| match r {
+        Err(e) => Promise::err(e.into()),
+        Ok((buf, n)) => if already_read + n < end {
+            Promise::ok((stream, buf.words, already_read + n))
+        } else {
+            let yield_first = budget.spend(end - already_read);
+            continue_after(yield_first, move || {
+                salvage_segments_loop(stream, buf.words, end, options, budget)
+            })
+        }
+    })
+}
+
+/// Reads a message into `space`, or into new space if `space` is empty, and passes it to
+/// `finish`, which also sees EOF as `None`. Handing `finish` down to the last step of the read,
+/// rather than mapping over the result, saves a promise node per message.
+fn read_message_inner<S, T, E, F>(mut stream: S,
+                                  reader_options: message::ReaderOptions,
+                                  options: Options,
+                                  space: Vec<Word>,
+                                  finish: F) -> Promise<(S, T), E>
+    where S: AsyncRead, E: From<Error> + 'static,
+          F: FnOnce(Option<message::Reader<OwnedSegments>>) -> Result<T, E> + 'static
+{
+    let buf: Vec<u8> = vec![0; 8];
+    stream.try_read(buf, 8).then_else(move |r| match r {
+        Err(e) => Promise::err(Error::Io(e).into()),
+        Ok((_, 0)) => match finish(None) {
+            Ok(t) => Promise::ok((stream, t)),
+            Err(e) => Promise::err(e),
+        },
+        Ok(( _, n)) if n < 8 =>
+            Promise::err(Error::TruncatedHeader { received: n, expected: 8 }.into()),
+        Ok((buf, _)) => {
+            let segment_count = pry!(parse_segment_count(&buf[0..4], &options));
+            if segment_count == 1 {
+                let (total_words, segment_slices) = parse_segment_lengths(&buf[4..8]);
+                pry!(options.check_segment_table(total_words, &segment_slices));
+                return read_segments(stream, total_words, segment_slices, reader_options, options, space, finish)
+            }
+            let table_len = segment_table_len_in_bytes(segment_count);
+            let mut table: Vec<u8> = vec![0; table_len];
+            table[0..8].copy_from_slice(&buf[..]);
+            let table = BufferRange { buf: table, start: 8, end: table_len };
+            stream.try_read(table, table_len - 8).then_else(move |r| match r {
+                Err(e) => Promise::err(Error::Io(e).into()),
+                Ok((_, n)) if n < table_len - 8 =>
+                    Promise::err(Error::TruncatedHeader { received: 8 + n, expected: table_len }.into()),
+                Ok((table, _)) => {
+                    let (total_words, segment_slices) =
+                        parse_segment_lengths(&table.buf[4..(4 + 4 * segment_count)]);
+                    pry!(options.check_segment_table(total_words, &segment_slices));
+                    read_segments(stream, total_words, segment_slices, reader_options, options, space, finish)
+                }
+            })
+        }
+    })
+}
+
+/// Decodes the first word of a segment table, which holds the segment count minus one.
+fn parse_segment_count(bytes: &[u8], options: &Options) -> Result<usize, Error> {
+    let segment_count = u64::from(LittleEndian::read_u32(bytes)) + 1;
+    try!(options.check_segment_count(segment_count));
+    Ok(segment_count as usize)
+}
+
+/// Decodes the segment lengths of a segment table into the total size and the word range of
+/// each segment.
+fn parse_segment_lengths(bytes: &[u8]) -> (usize, Vec<(usize, usize)>) {
+    let segment_count = bytes.len() / 4;
+    let mut segment_slices = Vec::with_capacity(segment_count);
+    let mut total_words = 0;
+    for idx in 0..segment_count {
+        let segment_len = LittleEndian::read_u32(&bytes[(idx * 4)..((idx + 1) * 4)]) as usize;
+        segment_slices.push((total_words, total_words + segment_len));
+        total_words += segment_len;
+    }
+    (total_words, segment_slices)
+}
+
+fn segment_table_len_in_bytes(segment_count: usize) -> usize {
+    ((segment_count + 2) & !1) * 4
+}
+
+/// Rejects a message of `total_words` that could not be traversed within the limit in
+/// `reader_options`. Checking this against the segment table, before any space is allocated for
+/// the body, keeps a bogus table from making us allocate more memory than any valid message
+/// could need.
+fn check_traversal_limit(total_words: u64, reader_options: &message::ReaderOptions) -> Result<(), Error> {
+    if total_words > reader_options.traversal_limit_in_words {
+        Err(Error::MessageTooLarge { words: total_words, limit: reader_options.traversal_limit_in_words })
+    } else {
+        Ok(())
+    }
+}
+
+/// A byte range within a vector of words. Used to read a message body piece by piece.
+struct WordVecRange {
+    words: Vec<Word>,
+    start: usize,
+    end: usize,
+}
+
+impl AsMut<[u8]> for WordVecRange {
+    fn as_mut<'a>(&'a mut self) -> &'a mut [u8] {
+        &mut Word::words_to_bytes_mut(&mut self.words[..])[self.start..self.end]
+    }
+}
+
+/// Allocates space for `length` words that are about to be overwritten by a read.
+///
+/// The space is still zeroed, but as a `vec![0u64; length]`, which the allocator can serve
+/// calloc-style: memory it already knows to be zero, such as freshly mapped pages, is handed back
+/// without being written. `Word::allocate_zeroed_vec()` instead writes zeros over the whole
+/// buffer, which for a large message costs about as much as the read itself.
+fn allocate_read_buffer(length: usize) -> Vec<Word> {
+    let mut buf: Vec<u64> = vec![0; length];
+    let words = unsafe {
+        // Word is a #[repr(C)] wrapper around a u64, so the two have the same layout.
+        Vec::from_raw_parts(buf.as_mut_ptr() as *mut Word, buf.len(), buf.capacity())
+    };
+    ::std::mem::forget(buf);
+    words
+}
+
+/// Makes `space` hold `length` words that are about to be overwritten by a read, reallocating
+/// only if its capacity is too small. Only the words past its current length are zeroed.
+fn reuse_read_buffer(mut space: Vec<Word>, length: usize) -> Vec<Word> {
+    if space.capacity() < length {
+        allocate_read_buffer(length)
+    } else {
+        if space.len() > length {
+            space.truncate(length);
+        } else {
+            space.resize(length, frame::zero_word());
+        }
+        space
+    }
+}
+
+fn read_segments<S, T, E, F>(stream: S,
+                             total_words: usize,
+                             segment_slices: Vec<(usize, usize)>,
+                             reader_options: message::ReaderOptions,
+                             options: Options,
+                             space: Vec<Word>,
+                             finish: F) -> Promise<(S, T), E>
+    where S: AsyncRead, E: From<Error> + 'static,
+          F: FnOnce(Option<message::Reader<OwnedSegments>>) -> Result<T, E> + 'static
+{
+    pry!(check_traversal_limit(total_words as u64, &reader_options));
+    let owned_space = reuse_read_buffer(space, total_words);
+    read_segments_loop(stream, owned_space, 0, options, TurnBudget::new(&options), move |owned_space| {
+        let segments = OwnedSegments { segment_slices: segment_slices, owned_space: owned_space };
+        finish(Some(message::Reader::new(segments, reader_options)))
+    })
+}
+
+/// Fills `owned_space`, starting at byte offset `already_read`, and then passes it to `finish`.
+fn read_segments_loop<S, T, E, F>(mut stream: S,
+                                  owned_space: Vec<Word>,
+                                  already_read: usize,
+                                  options: Options,
+                                  mut budget: TurnBudget,
+                                  finish: F) -> Promise<(S, T), E>
+    where S: AsyncRead + 'static, E: From<Error> + 'static, F: FnOnce(Vec<Word>) -> Result<T, E> + 'static
+{
+    let total_bytes = owned_space.len() * 8;
+    let end = already_read + options.chunk_len(total_bytes - already_read);
+    let buf = WordVecRange { words: owned_space, start: already_read, end: end };
+    let truncated = move |n: usize| {
+        Error::TruncatedBody { received: (already_read + n) as u64, expected: total_bytes as u64 }.into()
+    };
+    if end < total_bytes {
+        stream.try_read(buf, end - already_read).then_else(move |r| match r {
+            Err(e) => Promise::err(Error::Io(e).into()),
+            Ok((_, n)) if n < end - already_read => Promise::err(truncated(n)),
+            Ok((buf, _)) => {
+                let yield_first = budget.spend(end - already_read);
+                continue_after(yield_first, move || {
+                    read_segments_loop(stream, buf.words, end, options, budget, finish)
+                })
+            }
+        })
+    } else {
+        stream.try_read(buf, end - already_read).map_else(move |r| match r {
+            Err(e) => Err(Error::Io(e).into()),
+            Ok((_, n)) if n < end - already_read => Err(truncated(n)),
+            Ok((buf, _)) => finish(buf.words).map(|t| (stream, t)),
+        })
+    }
+}
+
+/// Returns the number of words that `message` will occupy on the wire, including the
+/// segment table.
+pub fn compute_serialized_size_in_words<A>(message: &message::Builder<A>) -> usize
+    where A: message::Allocator
+{
+    let segments = message.get_segments_for_output();
+    let mut size = segment_table_len_in_bytes(segments.len()) / 8;
+    for segment in segments.iter() {
+        size += segment.len();
+    }
+    size
+}
+
+/// The segments of a message that is being written.
+pub(crate) trait SegmentSource {
+    fn segment_count(&self) -> usize;
+    fn segment(&self, idx: usize) -> &[Word];
+}
+
+impl <'b> SegmentSource for [&'b [Word]] {
+    fn segment_count(&self) -> usize { self.len() }
+    fn segment(&self, idx: usize) -> &[Word] { self[idx] }
+}
+
+/// A message that is being written, along with how many segments it has. The segments are
+/// borrowed from the message afresh at each step of the write, since `OutputSegments` cannot be
+/// kept alongside the message that it borrows from.
+pub struct OutputSegmentsContainer<A> where A: message::Allocator {
+    message: message::Builder<A>,
+    segment_count: usize,
+}
+
+impl <A> OutputSegmentsContainer<A> where A: message::Allocator {
+    fn new(message: message::Builder<A>) -> OutputSegmentsContainer<A> {
+        let segment_count = message.get_segments_for_output().len();
+        OutputSegmentsContainer {
+            message: message,
+            segment_count: segment_count,
+        }
+    }
+}
+
+impl <A> SegmentSource for OutputSegmentsContainer<A> where A: message::Allocator {
+    fn segment_count(&self) -> usize { self.segment_count }
+    fn segment(&self, idx: usize) -> &[Word] {
+        self.message.get_segments_for_output()[idx]
+    }
+}
+
+/// Any `ReaderSegments`, along with how many segments there are.
+struct ReaderSegmentSource<R> {
+    segments: R,
+    segment_count: usize,
+}
+
+impl <R> SegmentSource for ReaderSegmentSource<R> where R: message::ReaderSegments {
+    fn segment_count(&self) -> usize { self.segment_count }
+    fn segment(&self, idx: usize) -> &[Word] {
+        self.segments.get_segment(idx as u32).unwrap_or(&[])
+    }
+}
+
+pub fn write_message<S, A>(stream: S,
+                           message: message::Builder<A>)
+                           -> Promise<(S, message::Builder<A>), ::capnp::Error>
+    where S: AsyncWrite, A: message::Allocator + 'static
+{
+    write_message_with_options(stream, message, Options::new())
+}
+
+/// Like `write_message()`, but with control over how the bytes are written.
+pub fn write_message_with_options<S, A>(stream: S,
+                                        message: message::Builder<A>,
+                                        options: Options)
+                                        -> Promise<(S, message::Builder<A>), ::capnp::Error>
+    where S: AsyncWrite, A: message::Allocator + 'static
+{
+    write_segments(stream, OutputSegmentsContainer::new(message), options, |stream, segments| {
+        (stream, segments.message)
+    })
+}
+
+/// Like `write_message_with_options()`, but gives up on a message that has not been written
+/// within `timeout`, as happens when the peer stops reading. Fails with `Error::WriteTimedOut`,
+/// which says how many bytes were known to be written, and drops the stream and the message.
+/// Since the peer may have received part of the message, the connection cannot be used again.
+pub fn write_message_with_timeout<S, A>(stream: S,
+                                        message: message::Builder<A>,
+                                        options: Options,
+                                        timer: &Timer,
+                                        timeout: ::std::time::Duration)
+                                        -> Promise<(S, message::Builder<A>), Error>
+    where S: AsyncWrite + 'static, A: message::Allocator + 'static
+{
+    let expected = compute_serialized_size_in_words(&message) as u64 * 8;
+    let written = Rc::new(Cell::new(0));
+    let stream = CountingStream { inner: stream, written: written.clone() };
+    let write = write_segments(stream, OutputSegmentsContainer::new(message), options, |stream, segments| {
+        (stream.inner, segments.message)
+    });
+    let timed_out = timer.after_delay(timeout).map_else(move |r| match r {
+        Ok(()) => Err(Error::WriteTimedOut { written: written.get(), expected: expected }),
+        Err(e) => Err(Error::Io(e)),
+    });
+    write.exclusive_join(timed_out)
+}
+
+/// Counts the bytes of each write that completes.
+struct CountingStream<S> {
+    inner: S,
+    written: Rc<Cell<u64>>,
+}
+
+impl <S> AsyncWrite for CountingStream<S> where S: AsyncWrite {
+    fn write<T>(&mut self, buf: T) -> Promise<T, ::std::io::Error> where T: AsRef<[u8]> {
+        let len = buf.as_ref().len() as u64;
+        let written = self.written.clone();
+        self.inner.write(buf).map(move |buf| {
+            written.set(written.get() + len);
+            Ok(buf)
+        })
+    }
+}
+
+/// Like `write_message_with_options()`, and calls `report(done, total)` with the bytes of the
+/// message written so far and its size on the wire, segment table included, after each write to
+/// the stream that completes. Use `Options::max_chunk_bytes()` and `Options::gather_bytes()` to
+/// control how many bytes go in each write.
+pub fn write_message_with_progress<S, A, F>(stream: S,
+                                            message: message::Builder<A>,
+                                            options: Options,
+                                            mut report: F)
+                                            -> Promise<(S, message::Builder<A>), ::capnp::Error>
+    where S: AsyncWrite + 'static, A: message::Allocator + 'static, F: FnMut(u64, u64) + 'static
 {
-    if idx >= segments.get().len() {
-        Promise::ok((stream, segments))
+    let total = compute_serialized_size_in_words(&message) as u64 * 8;
+    let report = Rc::new(RefCell::new(move |done| report(done, total)));
+    let stream = ProgressWriter { inner: stream, written: Rc::new(Cell::new(0)), report: report };
+    write_segments(stream, OutputSegmentsContainer::new(message), options, |stream, segments| {
+        (stream.inner, segments.message)
+    })
+}
+
+/// Counts the bytes of each write that completes and reports the running total.
+struct ProgressWriter<S, F> {
+    inner: S,
+    written: Rc<Cell<u64>>,
+    report: Rc<RefCell<F>>,
+}
+
+impl <S, F> AsyncWrite for ProgressWriter<S, F> where S: AsyncWrite, F: FnMut(u64) + 'static {
+    fn write<T>(&mut self, buf: T) -> Promise<T, ::std::io::Error> where T: AsRef<[u8]> {
+        let len = buf.as_ref().len() as u64;
+        let written = self.written.clone();
+        let report = self.report.clone();
+        self.inner.write(buf).map(move |buf| {
+            written.set(written.get() + len);
+            (&mut *report.borrow_mut())(written.get());
+            Ok(buf)
+        })
+    }
+}
+
+/// Writes a message from any `ReaderSegments`, such as those of a message that was received and
+/// is being passed on unchanged, or segments that were put together by hand. Resolves to the
+/// segments once they have been written. Fails if there are no segments.
+pub fn write_message_segments<S, R>(stream: S,
+                                    segments: R,
+                                    options: Options)
+                                    -> Promise<(S, R), ::capnp::Error>
+    where S: AsyncWrite, R: message::ReaderSegments + 'static
+{
+    let segment_count = (0..).take_while(|&id| segments.get_segment(id).is_some()).count();
+    if segment_count == 0 {
+        return Promise::err(Error::NoSegments.into())
+    }
+    let source = ReaderSegmentSource { segments: segments, segment_count: segment_count };
+    write_segments(stream, source, options, |stream, source| (stream, source.segments))
+}
+
+/// Like `write_message_segments()`, for the segments of `message`. Resolves to the segments,
+/// from which `message::Reader::new()` can make a reader again if one is needed.
+pub fn write_message_reader<S, R>(stream: S,
+                                  message: message::Reader<R>,
+                                  options: Options)
+                                  -> Promise<(S, R), ::capnp::Error>
+    where S: AsyncWrite, R: message::ReaderSegments + 'static
+{
+    write_message_segments(stream, message.into_segments(), options)
+}
+
+/// Writes the segment table and then the segments of `segments`, and passes the stream and
+/// `segments` to `finish`.
+fn write_segments<S, G, T, E, F>(mut stream: S, segments: G, options: Options, finish: F) -> Promise<T, E>
+    where S: AsyncWrite + 'static, G: SegmentSource + 'static, E: From<::std::io::Error> + From<Error> + 'static,
+          F: FnOnce(S, G) -> T + 'static
+{
+    let words = (0..segments.segment_count()).fold(0, |n, idx| n + segments.segment(idx).len() as u64);
+    if let Err(e) = options.check_outgoing_words(words) {
+        return Promise::err(e.into())
+    }
+    let table = match segment_table(&segments) {
+        Ok(table) => table,
+        Err(e) => return Promise::err(e.into()),
+    };
+    let (buf, idx) = gather_segments(&segments, 0, table, &options);
+    stream.write(buf).then_else(move |r| match r {
+        Err(e) => Promise::err(e.into()),
+        Ok(_) if idx == segments.segment_count() => Promise::ok(finish(stream, segments)),
+        Ok(_) => write_segments_loop(stream, segments, idx, 0, options, TurnBudget::new(&options), finish),
+    })
+}
+
+/// Appends to `buf` the segments from `idx` on, for as long as `buf` stays within the gather
+/// limit in `options`. Returns `buf` and the index of the first segment that was not appended.
+fn gather_segments<G>(segments: &G, mut idx: usize, mut buf: Vec<u8>, options: &Options) -> (Vec<u8>, usize)
+    where G: SegmentSource + ?Sized
+{
+    let limit = options.chunk_len(options.gather_bytes);
+    while idx < segments.segment_count() && buf.len() + segments.segment(idx).len() * 8 <= limit {
+        buf.extend_from_slice(Word::words_to_bytes(segments.segment(idx)));
+        idx += 1;
+    }
+    (buf, idx)
+}
+
+/// Copies the segment table and then every segment into one buffer, with room for `extra` more
+/// bytes after them. Fails with `Error::NoSegments` if there are no segments.
+fn gather_message<G>(segments: &G, extra: usize) -> Result<Vec<u8>, Error> where G: SegmentSource + ?Sized {
+    let table = try!(segment_table(segments));
+    let body_len = (0..segments.segment_count()).fold(0, |n, idx| n + segments.segment(idx).len() * 8);
+    let mut buf = Vec::with_capacity(table.len() + body_len + extra);
+    buf.extend_from_slice(&table);
+    for idx in 0..segments.segment_count() {
+        buf.extend_from_slice(Word::words_to_bytes(segments.segment(idx)));
+    }
+    Ok(buf)
+}
+
+/// Fails with `Error::NoSegments` if there are no segments.
+pub(crate) fn segment_table<G>(segments: &G) -> Result<Vec<u8>, Error> where G: SegmentSource + ?Sized {
+    let segment_count = segments.segment_count();
+    if segment_count == 0 {
+        return Err(Error::NoSegments)
+    }
+    let mut buf: Vec<u8> = vec![0; segment_table_len_in_bytes(segment_count)];
+
+    LittleEndian::write_u32(&mut buf[0..4], segment_count as u32 - 1);
+    for idx in 0..segment_count {
+        LittleEndian::write_u32(&mut buf[((idx + 1) * 4)..((idx + 2) * 4)], segments.segment(idx).len() as u32);
+    }
+    Ok(buf)
+}
+
+/// A hash function, such as SHA-256, that can be fed the bytes of a message as it is written.
+pub trait WireDigest {
+    type Output;
+    fn update(&mut self, bytes: &[u8]);
+    fn finish(self) -> Self::Output;
+}
+
+/// Adapts a `std::hash::Hasher` to `WireDigest`.
+pub struct HasherDigest<H>(pub H);
+
+impl <H> WireDigest for HasherDigest<H> where H: ::std::hash::Hasher {
+    type Output = u64;
+    fn update(&mut self, bytes: &[u8]) {
+        self.0.write(bytes);
+    }
+    fn finish(self) -> u64 {
+        self.0.finish()
+    }
+}
+
+/// Like `write_message_with_options()`, but also feeds `digest` the exact bytes that are written,
+/// segment table included, as they are handed to the stream, and resolves to the resulting
+/// digest. This saves serializing the message a second time just to hash or sign it.
+pub fn write_message_with_digest<S, A, D>(stream: S,
+                                          message: message::Builder<A>,
+                                          digest: D,
+                                          options: Options)
+                                          -> Promise<(S, message::Builder<A>, D::Output), ::capnp::Error>
+    where S: AsyncWrite + 'static, A: message::Allocator + 'static, D: WireDigest + 'static
+{
+    let stream = DigestingStream { inner: stream, digest: digest };
+    write_segments(stream, OutputSegmentsContainer::new(message), options, |stream, segments| {
+        let DigestingStream { inner, digest } = stream;
+        (inner, segments.message, digest.finish())
+    })
+}
+
+/// Feeds a digest each buffer written to the stream.
+struct DigestingStream<S, D> {
+    inner: S,
+    digest: D,
+}
+
+impl <S, D> AsyncWrite for DigestingStream<S, D> where S: AsyncWrite, D: WireDigest {
+    fn write<T>(&mut self, buf: T) -> Promise<T, ::std::io::Error> where T: AsRef<[u8]> {
+        // The stream either takes all of `buf` or fails the write, and the message with it.
+        self.digest.update(buf.as_ref());
+        self.inner.write(buf)
+    }
+}
+
+struct WritingSegment<G> {
+    idx: usize,
+    start: usize,
+    end: usize,
+    segments: G,
+}
+
+impl <G> AsRef<[u8]> for WritingSegment<G> where G: SegmentSource {
+    fn as_ref<'a>(&'a self) -> &'a [u8] {
+        &Word::words_to_bytes(self.segments.segment(self.idx))[self.start..self.end]
+    }
+}
+
+fn write_segments_loop<S, G, T, E, F>(mut stream: S,
+                                      segments: G,
+                                      idx: usize,
+                                      already_written: usize,
+                                      options: Options,
+                                      mut budget: TurnBudget,
+                                      finish: F)
+                                      -> Promise<T, E>
+    where S: AsyncWrite + 'static, G: SegmentSource + 'static, E: From<::std::io::Error> + 'static,
+          F: FnOnce(S, G) -> T + 'static
+{
+    let segment_count = segments.segment_count();
+    let segment_bytes = segments.segment(idx).len() * 8;
+    if already_written == 0 {
+        let (buf, next) = gather_segments(&segments, idx, Vec::new(), &options);
+        if next > idx {
+            return stream.write(buf).then_else(move |r| match r {
+                Err(e) => Promise::err(e.into()),
+                Ok(buf) => {
+                    if next == segment_count {
+                        return Promise::ok(finish(stream, segments))
+                    }
+                    let yield_first = budget.spend(buf.len());
+                    continue_after(yield_first, move || {
+                        write_segments_loop(stream, segments, next, 0, options, budget, finish)
+                    })
+                }
+            })
+        }
+    }
+    let end = already_written + options.chunk_len(segment_bytes - already_written);
+    let buf = WritingSegment { idx: idx, start: already_written, end: end, segments: segments };
+    if end == segment_bytes && idx + 1 == segment_count {
+        stream.write(buf).map_else(move |r| match r {
+            Err(e) => Err(e.into()),
+            Ok(buf) => Ok(finish(stream, buf.segments)),
+        })
     } else {
-        let buf = WritingSegment { idx: idx, segments: segments };
         stream.write(buf).then_else(move |r| match r {
             Err(e) => Promise::err(e.into()),
-            Ok(buf) =>
-                write_segments_loop(stream, buf.segments, idx + 1)
+            Ok(buf) => {
+                let yield_first = budget.spend(end - already_written);
+                continue_after(yield_first, move || {
+                    if end < segment_bytes {
+                        write_segments_loop(stream, buf.segments, idx, end, options, budget, finish)
+                    } else {
+                        write_segments_loop(stream, buf.segments, idx + 1, 0, options, budget, finish)
+                    }
+                })
+            }
+        })
+    }
+}
+
+/// Writes segments that were obtained from `read_message()`, without first copying them
+/// into a `message::Builder`.
+pub fn write_owned_segments<S>(mut stream: S,
+                               segments: OwnedSegments,
+                               options: Options)
+                               -> Promise<(S, OwnedSegments), ::capnp::Error>
+    where S: AsyncWrite
+{
+    pry!(options.check_outgoing_words(segments.owned_space.len() as u64));
+    let OwnedSegments { segment_slices, owned_space } = segments;
+    let segment_count = segment_slices.len();
+    if segment_count == 0 {
+        return Promise::err(Error::NoSegments.into())
+    }
+    let mut buf: Vec<u8> = vec![0; segment_table_len_in_bytes(segment_count)];
+
+    LittleEndian::write_u32(&mut buf[0..4], segment_count as u32 - 1);
+    for idx in 0..segment_count {
+        let (a, b) = segment_slices[idx];
+        LittleEndian::write_u32(&mut buf[((idx + 1) * 4)..((idx + 2) * 4)], (b - a) as u32);
+    }
+    if buf.len() + owned_space.len() * 8 <= options.chunk_len(options.gather_bytes) {
+        buf.extend_from_slice(Word::words_to_bytes(&owned_space[..]));
+        return stream.write(buf).map_else(move |r| match r {
+            Err(e) => Err(e.into()),
+            Ok(_) => Ok((stream, OwnedSegments { segment_slices: segment_slices, owned_space: owned_space })),
+        })
+    }
+    stream.write(buf).then_else(move |r| match r {
+        Err(e) => Promise::err(e.into()),
+        Ok(_) => write_buffer(stream, WordBuffer(owned_space), options, move |stream, WordBuffer(owned_space)| {
+            (stream, OwnedSegments { segment_slices: segment_slices, owned_space: owned_space })
+        }),
+    })
+}
+
+/// Writes `message` without taking ownership of it, so that its allocator need not be
+/// `'static`. The borrow of `message` cannot be held while the write is in progress, so the
+/// segment table and segments are gathered into a single buffer up front, which is then written.
+/// `write_message()` instead writes large segments in place.
+pub fn write_message_ref<S, A>(stream: S,
+                               message: &message::Builder<A>)
+                               -> Promise<S, ::capnp::Error>
+    where S: AsyncWrite, A: message::Allocator
+{
+    write_message_ref_with_options(stream, message, Options::new())
+}
+
+/// Like `write_message_ref()`, but with control over how the bytes are written.
+pub fn write_message_ref_with_options<S, A>(stream: S,
+                                            message: &message::Builder<A>,
+                                            options: Options)
+                                            -> Promise<S, ::capnp::Error>
+    where S: AsyncWrite, A: message::Allocator
+{
+    pry!(options.check_outgoing_words(builder_words(message)));
+    let bytes = pry!(gather_message(&message.get_segments_for_output()[..], 0));
+    write_buffer(stream, bytes, options, |stream, _| stream)
+}
+
+/// The number of words in the segments of `message`.
+fn builder_words<A>(message: &message::Builder<A>) -> u64 where A: message::Allocator {
+    message.get_segments_for_output().iter().fold(0, |n, segment| n + segment.len() as u64)
+}
+
+/// Checks that `bytes` holds exactly one message in the standard framing: a well-formed
+/// segment table followed by segments of the sizes it lists, with nothing left over. Also
+/// checks the message size against `reader_options.traversal_limit_in_words`.
+pub fn check_raw_message(bytes: &[u8],
+                         reader_options: &message::ReaderOptions) -> ::capnp::Result<()>
+{
+    check_raw_message_with_options(bytes, reader_options, &Options::new())
+}
+
+/// Like `check_raw_message()`, but also applies the limits on segments in `options`.
+pub fn check_raw_message_with_options(bytes: &[u8],
+                                      reader_options: &message::ReaderOptions,
+                                      options: &Options) -> ::capnp::Result<()>
+{
+    if bytes.len() < 8 {
+        return Err(::capnp::Error::failed(
+            format!("Message too short for a segment table: {} bytes", bytes.len())))
+    }
+    let segment_count = try!(parse_segment_count(&bytes[0..4], options));
+    let table_len = segment_table_len_in_bytes(segment_count);
+    if bytes.len() < table_len {
+        return Err(::capnp::Error::failed(
+            format!("Message too short for a table of {} segments: {} bytes", segment_count, bytes.len())))
+    }
+    let mut total_words: u64 = 0;
+    for idx in 0..segment_count {
+        total_words += u64::from(LittleEndian::read_u32(&bytes[((idx + 1) * 4)..((idx + 2) * 4)]));
+    }
+    {
+        let (total_words, segment_slices) = parse_segment_lengths(&bytes[4..(4 + 4 * segment_count)]);
+        try!(options.check_segment_table(total_words, &segment_slices));
+    }
+    try!(check_traversal_limit(total_words, reader_options));
+    let body_len = (bytes.len() - table_len) as u64;
+    if body_len != total_words * 8 {
+        return Err(::capnp::Error::failed(
+            format!("Segment table lists {} bytes of segments, but {} bytes follow it",
+                    total_words * 8, body_len)))
+    }
+    Ok(())
+}
+
+/// Writes a message that is already in its serialized form, such as one that was persisted
+/// to disk, without parsing it. If `validate` is `Some`, the bytes are first checked with
+/// `check_raw_message()`, and nothing is written if the check fails.
+pub fn write_raw_message<S, B>(stream: S,
+                               bytes: B,
+                               validate: Option<message::ReaderOptions>)
+                               -> Promise<(S, B), ::capnp::Error>
+    where S: AsyncWrite, B: AsRef<[u8]> + 'static
+{
+    write_raw_message_with_options(stream, bytes, validate, Options::new())
+}
+
+/// Like `write_raw_message()`, but with control over how the bytes are written.
+pub fn write_raw_message_with_options<S, B>(stream: S,
+                                            bytes: B,
+                                            validate: Option<message::ReaderOptions>,
+                                            options: Options)
+                                            -> Promise<(S, B), ::capnp::Error>
+    where S: AsyncWrite, B: AsRef<[u8]> + 'static
+{
+    if let Some(ref reader_options) = validate {
+        pry!(check_raw_message_with_options(bytes.as_ref(), reader_options, &options));
+    }
+    if options.max_message_words.is_some() {
+        // Everything after the segment table, whether or not the table agrees.
+        let raw = bytes.as_ref();
+        let table_len = if raw.len() < 4 {
+            raw.len()
+        } else {
+            segment_table_len_in_bytes(LittleEndian::read_u32(&raw[0..4]) as usize + 1)
+        };
+        pry!(options.check_outgoing_words((raw.len().saturating_sub(table_len) / 8) as u64));
+    }
+    write_buffer(stream, bytes, options, |stream, bytes| (stream, bytes))
+}
+
+/// Reads a message without parsing it. The segment table is checked against the limits in
+/// `reader_options` and `options` as usual. Returns None on EOF.
+pub fn try_read_raw_message<S>(stream: S,
+                               reader_options: message::ReaderOptions,
+                               options: Options)
+                               -> Promise<(S, Option<RawMessage>), ::capnp::Error>
+    where S: AsyncRead + 'static
+{
+    read_segment_table(stream, reader_options, options).then(move |(stream, table)| {
+        let table = match table {
+            Some(t) => t,
+            None => return Promise::ok((stream, None)),
+        };
+        let space = allocate_read_buffer(table.total_words);
+        let (table, segment_slices) = table.into_parts();
+        read_segments_loop(stream, space, 0, options, TurnBudget::new(&options), move |words| {
+            Ok(Some(RawMessage { table: table, segment_slices: segment_slices, words: words }))
+        })
+    })
+}
+
+/// Like `try_read_raw_message()`, but fails with `Error::CleanEof` on EOF.
+pub fn read_raw_message<S>(stream: S,
+                           reader_options: message::ReaderOptions,
+                           options: Options)
+                           -> Promise<(S, RawMessage), ::capnp::Error>
+    where S: AsyncRead + 'static
+{
+    try_read_raw_message(stream, reader_options, options).map(|(stream, message)| {
+        Ok((stream, try!(expect_message::<_, ::capnp::Error>(message))))
+    })
+}
+
+/// Writes a message read by `try_read_raw_message()`, table and segments exactly as they were
+/// read.
+pub fn write_raw_frame<S>(stream: S,
+                          message: RawMessage,
+                          options: Options)
+                          -> Promise<(S, RawMessage), ::capnp::Error>
+    where S: AsyncWrite + 'static
+{
+    pry!(options.check_outgoing_words(message.words.len() as u64));
+    let RawMessage { table, segment_slices, words } = message;
+    write_buffer(stream, table, options, |stream, table| (stream, table)).then(move |(stream, table)| {
+        write_buffer(stream, WordBuffer(words), options, move |stream, words| {
+            (stream, RawMessage { table: table, segment_slices: segment_slices, words: words.0 })
+        })
+    })
+}
+
+/// How many bytes of a message body `copy_message()` holds at a time.
+pub const DEFAULT_COPY_BUFFER_BYTES: usize = 8192;
+
+/// Copies one message from `from` to `to` without parsing it, as a proxy or load balancer
+/// would. The segment table is checked against the limits in `reader_options` and `options`
+/// before anything is written, and the body then passes through a buffer of
+/// `DEFAULT_COPY_BUFFER_BYTES`, so that a large message is never held in memory all at once.
+/// Resolves to both streams and the number of bytes copied, or None in place of the count if
+/// `from` was at EOF. If `from` ends partway through the body, `to` has already received part of
+/// the message and should be closed.
+pub fn copy_message<R, W>(from: R,
+                          to: W,
+                          reader_options: message::ReaderOptions,
+                          options: Options)
+                          -> Promise<(R, W, Option<u64>), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    copy_message_with_buffer(from, to, DEFAULT_COPY_BUFFER_BYTES, reader_options, options)
+}
+
+/// Like `copy_message()`, but with a buffer of `buffer_bytes`.
+pub fn copy_message_with_buffer<R, W>(from: R,
+                                      to: W,
+                                      buffer_bytes: usize,
+                                      reader_options: message::ReaderOptions,
+                                      options: Options)
+                                      -> Promise<(R, W, Option<u64>), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    read_segment_table(from, reader_options, options).then(move |(from, table)| {
+        let table = match table {
+            Some(t) => t,
+            None => return Promise::ok((from, to, None)),
+        };
+        pry!(options.check_outgoing_words(table.total_words as u64));
+        let copied = table.message_bytes();
+        let body_len = table.total_words * 8;
+        let (table, _) = table.into_parts();
+        let buf = vec![0; ::std::cmp::max(::std::cmp::min(buffer_bytes, body_len), 1)];
+        write_buffer(to, table, options, |to, _| to).then(move |to| {
+            copy_body_loop(from, to, buf, body_len, body_len, options)
+        }).map(move |(from, to)| Ok((from, to, Some(copied))))
+    })
+}
+
+/// Copies the last `remaining` bytes of a body of `total` bytes, a buffer at a time.
+fn copy_body_loop<R, W>(mut from: R,
+                        to: W,
+                        buf: Vec<u8>,
+                        remaining: usize,
+                        total: usize,
+                        options: Options)
+                        -> Promise<(R, W), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    if remaining == 0 {
+        return Promise::ok((from, to))
+    }
+    let n = ::std::cmp::min(remaining, buf.len());
+    from.try_read(BufferRange { buf: buf, start: 0, end: n }, n).then_else(move |r| match r {
+        Err(e) => Promise::err(Error::Io(e).into()),
+        Ok((_, got)) if got < n => {
+            let received = (total - remaining + got) as u64;
+            Promise::err(Error::TruncatedBody { received: received, expected: total as u64 }.into())
+        }
+        Ok((range, _)) => {
+            write_buffer(to, range, options, |to, range| (to, range.buf)).then(move |(to, buf)| {
+                copy_body_loop(from, to, buf, remaining - n, total, options)
+            })
+        }
+    })
+}
+
+/// Reads past one message without keeping it: the segment table is read and checked against
+/// the limits in `reader_options` and `options`, and the body is then read a piece at a time into
+/// a small scratch buffer and thrown away. Resolves to the number of bytes skipped, segment
+/// table included, or None on EOF.
+pub fn skip_message<S>(stream: S,
+                       reader_options: message::ReaderOptions,
+                       options: Options)
+                       -> Promise<(S, Option<u64>), ::capnp::Error>
+    where S: AsyncRead + 'static
+{
+    let scratch = vec![0; DEFAULT_COPY_BUFFER_BYTES];
+    skip_message_with_scratch(stream, scratch, reader_options, options).map(|(stream, _, skipped)| {
+        Ok((stream, skipped))
+    })
+}
+
+/// Like `skip_message()`, but reads into `scratch`, which is handed back so that it can be used
+/// again for the next message. An empty `scratch` is given one word.
+pub fn skip_message_with_scratch<S>(stream: S,
+                                    scratch: Vec<u8>,
+                                    reader_options: message::ReaderOptions,
+                                    options: Options)
+                                    -> Promise<(S, Vec<u8>, Option<u64>), ::capnp::Error>
+    where S: AsyncRead + 'static
+{
+    read_segment_table(stream, reader_options, options).then(move |(stream, table)| {
+        let table = match table {
+            Some(t) => t,
+            None => return Promise::ok((stream, scratch, None)),
+        };
+        let skipped = table.message_bytes();
+        skip_message_body(stream, table, scratch).map(move |(stream, scratch)| {
+            Ok((stream, scratch, Some(skipped)))
+        })
+    })
+}
+
+/// Discards the last `remaining` bytes of a body of `total` bytes.
+fn skip_body_loop<S>(mut stream: S,
+                     scratch: Vec<u8>,
+                     remaining: usize,
+                     total: usize)
+                     -> Promise<(S, Vec<u8>), ::capnp::Error>
+    where S: AsyncRead + 'static
+{
+    if remaining == 0 {
+        return Promise::ok((stream, scratch))
+    }
+    let n = ::std::cmp::min(remaining, scratch.len());
+    stream.try_read(BufferRange { buf: scratch, start: 0, end: n }, n).then_else(move |r| match r {
+        Err(e) => Promise::err(Error::Io(e).into()),
+        Ok((_, got)) if got < n => {
+            let received = (total - remaining + got) as u64;
+            Promise::err(Error::TruncatedBody { received: received, expected: total as u64 }.into())
+        }
+        Ok((range, _)) => skip_body_loop(stream, range.buf, remaining - n, total),
+    })
+}
+
+fn check_slot_bytes(slot_bytes: usize) -> ::capnp::Result<()> {
+    if slot_bytes == 0 || slot_bytes % 8 != 0 {
+        Err(::capnp::Error::failed(
+            format!("Slot size must be a positive multiple of eight bytes: {}", slot_bytes)))
+    } else {
+        Ok(())
+    }
+}
+
+/// Writes `message` into a slot of exactly `slot_bytes` bytes, padding the remainder of the slot
+/// with zeros. This suits transports that move data in fixed-size units, such as shared-memory
+/// rings and block devices. `slot_bytes` must be a multiple of eight, and the message must fit in
+/// the slot.
+pub fn write_message_to_slot<S, A>(stream: S,
+                                   message: &message::Builder<A>,
+                                   slot_bytes: usize,
+                                   options: Options)
+                                   -> Promise<S, ::capnp::Error>
+    where S: AsyncWrite, A: message::Allocator
+{
+    pry!(check_slot_bytes(slot_bytes));
+    pry!(options.check_outgoing_words(builder_words(message)));
+    let mut words = ::capnp::serialize::write_message_to_words(message);
+    if words.len() * 8 > slot_bytes {
+        return Promise::err(::capnp::Error::failed(
+            format!("Message of {} bytes does not fit in a slot of {} bytes", words.len() * 8, slot_bytes)))
+    }
+    words.resize(slot_bytes / 8, Word::allocate_zeroed_vec(1)[0]);
+    write_buffer(stream, WordBuffer(words), options, |stream, _| stream)
+}
+
+/// Reads a message that was written by `write_message_to_slot()` with the same `slot_bytes`.
+/// Fails if the segment table does not fit in the slot or if the padding is not all zeros.
+/// Returns None on EOF at a slot boundary.
+pub fn try_read_message_from_slot<S>(mut stream: S,
+                                     slot_bytes: usize,
+                                     reader_options: message::ReaderOptions,
+                                     options: Options)
+                                     -> Promise<(S, Option<message::Reader<OwnedSegments>>), ::capnp::Error>
+    where S: AsyncRead
+{
+    pry!(check_slot_bytes(slot_bytes));
+    let buf = WordVecRange { words: allocate_read_buffer(slot_bytes / 8), start: 0, end: 8 };
+    stream.try_read(buf, 8).then_else(move |r| match r {
+        Err(e) => Promise::err(e.into()),
+        Ok((_, 0)) => Promise::ok((stream, None)),
+        Ok((_, n)) if n < 8 =>
+            Promise::err(Error::TruncatedHeader { received: n, expected: 8 }.into()),
+        Ok((buf, _)) => {
+            read_segments_loop(stream, buf.words, 8, options, TurnBudget::new(&options), move |words| {
+                let segments = try!(words_to_segments(words, &options, false));
+                Ok(Some(message::Reader::new(segments, reader_options)))
+            })
+        }
+    })
+}
+
+/// Like `try_read_message_from_slot()`, but treats EOF as an error.
+pub fn read_message_from_slot<S>(stream: S,
+                                 slot_bytes: usize,
+                                 reader_options: message::ReaderOptions,
+                                 options: Options)
+                                 -> Promise<(S, message::Reader<OwnedSegments>), ::capnp::Error>
+    where S: AsyncRead
+{
+    try_read_message_from_slot(stream, slot_bytes, reader_options, options).map(|(s, r)| {
+        match r {
+            Some(m) => Ok((s, m)),
+            None => Err(Error::CleanEof.into()),
+        }
+    })
+}
+
+/// Writes `message` as a flat single-segment message: the segment's words with no segment table
+/// in front of them, as produced by `capnp convert --flat`. Fails if `message` has more than one
+/// segment; allocate it with a first segment large enough to hold the whole message.
+pub fn write_flat_message<S, A>(stream: S,
+                                message: &message::Builder<A>,
+                                options: Options)
+                                -> Promise<S, ::capnp::Error>
+    where S: AsyncWrite, A: message::Allocator
+{
+    let words = {
+        let segments = message.get_segments_for_output();
+        if segments.len() != 1 {
+            return Promise::err(::capnp::Error::failed(
+                format!("A flat message must have exactly one segment, but this one has {}", segments.len())))
+        }
+        pry!(options.check_outgoing_words(segments[0].len() as u64));
+        segments[0].to_vec()
+    };
+    write_buffer(stream, WordBuffer(words), options, |stream, _| stream)
+}
+
+/// Reads the whole of `stream`, up to EOF, as a flat single-segment message with no segment
+/// table, as written by `write_flat_message()` or `capnp convert --flat`. The traversal limit in
+/// `reader_options` and `max_segment_words` in `options`, whichever is lower, bound how much is
+/// read, and `remaining_source_bytes`, if set, sizes the buffer up front.
+pub fn read_flat_message<S>(stream: S,
+                            reader_options: message::ReaderOptions,
+                            options: Options)
+                            -> Promise<(S, message::Reader<OwnedSegments>), ::capnp::Error>
+    where S: AsyncRead
+{
+    let initial_words = match options.remaining_source_bytes {
+        Some(n) => ((n + 7) / 8) as usize,
+        None => FLAT_READ_INITIAL_WORDS,
+    };
+    let traversal_limit = ::std::cmp::min(reader_options.traversal_limit_in_words, ::std::usize::MAX as u64);
+    let max_words = match options.max_segment_words {
+        Some(max) => ::std::cmp::min(max, traversal_limit as usize),
+        None => traversal_limit as usize,
+    };
+    let initial_words = ::std::cmp::min(initial_words, max_words);
+    let words = allocate_read_buffer(::std::cmp::max(initial_words, 1));
+    read_flat_loop(stream, words, 0, max_words, options, TurnBudget::new(&options)).map(move |(stream, mut words, len)| {
+        if len == 0 {
+            return Err(Error::CleanEof.into())
+        }
+        if len % 8 != 0 {
+            return Err(::capnp::Error::failed(
+                format!("Flat message of {} bytes is not a whole number of words", len)))
+        }
+        words.truncate(len / 8);
+        let segments = OwnedSegments { segment_slices: vec![(0, len / 8)], owned_space: words };
+        Ok((stream, message::Reader::new(segments, reader_options)))
+    })
+}
+
+/// How many words `read_flat_message()` allocates before it knows how long the stream is.
+const FLAT_READ_INITIAL_WORDS: usize = 1024;
+
+/// Reads into `words`, starting at byte offset `already_read` and growing `words` up to
+/// `max_words` as needed, until EOF. Returns the words along with the number of bytes read.
+fn read_flat_loop<S>(mut stream: S,
+                     mut words: Vec<Word>,
+                     already_read: usize,
+                     max_words: usize,
+                     options: Options,
+                     mut budget: TurnBudget) -> Promise<(S, Vec<Word>, usize), ::capnp::Error>
+    where S: AsyncRead + 'static
+{
+    if already_read == words.len() * 8 {
+        if words.len() >= max_words {
+            // Check for EOF before complaining, in case the message is exactly `max_words` long.
+            let buf: Vec<u8> = vec![0; 1];
+            return stream.try_read(buf, 1).map_else(move |r| match r {
+                Err(e) => Err(e.into()),
+                Ok((_, 0)) => Ok((stream, words, already_read)),
+                Ok(_) => Err(::capnp::Error::failed(
+                    format!("Flat message exceeds the limit of {} words", max_words))),
+            })
+        }
+        let new_len = ::std::cmp::min(words.len().saturating_mul(2), max_words);
+        let mut grown = allocate_read_buffer(new_len);
+        grown[..words.len()].copy_from_slice(&words[..]);
+        words = grown;
+    }
+    let end = already_read + options.chunk_len(words.len() * 8 - already_read);
+    let buf = WordVecRange { words: words, start: already_read, end: end };
+    stream.try_read(buf, 1).then_else(move |r| match r {
+        Err(e) => Promise::err(e.into()),
+        Ok((buf, 0)) => Promise::ok((stream, buf.words, already_read)),
+        Ok((buf, n)) => {
+            let yield_first = budget.spend(n);
+            continue_after(yield_first, move || {
+                read_flat_loop(stream, buf.words, already_read + n, max_words, options, budget)
+            })
+        }
+    })
+}
+
+/// Writes `message` in the standard framing followed by a CRC-32C of everything before it, the
+/// segment table included, as a little-endian `u32`. The message is first copied into a single
+/// buffer, which is then written. Read it with `try_read_checksummed_message()`.
+pub fn write_checksummed_message<S, A>(stream: S,
+                                       message: &message::Builder<A>,
+                                       options: Options)
+                                       -> Promise<S, ::capnp::Error>
+    where S: AsyncWrite + 'static, A: message::Allocator
+{
+    pry!(options.check_outgoing_words(builder_words(message)));
+    let mut bytes = pry!(gather_message(&message.get_segments_for_output()[..], 4));
+    let mut trailer = [0; 4];
+    LittleEndian::write_u32(&mut trailer, crc32c::crc32c(&bytes));
+    bytes.extend_from_slice(&trailer);
+    write_buffer(stream, bytes, options, |stream, _| stream)
+}
+
+/// Reads a message written by `write_checksummed_message()`, failing with
+/// `Error::ChecksumMismatch` if it was corrupted on the way. Returns None on EOF.
+pub fn try_read_checksummed_message<S>(stream: S,
+                                       reader_options: message::ReaderOptions,
+                                       options: Options)
+                                       -> Promise<(S, Option<message::Reader<OwnedSegments>>), Error>
+    where S: AsyncRead + 'static
+{
+    // The checksum covers the bytes as they arrive, padding included, rather than a segment table
+    // rebuilt from them.
+    let crc = Rc::new(RefCell::new(crc32c::Crc32c::new()));
+    let stream = ChecksummingStream { inner: stream, crc: crc.clone() };
+    read_message_inner(stream, reader_options, options, Vec::new(), Ok).then(move |(stream, message)| {
+        let mut stream = stream.inner;
+        let segments = match message {
+            Some(m) => m.into_segments(),
+            None => return Promise::ok((stream, None)),
+        };
+        let body_bytes = segments.owned_space.len() as u64 * 8;
+        let actual = crc.borrow().finish();
+        stream.try_read(vec![0u8; 4], 4).map_else(move |r| match r {
+            Err(e) => Err(Error::Io(e)),
+            Ok((_, n)) if n < 4 =>
+                Err(Error::TruncatedBody { received: body_bytes + n as u64, expected: body_bytes + 4 }),
+            Ok((trailer, _)) => {
+                let expected = LittleEndian::read_u32(&trailer);
+                if expected != actual {
+                    return Err(Error::ChecksumMismatch { expected: expected, actual: actual })
+                }
+                Ok((stream, Some(message::Reader::new(segments, reader_options))))
+            }
+        })
+    })
+}
+
+/// Feeds a CRC-32C every byte read from the stream.
+struct ChecksummingStream<S> {
+    inner: S,
+    crc: Rc<RefCell<crc32c::Crc32c>>,
+}
+
+impl <S> AsyncRead for ChecksummingStream<S> where S: AsyncRead {
+    fn try_read<T>(&mut self, buf: T, min_bytes: usize) -> Promise<(T, usize), ::std::io::Error>
+        where T: AsMut<[u8]>
+    {
+        let crc = self.crc.clone();
+        self.inner.try_read(buf, min_bytes).map(move |(mut buf, n)| {
+            crc.borrow_mut().update(&buf.as_mut()[..n]);
+            Ok((buf, n))
+        })
+    }
+}
+
+/// Like `try_read_checksummed_message()`, but treats EOF as an error.
+pub fn read_checksummed_message<S>(stream: S,
+                                   reader_options: message::ReaderOptions,
+                                   options: Options)
+                                   -> Promise<(S, message::Reader<OwnedSegments>), Error>
+    where S: AsyncRead + 'static
+{
+    try_read_checksummed_message(stream, reader_options, options).map(|(stream, message)| {
+        expect_message(message).map(|m| (stream, m))
+    })
+}
+
+/// Parses the segment table at the start of `words` and strips it from them. If `exact` is
+/// false, `words` may continue past the end of the message, as long as the excess is all zeros,
+/// and the excess is stripped as well.
+fn words_to_segments(mut words: Vec<Word>, options: &Options, exact: bool) -> ::capnp::Result<OwnedSegments> {
+    let (table_words, total_words, segment_slices) = {
+        let bytes = Word::words_to_bytes(&words[..]);
+        if bytes.len() < 8 {
+            return Err(::capnp::Error::failed(
+                format!("Message too short for a segment table: {} bytes", bytes.len())))
+        }
+        let segment_count = try!(parse_segment_count(&bytes[0..4], options));
+        let table_len = segment_table_len_in_bytes(segment_count);
+        if table_len > bytes.len() {
+            return Err(::capnp::Error::failed(
+                format!("Table of {} segments does not fit in {} bytes", segment_count, bytes.len())))
+        }
+        let (total_words, segment_slices) = parse_segment_lengths(&bytes[4..(4 + 4 * segment_count)]);
+        try!(options.check_segment_table(total_words, &segment_slices));
+        let message_len = table_len + total_words * 8;
+        if message_len > bytes.len() {
+            return Err(::capnp::Error::failed(
+                format!("Message of {} bytes does not fit in {} bytes", message_len, bytes.len())))
+        }
+        if exact && message_len < bytes.len() {
+            return Err(::capnp::Error::failed(
+                format!("{} bytes follow the end of the message", bytes.len() - message_len)))
+        }
+        if bytes[message_len..].iter().any(|&b| b != 0) {
+            return Err(::capnp::Error::failed("Slot padding is not zeroed".to_string()))
+        }
+        (table_len / 8, total_words, segment_slices)
+    };
+    words.truncate(table_words + total_words);
+    words.drain(0..table_words);
+    Ok(OwnedSegments { segment_slices: segment_slices, owned_space: words })
+}
+
+/// The bytes of one `Data` or `Text` field of a message, found by calling `get_field`.
+struct MessageField<F> {
+    message: message::Reader<OwnedSegments>,
+    get_field: F,
+}
+
+impl <F> AsRef<[u8]> for MessageField<F>
+    where F: Fn(&message::Reader<OwnedSegments>) -> ::capnp::Result<&[u8]>
+{
+    fn as_ref(&self) -> &[u8] {
+        // `write_field()` has already checked that this succeeds.
+        (self.get_field)(&self.message).unwrap_or(&[])
+    }
+}
+
+/// Writes the raw contents of a `Data` or `Text` field of `message` to `stream`, directly from
+/// the message's own buffer. `get_field` locates the field; it is called again for each chunk,
+/// so it should be a plain traversal such as
+/// `|m| m.get_root::<foo::Reader>().and_then(|r| r.get_blob())`. For a `Text` field, return
+/// `as_bytes()` of the text.
+pub fn write_field<S, F>(stream: S,
+                         message: message::Reader<OwnedSegments>,
+                         get_field: F,
+                         options: Options)
+                         -> Promise<(S, message::Reader<OwnedSegments>), ::capnp::Error>
+    where S: AsyncWrite + 'static,
+          F: Fn(&message::Reader<OwnedSegments>) -> ::capnp::Result<&[u8]> + 'static
+{
+    pry!(get_field(&message));
+    let field = MessageField { message: message, get_field: get_field };
+    write_buffer(stream, field, options, |stream, field| (stream, field.message))
+}
+
+/// Lets a vector of words be written as bytes.
+struct WordBuffer(Vec<Word>);
+
+impl AsRef<[u8]> for WordBuffer {
+    fn as_ref(&self) -> &[u8] {
+        Word::words_to_bytes(&self.0[..])
+    }
+}
+
+/// Writes all of `buf` and then passes the stream and `buf` to `finish`.
+fn write_buffer<S, B, T, F>(stream: S,
+                            buf: B,
+                            options: Options,
+                            finish: F) -> Promise<T, ::capnp::Error>
+    where S: AsyncWrite + 'static, B: AsRef<[u8]> + 'static, F: FnOnce(S, B) -> T + 'static
+{
+    write_bytes_loop(stream, buf, 0, options, TurnBudget::new(&options), finish)
+}
+
+/// A byte range within some buffer. Used to transfer a buffer piece by piece.
+struct BufferRange<B> {
+    buf: B,
+    start: usize,
+    end: usize,
+}
+
+impl <B> AsRef<[u8]> for BufferRange<B> where B: AsRef<[u8]> {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf.as_ref()[self.start..self.end]
+    }
+}
+
+impl <B> AsMut<[u8]> for BufferRange<B> where B: AsMut<[u8]> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.buf.as_mut()[self.start..self.end]
+    }
+}
+
+fn write_bytes_loop<S, B, T, F>(mut stream: S,
+                                buf: B,
+                                already_written: usize,
+                                options: Options,
+                                mut budget: TurnBudget,
+                                finish: F) -> Promise<T, ::capnp::Error>
+    where S: AsyncWrite + 'static, B: AsRef<[u8]> + 'static, F: FnOnce(S, B) -> T + 'static
+{
+    let total_bytes = buf.as_ref().len();
+    let end = already_written + options.chunk_len(total_bytes - already_written);
+    let range = BufferRange { buf: buf, start: already_written, end: end };
+    if end < total_bytes {
+        stream.write(range).then_else(move |r| match r {
+            Err(e) => Promise::err(e.into()),
+            Ok(range) => {
+                let yield_first = budget.spend(end - already_written);
+                continue_after(yield_first, move || {
+                    write_bytes_loop(stream, range.buf, end, options, budget, finish)
+                })
+            }
+        })
+    } else {
+        stream.write(range).map_else(move |r| match r {
+            Err(e) => Err(e.into()),
+            Ok(range) => Ok(finish(stream, range.buf)),
         })
     }
 }
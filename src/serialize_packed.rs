@@ -0,0 +1,272 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Reading and writing messages in the packed encoding.
+//!
+//! Packing replaces each word with a tag byte, whose bits mark which of the word's bytes are
+//! nonzero, followed by just those bytes. A word of all zeros is followed by a count of further
+//! zero words, and a word with no zero bytes by a count of further words that are copied
+//! verbatim. See [the encoding spec](https://capnproto.org/encoding.html#packing). The output is
+//! the same as that of `capnp::writePackedMessage()` in C++.
+//!
+//! Packing gives no way to tell where a message ends without unpacking it. So that the stream is
+//! left at the start of the next message, reading takes no more from the stream than the message
+//! occupies, which costs a read per word or run of words.
+
+use byteorder::{ByteOrder, LittleEndian};
+use capnp::{message, Word};
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite};
+
+use serialize::{self, OwnedSegments};
+
+/// Reads a packed message from `stream`.
+pub fn read_packed_message<S>(stream: S, options: message::ReaderOptions)
+                              -> Promise<(S, message::Reader<OwnedSegments>), ::capnp::Error>
+    where S: AsyncRead
+{
+    read_packed_message_with_options(stream, options, serialize::Options::new())
+}
+
+/// Like `read_packed_message()`, but with the limits on segments in `options`. The options
+/// that control how bytes are moved do not apply to packed messages.
+pub fn read_packed_message_with_options<S>(stream: S,
+                                           reader_options: message::ReaderOptions,
+                                           options: serialize::Options)
+                                           -> Promise<(S, message::Reader<OwnedSegments>), ::capnp::Error>
+    where S: AsyncRead
+{
+    try_read_packed_message_with_options(stream, reader_options, options).map(|(stream, message)| match message {
+        Some(m) => Ok((stream, m)),
+        None => Err(::Error::CleanEof.into()),
+    })
+}
+
+/// Like `read_packed_message()`, but returns None on EOF.
+pub fn try_read_packed_message<S>(stream: S, options: message::ReaderOptions)
+                                  -> Promise<(S, Option<message::Reader<OwnedSegments>>), ::capnp::Error>
+    where S: AsyncRead
+{
+    try_read_packed_message_with_options(stream, options, serialize::Options::new())
+}
+
+/// Like `try_read_packed_message()`, but with the limits on segments in `options`.
+pub fn try_read_packed_message_with_options<S>(stream: S,
+                                               reader_options: message::ReaderOptions,
+                                               options: serialize::Options)
+                                               -> Promise<(S, Option<message::Reader<OwnedSegments>>),
+                                                          ::capnp::Error>
+    where S: AsyncRead
+{
+    unpack(stream, Vec::new(), 8, Run::default()).then(move |(stream, bytes, run)| {
+        if bytes.is_empty() {
+            return Promise::ok((stream, None))
+        }
+        let segment_count = u64::from(LittleEndian::read_u32(&bytes[0..4])) + 1;
+        pry!(options.check_segment_count(segment_count));
+        let segment_count = segment_count as usize;
+        let table_len = ((segment_count + 2) & !1) * 4;
+        unpack(stream, bytes, table_len, run).then(move |(stream, bytes, run)| {
+            let total_words = (0..segment_count)
+                .map(|idx| u64::from(LittleEndian::read_u32(&bytes[(4 + idx * 4)..(8 + idx * 4)])))
+                .sum::<u64>();
+            if total_words > reader_options.traversal_limit_in_words {
+                return Promise::err(::capnp::Error::failed(
+                    format!("Message has {} words, which is too large. To increase the limit on the \
+                             receiving end, see capnp::message::ReaderOptions.", total_words)))
+            }
+            let message_len = table_len + total_words as usize * 8;
+            unpack(stream, bytes, message_len, run).map(move |(stream, bytes, run)| {
+                if run.zero_words > 0 || run.raw_words > 0 {
+                    return Err(::capnp::Error::failed(
+                        "Packed run continues past the end of the message".to_string()))
+                }
+                let mut words = Word::allocate_zeroed_vec(bytes.len() / 8);
+                Word::words_to_bytes_mut(&mut words[..]).copy_from_slice(&bytes[..]);
+                let segments = try!(OwnedSegments::from_words_with_options(words, &options));
+                Ok((stream, Some(message::Reader::new(segments, reader_options))))
+            })
+        })
+    })
+}
+
+/// Writes `message` to `stream` in packed form.
+pub fn write_packed_message<S, A>(mut stream: S, message: &message::Builder<A>) -> Promise<S, ::capnp::Error>
+    where S: AsyncWrite, A: message::Allocator
+{
+    let words = ::capnp::serialize::write_message_to_words(message);
+    let packed = pack(Word::words_to_bytes(&words[..]));
+    stream.write(packed).map_else(move |r| match r {
+        Err(e) => Err(e.into()),
+        Ok(_) => Ok(stream),
+    })
+}
+
+/// Packs `bytes`, whose length must be a multiple of eight.
+pub(crate) fn pack(bytes: &[u8]) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(bytes.len() / 2);
+    let mut words = bytes.chunks(8).peekable();
+    while let Some(word) = words.next() {
+        let mut tag = 0u8;
+        let tag_pos = packed.len();
+        packed.push(0);
+        for (bit, &b) in word.iter().enumerate() {
+            if b != 0 {
+                tag |= 1 << bit;
+                packed.push(b);
+            }
+        }
+        packed[tag_pos] = tag;
+        if tag == 0 {
+            let mut count = 0u8;
+            while count < 255 && words.peek().map_or(false, |w| w.iter().all(|&b| b == 0)) {
+                words.next();
+                count += 1;
+            }
+            packed.push(count);
+        } else if tag == 0xff {
+            // Copying a word verbatim only loses once it has at least two zero bytes.
+            let count_pos = packed.len();
+            packed.push(0);
+            let mut count = 0u8;
+            while count < 255 && words.peek().map_or(false, |w| zero_bytes(w) < 2) {
+                packed.extend_from_slice(words.next().unwrap());
+                count += 1;
+            }
+            packed[count_pos] = count;
+        }
+    }
+    packed
+}
+
+fn zero_bytes(word: &[u8]) -> usize {
+    let mut count = 0;
+    for &b in word {
+        if b == 0 {
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Unpacks all of `packed`, failing rather than produce more than `max_bytes` bytes.
+pub(crate) fn unpack_bytes(packed: &[u8], max_bytes: usize) -> ::capnp::Result<Vec<u8>> {
+    fn truncated() -> ::capnp::Error {
+        ::capnp::Error::failed("Packed bytes end partway through a word".to_string())
+    }
+
+    let mut bytes = Vec::with_capacity(::std::cmp::min(packed.len() * 2, max_bytes));
+    let mut pos = 0;
+    while pos < packed.len() {
+        let tag = packed[pos];
+        pos += 1;
+        try!(::layer::check_decoded_len(bytes.len() + 8, max_bytes));
+        for bit in 0..8 {
+            if tag & (1 << bit) != 0 {
+                bytes.push(*try!(packed.get(pos).ok_or_else(truncated)));
+                pos += 1;
+            } else {
+                bytes.push(0);
+            }
+        }
+        if tag == 0 || tag == 0xff {
+            let count = *try!(packed.get(pos).ok_or_else(truncated)) as usize;
+            pos += 1;
+            try!(::layer::check_decoded_len(bytes.len() + count * 8, max_bytes));
+            if tag == 0 {
+                let len = bytes.len();
+                bytes.resize(len + count * 8, 0);
+            } else {
+                if packed.len() - pos < count * 8 {
+                    return Err(truncated())
+                }
+                bytes.extend_from_slice(&packed[pos..(pos + count * 8)]);
+                pos += count * 8;
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+/// Words that a tag has promised but that have not been unpacked yet.
+#[derive(Clone, Copy, Default)]
+struct Run {
+    zero_words: usize,
+    raw_words: usize,
+}
+
+/// Unpacks from `stream` onto the end of `bytes` until it holds `target` bytes. If the stream
+/// is at EOF before anything has been unpacked, returns `bytes` empty.
+fn unpack<S>(mut stream: S, mut bytes: Vec<u8>, target: usize, mut run: Run)
+             -> Promise<(S, Vec<u8>, Run), ::capnp::Error>
+    where S: AsyncRead + 'static
+{
+    if bytes.len() < target && run.zero_words > 0 {
+        let n = ::std::cmp::min(run.zero_words, (target - bytes.len()) / 8);
+        let len = bytes.len();
+        bytes.resize(len + n * 8, 0);
+        run.zero_words -= n;
+    }
+    if bytes.len() == target {
+        return Promise::ok((stream, bytes, run))
+    }
+    if run.raw_words > 0 {
+        let n = ::std::cmp::min(run.raw_words, (target - bytes.len()) / 8);
+        return stream.read(vec![0u8; n * 8], n * 8).then_else(move |r| match r {
+            Err(e) => Promise::err(e.into()),
+            Ok((buf, _)) => {
+                bytes.extend_from_slice(&buf[..]);
+                run.raw_words -= n;
+                unpack(stream, bytes, target, run)
+            }
+        })
+    }
+    let at_start = bytes.is_empty();
+    stream.try_read(vec![0u8; 1], 1).then_else(move |r| match r {
+        Err(e) => Promise::err(e.into()),
+        Ok((_, 0)) => if at_start {
+            Promise::ok((stream, bytes, run))
+        } else {
+            Promise::err(::capnp::Error::disconnected("premature EOF partway through a packed message".to_string()))
+        },
+        Ok((tag, _)) => {
+            let tag = tag[0];
+            let count_len = if tag == 0 || tag == 0xff { 1 } else { 0 };
+            let data_len = tag.count_ones() as usize + count_len;
+            stream.read(vec![0u8; data_len], data_len).then_else(move |r| match r {
+                Err(e) => Promise::err(e.into()),
+                Ok((data, _)) => {
+                    let mut nonzero = data.iter();
+                    for bit in 0..8 {
+                        bytes.push(if tag & (1 << bit) != 0 { *nonzero.next().unwrap() } else { 0 });
+                    }
+                    if tag == 0 {
+                        run.zero_words = data[0] as usize;
+                    } else if tag == 0xff {
+                        run.raw_words = data[8] as usize;
+                    }
+                    unpack(stream, bytes, target, run)
+                }
+            })
+        }
+    })
+}
@@ -0,0 +1,660 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! An accept loop that hands each incoming connection to a handler.
+//!
+//! ```text
+//! let server = server::Server::new(listener, ServerOptions::new().max_connections(1000));
+//! server.serve(|connection| {
+//!     connection.read_message().then(|(connection, request)| { ... })
+//! })
+//! ```
+//!
+//! A handler that would rather not pass the stream from promise to promise can be given a
+//! `connection::Connection` instead, set up with the server's reader and serialize options:
+//!
+//! ```text
+//! server.serve_connections(|mut connection| {
+//!     connection.recv().then(move |request| { ... connection.send(&reply) })
+//! })
+//! ```
+
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+use capnp::message::{self, HeapAllocator, SegmentArray};
+use gj::{Promise, TaskReaper, TaskSet};
+use gjio::{SocketListener, SocketStream, Timer};
+use fulfiller::{self, Fulfiller};
+use peer::Identity;
+use serialize::{self, OwnedSegments};
+
+/// A source of incoming connections.
+pub trait Acceptor: Sized + 'static {
+    /// Waits for the next connection.
+    fn accept_connection(self) -> Promise<(Self, SocketStream), io::Error>;
+
+    /// Waits for the next connection, and returns what the transport says about who is on the
+    /// other end of it. By default, that is nothing.
+    fn accept_identified(self) -> Promise<(Self, SocketStream, Identity), io::Error> {
+        self.accept_connection().map(|(acceptor, stream)| Ok((acceptor, stream, Identity::Unknown)))
+    }
+}
+
+impl Acceptor for SocketListener {
+    fn accept_connection(self) -> Promise<(SocketListener, SocketStream), io::Error> {
+        self.accept().map(move |stream| Ok((self, stream)))
+    }
+}
+
+#[cfg(unix)]
+impl Acceptor for ::activation::Listener {
+    fn accept_connection(self) -> Promise<(::activation::Listener, SocketStream), io::Error> {
+        self.accept()
+    }
+
+    fn accept_identified(self) -> Promise<(::activation::Listener, SocketStream, Identity), io::Error> {
+        self.accept_with_credentials().map(|(listener, stream, credentials)| {
+            Ok((listener, stream, credentials.map(Identity::Unix).unwrap_or(Identity::Unknown)))
+        })
+    }
+}
+
+/// What the server does with a connection while it is at its connection limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WhenFull {
+    /// Stops accepting until a connection closes. New connections wait in the kernel's backlog,
+    /// and once that fills up, further attempts to connect are refused or time out.
+    Wait,
+
+    /// Keeps accepting, and passes each connection over the limit to the `on_reject()` hook, or
+    /// closes it right away if there is none.
+    Reject,
+}
+
+/// Options controlling a `Server`.
+#[derive(Clone, Copy, Debug)]
+pub struct ServerOptions {
+    max_connections: Option<usize>,
+    when_full: WhenFull,
+    reader_options: message::ReaderOptions,
+    serialize_options: serialize::Options,
+}
+
+impl ServerOptions {
+    pub fn new() -> ServerOptions {
+        ServerOptions {
+            max_connections: None,
+            when_full: WhenFull::Wait,
+            reader_options: message::ReaderOptions::new(),
+            serialize_options: serialize::Options::new(),
+        }
+    }
+
+    /// Handles at most this many connections at once. By default, there is no limit.
+    pub fn max_connections(mut self, value: usize) -> ServerOptions {
+        self.max_connections = Some(value);
+        self
+    }
+
+    /// What to do with connections beyond `max_connections()`. Defaults to `WhenFull::Wait`.
+    pub fn when_full(mut self, value: WhenFull) -> ServerOptions {
+        self.when_full = value;
+        self
+    }
+
+    /// Options for messages read from connections.
+    pub fn reader_options(mut self, value: message::ReaderOptions) -> ServerOptions {
+        self.reader_options = value;
+        self
+    }
+
+    /// Options used when reading from and writing to connections.
+    pub fn serialize_options(mut self, value: serialize::Options) -> ServerOptions {
+        self.serialize_options = value;
+        self
+    }
+}
+
+impl Default for ServerOptions {
+    fn default() -> ServerOptions { ServerOptions::new() }
+}
+
+/// Values attached to a connection, at most one of each type. Wrapping a value in a type of its
+/// own, such as `struct TenantId(String)`, keeps it from colliding with others of the same type.
+pub struct Extensions {
+    map: HashMap<TypeId, Box<Any>>,
+}
+
+impl Extensions {
+    pub fn new() -> Extensions {
+        Extensions { map: HashMap::new() }
+    }
+
+    /// Attaches `value`, returning the value of the same type that it replaces, if any.
+    pub fn insert<T>(&mut self, value: T) -> Option<T> where T: Any {
+        self.map.insert(TypeId::of::<T>(), Box::new(value)).map(unbox)
+    }
+
+    pub fn get<T>(&self) -> Option<&T> where T: Any {
+        self.map.get(&TypeId::of::<T>()).and_then(|v| v.downcast_ref())
+    }
+
+    pub fn get_mut<T>(&mut self) -> Option<&mut T> where T: Any {
+        self.map.get_mut(&TypeId::of::<T>()).and_then(|v| v.downcast_mut())
+    }
+
+    pub fn remove<T>(&mut self) -> Option<T> where T: Any {
+        self.map.remove(&TypeId::of::<T>()).map(unbox)
+    }
+
+    pub fn contains<T>(&self) -> bool where T: Any {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+}
+
+impl Default for Extensions {
+    fn default() -> Extensions { Extensions::new() }
+}
+
+// Only called on boxes found under `TypeId::of::<T>()`, which always hold a `T`.
+fn unbox<T>(value: Box<Any>) -> T where T: Any {
+    match value.downcast() {
+        Ok(v) => *v,
+        Err(_) => unreachable!(),
+    }
+}
+
+/// Sees each message that a server's connections read or write, to layer concerns such as
+/// authorization, quotas and metrics over every handler. Errors short-circuit: an error from
+/// `received()` fails the read before the handler sees the message, and an error from
+/// `sending()` fails the write before anything is written.
+///
+/// Interceptors see received messages in the order in which they were added with
+/// `Server::interceptor()`, and messages being sent in the reverse order.
+pub trait Interceptor {
+    /// Called with each message read, before the handler sees it. Returns the message to pass
+    /// on, which may be a different one.
+    fn received(&mut self, _extensions: &mut Extensions, message: message::Reader<OwnedSegments>)
+                -> ::capnp::Result<message::Reader<OwnedSegments>>
+    {
+        Ok(message)
+    }
+
+    /// Called with each message about to be written. Returns a message to write in its place,
+    /// or None to write it as it is.
+    fn sending(&mut self, _extensions: &mut Extensions, _message: message::Reader<SegmentArray>)
+               -> ::capnp::Result<Option<message::Builder<HeapAllocator>>>
+    {
+        Ok(None)
+    }
+
+    /// Called once a message has been written.
+    fn sent(&mut self, _extensions: &mut Extensions) {}
+}
+
+type Interceptors = Rc<RefCell<Vec<Box<Interceptor>>>>;
+
+/// What `Server::serve_connections()` hands to its handler.
+pub type MessageConnection = ::connection::Connection<SocketStream, SocketStream>;
+
+/// A connection accepted by a `Server`.
+pub struct Connection {
+    id: u64,
+    stream: SocketStream,
+    peer_identity: Identity,
+    extensions: Extensions,
+    server: Rc<RefCell<ServerState>>,
+}
+
+impl Connection {
+    /// Identifies this connection among those accepted by the same server.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Who is on the other end, as far as is known. Starts out as whatever the listener could
+    /// tell, such as the credentials of a process connecting over a Unix domain socket.
+    pub fn peer_identity(&self) -> &Identity {
+        &self.peer_identity
+    }
+
+    /// Records who is on the other end, once an authentication step has established it.
+    pub fn set_peer_identity(&mut self, identity: Identity) {
+        self.peer_identity = identity;
+    }
+
+    /// State attached to this connection, such as the peer's identity once it has authenticated.
+    /// It travels with the connection through reads and writes.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// The server's options as of now. Each read and write picks up the options current when it
+    /// starts, so a change made through `Server::options_handle()` applies to the next message.
+    pub fn options(&self) -> ServerOptions {
+        self.server.borrow().options
+    }
+
+    /// Returns None on EOF.
+    pub fn try_read_message(self) -> Promise<(Connection, Option<message::Reader<OwnedSegments>>), ::capnp::Error> {
+        let options = self.options();
+        let interceptors = self.server.borrow().interceptors.clone();
+        let Connection { id, stream, peer_identity, mut extensions, server } = self;
+        serialize::try_read_message_with_options(stream, options.reader_options, options.serialize_options)
+            .map(move |(stream, message)| {
+                let message = match message {
+                    Some(mut m) => {
+                        for interceptor in interceptors.borrow_mut().iter_mut() {
+                            m = try!(interceptor.received(&mut extensions, m));
+                        }
+                        Some(m)
+                    }
+                    None => None,
+                };
+                Ok((Connection {
+                    id: id,
+                    stream: stream,
+                    peer_identity: peer_identity,
+                    extensions: extensions,
+                    server: server,
+                }, message))
+            })
+    }
+
+    pub fn read_message(self) -> Promise<(Connection, message::Reader<OwnedSegments>), ::capnp::Error> {
+        self.try_read_message().map(|(connection, message)| match message {
+            Some(m) => Ok((connection, m)),
+            None => Err(::Error::CleanEof.into()),
+        })
+    }
+
+    pub fn write_message<A>(self, message: &message::Builder<A>) -> Promise<Connection, ::capnp::Error>
+        where A: message::Allocator
+    {
+        let options = self.options();
+        let interceptors = self.server.borrow().interceptors.clone();
+        let Connection { id, stream, peer_identity, mut extensions, server } = self;
+        let mut replacement: Option<message::Builder<HeapAllocator>> = None;
+        for interceptor in interceptors.borrow_mut().iter_mut().rev() {
+            let result = {
+                let segments = match replacement {
+                    Some(ref m) => m.get_segments_for_output(),
+                    None => message.get_segments_for_output(),
+                };
+                let reader = message::Reader::new(SegmentArray::new(&segments), options.reader_options);
+                interceptor.sending(&mut extensions, reader)
+            };
+            match result {
+                Ok(Some(m)) => replacement = Some(m),
+                Ok(None) => (),
+                Err(e) => return Promise::err(e),
+            }
+        }
+        let written = match replacement {
+            Some(ref m) => serialize::write_message_ref_with_options(stream, m, options.serialize_options),
+            None => serialize::write_message_ref_with_options(stream, message, options.serialize_options),
+        };
+        written.map(move |stream| {
+            for interceptor in interceptors.borrow_mut().iter_mut().rev() {
+                interceptor.sent(&mut extensions);
+            }
+            Ok(Connection {
+                id: id,
+                stream: stream,
+                peer_identity: peer_identity,
+                extensions: extensions,
+                server: server,
+            })
+        })
+    }
+
+    /// Returns true once the server has started to drain.
+    pub fn is_draining(&self) -> bool {
+        self.server.borrow().draining
+    }
+
+    /// Resolves once the server starts to drain. A handler can race this against its next read
+    /// to learn that it should wrap up, for example by sending the peer a message saying that the
+    /// server is going away and then returning.
+    pub fn drain_requested(&self) -> Promise<(), ::capnp::Error> {
+        let mut s = self.server.borrow_mut();
+        if s.draining {
+            Promise::ok(())
+        } else {
+            let (requested, fulfiller) = fulfiller::and_fulfiller();
+            s.drain_notices.push(fulfiller);
+            requested
+        }
+    }
+
+    pub fn into_stream(self) -> SocketStream {
+        self.stream
+    }
+
+    /// Turns this into a `connection::Connection` over the same stream, using the server's
+    /// current reader and serialize options. Interceptors do not see the messages sent and
+    /// received on it.
+    pub fn into_message_connection(self) -> MessageConnection {
+        let options = self.options();
+        ::connection::Connection::with_options(self.stream.clone(), self.stream,
+                                               options.reader_options, options.serialize_options)
+    }
+}
+
+type RejectHook = Box<FnMut(SocketStream) -> Promise<(), ::capnp::Error>>;
+
+/// Accepts connections from a listener and runs a handler for each one.
+pub struct Server<L> where L: Acceptor {
+    listener: L,
+    state: Rc<RefCell<ServerState>>,
+    on_reject: Option<RejectHook>,
+}
+
+struct ServerState {
+    options: ServerOptions,
+    interceptors: Interceptors,
+    active: usize,
+    next_id: u64,
+    capacity_available: Option<Fulfiller<()>>,
+
+    draining: bool,
+    accepting: Option<Promise<(), ::capnp::Error>>,
+    drain_notices: Vec<Fulfiller<()>>,
+    all_closed: Option<Fulfiller<()>>,
+    drained: Option<Fulfiller<()>>,
+}
+
+impl ServerState {
+    fn full(&self) -> bool {
+        match self.options.max_connections {
+            Some(max) => self.active >= max,
+            None => false,
+        }
+    }
+
+    fn connection_closed(&mut self) {
+        self.active -= 1;
+        if let Some(f) = self.capacity_available.take() {
+            f.fulfill(());
+        }
+        if self.active == 0 {
+            if let Some(f) = self.all_closed.take() {
+                f.fulfill(());
+            }
+        }
+    }
+}
+
+/// Drops the fulfillers held by a server's state once its `serve()` promise is gone. The promises
+/// waiting on them lead back to the state, so they would otherwise keep each other alive.
+struct ServeGuard(Rc<RefCell<ServerState>>);
+
+impl Drop for ServeGuard {
+    fn drop(&mut self) {
+        let mut s = self.0.borrow_mut();
+        s.capacity_available = None;
+        s.accepting = None;
+        s.drain_notices.clear();
+        s.all_closed = None;
+        s.drained = None;
+    }
+}
+
+/// Handlers report their own errors; a failed connection does not affect the others.
+struct DiscardErrors;
+
+impl TaskReaper<(), ::capnp::Error> for DiscardErrors {
+    fn task_failed(&mut self, _error: ::capnp::Error) {}
+}
+
+impl <L> Server<L> where L: Acceptor {
+    pub fn new(listener: L, options: ServerOptions) -> Server<L> {
+        let state = ServerState {
+            options: options,
+            interceptors: Rc::new(RefCell::new(Vec::new())),
+            active: 0,
+            next_id: 0,
+            capacity_available: None,
+            draining: false,
+            accepting: None,
+            drain_notices: Vec::new(),
+            all_closed: None,
+            drained: None,
+        };
+        Server { listener: listener, state: Rc::new(RefCell::new(state)), on_reject: None }
+    }
+
+    /// Calls `hook` with each connection that is turned away under `WhenFull::Reject`, for
+    /// example to count it or to write a final message explaining why. The connection is closed
+    /// once the returned promise resolves.
+    pub fn on_reject<F>(mut self, hook: F) -> Server<L>
+        where F: FnMut(SocketStream) -> Promise<(), ::capnp::Error> + 'static
+    {
+        self.on_reject = Some(Box::new(hook));
+        self
+    }
+
+    /// Adds `interceptor` to the end of the chain that sees every message read or written on the
+    /// server's connections.
+    pub fn interceptor<I>(self, interceptor: I) -> Server<L> where I: Interceptor + 'static {
+        {
+            let s = self.state.borrow();
+            s.interceptors.borrow_mut().push(Box::new(interceptor));
+        }
+        self
+    }
+
+    /// Returns a handle with which to change the server's options while it is serving.
+    pub fn options_handle(&self) -> OptionsHandle {
+        OptionsHandle { state: self.state.clone() }
+    }
+
+    /// Returns a handle with which to shut the server down once it is serving.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        Shutdown { state: self.state.clone() }
+    }
+
+    /// Accepts connections and calls `handler` with each one. The connection is closed once the
+    /// returned promise resolves, and an error from it is dropped.
+    ///
+    /// The returned promise resolves when a drain started through `shutdown_handle()` finishes,
+    /// at which point any connections still open are closed. It fails if accepting fails.
+    /// Dropping it closes every connection.
+    pub fn serve<F>(self, handler: F) -> Promise<(), ::capnp::Error>
+        where F: FnMut(Connection) -> Promise<(), ::capnp::Error> + 'static
+    {
+        let Server { listener, state, on_reject } = self;
+        let (drained, fulfiller) = fulfiller::and_fulfiller();
+        state.borrow_mut().drained = Some(fulfiller);
+        let tasks = Rc::new(RefCell::new(TaskSet::new(Box::new(DiscardErrors))));
+        let tasks1 = tasks.clone();
+        let guard = ServeGuard(state.clone());
+        accept_loop(state, tasks, listener, handler, on_reject).then(move |()| {
+            // Holding on to the tasks until the drain is over keeps their connections open.
+            drained.map(move |()| {
+                drop(tasks1);
+                Ok(())
+            })
+        }).attach(guard)
+    }
+
+    /// Like `serve()`, but calls `handler` with each connection already turned into a
+    /// `MessageConnection`, on which messages can be sent and received in any order. For a
+    /// connection that needs more than that, such as layers or a look at its peer's identity,
+    /// use `serve()` and set it up in the handler.
+    pub fn serve_connections<F>(self, mut handler: F) -> Promise<(), ::capnp::Error>
+        where F: FnMut(MessageConnection) -> Promise<(), ::capnp::Error> + 'static
+    {
+        self.serve(move |connection| handler(connection.into_message_connection()))
+    }
+}
+
+/// Changes the options of a running `Server`.
+///
+/// A new connection limit applies to the next connection accepted; raising it lets a server that
+/// is waiting under `WhenFull::Wait` resume accepting right away, and lowering it below the number
+/// of open connections closes none of them. New reader and serialize options apply to the next
+/// message read or written on every connection, including those already open.
+#[derive(Clone)]
+pub struct OptionsHandle {
+    state: Rc<RefCell<ServerState>>,
+}
+
+impl OptionsHandle {
+    pub fn get(&self) -> ServerOptions {
+        self.state.borrow().options
+    }
+
+    pub fn set(&self, options: ServerOptions) {
+        let mut s = self.state.borrow_mut();
+        s.options = options;
+        if !s.full() || s.options.when_full == WhenFull::Reject {
+            if let Some(f) = s.capacity_available.take() {
+                f.fulfill(());
+            }
+        }
+    }
+
+    /// Sets the options to `f` applied to the current ones.
+    pub fn update<F>(&self, f: F) where F: FnOnce(ServerOptions) -> ServerOptions {
+        let options = f(self.get());
+        self.set(options)
+    }
+}
+
+/// Shuts down a `Server`.
+pub struct Shutdown {
+    state: Rc<RefCell<ServerState>>,
+}
+
+impl Shutdown {
+    /// Stops accepting connections, closing the listener, and tells each open connection through
+    /// `Connection::drain_requested()`. Then waits for those connections to finish, for at most
+    /// `deadline`, and closes any that are left. Resolves with the number of connections that
+    /// were cut off at the deadline.
+    pub fn drain(&self, timer: &Timer, deadline: Duration) -> Promise<usize, ::capnp::Error> {
+        let all_closed = {
+            let mut s = self.state.borrow_mut();
+            if s.draining {
+                return Promise::err(::capnp::Error::failed("server is already draining".to_string()))
+            }
+            s.draining = true;
+            s.accepting = None;
+            for f in s.drain_notices.drain(..) {
+                f.fulfill(());
+            }
+            if s.active == 0 {
+                Promise::ok(())
+            } else {
+                let (all_closed, fulfiller) = fulfiller::and_fulfiller();
+                s.all_closed = Some(fulfiller);
+                all_closed
+            }
+        };
+        let state = self.state.clone();
+        all_closed.exclusive_join(timer.after_delay(deadline).lift()).map(move |()| {
+            let mut s = state.borrow_mut();
+            if let Some(f) = s.drained.take() {
+                f.fulfill(());
+            }
+            Ok(s.active)
+        })
+    }
+}
+
+fn accept_loop<L, F>(state: Rc<RefCell<ServerState>>,
+                     tasks: Rc<RefCell<TaskSet<(), ::capnp::Error>>>,
+                     listener: L,
+                     mut handler: F,
+                     mut on_reject: Option<RejectHook>) -> Promise<(), ::capnp::Error>
+    where L: Acceptor, F: FnMut(Connection) -> Promise<(), ::capnp::Error> + 'static
+{
+    let next = {
+        let mut s = state.borrow_mut();
+        if s.draining {
+            return Promise::ok(())
+        }
+        let ready = if s.full() && s.options.when_full == WhenFull::Wait {
+            let (ready, fulfiller) = fulfiller::and_fulfiller();
+            s.capacity_available = Some(fulfiller);
+            ready
+        } else {
+            Promise::ok(())
+        };
+        // The accept runs on its own, so that draining can cancel it by dropping it, which
+        // closes the listener.
+        let (next, fulfiller) = fulfiller::and_fulfiller();
+        s.accepting = Some(ready.then(move |()| listener.accept_identified().lift()).map_else(move |r| {
+            fulfiller.resolve(r);
+            Ok(())
+        }).eagerly_evaluate());
+        next
+    };
+    let state1 = state.clone();
+    next.then_else(move |r| {
+        let (listener, stream, peer_identity) = match r {
+            Ok(accepted) => accepted,
+            Err(_) if state1.borrow().draining => return Promise::ok(()),
+            Err(e) => return Promise::err(e),
+        };
+        let state = state1;
+        let admitted = {
+            let mut s = state.borrow_mut();
+            if s.full() {
+                None
+            } else {
+                s.active += 1;
+                s.next_id += 1;
+                Some(s.next_id)
+            }
+        };
+        let task = match admitted {
+            Some(id) => {
+                let connection = Connection {
+                    id: id,
+                    stream: stream,
+                    peer_identity: peer_identity,
+                    extensions: Extensions::new(),
+                    server: state.clone(),
+                };
+                let state = state.clone();
+                Some(handler(connection).map_else(move |r| {
+                    state.borrow_mut().connection_closed();
+                    r
+                }))
+            }
+            None => on_reject.as_mut().map(|hook| hook(stream)),
+        };
+        if let Some(task) = task {
+            tasks.borrow_mut().add(task);
+        }
+        accept_loop(state, tasks, listener, handler, on_reject)
+    })
+}
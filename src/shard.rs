@@ -0,0 +1,248 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Spreading connections over several threads, each with its own event loop.
+//!
+//! One thread accepts connections and deals them out in turn to a pool of workers. Each worker
+//! runs a `server::Server` of its own, so that everything tied to an event loop, from the
+//! `SocketStream` up to the `Connection` and the handler, is created on the thread that uses it.
+//! Only the plain `std::net::TcpStream` crosses threads.
+//!
+//! Alternatively, `serve_reuse_port()` has every worker listen on the same address with
+//! `SO_REUSEPORT`, and leaves it to the kernel to spread connections over them. That avoids
+//! funnelling every accept through one thread, for servers with a high rate of new connections.
+//!
+//! ```text
+//! let pool = try!(shard::serve(listener, 4, ServerOptions::new(), SocketOptions::new(), |worker| {
+//!     move |connection| handle(connection)
+//! }));
+//! try!(pool.join());
+//! ```
+
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+
+use gj::{EventLoop, Promise};
+use gjio::{AsyncRead, EventPort, Network, SocketStream};
+
+use net2::TcpBuilder;
+use net2::unix::UnixTcpBuilderExt;
+
+use activation;
+use connect::{self, SocketOptions};
+use server::{Acceptor, Connection, Server, ServerOptions};
+
+/// Threads serving connections from a shared listener.
+pub struct Pool {
+    acceptor: Option<thread::JoinHandle<io::Error>>,
+    local_addr: Option<SocketAddr>,
+    workers: Vec<thread::JoinHandle<Result<(), ::capnp::Error>>>,
+}
+
+impl Pool {
+    /// The number of worker threads.
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// The address that the workers listen on, for a pool started with `serve_reuse_port()`.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Waits for the pool to stop, which happens when accepting a connection fails, and returns
+    /// that failure. Each worker then closes its connections and exits.
+    pub fn join(self) -> io::Result<()> {
+        let acceptor = match self.acceptor {
+            Some(acceptor) => acceptor,
+            None => {
+                // Under `serve_reuse_port()`, each worker accepts for itself and stops on its own.
+                for worker in self.workers {
+                    match worker.join() {
+                        Ok(Ok(())) => (),
+                        Ok(Err(e)) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+                        Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "worker thread panicked")),
+                    }
+                }
+                return Ok(())
+            }
+        };
+        let error = match acceptor.join() {
+            Ok(e) => e,
+            Err(_) => io::Error::new(io::ErrorKind::Other, "accept thread panicked"),
+        };
+        for worker in self.workers {
+            // Every worker fails once the accept thread is gone; only a panic is news.
+            if worker.join().is_err() {
+                return Err(io::Error::new(io::ErrorKind::Other, "worker thread panicked"))
+            }
+        }
+        Err(error)
+    }
+}
+
+/// Accepts connections on `listener` and serves them on `threads` worker threads, dealt out in
+/// turn. Each worker calls `make_handler` with its index, from 0, to get the handler for its
+/// connections, and applies `options` and `socket_options` to them.
+pub fn serve<F, H>(listener: TcpListener, threads: usize, options: ServerOptions, socket_options: SocketOptions,
+                   make_handler: F) -> io::Result<Pool>
+    where F: Fn(usize) -> H + Send + Sync + 'static,
+          H: FnMut(Connection) -> Promise<(), ::capnp::Error> + 'static
+{
+    if threads == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "a pool needs at least one worker"))
+    }
+    // The accept thread blocks in accept(), whatever the socket was set up with.
+    try!(listener.set_nonblocking(false));
+    let make_handler = Arc::new(make_handler);
+    let mut handoffs = Vec::new();
+    let mut workers = Vec::new();
+    for index in 0..threads {
+        let (sender, receiver) = mpsc::channel();
+        let (wakeup, worker_wakeup) = try!(UnixStream::pair());
+        workers.push(spawn_worker(index, make_handler.clone(), options, move |network| {
+            Ok(ShardListener {
+                network: network.clone(),
+                options: socket_options,
+                wakeup: try!(unsafe { network.wrap_raw_socket_descriptor(worker_wakeup.into_raw_fd()) }),
+                receiver: receiver,
+            })
+        }));
+        handoffs.push(Handoff { sender: sender, wakeup: wakeup });
+    }
+
+    let acceptor = thread::spawn(move || {
+        let mut next = 0;
+        loop {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted => continue,
+                Err(e) => return e,
+            };
+            if let Err(e) = handoffs[next].pass(stream) {
+                return e
+            }
+            next = (next + 1) % handoffs.len();
+        }
+    });
+    Ok(Pool { acceptor: Some(acceptor), local_addr: None, workers: workers })
+}
+
+/// Binds a listening socket to `addr` with `SO_REUSEPORT` set, so that other sockets set up the
+/// same way can listen on the same address.
+pub fn bind_reuse_port(addr: SocketAddr) -> io::Result<TcpListener> {
+    let builder = match addr {
+        SocketAddr::V4(_) => try!(TcpBuilder::new_v4()),
+        SocketAddr::V6(_) => try!(TcpBuilder::new_v6()),
+    };
+    try!(builder.reuse_port(true));
+    try!(builder.bind(addr));
+    builder.listen(128)
+}
+
+/// Serves connections to `addr` on `threads` worker threads, each with a listening socket of its
+/// own bound with `bind_reuse_port()`. If the port in `addr` is 0, they all share the port that
+/// the first is given. Otherwise like `serve()`.
+///
+/// The kernel picks the socket for each connection, by a hash of its addresses, so the split is
+/// even only over many connections.
+pub fn serve_reuse_port<F, H>(addr: SocketAddr, threads: usize, options: ServerOptions,
+                              socket_options: SocketOptions, make_handler: F) -> io::Result<Pool>
+    where F: Fn(usize) -> H + Send + Sync + 'static,
+          H: FnMut(Connection) -> Promise<(), ::capnp::Error> + 'static
+{
+    if threads == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "a pool needs at least one worker"))
+    }
+    // Bind them all up front, so that a failure shows up here.
+    let first = try!(bind_reuse_port(addr));
+    let addr = try!(first.local_addr());
+    let mut listeners = vec![first];
+    for _ in 1..threads {
+        listeners.push(try!(bind_reuse_port(addr)));
+    }
+
+    let make_handler = Arc::new(make_handler);
+    let mut workers = Vec::new();
+    for (index, listener) in listeners.into_iter().enumerate() {
+        workers.push(spawn_worker(index, make_handler.clone(), options, move |network| {
+            activation::Listener::from_tcp_listener(network, listener, socket_options)
+        }));
+    }
+    Ok(Pool { acceptor: None, local_addr: Some(addr), workers: workers })
+}
+
+/// Starts a worker thread, which sets up its event loop, calls `make_listener` to get its source
+/// of connections, and serves them.
+fn spawn_worker<F, H, L, M>(index: usize, make_handler: Arc<F>, options: ServerOptions, make_listener: M)
+                            -> thread::JoinHandle<Result<(), ::capnp::Error>>
+    where F: Fn(usize) -> H + Send + Sync + 'static,
+          H: FnMut(Connection) -> Promise<(), ::capnp::Error> + 'static,
+          L: Acceptor,
+          M: FnOnce(&Network) -> io::Result<L> + Send + 'static
+{
+    thread::spawn(move || EventLoop::top_level(move |wait_scope| -> Result<(), ::capnp::Error> {
+        let mut event_port = try!(EventPort::new());
+        let listener = try!(make_listener(&event_port.get_network()));
+        Server::new(listener, options).serve(make_handler(index)).wait(wait_scope, &mut event_port)
+    }))
+}
+
+/// The accept thread's end of the way to a worker.
+struct Handoff {
+    sender: mpsc::Sender<TcpStream>,
+    wakeup: UnixStream,
+}
+
+impl Handoff {
+    fn pass(&mut self, stream: TcpStream) -> io::Result<()> {
+        if self.sender.send(stream).is_err() {
+            return Err(io::Error::new(io::ErrorKind::Other, "worker thread has exited"))
+        }
+        self.wakeup.write_all(&[0])
+    }
+}
+
+/// A worker's source of connections.
+struct ShardListener {
+    network: Network,
+    options: SocketOptions,
+    wakeup: SocketStream,
+    receiver: mpsc::Receiver<TcpStream>,
+}
+
+impl Acceptor for ShardListener {
+    fn accept_connection(mut self) -> Promise<(ShardListener, SocketStream), io::Error> {
+        self.wakeup.try_read(vec![0u8], 1).map(move |(_, n)| {
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::Other, "accept thread has exited"))
+            }
+            let stream = self.receiver.recv().expect("wakeup without a connection");
+            let stream = try!(connect::wrap_tcp_stream(&self.network, stream, self.options));
+            Ok((self, stream))
+        })
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Helpers for testing code that reads messages, this crate's and layers built on it.
+//!
+//! Framing bugs tend to hide where a read comes back with only part of what was asked for. A
+//! `ChunkedStream` replays a fixed sequence of bytes in chunks of chosen lengths, and
+//! `check_every_split()` runs a check against the same bytes cut at every possible point, one
+//! byte at a time, and along random partitions:
+//!
+//! ```text
+//! testing::check_every_split(&bytes, 100, 1, |stream| {
+//!     let (_, message) = try!(serialize::read_message(stream, ReaderOptions::new())
+//!                                 .wait(wait_scope, &mut event_port));
+//!     check_contents(message)
+//! }).unwrap();
+//! ```
+
+use std::cmp;
+use std::fmt;
+use std::io;
+
+use gj::Promise;
+use gjio::AsyncRead;
+
+/// Replays bytes in chunks. A read returns once it has what it asked for, and stops early at the
+/// end of a chunk when it can; each read completes in a later turn of the event loop, as a read
+/// from a socket would.
+pub struct ChunkedStream {
+    bytes: Vec<u8>,
+    chunk_lens: Vec<usize>,
+    pos: usize,
+
+    /// Where the current chunk ends.
+    chunk_end: usize,
+    next_chunk: usize,
+}
+
+impl ChunkedStream {
+    /// Replays `bytes` in chunks of `chunk_lens`. Whatever the lengths do not cover comes as one
+    /// final chunk.
+    pub fn new(bytes: Vec<u8>, chunk_lens: Vec<usize>) -> ChunkedStream {
+        let mut stream = ChunkedStream { bytes: bytes, chunk_lens: chunk_lens, pos: 0, chunk_end: 0, next_chunk: 0 };
+        stream.advance_chunk();
+        stream
+    }
+
+    /// The chunk lengths this stream was made with.
+    pub fn chunk_lens(&self) -> &[usize] {
+        &self.chunk_lens
+    }
+
+    /// The number of bytes that have not yet been read.
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    fn advance_chunk(&mut self) {
+        let len = match self.chunk_lens.get(self.next_chunk) {
+            Some(&len) => len,
+            None => self.bytes.len(),
+        };
+        self.next_chunk += 1;
+        self.chunk_end = cmp::min(self.chunk_end + cmp::max(len, 1), self.bytes.len());
+    }
+}
+
+impl AsyncRead for ChunkedStream {
+    fn try_read<T>(&mut self, mut buf: T, min_bytes: usize) -> Promise<(T, usize), io::Error>
+        where T: AsMut<[u8]>
+    {
+        let start = self.pos;
+        let want = cmp::min(buf.as_mut().len(), self.bytes.len() - start);
+        let min_bytes = cmp::min(min_bytes, want);
+        while self.chunk_end - start < min_bytes {
+            self.advance_chunk();
+        }
+        let n = cmp::min(want, cmp::max(self.chunk_end - start, min_bytes));
+        buf.as_mut()[..n].copy_from_slice(&self.bytes[start..(start + n)]);
+        self.pos += n;
+        if self.pos == self.chunk_end {
+            self.advance_chunk();
+        }
+        Promise::ok(()).then(move |()| Promise::ok((buf, n)))
+    }
+}
+
+/// A check that failed, with the chunk lengths that made it fail.
+#[derive(Debug)]
+pub struct SplitFailure {
+    pub chunk_lens: Vec<usize>,
+    pub error: ::capnp::Error,
+}
+
+impl fmt::Display for SplitFailure {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "with chunks of {:?} bytes: {}", self.chunk_lens, self.error)
+    }
+}
+
+impl ::std::error::Error for SplitFailure {
+    fn description(&self) -> &str {
+        &self.error.description
+    }
+}
+
+/// Calls `check` with a `ChunkedStream` over `bytes` for each of these partitions: the whole
+/// of `bytes` in one chunk, two chunks split at each possible point, one byte per chunk, and
+/// `random_partitions` random partitions generated from `seed`. Stops at the first failure.
+pub fn check_every_split<F>(bytes: &[u8], random_partitions: usize, seed: u64, mut check: F)
+                            -> Result<(), SplitFailure>
+    where F: FnMut(ChunkedStream) -> ::capnp::Result<()>
+{
+    let mut partitions = vec![Vec::new()];
+    for split in 1..bytes.len() {
+        partitions.push(vec![split]);
+    }
+    partitions.push(vec![1; bytes.len()]);
+    let mut rng = XorShift(seed | 1);
+    for _ in 0..random_partitions {
+        let mut chunk_lens = Vec::new();
+        let mut covered = 0;
+        while covered < bytes.len() {
+            // Mostly short chunks, with the odd long one.
+            let len = if rng.next() % 4 == 0 { 1 + rng.next() % 64 } else { 1 + rng.next() % 8 } as usize;
+            chunk_lens.push(len);
+            covered += len;
+        }
+        partitions.push(chunk_lens);
+    }
+    for chunk_lens in partitions {
+        if let Err(e) = check(ChunkedStream::new(bytes.to_vec(), chunk_lens.clone())) {
+            return Err(SplitFailure { chunk_lens: chunk_lens, error: e })
+        }
+    }
+    Ok(())
+}
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
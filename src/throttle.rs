@@ -0,0 +1,182 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Capping the rate at which bytes are written, for connections that share a constrained link.
+//!
+//! A `TokenBucket` lets through bursts of up to a set size and, beyond that, a set number of
+//! bytes per second. Writes to a `Throttled` stream wait on its bucket before they reach the
+//! stream, so any message written to it, by `serialize::write_message()` or a
+//! `writer::StreamWriter`, completes no faster than the rate allows. Give each connection its
+//! own bucket for a per-connection cap, or share a clone of one bucket for a combined cap.
+//!
+//! ```text
+//! let bucket = TokenBucket::new(&timer, 128 * 1024, 16 * 1024);
+//! let writer = StreamWriter::new(Throttled::new(stream, bucket));
+//! ```
+
+use std::cell::RefCell;
+use std::cmp;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite, Timer};
+
+struct Bucket {
+    bytes_per_second: u64,
+    burst_bytes: u64,
+
+    /// Bytes that may be written now. Negative once writes have been promised bytes that have
+    /// yet to accrue.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Takes `n` bytes' worth of tokens and returns how long to wait before writing them.
+    fn reserve(&mut self, n: u64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now - self.updated;
+        let accrued = (elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9)
+            * self.bytes_per_second as f64;
+        self.tokens = (self.tokens + accrued).min(self.burst_bytes as f64);
+        self.updated = now;
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_nanos((-self.tokens / self.bytes_per_second as f64 * 1e9) as u64)
+        }
+    }
+}
+
+/// A budget of bytes that refills at a steady rate. Clones share the budget.
+#[derive(Clone)]
+pub struct TokenBucket {
+    bucket: Rc<RefCell<Bucket>>,
+    timer: Timer,
+}
+
+impl TokenBucket {
+    /// Allows `bytes_per_second` on average, in bursts of up to `burst_bytes`. The bucket starts
+    /// full.
+    pub fn new(timer: &Timer, bytes_per_second: u64, burst_bytes: u64) -> TokenBucket {
+        let burst_bytes = cmp::max(burst_bytes, 1);
+        TokenBucket {
+            bucket: Rc::new(RefCell::new(Bucket {
+                bytes_per_second: cmp::max(bytes_per_second, 1),
+                burst_bytes: burst_bytes,
+                tokens: burst_bytes as f64,
+                updated: Instant::now(),
+            })),
+            timer: timer.clone(),
+        }
+    }
+
+    /// Resolves once `n` more bytes may be written. Waits are served in the order they were
+    /// asked for.
+    pub fn acquire(&self, n: u64) -> Promise<(), io::Error> {
+        let delay = self.bucket.borrow_mut().reserve(n);
+        if delay == Duration::from_secs(0) {
+            Promise::ok(())
+        } else {
+            self.timer.after_delay(delay)
+        }
+    }
+
+    fn burst_bytes(&self) -> usize {
+        self.bucket.borrow().burst_bytes as usize
+    }
+}
+
+/// A stream whose writes are held to the rate of a `TokenBucket`. A write larger than the
+/// bucket's burst is passed on in pieces no larger than the burst. Writes are queued, so that
+/// the pieces of one never end up between those of another: each starts once the one before it
+/// has finished, and carries on even if its promise is dropped. Reads pass straight through.
+pub struct Throttled<S> {
+    stream: S,
+    bucket: TokenBucket,
+
+    /// Resolves once the last write queued has finished, whether or not it succeeded.
+    writes: Promise<(), io::Error>,
+}
+
+impl <S> Throttled<S> {
+    pub fn new(stream: S, bucket: TokenBucket) -> Throttled<S> {
+        Throttled { stream: stream, bucket: bucket, writes: Promise::ok(()) }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+/// A byte range of a buffer that is being written piece by piece.
+struct Window<T> {
+    buf: T,
+    start: usize,
+    end: usize,
+}
+
+impl <T> AsRef<[u8]> for Window<T> where T: AsRef<[u8]> {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf.as_ref()[self.start..self.end]
+    }
+}
+
+fn write_loop<S, T>(mut stream: S, bucket: TokenBucket, buf: T, done: usize) -> Promise<T, io::Error>
+    where S: AsyncWrite + 'static, T: AsRef<[u8]> + 'static
+{
+    let len = buf.as_ref().len();
+    if done >= len {
+        return Promise::ok(buf)
+    }
+    let end = cmp::min(len, done + bucket.burst_bytes());
+    bucket.acquire((end - done) as u64).then(move |()| {
+        stream.write(Window { buf: buf, start: done, end: end }).then(move |window| {
+            write_loop(stream, bucket, window.buf, end)
+        })
+    })
+}
+
+impl <S> AsyncWrite for Throttled<S> where S: AsyncWrite + Clone + 'static {
+    fn write<T>(&mut self, buf: T) -> Promise<T, io::Error> where T: AsRef<[u8]> {
+        let stream = self.stream.clone();
+        let bucket = self.bucket.clone();
+        let (written, fulfiller) = Promise::and_fulfiller();
+        let previous = ::std::mem::replace(&mut self.writes, Promise::ok(()));
+        self.writes = previous.then(move |()| write_loop(stream, bucket, buf, 0)).map_else(move |r| {
+            fulfiller.resolve(r);
+            Ok(())
+        }).eagerly_evaluate();
+        written
+    }
+}
+
+impl <S> AsyncRead for Throttled<S> where S: AsyncRead {
+    fn try_read<T>(&mut self, buf: T, min_bytes: usize) -> Promise<(T, usize), io::Error>
+        where T: AsMut<[u8]>
+    {
+        self.stream.try_read(buf, min_bytes)
+    }
+}
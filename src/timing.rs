@@ -0,0 +1,183 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Timing the steps of reading and writing messages.
+//!
+//! When messages are slow to arrive, it helps to know where the time goes: waiting for the peer
+//! to start sending, moving the bytes, copying the message into its wire form, or waiting
+//! behind other messages. The functions here do what their namesakes in `serialize` do, and
+//! also report how long each of those steps took to a `Recorder`. Time spent in the application
+//! between one read and the next is not counted anywhere, so it is what is left over.
+//!
+//! ```text
+//! let recorder = timing::Recorder::new(|t| histogram(t.span).record(t.elapsed));
+//! timing::read_message(stream, ReaderOptions::new(), serialize::Options::new(), &recorder)
+//! ```
+
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use capnp::{message, Word};
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite};
+use serialize::{self, OwnedSegments};
+
+/// A step in reading or writing a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Span {
+    /// From the start of a read until the first bytes of the message arrive. Mostly time spent
+    /// waiting for the peer.
+    HeaderWait,
+
+    /// Moving the bytes of a message: for a read, from the arrival of its first bytes until the
+    /// last, and for a write, from the first byte handed to the stream until the last.
+    BodyTransfer,
+
+    /// Copying a message into its wire form before it is written.
+    Serialization,
+
+    /// Waiting behind messages queued earlier on the same `writer::StreamWriter`.
+    Queueing,
+}
+
+/// How long one step took.
+#[derive(Clone, Copy, Debug)]
+pub struct Timing {
+    pub span: Span,
+    pub elapsed: Duration,
+
+    /// The size of the message on the wire, segment table included.
+    pub bytes: u64,
+}
+
+type Report = Box<FnMut(&Timing)>;
+
+/// Passes timings to a callback. Clones share the callback.
+#[derive(Clone)]
+pub struct Recorder {
+    report: Rc<RefCell<Report>>,
+}
+
+impl Recorder {
+    pub fn new<F>(report: F) -> Recorder where F: FnMut(&Timing) + 'static {
+        Recorder { report: Rc::new(RefCell::new(Box::new(report))) }
+    }
+
+    /// Reports that `span` ran from `start` to `end`.
+    pub fn record(&self, span: Span, start: Instant, end: Instant, bytes: u64) {
+        let timing = Timing { span: span, elapsed: end - start, bytes: bytes };
+        (&mut *self.report.borrow_mut())(&timing);
+    }
+}
+
+/// Passes reads through, noting when the first one completes and how many bytes they bring.
+struct Observed<S> {
+    stream: S,
+    first_read: Rc<Cell<Option<Instant>>>,
+    bytes: Rc<Cell<u64>>,
+}
+
+impl <S> AsyncRead for Observed<S> where S: AsyncRead {
+    fn try_read<T>(&mut self, buf: T, min_bytes: usize) -> Promise<(T, usize), io::Error>
+        where T: AsMut<[u8]>
+    {
+        let first_read = self.first_read.clone();
+        let bytes = self.bytes.clone();
+        self.stream.try_read(buf, min_bytes).map(move |(buf, n)| {
+            if first_read.get().is_none() {
+                first_read.set(Some(Instant::now()));
+            }
+            bytes.set(bytes.get() + n as u64);
+            Ok((buf, n))
+        })
+    }
+}
+
+/// Like `serialize::try_read_message_with_options()`, and records `HeaderWait` and
+/// `BodyTransfer`. Nothing is recorded on EOF.
+pub fn try_read_message<S>(stream: S,
+                           reader_options: message::ReaderOptions,
+                           options: serialize::Options,
+                           recorder: &Recorder)
+                           -> Promise<(S, Option<message::Reader<OwnedSegments>>), ::capnp::Error>
+    where S: AsyncRead + 'static
+{
+    let started = Instant::now();
+    let first_read = Rc::new(Cell::new(None));
+    let bytes = Rc::new(Cell::new(0));
+    let stream = Observed { stream: stream, first_read: first_read.clone(), bytes: bytes.clone() };
+    let recorder = recorder.clone();
+    serialize::try_read_message_with_options(stream, reader_options, options).map(move |(stream, message)| {
+        if message.is_some() {
+            let ended = Instant::now();
+            let first_read = first_read.get().unwrap_or(ended);
+            recorder.record(Span::HeaderWait, started, first_read, bytes.get());
+            recorder.record(Span::BodyTransfer, first_read, ended, bytes.get());
+        }
+        Ok((stream.stream, message))
+    })
+}
+
+/// Like `serialize::read_message_with_options()`, and records `HeaderWait` and `BodyTransfer`.
+pub fn read_message<S>(stream: S,
+                       reader_options: message::ReaderOptions,
+                       options: serialize::Options,
+                       recorder: &Recorder)
+                       -> Promise<(S, message::Reader<OwnedSegments>), ::capnp::Error>
+    where S: AsyncRead + 'static
+{
+    try_read_message(stream, reader_options, options, recorder).map(|(stream, message)| match message {
+        Some(m) => Ok((stream, m)),
+        None => Err(::Error::CleanEof.into()),
+    })
+}
+
+/// Lets a vector of words be written as bytes.
+struct WordBuffer(Vec<Word>);
+
+impl AsRef<[u8]> for WordBuffer {
+    fn as_ref(&self) -> &[u8] {
+        Word::words_to_bytes(&self.0[..])
+    }
+}
+
+/// Like `serialize::write_message_ref_with_options()`, and records `Serialization` and
+/// `BodyTransfer`.
+pub fn write_message_ref<S, A>(stream: S,
+                               message: &message::Builder<A>,
+                               options: serialize::Options,
+                               recorder: &Recorder)
+                               -> Promise<S, ::capnp::Error>
+    where S: AsyncWrite + 'static, A: message::Allocator
+{
+    let started = Instant::now();
+    let words = ::capnp::serialize::write_message_to_words(message);
+    let bytes = words.len() as u64 * 8;
+    let serialized = Instant::now();
+    recorder.record(Span::Serialization, started, serialized, bytes);
+    let recorder = recorder.clone();
+    serialize::write_raw_message_with_options(stream, WordBuffer(words), None, options).map(move |(stream, _)| {
+        recorder.record(Span::BodyTransfer, serialized, Instant::now(), bytes);
+        Ok(stream)
+    })
+}
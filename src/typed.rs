@@ -0,0 +1,92 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Reading messages whose root type is known in advance.
+//!
+//! ```text
+//! typed::read_typed_message::<_, address_book::Owned>(stream, ReaderOptions::new(), serialize::Options::new())
+//!     .map(|(stream, message)| {
+//!         let address_book = try!(message.get());
+//!         ...
+//!     })
+//! ```
+
+use std::marker::PhantomData;
+
+use capnp::message;
+use capnp::traits::Owned;
+use gj::Promise;
+use gjio::AsyncRead;
+
+use serialize::{self, OwnedSegments};
+
+/// A message whose root has been checked to be a `T`.
+pub struct TypedReader<S, T> where S: message::ReaderSegments {
+    message: message::Reader<S>,
+    marker: PhantomData<T>,
+}
+
+impl <S, T> TypedReader<S, T> where S: message::ReaderSegments, T: for<'a> Owned<'a> {
+    /// Checks that the root of `message` can be read as a `T`.
+    pub fn new(message: message::Reader<S>) -> ::capnp::Result<TypedReader<S, T>> {
+        try!(message.get_root::<<T as Owned>::Reader>());
+        Ok(TypedReader { message: message, marker: PhantomData })
+    }
+
+    /// Returns the root. Since `new()` has already read it once, this fails only if the message
+    /// is corrupt in a way that the traversal limit in its `ReaderOptions` catches the second
+    /// time around.
+    pub fn get<'a>(&'a self) -> ::capnp::Result<<T as Owned<'a>>::Reader> {
+        self.message.get_root()
+    }
+
+    pub fn into_inner(self) -> message::Reader<S> {
+        self.message
+    }
+}
+
+/// Reads a message and checks that its root is a `T`.
+pub fn read_typed_message<S, T>(stream: S,
+                                reader_options: message::ReaderOptions,
+                                options: serialize::Options)
+                                -> Promise<(S, TypedReader<OwnedSegments, T>), ::capnp::Error>
+    where S: AsyncRead, T: for<'a> Owned<'a> + 'static
+{
+    serialize::read_message_with_options(stream, reader_options, options).map(|(stream, message)| {
+        Ok((stream, try!(TypedReader::new(message))))
+    })
+}
+
+/// Like `read_typed_message()`, but returns None on EOF.
+pub fn try_read_typed_message<S, T>(stream: S,
+                                    reader_options: message::ReaderOptions,
+                                    options: serialize::Options)
+                                    -> Promise<(S, Option<TypedReader<OwnedSegments, T>>), ::capnp::Error>
+    where S: AsyncRead, T: for<'a> Owned<'a> + 'static
+{
+    serialize::try_read_message_with_options(stream, reader_options, options).map(|(stream, message)| {
+        match message {
+            Some(m) => Ok((stream, Some(try!(TypedReader::new(m))))),
+            None => Ok((stream, None)),
+        }
+    })
+}
@@ -0,0 +1,213 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Sending the contents of a stream, such as a file, as a series of messages.
+//!
+//! Each message carries one chunk of the stream and the offset at which the chunk starts.
+//! `send_file()` waits for each message to be written before reading the next chunk, so a slow
+//! destination holds back reading from the source instead of letting chunks pile up in memory.
+//! `receive_file()` does the reverse, writing each chunk straight out of the received message.
+
+use capnp::message;
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite};
+use serialize;
+
+/// Accessors for the messages sent by `send_file()`. They match what the Cap'n Proto compiler
+/// would generate for:
+///
+/// ```text
+/// struct FileChunk {
+///   offset @0 :UInt64;  # Position of `data` within the stream.
+///   data @1 :Data;      # Empty in the final message, which marks the end of the stream.
+/// }
+/// ```
+pub mod file_chunk {
+    use capnp::Result;
+    use capnp::private::layout;
+
+    const STRUCT_SIZE: layout::StructSize = layout::StructSize { data: 1, pointers: 1 };
+
+    #[derive(Clone, Copy)]
+    pub struct Reader<'a> { reader: layout::StructReader<'a> }
+
+    impl <'a> ::capnp::traits::FromPointerReader<'a> for Reader<'a> {
+        fn get_from_pointer(reader: &layout::PointerReader<'a>) -> Result<Reader<'a>> {
+            Ok(Reader { reader: try!(reader.get_struct(::std::ptr::null())) })
+        }
+    }
+
+    impl <'a> Reader<'a> {
+        pub fn get_offset(self) -> u64 {
+            self.reader.get_data_field::<u64>(0)
+        }
+
+        pub fn get_data(self) -> Result<::capnp::data::Reader<'a>> {
+            self.reader.get_pointer_field(0).get_data(::std::ptr::null(), 0)
+        }
+    }
+
+    pub struct Builder<'a> { builder: layout::StructBuilder<'a> }
+
+    impl <'a> ::capnp::traits::FromPointerBuilder<'a> for Builder<'a> {
+        fn init_pointer(builder: layout::PointerBuilder<'a>, _size: u32) -> Builder<'a> {
+            Builder { builder: builder.init_struct(STRUCT_SIZE) }
+        }
+
+        fn get_from_pointer(builder: layout::PointerBuilder<'a>) -> Result<Builder<'a>> {
+            Ok(Builder { builder: try!(builder.get_struct(STRUCT_SIZE, ::std::ptr::null())) })
+        }
+    }
+
+    impl <'a> Builder<'a> {
+        pub fn set_offset(&mut self, value: u64) {
+            self.builder.set_data_field::<u64>(0, value);
+        }
+
+        pub fn set_data(&mut self, value: ::capnp::data::Reader) {
+            self.builder.get_pointer_field(0).set_data(value);
+        }
+    }
+}
+
+/// Options controlling a call to `send_file()` or `receive_file()`.
+#[derive(Clone, Copy, Debug)]
+pub struct FileOptions {
+    chunk_bytes: usize,
+    reader_options: message::ReaderOptions,
+    serialize_options: serialize::Options,
+}
+
+impl FileOptions {
+    pub fn new() -> FileOptions {
+        FileOptions {
+            chunk_bytes: 64 * 1024,
+            reader_options: message::ReaderOptions::new(),
+            serialize_options: serialize::Options::new(),
+        }
+    }
+
+    /// The largest amount of data to put in one message. Defaults to 64 KiB.
+    pub fn chunk_bytes(mut self, value: usize) -> FileOptions {
+        self.chunk_bytes = ::std::cmp::max(value, 1);
+        self
+    }
+
+    /// Options used by `receive_file()` when reading messages.
+    pub fn reader_options(mut self, value: message::ReaderOptions) -> FileOptions {
+        self.reader_options = value;
+        self
+    }
+
+    /// Options used when reading and writing messages.
+    pub fn serialize_options(mut self, value: serialize::Options) -> FileOptions {
+        self.serialize_options = value;
+        self
+    }
+}
+
+impl Default for FileOptions {
+    fn default() -> FileOptions { FileOptions::new() }
+}
+
+/// Reads `from` until EOF and writes its contents to `to` as `file_chunk` messages, followed by a
+/// message with no data. Resolves to both streams and the number of bytes sent.
+pub fn send_file<R, W>(from: R, to: W, options: FileOptions) -> Promise<(R, W, u64), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let buf: Vec<u8> = vec![0; options.chunk_bytes];
+    send_file_loop(from, to, options, buf, 0)
+}
+
+fn send_file_loop<R, W>(mut from: R,
+                        to: W,
+                        options: FileOptions,
+                        buf: Vec<u8>,
+                        offset: u64) -> Promise<(R, W, u64), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let chunk_bytes = options.chunk_bytes;
+    from.try_read(buf, chunk_bytes).then_else(move |r| match r {
+        Err(e) => Promise::err(e.into()),
+        Ok((buf, n)) => {
+            write_chunk(to, offset, &buf[..n], options).then(move |to| {
+                let offset = offset + n as u64;
+                if n == 0 {
+                    Promise::ok((from, to, offset))
+                } else if n < chunk_bytes {
+                    // Reached EOF. Send the terminating empty chunk.
+                    write_chunk(to, offset, &[], options).map(move |to| Ok((from, to, offset)))
+                } else {
+                    send_file_loop(from, to, options, buf, offset)
+                }
+            })
+        }
+    })
+}
+
+fn write_chunk<W>(to: W, offset: u64, data: &[u8], options: FileOptions) -> Promise<W, ::capnp::Error>
+    where W: AsyncWrite + 'static
+{
+    let mut message = message::Builder::new_default();
+    {
+        let mut chunk = message.init_root::<file_chunk::Builder>();
+        chunk.set_offset(offset);
+        chunk.set_data(data);
+    }
+    serialize::write_message_with_options(to, message, options.serialize_options).map(|(to, _)| Ok(to))
+}
+
+/// Reads the messages sent by `send_file()` from `from` and writes their data to `to`, checking
+/// that the chunks arrive in order. Resolves to both streams and the number of bytes received
+/// once the terminating empty chunk has been read.
+pub fn receive_file<R, W>(from: R, to: W, options: FileOptions) -> Promise<(R, W, u64), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    receive_file_loop(from, to, options, 0)
+}
+
+fn receive_file_loop<R, W>(from: R,
+                           to: W,
+                           options: FileOptions,
+                           offset: u64) -> Promise<(R, W, u64), ::capnp::Error>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    serialize::read_message_with_options(from, options.reader_options, options.serialize_options)
+        .then(move |(from, message)| {
+            let len = {
+                let chunk = pry!(message.get_root::<file_chunk::Reader>());
+                if chunk.get_offset() != offset {
+                    return Promise::err(::capnp::Error::failed(
+                        format!("Expected a chunk at offset {}, but got one at offset {}",
+                                offset, chunk.get_offset())))
+                }
+                pry!(chunk.get_data()).len()
+            };
+            if len == 0 {
+                return Promise::ok((from, to, offset))
+            }
+            serialize::write_field(to, message, |m| {
+                m.get_root::<file_chunk::Reader>().and_then(|chunk| chunk.get_data())
+            }, options.serialize_options).then(move |(to, _)| {
+                receive_file_loop(from, to, options, offset + len as u64)
+            })
+        })
+}
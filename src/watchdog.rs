@@ -0,0 +1,362 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Noticing reads and writes that have stopped making progress.
+//!
+//! A peer that stops sending halfway through a message, or stops reading so that writes back up,
+//! leaves the promises waiting on it pending forever, and the application hangs without saying
+//! why. Streams wrapped by a `Watchdog` keep track of their reads and writes, and the watchdog
+//! reports any that have gone without transferring a byte for longer than a set time.
+//!
+//! ```text
+//! let watchdog = Watchdog::new(Duration::from_secs(30), |stall| println!("{}", stall));
+//! let stream = watchdog.watch(&format!("connection {}", id), stream);
+//! let checking = watchdog.run(&timer, Duration::from_secs(5));
+//! ```
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+
+use gj::Promise;
+use gjio::{AsyncRead, AsyncWrite, Timer};
+
+/// Writes are passed on in pieces of at most this many bytes, so that progress within a large
+/// write can be seen.
+const WRITE_CHUNK_BYTES: usize = 64 * 1024;
+
+/// The kind of operation that stalled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+/// A read or write that has not transferred a byte for a while.
+#[derive(Clone, Debug)]
+pub struct Stall {
+    /// The label that the stream was given in `Watchdog::watch()`.
+    pub label: String,
+    pub operation: Operation,
+
+    /// Bytes transferred so far by this operation.
+    pub bytes_done: u64,
+
+    /// Bytes that the operation still needs before it can complete. For a read, this counts up
+    /// to the least number of bytes that was asked for.
+    pub bytes_remaining: u64,
+
+    /// Bytes transferred in this direction over the life of the stream, this operation included.
+    pub total_bytes: u64,
+
+    /// Time since the operation last made progress, or since it started if it has made none.
+    pub stalled_for: Duration,
+
+    /// Further writes waiting on the stream behind this one. Always 0 for a read.
+    pub queued: usize,
+}
+
+impl fmt::Display for Stall {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let operation = match self.operation {
+            Operation::Read => "read",
+            Operation::Write => "write",
+        };
+        try!(write!(fmt, "{}: {} stalled for {}.{:03}s with {} bytes done and {} to go ({} in total)",
+                    self.label, operation, self.stalled_for.as_secs(), self.stalled_for.subsec_millis(),
+                    self.bytes_done, self.bytes_remaining, self.total_bytes));
+        if self.queued > 0 {
+            try!(write!(fmt, ", {} more queued behind it", self.queued));
+        }
+        Ok(())
+    }
+}
+
+struct Pending {
+    last_progress: Instant,
+    done: u64,
+    wanted: u64,
+    reported: bool,
+}
+
+impl Pending {
+    fn new(wanted: u64) -> Pending {
+        Pending { last_progress: Instant::now(), done: 0, wanted: wanted, reported: false }
+    }
+}
+
+/// What a watched stream is up to.
+struct Activity {
+    label: String,
+    reading: Option<Pending>,
+
+    /// Every write not yet finished, in the order they go to the stream. Only the first is in
+    /// progress.
+    writing: VecDeque<Pending>,
+    read_bytes: u64,
+    written_bytes: u64,
+}
+
+impl Activity {
+    /// The operation of this kind that is in progress, if any.
+    fn current(&mut self, operation: Operation) -> Option<&mut Pending> {
+        match operation {
+            Operation::Read => self.reading.as_mut(),
+            Operation::Write => self.writing.front_mut(),
+        }
+    }
+
+    fn progress(&mut self, operation: Operation, bytes: usize) {
+        match operation {
+            Operation::Read => self.read_bytes += bytes as u64,
+            Operation::Write => self.written_bytes += bytes as u64,
+        }
+        if let Some(pending) = self.current(operation) {
+            pending.last_progress = Instant::now();
+            pending.done += bytes as u64;
+            pending.reported = false;
+        }
+    }
+
+    fn stall(&mut self, operation: Operation, now: Instant, stall_after: Duration) -> Option<Stall> {
+        let total_bytes = match operation {
+            Operation::Read => self.read_bytes,
+            Operation::Write => self.written_bytes,
+        };
+        let label = self.label.clone();
+        let queued = match operation {
+            Operation::Read => 0,
+            Operation::Write => self.writing.len().saturating_sub(1),
+        };
+        let pending = match self.current(operation) {
+            Some(pending) => pending,
+            None => return None,
+        };
+        if pending.reported || now - pending.last_progress < stall_after {
+            return None
+        }
+        pending.reported = true;
+        Some(Stall {
+            label: label,
+            operation: operation,
+            bytes_done: pending.done,
+            bytes_remaining: pending.wanted.saturating_sub(pending.done),
+            total_bytes: total_bytes,
+            stalled_for: now - pending.last_progress,
+            queued: queued,
+        })
+    }
+}
+
+struct WatchdogInner {
+    stall_after: Duration,
+    report: Box<FnMut(&Stall)>,
+    streams: Vec<Weak<RefCell<Activity>>>,
+}
+
+/// Keeps an eye on the streams passed to `watch()`.
+#[derive(Clone)]
+pub struct Watchdog {
+    inner: Rc<RefCell<WatchdogInner>>,
+}
+
+impl Watchdog {
+    /// Calls `report` with each read or write that goes `stall_after` without progress. A stall
+    /// is reported once; if the operation makes progress and then stalls again, it is reported
+    /// again.
+    pub fn new<F>(stall_after: Duration, report: F) -> Watchdog where F: FnMut(&Stall) + 'static {
+        Watchdog {
+            inner: Rc::new(RefCell::new(WatchdogInner {
+                stall_after: stall_after,
+                report: Box::new(report),
+                streams: Vec::new(),
+            })),
+        }
+    }
+
+    /// Wraps `stream` so that the watchdog sees its reads and writes. `label` identifies it in
+    /// reports.
+    pub fn watch<S>(&self, label: &str, stream: S) -> Watched<S> {
+        let activity = Rc::new(RefCell::new(Activity {
+            label: label.to_string(),
+            reading: None,
+            writing: VecDeque::new(),
+            read_bytes: 0,
+            written_bytes: 0,
+        }));
+        self.inner.borrow_mut().streams.push(Rc::downgrade(&activity));
+        Watched { stream: stream, activity: activity, writes: Promise::ok(()) }
+    }
+
+    /// Looks for stalls now, reports any new ones, and returns how many there were. Forgets
+    /// streams that have been dropped.
+    pub fn check(&self) -> usize {
+        let now = Instant::now();
+        let mut stalls = Vec::new();
+        let stall_after = {
+            let mut inner = self.inner.borrow_mut();
+            inner.streams.retain(|s| s.upgrade().is_some());
+            inner.stall_after
+        };
+        for stream in self.inner.borrow().streams.iter() {
+            if let Some(activity) = stream.upgrade() {
+                let mut activity = activity.borrow_mut();
+                for &operation in &[Operation::Read, Operation::Write] {
+                    if let Some(stall) = activity.stall(operation, now, stall_after) {
+                        stalls.push(stall);
+                    }
+                }
+            }
+        }
+        // The report is called with nothing borrowed, so that it can use the watchdog itself.
+        let mut report = ::std::mem::replace(&mut self.inner.borrow_mut().report, Box::new(|_: &Stall| ()));
+        for stall in &stalls {
+            report(stall);
+        }
+        self.inner.borrow_mut().report = report;
+        stalls.len()
+    }
+
+    /// Calls `check()` every `interval`, until the returned promise is dropped.
+    pub fn run(&self, timer: &Timer, interval: Duration) -> Promise<(), ::capnp::Error> {
+        let watchdog = self.clone();
+        let timer1 = timer.clone();
+        timer.after_delay(interval).lift().then(move |()| {
+            watchdog.check();
+            watchdog.run(&timer1, interval)
+        })
+    }
+}
+
+/// A stream whose reads and writes a `Watchdog` keeps track of. Reads and writes need the
+/// underlying stream to be cloneable, as `gjio::SocketStream` is, so that they can be carried
+/// out in steps. Writes are queued, so that the steps of one never end up between those of
+/// another: each starts once the one before it has finished, and carries on even if its
+/// promise is dropped.
+pub struct Watched<S> {
+    stream: S,
+    activity: Rc<RefCell<Activity>>,
+
+    /// Resolves once the last write queued has finished, whether or not it succeeded.
+    writes: Promise<(), io::Error>,
+}
+
+impl <S> Watched<S> {
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+/// The part of a buffer after `start`.
+struct Suffix<T> {
+    buf: T,
+    start: usize,
+}
+
+impl <T> AsMut<[u8]> for Suffix<T> where T: AsMut<[u8]> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.buf.as_mut()[self.start..]
+    }
+}
+
+/// The part of a buffer from `start` to `end`.
+struct Window<T> {
+    buf: T,
+    start: usize,
+    end: usize,
+}
+
+impl <T> AsRef<[u8]> for Window<T> where T: AsRef<[u8]> {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf.as_ref()[self.start..self.end]
+    }
+}
+
+fn read_loop<S, T>(mut stream: S, activity: Rc<RefCell<Activity>>, buf: T, done: usize, min_bytes: usize)
+                   -> Promise<(T, usize), io::Error>
+    where S: AsyncRead + 'static, T: AsMut<[u8]> + 'static
+{
+    stream.try_read(Suffix { buf: buf, start: done }, 1).then(move |(suffix, n)| {
+        let done = done + n;
+        activity.borrow_mut().progress(Operation::Read, n);
+        if n == 0 || done >= min_bytes {
+            Promise::ok((suffix.buf, done))
+        } else {
+            read_loop(stream, activity, suffix.buf, done, min_bytes)
+        }
+    })
+}
+
+fn write_loop<S, T>(mut stream: S, activity: Rc<RefCell<Activity>>, buf: T, done: usize) -> Promise<T, io::Error>
+    where S: AsyncWrite + 'static, T: AsRef<[u8]> + 'static
+{
+    let len = buf.as_ref().len();
+    if done >= len {
+        return Promise::ok(buf)
+    }
+    let end = ::std::cmp::min(len, done + WRITE_CHUNK_BYTES);
+    stream.write(Window { buf: buf, start: done, end: end }).then(move |window| {
+        activity.borrow_mut().progress(Operation::Write, end - done);
+        write_loop(stream, activity, window.buf, end)
+    })
+}
+
+impl <S> AsyncRead for Watched<S> where S: AsyncRead + Clone + 'static {
+    fn try_read<T>(&mut self, mut buf: T, min_bytes: usize) -> Promise<(T, usize), io::Error>
+        where T: AsMut<[u8]>
+    {
+        let min_bytes = ::std::cmp::min(min_bytes, buf.as_mut().len());
+        if min_bytes == 0 {
+            return self.stream.try_read(buf, 0)
+        }
+        self.activity.borrow_mut().reading = Some(Pending::new(min_bytes as u64));
+        let activity = self.activity.clone();
+        read_loop(self.stream.clone(), activity.clone(), buf, 0, min_bytes).map_else(move |r| {
+            activity.borrow_mut().reading = None;
+            r
+        })
+    }
+}
+
+impl <S> AsyncWrite for Watched<S> where S: AsyncWrite + Clone + 'static {
+    fn write<T>(&mut self, buf: T) -> Promise<T, io::Error> where T: AsRef<[u8]> {
+        self.activity.borrow_mut().writing.push_back(Pending::new(buf.as_ref().len() as u64));
+        let stream = self.stream.clone();
+        let activity = self.activity.clone();
+        let (written, fulfiller) = Promise::and_fulfiller();
+        let previous = ::std::mem::replace(&mut self.writes, Promise::ok(()));
+        self.writes = previous.then(move |()| {
+            // Time spent waiting behind earlier writes is not this write's stall.
+            if let Some(pending) = activity.borrow_mut().writing.front_mut() {
+                pending.last_progress = Instant::now();
+            }
+            write_loop(stream, activity.clone(), buf, 0).map_else(move |r| {
+                activity.borrow_mut().writing.pop_front();
+                fulfiller.resolve(r);
+                Ok(())
+            })
+        }).eagerly_evaluate();
+        written
+    }
+}
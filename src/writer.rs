@@ -0,0 +1,347 @@
+// Copyright (c) 2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Message sinks that can be stored and swapped behind a common trait object.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::rc::Rc;
+use std::time::Instant;
+
+use byteorder::{ByteOrder, LittleEndian};
+use capnp::{message, Word};
+use capnp::message::ReaderSegments;
+use gj::Promise;
+use gjio::AsyncWrite;
+use fulfiller::{self, Fulfiller};
+use metrics::{MessageStats, Metrics};
+use serialize;
+use timing::{Recorder, Span};
+use Error;
+
+/// A destination for messages. This trait is object safe, so that an application can keep
+/// destinations of different kinds in one collection as `Box<AsyncMessageWriter>`.
+pub trait AsyncMessageWriter {
+    /// Queues a message for writing, given as its segments. For a `message::Builder`, pass
+    /// `&message.get_segments_for_output()`. The segments are copied before this method returns.
+    /// The returned promise resolves once the message has been written, but writes happen
+    /// in order and make progress even if the promise is dropped. Fails with
+    /// `Error::NoSegments` if `segments` is empty.
+    fn write_segments(&mut self, segments: &[&[Word]]) -> Promise<(), ::capnp::Error>;
+}
+
+impl <W> AsyncMessageWriter for Box<W> where W: AsyncMessageWriter + ?Sized {
+    fn write_segments(&mut self, segments: &[&[Word]]) -> Promise<(), ::capnp::Error> {
+        (**self).write_segments(segments)
+    }
+}
+
+/// Lets a vector of words be written as bytes.
+struct Words(Rc<Vec<Word>>);
+
+impl AsRef<[u8]> for Words {
+    fn as_ref(&self) -> &[u8] {
+        Word::words_to_bytes(&self.0[..])
+    }
+}
+
+/// Copies `segments`, preceded by their segment table, into a single buffer.
+pub(crate) fn flatten(segments: &[&[Word]]) -> Result<Vec<Word>, Error> {
+    if segments.is_empty() {
+        return Err(Error::NoSegments)
+    }
+    let table_words = (segments.len() + 2) / 2;
+    let total_words = segments.iter().fold(table_words, |n, s| n + s.len());
+    let mut words = Word::allocate_zeroed_vec(table_words);
+    words.reserve(total_words - table_words);
+    {
+        let table = Word::words_to_bytes_mut(&mut words[..]);
+        LittleEndian::write_u32(&mut table[0..4], segments.len() as u32 - 1);
+        for (idx, segment) in segments.iter().enumerate() {
+            LittleEndian::write_u32(&mut table[((idx + 1) * 4)..((idx + 2) * 4)], segment.len() as u32);
+        }
+    }
+    for segment in segments {
+        words.extend_from_slice(segment);
+    }
+    Ok(words)
+}
+
+/// Writes messages to an `AsyncWrite`, such as a socket or a file, one after another.
+pub struct StreamWriter<S> where S: AsyncWrite + 'static {
+    queue: Promise<S, ::capnp::Error>,
+    options: serialize::Options,
+
+    // Messages not yet known to be written, oldest first.
+    pending: Rc<RefCell<VecDeque<Rc<Vec<Word>>>>>,
+    waiters: Rc<RefCell<Waiters>>,
+    recorder: Option<Recorder>,
+    metrics: Option<Rc<Metrics>>,
+}
+
+/// Callers of `StreamWriter::wait_for_pending()`, each with the queue length it is waiting for.
+struct Waiters {
+    waiting: Vec<(usize, Fulfiller<()>)>,
+    failure: Option<::capnp::Error>,
+}
+
+impl Waiters {
+    fn wake(&mut self, pending_len: usize) {
+        let waiting = ::std::mem::replace(&mut self.waiting, Vec::new());
+        for (at_most, fulfiller) in waiting {
+            if pending_len <= at_most {
+                fulfiller.fulfill(());
+            } else {
+                self.waiting.push((at_most, fulfiller));
+            }
+        }
+    }
+
+    fn fail(&mut self, error: &::capnp::Error) {
+        for (_, fulfiller) in self.waiting.drain(..) {
+            fulfiller.reject(error.clone());
+        }
+        self.failure = Some(error.clone());
+    }
+}
+
+impl <S> StreamWriter<S> where S: AsyncWrite + 'static {
+    pub fn new(stream: S) -> StreamWriter<S> {
+        StreamWriter::with_options(stream, serialize::Options::new())
+    }
+
+    pub fn with_options(stream: S, options: serialize::Options) -> StreamWriter<S> {
+        StreamWriter {
+            queue: Promise::ok(stream),
+            options: options,
+            pending: Rc::new(RefCell::new(VecDeque::new())),
+            waiters: Rc::new(RefCell::new(Waiters { waiting: Vec::new(), failure: None })),
+            recorder: None,
+            metrics: None,
+        }
+    }
+
+    /// Reports how long each message spends on `timing::Span::Serialization`, `Queueing` and
+    /// `BodyTransfer` to `recorder`.
+    pub fn record_timings(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Reports each message to `metrics` once it has been written, with the time since it was
+    /// queued.
+    pub fn set_metrics(&mut self, metrics: Rc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// The number of messages that have been queued but not yet written, including one that is
+    /// partly written and any that will never be written because an earlier write failed.
+    pub fn pending_len(&self) -> usize {
+        self.pending.borrow().len()
+    }
+
+    /// Resolves once `pending_len()` is at most `at_most`, or fails if a write has failed.
+    pub fn wait_for_pending(&self, at_most: usize) -> Promise<(), ::capnp::Error> {
+        let mut waiters = self.waiters.borrow_mut();
+        if let Some(e) = waiters.failure.clone() {
+            Promise::err(e)
+        } else if self.pending_len() <= at_most {
+            Promise::ok(())
+        } else {
+            let (promise, fulfiller) = fulfiller::and_fulfiller();
+            waiters.waiting.push((at_most, fulfiller));
+            promise
+        }
+    }
+
+    /// Saves the messages counted by `pending_len()`, in order, in the standard framing, so that
+    /// a later process can pass them to `restore_pending()`. A message that was partly written
+    /// is included, so a receiver may see it twice.
+    pub fn save_pending<W>(&self, out: &mut W) -> io::Result<()> where W: Write {
+        for words in self.pending.borrow().iter() {
+            try!(out.write_all(Word::words_to_bytes(&words[..])));
+        }
+        Ok(())
+    }
+
+    /// Reads messages saved by `save_pending()` and queues them for writing. Returns how many
+    /// there were.
+    pub fn restore_pending<R>(&mut self, input: R) -> ::capnp::Result<usize> where R: Read {
+        let mut options = message::ReaderOptions::new();
+        options.traversal_limit_in_words(::std::u64::MAX);
+        let mut input = BufReader::new(input);
+        let mut count = 0;
+        while !try!(input.fill_buf()).is_empty() {
+            let segments = try!(::capnp::serialize::read_message(&mut input, options)).into_segments();
+            let segments: Vec<&[Word]> = (0..).map(|id| segments.get_segment(id)).take_while(Option::is_some)
+                                              .map(Option::unwrap).collect();
+            // Like any write, this makes progress without its promise.
+            let _ = self.write_segments(&segments);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Waits for all queued messages to be written and then returns the stream.
+    pub fn into_stream(self) -> Promise<S, ::capnp::Error> {
+        self.queue
+    }
+}
+
+impl <S> AsyncMessageWriter for StreamWriter<S> where S: AsyncWrite + 'static {
+    fn write_segments(&mut self, segments: &[&[Word]]) -> Promise<(), ::capnp::Error> {
+        let started = Instant::now();
+        let words = Rc::new(pry!(flatten(segments)));
+        let bytes = words.len() as u64 * 8;
+        let serialized = Instant::now();
+        if let Some(ref recorder) = self.recorder {
+            recorder.record(Span::Serialization, started, serialized, bytes);
+        }
+        self.pending.borrow_mut().push_back(words.clone());
+        let words = Words(words);
+        let options = self.options;
+        let pending = self.pending.clone();
+        let waiters = self.waiters.clone();
+        let recorder = self.recorder.clone();
+        let metrics = self.metrics.clone();
+        let segment_count = segments.len();
+        let (done, fulfiller) = fulfiller::and_fulfiller();
+        let previous = ::std::mem::replace(&mut self.queue, Promise::never_done());
+        self.queue = previous.then(move |stream| {
+            let dequeued = Instant::now();
+            if let Some(ref recorder) = recorder {
+                recorder.record(Span::Queueing, serialized, dequeued, bytes);
+            }
+            serialize::write_raw_message_with_options(stream, words, None, options).map(move |written| {
+                if let Some(ref recorder) = recorder {
+                    recorder.record(Span::BodyTransfer, dequeued, Instant::now(), bytes);
+                }
+                Ok(written)
+            })
+        }).map_else(move |r| match r {
+            Ok((stream, _)) => {
+                if let Some(ref metrics) = metrics {
+                    metrics.message_written(&MessageStats { bytes: bytes, segments: segment_count,
+                                                            elapsed: started.elapsed() });
+                }
+                pending.borrow_mut().pop_front();
+                fulfiller.fulfill(());
+                waiters.borrow_mut().wake(pending.borrow().len());
+                Ok(stream)
+            }
+            Err(e) => {
+                fulfiller.reject(e.clone());
+                waiters.borrow_mut().fail(&e);
+                Err(e)
+            }
+        }).eagerly_evaluate();
+        done
+    }
+}
+
+/// A `StreamWriter` whose queue holds at most a fixed number of messages, for producers that
+/// need backpressure. A producer sends until `is_full()` and then waits on `ready()`, which
+/// resolves once the queue has drained down to the low watermark.
+pub struct MessageSender<S> where S: AsyncWrite + 'static {
+    writer: StreamWriter<S>,
+    capacity: usize,
+    low_watermark: usize,
+}
+
+impl <S> MessageSender<S> where S: AsyncWrite + 'static {
+    pub fn new(stream: S, capacity: usize, low_watermark: usize) -> MessageSender<S> {
+        MessageSender::with_options(stream, capacity, low_watermark, serialize::Options::new())
+    }
+
+    pub fn with_options(stream: S, capacity: usize, low_watermark: usize,
+                        options: serialize::Options) -> MessageSender<S> {
+        assert!(low_watermark < capacity, "low watermark must be below the capacity");
+        MessageSender {
+            writer: StreamWriter::with_options(stream, options),
+            capacity: capacity,
+            low_watermark: low_watermark,
+        }
+    }
+
+    /// Queues `message` for writing, like `write_segments()`.
+    pub fn send<A>(&mut self, message: &message::Builder<A>) -> Promise<(), ::capnp::Error>
+        where A: message::Allocator
+    {
+        self.write_segments(&message.get_segments_for_output())
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.writer.pending_len() >= self.capacity
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.writer.pending_len()
+    }
+
+    /// Resolves once at most the low watermark's number of messages are waiting to be written.
+    pub fn ready(&self) -> Promise<(), ::capnp::Error> {
+        self.writer.wait_for_pending(self.low_watermark)
+    }
+
+    /// Waits for all queued messages to be written and then returns the stream.
+    pub fn into_stream(self) -> Promise<S, ::capnp::Error> {
+        self.writer.into_stream()
+    }
+}
+
+impl <S> AsyncMessageWriter for MessageSender<S> where S: AsyncWrite + 'static {
+    /// Fails with an `Overloaded` error, without queueing the message, if the queue is full.
+    fn write_segments(&mut self, segments: &[&[Word]]) -> Promise<(), ::capnp::Error> {
+        if self.is_full() {
+            return Promise::err(::capnp::Error::overloaded(
+                format!("send queue is full: {} messages are waiting to be written", self.capacity)));
+        }
+        self.writer.write_segments(segments)
+    }
+}
+
+/// Writes each message to every one of a set of destinations.
+pub struct Broadcast {
+    writers: Vec<Box<AsyncMessageWriter>>,
+}
+
+impl Broadcast {
+    pub fn new(writers: Vec<Box<AsyncMessageWriter>>) -> Broadcast {
+        Broadcast { writers: writers }
+    }
+
+    pub fn push(&mut self, writer: Box<AsyncMessageWriter>) {
+        self.writers.push(writer);
+    }
+
+    pub fn into_writers(self) -> Vec<Box<AsyncMessageWriter>> {
+        self.writers
+    }
+}
+
+impl AsyncMessageWriter for Broadcast {
+    /// Resolves once every destination has written the message, or fails as soon as one of them
+    /// fails.
+    fn write_segments(&mut self, segments: &[&[Word]]) -> Promise<(), ::capnp::Error> {
+        let writes: Vec<_> = self.writers.iter_mut().map(|w| w.write_segments(segments)).collect();
+        Promise::all(writes.into_iter()).map(|_| Ok(()))
+    }
+}